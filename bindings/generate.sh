@@ -0,0 +1,21 @@
+#!/usr/bin/env bash
+# Builds blitzi as a shared library for the host and generates the Kotlin and
+# Swift bindings from it into bindings/kotlin and bindings/swift. Pass extra
+# cargo flags (e.g. `--release` or `--features db-redb`) as arguments.
+set -euo pipefail
+
+cd "$(dirname "$0")/.."
+
+cargo rustc --lib --features uniffi --crate-type cdylib "$@"
+case "$(uname)" in
+    Darwin) library=target/debug/libblitzi.dylib ;;
+    *) library=target/debug/libblitzi.so ;;
+esac
+if [[ " $* " == *" --release "* ]]; then
+    library=${library/debug/release}
+fi
+
+for language in kotlin swift; do
+    cargo run --features uniffi-bindgen --bin uniffi-bindgen -- generate \
+        --library "$library" --language "$language" --out-dir "bindings/$language"
+done
@@ -0,0 +1,174 @@
+#ifndef BLITZI_H
+#define BLITZI_H
+
+/* Generated by cbindgen from src/capi.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Result of a call to the C interface.
+ */
+typedef enum BlitziStatus {
+  /**
+   * The call succeeded
+   */
+  BLITZI_STATUS_OK = 0,
+  /**
+   * A required pointer was null or a string wasn't valid UTF-8
+   */
+  BLITZI_STATUS_INVALID_ARGUMENT = 1,
+  /**
+   * The invoice couldn't be parsed or paid, e.g. because it expired
+   */
+  BLITZI_STATUS_INVALID_INVOICE = 2,
+  /**
+   * The invoice amount is zero or too large
+   */
+  BLITZI_STATUS_INVALID_AMOUNT = 3,
+  /**
+   * The invoice description is too long or contains control characters
+   */
+  BLITZI_STATUS_INVALID_DESCRIPTION = 4,
+  /**
+   * No Lightning gateway is available
+   */
+  BLITZI_STATUS_GATEWAY_UNAVAILABLE = 5,
+  /**
+   * Receiving the amount would exceed the configured balance cap
+   */
+  BLITZI_STATUS_BALANCE_CAP_EXCEEDED = 6,
+  /**
+   * The payment would exceed the configured spend limit
+   */
+  BLITZI_STATUS_SPEND_LIMIT_EXCEEDED = 7,
+  /**
+   * The configured payment policy rejected the payment
+   */
+  BLITZI_STATUS_POLICY_DENIED = 8,
+  /**
+   * The invoice was already paid
+   */
+  BLITZI_STATUS_ALREADY_PAID = 9,
+  /**
+   * The data directory is in use by another process
+   */
+  BLITZI_STATUS_DATADIR_LOCKED = 10,
+  /**
+   * The wallet's seed is encrypted and no passphrase was given
+   */
+  BLITZI_STATUS_PASSPHRASE_REQUIRED = 11,
+  /**
+   * The passphrase doesn't decrypt the wallet's seed
+   */
+  BLITZI_STATUS_WRONG_PASSPHRASE = 12,
+  /**
+   * Blitzi panicked, see [`blitzi_last_error_message`]. The handle may be in
+   * an inconsistent state and should be freed.
+   */
+  BLITZI_STATUS_PANICKED = 13,
+  /**
+   * Any other error, see [`blitzi_last_error_message`]
+   */
+  BLITZI_STATUS_OTHER = 99,
+} BlitziStatus;
+
+/**
+ * Opaque handle of a Blitzi client created using [`blitzi_new`].
+ */
+typedef struct BlitziHandle BlitziHandle;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Opens the wallet in `datadir`, joining the federation with the invite code
+ * `invite` (or the default federation if `invite` is null) if the directory
+ * doesn't contain one yet, and writes its handle to `out_handle`.
+ *
+ * # Safety
+ * `datadir` and `invite` (if not null) must be valid NUL-terminated strings
+ * and `out_handle` must be valid for writes.
+ */
+BlitziStatus blitzi_new(const char *datadir, const char *invite, BlitziHandle **out_handle);
+
+#if defined(BLITZI_TEST_UTIL)
+/**
+ * Creates a handle backed by an in-memory mock instead of a federation,
+ * whose invoices are paid right away, for testing programs using the C
+ * interface. Requires the `test-util` feature.
+ *
+ * # Safety
+ * `out_handle` must be valid for writes.
+ */
+BlitziStatus blitzi_new_mock(BlitziHandle **out_handle);
+#endif
+
+/**
+ * Creates a Lightning invoice for `amount_msats` and writes it, BOLT11
+ * encoded, to `out_bolt11`.
+ *
+ * # Safety
+ * `handle` must have been returned by [`blitzi_new`] and not been freed,
+ * `description` must be a valid NUL-terminated string and `out_bolt11` must
+ * be valid for writes.
+ */
+BlitziStatus blitzi_invoice(const BlitziHandle *handle,
+                            uint64_t amount_msats,
+                            const char *description,
+                            char **out_bolt11);
+
+/**
+ * Pays the BOLT11 invoice `bolt11` and writes the hex encoded preimage to
+ * `out_preimage_hex`.
+ *
+ * # Safety
+ * `handle` must have been returned by [`blitzi_new`] and not been freed,
+ * `bolt11` must be a valid NUL-terminated string and `out_preimage_hex` must
+ * be valid for writes.
+ */
+BlitziStatus blitzi_pay(const BlitziHandle *handle, const char *bolt11, char **out_preimage_hex);
+
+/**
+ * Writes the balance in millisatoshi to `out_msats`.
+ *
+ * # Safety
+ * `handle` must have been returned by [`blitzi_new`] and not been freed and
+ * `out_msats` must be valid for writes.
+ */
+BlitziStatus blitzi_balance(const BlitziHandle *handle, uint64_t *out_msats);
+
+/**
+ * Shuts down the client and frees `handle`. Does nothing if `handle` is
+ * null.
+ *
+ * # Safety
+ * `handle` must have been returned by [`blitzi_new`], must not be used by
+ * other threads anymore and must not be used after this call.
+ */
+void blitzi_free(BlitziHandle *handle);
+
+/**
+ * Frees a string returned by Blitzi. Does nothing if `s` is null.
+ *
+ * # Safety
+ * `s` must have been returned by Blitzi and must not be used after this
+ * call.
+ */
+void blitzi_string_free(char *s);
+
+/**
+ * Returns the message of the last error returned on the calling thread, or
+ * null if there was none. The message is owned by Blitzi and stays valid
+ * until the next call on the same thread.
+ */
+const char *blitzi_last_error_message(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* BLITZI_H */
@@ -0,0 +1,57 @@
+/*
+ * Links against the Blitzi shared library and creates an invoice using the
+ * in-memory mock, see capi/README.md.
+ */
+#define BLITZI_TEST_UTIL
+#include "blitzi.h"
+
+#include <stdio.h>
+#include <string.h>
+
+static int check(BlitziStatus status, const char *call) {
+    if (status != BLITZI_STATUS_OK) {
+        const char *message = blitzi_last_error_message();
+        fprintf(stderr, "%s failed with status %d: %s\n", call, status,
+                message ? message : "(no message)");
+        return 1;
+    }
+    return 0;
+}
+
+int main(void) {
+    BlitziHandle *handle = NULL;
+    if (check(blitzi_new_mock(&handle), "blitzi_new_mock")) {
+        return 1;
+    }
+
+    char *bolt11 = NULL;
+    if (check(blitzi_invoice(handle, 1000, "C test", &bolt11), "blitzi_invoice")) {
+        return 1;
+    }
+    if (strncmp(bolt11, "ln", 2) != 0) {
+        fprintf(stderr, "unexpected invoice %s\n", bolt11);
+        return 1;
+    }
+    printf("invoice: %s\n", bolt11);
+    blitzi_string_free(bolt11);
+
+    /* Invalid arguments are reported instead of crashing */
+    if (blitzi_invoice(handle, 1000, NULL, &bolt11) != BLITZI_STATUS_INVALID_ARGUMENT) {
+        fprintf(stderr, "expected an invalid argument error\n");
+        return 1;
+    }
+    if (blitzi_invoice(handle, 0, "zero", &bolt11) != BLITZI_STATUS_INVALID_AMOUNT) {
+        fprintf(stderr, "expected an invalid amount error\n");
+        return 1;
+    }
+    printf("error message: %s\n", blitzi_last_error_message());
+
+    uint64_t balance = 0;
+    if (check(blitzi_balance(handle, &balance), "blitzi_balance")) {
+        return 1;
+    }
+    printf("balance: %llu msat\n", (unsigned long long)balance);
+
+    blitzi_free(handle);
+    return 0;
+}
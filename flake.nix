@@ -68,6 +68,9 @@
           inherit src;
           strictDeps = true;
 
+          # The daemon's dependencies are behind the `daemon` feature
+          cargoExtraArgs = "--features daemon";
+
           nativeBuildInputs = with pkgs; [
             pkg-config
             cmake
@@ -105,7 +108,9 @@
           // {
             inherit cargoArtifacts;
 
-            cargoExtraArgs = "--bin blitzid";
+            cargoExtraArgs = "--bin blitzid --features daemon";
+            # Reported by GET /version, unset for builds of a dirty tree
+            BLITZID_GIT_HASH = self.rev or null;
 
             meta = with lib; {
               description = "Blitzi Lightning REST API daemon";
@@ -0,0 +1,163 @@
+//! Sub-accounts partitioning the funds of a wallet, see
+//! [`Blitzi::account`](crate::Blitzi::account).
+//!
+//! Every account other than the default one (index 0) is a separate Fedimint
+//! client whose root secret is derived from the wallet's mnemonic and whose
+//! data is stored under its own prefix of the wallet's database. Accounts
+//! therefore hold their own ecash notes and operation history, and can be
+//! recovered from the mnemonic like the default account.
+use std::ops::Deref;
+use std::sync::Arc;
+
+use fedimint_bip39::{Bip39RootSecretStrategy, Mnemonic};
+use fedimint_client::RootSecret;
+use fedimint_client::secret::RootSecretStrategy;
+use fedimint_core::db::{Database, IDatabaseTransactionOpsCore};
+use fedimint_derive_secret::ChildId;
+use futures_lite::StreamExt;
+
+use crate::Blitzi;
+
+/// Derivation path segment separating account secrets from the secrets
+/// Fedimint derives from the root secret itself ("blit" in ASCII).
+const ACCOUNT_CHILD_ID: ChildId = ChildId(0x626c_6974);
+
+/// Prefix of the databases of the accounts, followed by the index of the
+/// account. In the key range Fedimint reserves for external use
+/// (`0xb1..=0xcf`).
+const ACCOUNT_DB_PREFIX: &[u8] = b"\xb1blitzi/account-db/";
+
+/// Prefix of the indices of the accounts opened so far, see [`register`].
+const ACCOUNTS_PREFIX: &[u8] = b"\xb1blitzi/accounts/";
+
+/// An account of a wallet returned by
+/// [`Blitzi::account`](crate::Blitzi::account). Dereferences to [`Blitzi`], so
+/// it offers the same methods, e.g. [`Blitzi::lightning_invoice`],
+/// [`Blitzi::pay`] and [`Blitzi::balance`], which only use the account's own
+/// funds.
+pub struct BlitziAccount<'a> {
+    index: u32,
+    blitzi: AccountClient<'a>,
+}
+
+enum AccountClient<'a> {
+    /// The default account is the wallet itself
+    Default(&'a Blitzi),
+    Sub(Arc<Blitzi>),
+}
+
+impl<'a> BlitziAccount<'a> {
+    pub(crate) fn default_account(blitzi: &'a Blitzi) -> Self {
+        BlitziAccount {
+            index: 0,
+            blitzi: AccountClient::Default(blitzi),
+        }
+    }
+
+    pub(crate) fn sub_account(index: u32, blitzi: Arc<Blitzi>) -> Self {
+        BlitziAccount {
+            index,
+            blitzi: AccountClient::Sub(blitzi),
+        }
+    }
+
+    /// Returns the index of the account, 0 for the default account.
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+}
+
+impl Deref for BlitziAccount<'_> {
+    type Target = Blitzi;
+
+    fn deref(&self) -> &Blitzi {
+        match &self.blitzi {
+            AccountClient::Default(blitzi) => blitzi,
+            AccountClient::Sub(blitzi) => blitzi,
+        }
+    }
+}
+
+/// Returns the database of the account with `index` within the wallet's
+/// database `db`.
+pub(crate) fn account_db(db: &Database, index: u32) -> Database {
+    db.with_prefix([ACCOUNT_DB_PREFIX, &index.to_be_bytes()].concat())
+}
+
+/// Derives the root secret of the account with `index` from the wallet's
+/// `mnemonic`.
+pub(crate) fn root_secret(mnemonic: &Mnemonic, index: u32) -> RootSecret {
+    RootSecret::StandardDoubleDerive(
+        Bip39RootSecretStrategy::<12>::to_root_secret(mnemonic)
+            .child_key(ACCOUNT_CHILD_ID)
+            .child_key(ChildId(index.into())),
+    )
+}
+
+/// Records that the account with `index` was opened, so
+/// [`Blitzi::account_balances`](crate::Blitzi::account_balances) considers it.
+pub(crate) async fn register(db: &Database, index: u32) -> anyhow::Result<()> {
+    let mut dbtx = db.begin_transaction().await;
+    dbtx.raw_insert_bytes(&[ACCOUNTS_PREFIX, &index.to_be_bytes()].concat(), &[])
+        .await?;
+    dbtx.commit_tx_result().await?;
+    Ok(())
+}
+
+/// Returns the indices of the accounts opened so far, excluding the default
+/// account, in ascending order.
+pub(crate) async fn registered(db: &Database) -> anyhow::Result<Vec<u32>> {
+    let mut dbtx = db.begin_transaction_nc().await;
+    let keys = dbtx
+        .raw_find_by_prefix(ACCOUNTS_PREFIX)
+        .await?
+        .map(|(key, _)| key)
+        .collect::<Vec<_>>()
+        .await;
+
+    keys.into_iter()
+        .map(|key| {
+            let index = key[ACCOUNTS_PREFIX.len()..]
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Recorded account index must be 4 bytes"))?;
+            Ok(u32::from_be_bytes(index))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use fedimint_core::db::IRawDatabaseExt;
+    use fedimint_core::db::mem_impl::MemDatabase;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_registered_accounts() {
+        let db = MemDatabase::new().into_database();
+        assert!(registered(&db).await.unwrap().is_empty());
+
+        for index in [300, 1, 2, 1] {
+            register(&db, index).await.unwrap();
+        }
+        assert_eq!(registered(&db).await.unwrap(), vec![1, 2, 300]);
+    }
+
+    #[test]
+    fn test_root_secret() {
+        let mnemonic = Mnemonic::generate(12).unwrap();
+
+        // Every account gets its own secret, distinct from the default account
+        let mut secrets =
+            vec![Bip39RootSecretStrategy::<12>::to_root_secret(&mnemonic).to_random_bytes::<32>()];
+        for index in [1, 2, 1] {
+            let RootSecret::StandardDoubleDerive(secret) = root_secret(&mnemonic, index) else {
+                panic!("Accounts use the standard derivation");
+            };
+            secrets.push(secret.to_random_bytes::<32>());
+        }
+        assert_ne!(secrets[0], secrets[1]);
+        assert_ne!(secrets[1], secrets[2]);
+        assert_eq!(secrets[1], secrets[3]);
+    }
+}
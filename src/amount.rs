@@ -0,0 +1,128 @@
+//! Helpers for constructing and displaying [`Amount`]s.
+use fedimint_core::Amount;
+use fedimint_core::bitcoin::Network;
+
+const MSATS_PER_SAT: u64 = 1_000;
+const SATS_PER_BTC: u64 = 100_000_000;
+
+/// Creates an [`Amount`] from millisatoshi.
+pub const fn msats(msats: u64) -> Amount {
+    Amount::from_msats(msats)
+}
+
+/// Creates an [`Amount`] from satoshi.
+///
+/// # Panics
+/// Panics if the amount can't be represented in millisatoshi, which is
+/// roughly 184 times the total bitcoin supply. Use [`checked_sats`] to handle
+/// that case.
+pub const fn sats(sats: u64) -> Amount {
+    match checked_sats(sats) {
+        Some(amount) => amount,
+        None => panic!("Amount in sats overflows millisatoshi representation"),
+    }
+}
+
+/// Creates an [`Amount`] from satoshi, returns `None` if the amount can't be
+/// represented in millisatoshi.
+pub const fn checked_sats(sats: u64) -> Option<Amount> {
+    match sats.checked_mul(MSATS_PER_SAT) {
+        Some(msats) => Some(Amount::from_msats(msats)),
+        None => None,
+    }
+}
+
+/// Formats an amount as satoshi, e.g. `1234 sats` or `1234.567 sats` if the
+/// amount isn't a whole number of satoshi.
+pub fn format_sats(amount: Amount) -> String {
+    let sats = amount.msats / MSATS_PER_SAT;
+    let remainder = amount.msats % MSATS_PER_SAT;
+    if remainder == 0 {
+        format!("{} sats", sats)
+    } else {
+        format!("{}.{:03} sats", sats, remainder)
+    }
+}
+
+/// Formats an amount as bitcoin with 8 decimals, e.g. `0.00001234 BTC`. If the
+/// amount isn't a whole number of satoshi 11 decimals are used.
+pub fn format_btc(amount: Amount) -> String {
+    let msats_per_btc = SATS_PER_BTC * MSATS_PER_SAT;
+    let btc = amount.msats / msats_per_btc;
+    let remainder = amount.msats % msats_per_btc;
+    if remainder % MSATS_PER_SAT == 0 {
+        format!("{}.{:08} BTC", btc, remainder / MSATS_PER_SAT)
+    } else {
+        format!("{}.{:011} BTC", btc, remainder)
+    }
+}
+
+/// Formats an amount like [`format_sats`], but tags amounts on test networks
+/// as `tsats` (e.g. `1234 tsats`) so they can't be mistaken for real funds.
+/// Pass [`Blitzi::network`](crate::Blitzi::network) as `network`.
+pub fn format_sats_on(amount: Amount, network: Network) -> String {
+    let formatted = format_sats(amount);
+    match network {
+        Network::Bitcoin => formatted,
+        _ => format!("{}tsats", formatted.trim_end_matches("sats")),
+    }
+}
+
+/// Formats an amount like [`format_btc`], but tags amounts on test networks
+/// as `tBTC` (e.g. `0.00001234 tBTC`).
+pub fn format_btc_on(amount: Amount, network: Network) -> String {
+    let formatted = format_btc(amount);
+    match network {
+        Network::Bitcoin => formatted,
+        _ => format!("{}tBTC", formatted.trim_end_matches("BTC")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sats() {
+        assert_eq!(sats(1), Amount::from_msats(1_000));
+        assert_eq!(msats(1), Amount::from_msats(1));
+        assert_eq!(checked_sats(u64::MAX), None);
+        assert_eq!(checked_sats(u64::MAX / 1_000), Some(sats(u64::MAX / 1_000)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_sats_overflow() {
+        sats(u64::MAX);
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format_sats(sats(1234)), "1234 sats");
+        assert_eq!(format_sats(msats(1_234_567)), "1234.567 sats");
+        assert_eq!(format_sats(msats(5)), "0.005 sats");
+
+        assert_eq!(format_btc(sats(1234)), "0.00001234 BTC");
+        assert_eq!(format_btc(sats(150_000_000)), "1.50000000 BTC");
+        assert_eq!(format_btc(msats(1)), "0.00000000001 BTC");
+    }
+
+    #[test]
+    fn test_format_on_network() {
+        assert_eq!(format_sats_on(sats(1234), Network::Bitcoin), "1234 sats");
+        assert_eq!(format_sats_on(sats(1234), Network::Signet), "1234 tsats");
+        assert_eq!(
+            format_sats_on(msats(1_234_567), Network::Regtest),
+            "1234.567 tsats"
+        );
+
+        assert_eq!(
+            format_btc_on(sats(1234), Network::Bitcoin),
+            "0.00001234 BTC"
+        );
+        assert_eq!(
+            format_btc_on(sats(1234), Network::Testnet),
+            "0.00001234 tBTC"
+        );
+    }
+}
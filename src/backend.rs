@@ -0,0 +1,313 @@
+//! Abstraction over the payment functionality of [`Blitzi`] so applications
+//! can swap in a mock (see `MockLightning` behind the `test-util` feature) in
+//! their tests.
+use std::collections::BTreeMap;
+use std::future::Future;
+
+use fedimint_core::Amount;
+use fedimint_core::config::FederationId;
+use fedimint_core::util::BoxStream;
+use lightning_invoice::Bolt11Invoice;
+
+use crate::{
+    Blitzi, BlitziEvent, FederationCapabilities, GatewayInfo, HistoryEntry, HistoryEntryKind,
+    HistoryEntryStatus, IdempotentPayment, InvoiceStatus, OperationCursor, PayOptions, PaymentHash,
+    PaymentQuote, PaymentReceipt, Preimage, ReceiveState, ReceivedPayment, WalletStats,
+    WalletStatus,
+};
+
+/// The payment surface of Blitzi as a trait. Application code that is generic
+/// over this trait can be tested without a federation by using
+/// `MockLightning`, which is available with the `test-util` feature.
+///
+/// See the inherent methods of [`Blitzi`] for documentation of the individual
+/// methods.
+pub trait LightningBackend: Send + Sync + 'static {
+    /// See [`Blitzi::lightning_invoice`]
+    fn lightning_invoice(
+        &self,
+        amount: Amount,
+        description: &str,
+    ) -> impl Future<Output = anyhow::Result<Bolt11Invoice>> + Send;
+
+    /// See [`Blitzi::lightning_invoice_tagged`]
+    fn lightning_invoice_tagged(
+        &self,
+        amount: Amount,
+        description: &str,
+        external_id: &str,
+    ) -> impl Future<Output = anyhow::Result<Bolt11Invoice>> + Send;
+
+    /// See [`Blitzi::invoice_external_id`]
+    fn invoice_external_id(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<Option<String>>> + Send;
+
+    /// See [`Blitzi::pay`]
+    fn pay(&self, invoice: &Bolt11Invoice)
+    -> impl Future<Output = anyhow::Result<Preimage>> + Send;
+
+    /// See [`Blitzi::pay_idempotent`]
+    fn pay_idempotent(
+        &self,
+        key: &str,
+        invoice: &Bolt11Invoice,
+        options: PayOptions,
+    ) -> impl Future<Output = anyhow::Result<IdempotentPayment>> + Send;
+
+    /// See [`Blitzi::pay_detailed`]
+    fn pay_detailed(
+        &self,
+        invoice: &Bolt11Invoice,
+        options: PayOptions,
+    ) -> impl Future<Output = anyhow::Result<PaymentReceipt>> + Send;
+
+    /// See [`Blitzi::quote_payment`]
+    fn quote_payment(
+        &self,
+        invoice: &Bolt11Invoice,
+    ) -> impl Future<Output = anyhow::Result<PaymentQuote>> + Send;
+
+    /// See [`Blitzi::payment_receipt`]
+    fn payment_receipt(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<Option<PaymentReceipt>>> + Send;
+
+    /// See [`Blitzi::await_incoming_payment_by_hash`]
+    fn await_incoming_payment(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// See [`Blitzi::await_incoming_payment_details_by_hash`]
+    fn await_incoming_payment_details(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<ReceivedPayment>> + Send;
+
+    /// See [`Blitzi::invoice_status`]
+    fn invoice_status(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<InvoiceStatus>> + Send;
+
+    /// See [`Blitzi::subscribe_invoice_updates`]
+    fn subscribe_invoice_updates(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<BoxStream<'_, ReceiveState>>> + Send;
+
+    /// See [`Blitzi::issued_invoice`]
+    fn issued_invoice(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<Option<Bolt11Invoice>>> + Send;
+
+    /// See [`Blitzi::is_invoice_ours_by_hash`]
+    fn is_invoice_ours(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<bool>> + Send;
+
+    /// See [`Blitzi::balance`]
+    fn balance(&self) -> impl Future<Output = Amount> + Send;
+
+    /// See [`Blitzi::subscribe_events`]
+    fn subscribe_events(&self) -> impl Future<Output = BoxStream<'static, BlitziEvent>> + Send;
+
+    /// See [`Blitzi::list_operations`]
+    fn list_operations(
+        &self,
+        limit: usize,
+        before: Option<OperationCursor>,
+    ) -> impl Future<Output = Vec<HistoryEntry>> + Send;
+
+    /// See [`Blitzi::list_transactions`]
+    fn list_transactions(
+        &self,
+        kind: HistoryEntryKind,
+        status: Option<HistoryEntryStatus>,
+        limit: usize,
+        before: Option<OperationCursor>,
+    ) -> impl Future<Output = Vec<HistoryEntry>> + Send;
+
+    /// See [`Blitzi::wallet_stats`]
+    fn wallet_stats(&self) -> impl Future<Output = WalletStats> + Send;
+
+    /// See [`Blitzi::status`]
+    fn status(&self) -> impl Future<Output = WalletStatus> + Send;
+
+    /// See [`Blitzi::list_gateways`]
+    fn list_gateways(&self) -> impl Future<Output = Vec<GatewayInfo>> + Send;
+
+    /// See [`Blitzi::federation_id`]
+    fn federation_id(&self) -> FederationId;
+
+    /// See [`Blitzi::capabilities`]
+    fn capabilities(&self) -> impl Future<Output = anyhow::Result<FederationCapabilities>> + Send;
+
+    /// See [`Blitzi::meta`]
+    fn meta(&self) -> impl Future<Output = anyhow::Result<BTreeMap<String, String>>> + Send;
+}
+
+impl LightningBackend for Blitzi {
+    fn lightning_invoice(
+        &self,
+        amount: Amount,
+        description: &str,
+    ) -> impl Future<Output = anyhow::Result<Bolt11Invoice>> + Send {
+        Blitzi::lightning_invoice(self, amount, description)
+    }
+
+    async fn lightning_invoice_tagged(
+        &self,
+        amount: Amount,
+        description: &str,
+        external_id: &str,
+    ) -> anyhow::Result<Bolt11Invoice> {
+        Ok(
+            Blitzi::lightning_invoice_tagged(self, amount, description, external_id)
+                .await?
+                .invoice,
+        )
+    }
+
+    fn invoice_external_id(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<Option<String>>> + Send {
+        Blitzi::invoice_external_id(self, payment_hash)
+    }
+
+    fn pay(
+        &self,
+        invoice: &Bolt11Invoice,
+    ) -> impl Future<Output = anyhow::Result<Preimage>> + Send {
+        Blitzi::pay(self, invoice)
+    }
+
+    fn pay_idempotent(
+        &self,
+        key: &str,
+        invoice: &Bolt11Invoice,
+        options: PayOptions,
+    ) -> impl Future<Output = anyhow::Result<IdempotentPayment>> + Send {
+        Blitzi::pay_idempotent(self, key, invoice, options)
+    }
+
+    fn pay_detailed(
+        &self,
+        invoice: &Bolt11Invoice,
+        options: PayOptions,
+    ) -> impl Future<Output = anyhow::Result<PaymentReceipt>> + Send {
+        Blitzi::pay_detailed(self, invoice, options)
+    }
+
+    fn quote_payment(
+        &self,
+        invoice: &Bolt11Invoice,
+    ) -> impl Future<Output = anyhow::Result<PaymentQuote>> + Send {
+        Blitzi::quote_payment(self, invoice)
+    }
+
+    fn payment_receipt(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<Option<PaymentReceipt>>> + Send {
+        Blitzi::payment_receipt(self, payment_hash)
+    }
+
+    fn await_incoming_payment(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        Blitzi::await_incoming_payment_by_hash(self, payment_hash)
+    }
+
+    fn await_incoming_payment_details(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<ReceivedPayment>> + Send {
+        Blitzi::await_incoming_payment_details_by_hash(self, payment_hash)
+    }
+
+    fn invoice_status(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<InvoiceStatus>> + Send {
+        Blitzi::invoice_status(self, payment_hash)
+    }
+
+    async fn subscribe_invoice_updates(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> anyhow::Result<BoxStream<'_, ReceiveState>> {
+        Blitzi::subscribe_invoice_updates(self, payment_hash).await
+    }
+
+    fn issued_invoice(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<Option<Bolt11Invoice>>> + Send {
+        Blitzi::issued_invoice(self, payment_hash)
+    }
+
+    fn is_invoice_ours(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<bool>> + Send {
+        Blitzi::is_invoice_ours_by_hash(self, payment_hash)
+    }
+
+    fn balance(&self) -> impl Future<Output = Amount> + Send {
+        Blitzi::balance(self)
+    }
+
+    fn subscribe_events(&self) -> impl Future<Output = BoxStream<'static, BlitziEvent>> + Send {
+        Blitzi::subscribe_events(self)
+    }
+
+    fn list_operations(
+        &self,
+        limit: usize,
+        before: Option<OperationCursor>,
+    ) -> impl Future<Output = Vec<HistoryEntry>> + Send {
+        Blitzi::list_operations(self, limit, before)
+    }
+
+    fn list_transactions(
+        &self,
+        kind: HistoryEntryKind,
+        status: Option<HistoryEntryStatus>,
+        limit: usize,
+        before: Option<OperationCursor>,
+    ) -> impl Future<Output = Vec<HistoryEntry>> + Send {
+        Blitzi::list_transactions(self, kind, status, limit, before)
+    }
+
+    fn wallet_stats(&self) -> impl Future<Output = WalletStats> + Send {
+        Blitzi::wallet_stats(self)
+    }
+
+    fn status(&self) -> impl Future<Output = WalletStatus> + Send {
+        Blitzi::status(self)
+    }
+
+    fn list_gateways(&self) -> impl Future<Output = Vec<GatewayInfo>> + Send {
+        Blitzi::list_gateways(self)
+    }
+
+    fn federation_id(&self) -> FederationId {
+        Blitzi::federation_id(self)
+    }
+
+    fn capabilities(&self) -> impl Future<Output = anyhow::Result<FederationCapabilities>> + Send {
+        Blitzi::capabilities(self)
+    }
+
+    fn meta(&self) -> impl Future<Output = anyhow::Result<BTreeMap<String, String>>> + Send {
+        Blitzi::meta(self)
+    }
+}
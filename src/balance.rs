@@ -0,0 +1,97 @@
+//! Debounced balance updates returned by
+//! [`Blitzi::subscribe_balance`](crate::Blitzi::subscribe_balance).
+use std::time::Duration;
+
+use fedimint_core::Amount;
+use fedimint_core::util::BoxStream;
+use futures_lite::StreamExt;
+
+/// How long the balance has to stay unchanged before an update is yielded, so
+/// a burst of note operations (e.g. issuing the notes of a claimed invoice)
+/// results in a single update.
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+struct State {
+    updates: BoxStream<'static, Amount>,
+    last: Option<Amount>,
+    ended: bool,
+}
+
+/// Debounces the balance `updates` and drops updates that don't change the
+/// balance. The first balance is yielded right away.
+pub(crate) fn debounced(updates: BoxStream<'static, Amount>) -> BoxStream<'static, Amount> {
+    let state = State {
+        updates,
+        last: None,
+        ended: false,
+    };
+
+    Box::pin(futures_lite::stream::unfold(
+        state,
+        |mut state| async move {
+            loop {
+                if state.ended {
+                    return None;
+                }
+                let mut balance = state.updates.next().await?;
+
+                // Wait for the balance to settle, except for the initial balance
+                if state.last.is_some() {
+                    loop {
+                        match fedimint_core::runtime::timeout(DEBOUNCE, state.updates.next()).await
+                        {
+                            Ok(Some(update)) => balance = update,
+                            Ok(None) => {
+                                state.ended = true;
+                                break;
+                            }
+                            Err(_) => break,
+                        }
+                    }
+                }
+
+                if state.last != Some(balance) {
+                    state.last = Some(balance);
+                    return Some((balance, state));
+                }
+            }
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msats(amounts: &[u64]) -> Vec<Amount> {
+        amounts.iter().copied().map(Amount::from_msats).collect()
+    }
+
+    #[tokio::test]
+    async fn test_debounced() {
+        // A burst of updates results in a single one, unchanged balances are
+        // dropped
+        let updates = futures_lite::stream::iter(msats(&[1, 2, 3, 3, 4]));
+        assert_eq!(
+            debounced(Box::pin(updates)).collect::<Vec<_>>().await,
+            msats(&[1, 4])
+        );
+
+        let updates = futures_lite::stream::iter(msats(&[5, 5]));
+        assert_eq!(
+            debounced(Box::pin(updates)).collect::<Vec<_>>().await,
+            msats(&[5])
+        );
+
+        // Updates separated by more than the debounce interval are yielded
+        // separately
+        let updates = futures_lite::stream::iter(msats(&[1, 2])).then(|balance| async move {
+            fedimint_core::runtime::sleep(DEBOUNCE * 2).await;
+            balance
+        });
+        assert_eq!(
+            debounced(Box::pin(updates)).collect::<Vec<_>>().await,
+            msats(&[1, 2])
+        );
+    }
+}
@@ -0,0 +1,80 @@
+//! Capping the balance held in the federation, configured via
+//! [`BlitziBuilder::max_balance`](crate::BlitziBuilder::max_balance). Ecash
+//! isn't insured, so users shouldn't hold more in a federation than it
+//! recommends.
+use fedimint_client::Client;
+use fedimint_core::Amount;
+
+use crate::error::BalanceCapExceeded;
+
+/// Maximum balance enforced on incoming funds, see
+/// [`BlitziBuilder::max_balance`](crate::BlitziBuilder::max_balance).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BalanceCap {
+    /// A fixed maximum balance
+    Fixed(Amount),
+    /// The maximum balance the federation announces in its meta data (field
+    /// `max_balance_msats`, see
+    /// [`FederationCapabilities::max_balance`](crate::FederationCapabilities::max_balance)),
+    /// read whenever the cap is checked. No cap is enforced if the federation
+    /// doesn't announce one.
+    FromFederation,
+}
+
+impl BalanceCap {
+    /// Returns the maximum balance, `None` if the federation doesn't announce
+    /// one.
+    async fn max(self, client: &Client) -> Option<Amount> {
+        match self {
+            BalanceCap::Fixed(max) => Some(max),
+            BalanceCap::FromFederation => crate::capabilities::announced_max_balance(client).await,
+        }
+    }
+
+    /// Checks that receiving `amount` wouldn't push the balance of `client`
+    /// above the cap.
+    ///
+    /// # Errors
+    /// Returns a [`BalanceCapExceeded`] error if it would.
+    pub(crate) async fn check(self, client: &Client, amount: Amount) -> anyhow::Result<()> {
+        let Some(max) = self.max(client).await else {
+            return Ok(());
+        };
+        check_amount(max, client.get_balance().await?, amount)?;
+        Ok(())
+    }
+}
+
+fn check_amount(max: Amount, balance: Amount, amount: Amount) -> Result<(), BalanceCapExceeded> {
+    if balance.msats.saturating_add(amount.msats) > max.msats {
+        return Err(BalanceCapExceeded {
+            max,
+            balance,
+            amount,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_amount() {
+        let max = Amount::from_msats(10_000);
+
+        assert!(check_amount(max, Amount::ZERO, max).is_ok());
+        assert!(check_amount(max, Amount::from_msats(4_000), Amount::from_msats(6_000)).is_ok());
+        assert_eq!(
+            check_amount(max, Amount::from_msats(4_000), Amount::from_msats(6_001)),
+            Err(BalanceCapExceeded {
+                max,
+                balance: Amount::from_msats(4_000),
+                amount: Amount::from_msats(6_001),
+            })
+        );
+        // Doesn't overflow for absurd amounts
+        assert!(check_amount(max, Amount::from_msats(u64::MAX), Amount::from_msats(1)).is_err());
+    }
+}
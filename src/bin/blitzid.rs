@@ -1,27 +1,74 @@
+use std::path::{Path as FsPath, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use axum::body::Body;
-use axum::extract::{Path, State};
-use axum::http::{Request, StatusCode, header};
+use axum::extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade, close_code};
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode, header};
 use axum::middleware::{self, Next};
-use axum::response::Response;
+use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::{Json, Router};
-use blitzi::{Amount, Blitzi};
-use clap::Parser;
-use fedimint_core::BitcoinHash;
+use axum_server::tls_rustls::RustlsConfig;
+#[cfg(not(any(feature = "native", feature = "db-redb")))]
+compile_error!("blitzid needs a database backend, enable the `native` or `db-redb` feature");
+
+use blitzi::bitcoin::hashes::{Hash, sha256};
+use blitzi::{
+    Amount, Blitzi, Bolt11Invoice, DatabaseBackend, DescriptionTooLong, ExternalIdInUse,
+    FederationCapabilities, FeeTooHigh, GatewayInfo, HistoryEntry, HistoryEntryKind,
+    HistoryEntryStatus, IdempotencyKeyConflict, InvalidDescription, InvalidInvoiceError,
+    InvoiceAmountError, InvoiceStatus, LightningBackend, MAX_DESCRIPTION_LEN, MAX_EXTERNAL_ID_LEN,
+    OperationCursor, PayOptions, PaymentHash, PaymentQuote, PaymentRefunded, Preimage,
+    UnsupportedByLnv2, WalletStats, checked_sats, msats,
+};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
 use serde::{Deserialize, Serialize};
-use tracing::{error, info};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::trace::TraceLayer;
+use tracing::{Span, error, info, info_span, warn};
+
+mod idempotency;
+mod limits;
+mod metrics;
+mod nwc;
+mod sse;
+mod tls;
+mod webhook;
+
+use crate::idempotency::IdempotencyStore;
+use crate::limits::InvoiceLimits;
+use crate::metrics::Metrics;
+use crate::nwc::NwcServer;
+use crate::sse::EventLog;
+use crate::tls::TlsFiles;
+use crate::webhook::Webhooks;
 
 #[derive(Parser, Debug)]
 #[command(name = "blitzid")]
 #[command(about = "Blitzi Lightning REST API daemon", long_about = None)]
 struct Args {
+    #[arg(short, long, env = "BLITZID_CONFIG")]
+    #[arg(
+        help = "TOML or JSON file to read configuration from, command line arguments and \
+                  environment variables take precedence over it"
+    )]
+    config: Option<PathBuf>,
+
     #[arg(short, long, env = "BLITZID_DATADIR")]
     #[arg(help = "Directory where Fedimint data will be stored")]
     datadir: Option<String>,
 
+    #[arg(long, env = "BLITZID_DB_BACKEND", value_enum)]
+    #[arg(
+        help = "Database backend, `redb` requires the `db-redb` feature (default: rocksdb if \
+                  built with the `native` feature, redb otherwise)"
+    )]
+    db_backend: Option<DbBackend>,
+
     #[arg(short, long, env = "BLITZID_FEDERATION")]
     #[arg(help = "Federation invite code to connect to")]
     federation: Option<String>,
@@ -37,34 +84,439 @@ struct Args {
     #[arg(short = 'H', long, env = "BLITZID_HOST", default_value = "127.0.0.1")]
     #[arg(help = "Host to bind to")]
     host: String,
+
+    #[arg(long, env = "BLITZID_LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    #[arg(help = "Log output format")]
+    log_format: LogFormat,
+
+    #[arg(
+        long = "cors-origin",
+        env = "BLITZID_CORS_ORIGINS",
+        value_delimiter = ','
+    )]
+    #[arg(
+        help = "Origin allowed to make cross-origin requests, may be repeated or `*` to allow \
+                  any origin (CORS is disabled if not set)"
+    )]
+    cors_origins: Vec<String>,
+
+    #[arg(long, env = "BLITZID_NWC_RELAY")]
+    #[arg(
+        help = "Nostr relay to serve Nostr Wallet Connect (NIP-47) requests on (NWC is disabled if not set)"
+    )]
+    nwc_relay: Option<String>,
+
+    #[arg(long, env = "BLITZID_NWC_SECRET")]
+    #[arg(
+        help = "Secret key of the NWC wallet service (generated once and kept in the data \
+                  directory if not provided)"
+    )]
+    nwc_secret: Option<String>,
+
+    #[arg(long, env = "BLITZID_MAX_INVOICE_MSATS")]
+    #[arg(help = "Reject invoice requests above this amount in msats (unlimited if not set)")]
+    max_invoice_msats: Option<u64>,
+
+    #[arg(long, env = "BLITZID_INVOICE_DESCRIPTION_PREFIX")]
+    #[arg(
+        help = "Text prepended to the description of every invoice created via POST /invoice or \
+                  NWC"
+    )]
+    invoice_description_prefix: Option<String>,
+
+    #[arg(long, env = "BLITZID_READ_ONLY")]
+    #[arg(
+        help = "Only serve GET /balance, /history and /stats and reject all other API requests, \
+                  e.g. for a monitoring dashboard"
+    )]
+    read_only: bool,
+
+    #[arg(long, env = "BLITZID_WEBHOOK_SECRET")]
+    #[arg(
+        help = "Secret the signatures of webhook notifications are keyed by (auto-generated if \
+                  not provided)"
+    )]
+    webhook_secret: Option<String>,
+
+    #[arg(long, env = "BLITZID_CONFIG_CHECK_INTERVAL")]
+    #[arg(
+        help = "Check every this many seconds whether the federation config changed and log \
+                  a warning if it did (disabled if not set)"
+    )]
+    config_check_interval: Option<u64>,
+
+    #[arg(long, env = "BLITZID_METRICS_PORT")]
+    #[arg(
+        help = "Additionally serve GET /metrics without authentication on this port of the same \
+                  host, e.g. for a Prometheus server in the same network"
+    )]
+    metrics_port: Option<u16>,
+
+    #[arg(long, env = "BLITZID_TLS_CERT")]
+    #[arg(
+        help = "PEM encoded certificate chain to serve the API over HTTPS with, requires \
+                  --tls-key. Reloaded on SIGHUP"
+    )]
+    tls_cert: Option<PathBuf>,
+
+    #[arg(long, env = "BLITZID_TLS_KEY")]
+    #[arg(help = "PEM encoded private key of --tls-cert. Reloaded on SIGHUP")]
+    tls_key: Option<PathBuf>,
 }
 
-#[derive(Clone)]
-struct AppState {
-    blitzi: Arc<Blitzi>,
-    bearer_token: String,
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    /// Human readable logs
+    Text,
+    /// One JSON object per line, for log aggregation
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DbBackend {
+    /// RocksDB
+    Rocksdb,
+    /// redb
+    Redb,
+}
+
+impl From<DbBackend> for DatabaseBackend {
+    fn from(backend: DbBackend) -> Self {
+        match backend {
+            DbBackend::Rocksdb => DatabaseBackend::RocksDb,
+            DbBackend::Redb => DatabaseBackend::Redb,
+        }
+    }
+}
+
+/// Contents of the file passed via `--config`, every field corresponds to the
+/// command line argument of the same name.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    datadir: Option<String>,
+    db_backend: Option<DbBackend>,
+    federation: Option<String>,
+    bearer_token: Option<String>,
+    port: Option<u16>,
+    host: Option<String>,
+    log_format: Option<LogFormat>,
+    cors_origins: Option<Vec<String>>,
+    nwc_relay: Option<String>,
+    nwc_secret: Option<String>,
+    max_invoice_msats: Option<u64>,
+    invoice_description_prefix: Option<String>,
+    read_only: Option<bool>,
+    webhook_secret: Option<String>,
+    config_check_interval: Option<u64>,
+    metrics_port: Option<u16>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+}
+
+impl ConfigFile {
+    /// Reads a config file, files ending in `.json` are parsed as JSON and all
+    /// others as TOML.
+    fn load(path: &FsPath) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+
+        let config = if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            serde_json::from_str(&contents)?
+        } else {
+            toml::from_str(&contents)?
+        };
+        Ok(config)
+    }
+
+    /// Sets all arguments that weren't passed on the command line or via
+    /// environment variables to the values from the config file.
+    fn apply(self, args: &mut Args, matches: &ArgMatches) {
+        fn set<T>(matches: &ArgMatches, id: &str, arg: &mut T, value: Option<T>) {
+            let is_explicit = matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            );
+            if is_explicit {
+                return;
+            }
+            if let Some(value) = value {
+                *arg = value;
+            }
+        }
+
+        set(
+            matches,
+            "datadir",
+            &mut args.datadir,
+            self.datadir.map(Some),
+        );
+        set(
+            matches,
+            "db_backend",
+            &mut args.db_backend,
+            self.db_backend.map(Some),
+        );
+        set(
+            matches,
+            "federation",
+            &mut args.federation,
+            self.federation.map(Some),
+        );
+        set(
+            matches,
+            "bearer_token",
+            &mut args.bearer_token,
+            self.bearer_token.map(Some),
+        );
+        set(matches, "port", &mut args.port, self.port);
+        set(matches, "host", &mut args.host, self.host);
+        set(matches, "log_format", &mut args.log_format, self.log_format);
+        set(
+            matches,
+            "cors_origins",
+            &mut args.cors_origins,
+            self.cors_origins,
+        );
+        set(
+            matches,
+            "nwc_relay",
+            &mut args.nwc_relay,
+            self.nwc_relay.map(Some),
+        );
+        set(
+            matches,
+            "nwc_secret",
+            &mut args.nwc_secret,
+            self.nwc_secret.map(Some),
+        );
+        set(
+            matches,
+            "max_invoice_msats",
+            &mut args.max_invoice_msats,
+            self.max_invoice_msats.map(Some),
+        );
+        set(
+            matches,
+            "invoice_description_prefix",
+            &mut args.invoice_description_prefix,
+            self.invoice_description_prefix.map(Some),
+        );
+        set(matches, "read_only", &mut args.read_only, self.read_only);
+        set(
+            matches,
+            "webhook_secret",
+            &mut args.webhook_secret,
+            self.webhook_secret.map(Some),
+        );
+        set(
+            matches,
+            "config_check_interval",
+            &mut args.config_check_interval,
+            self.config_check_interval.map(Some),
+        );
+        set(
+            matches,
+            "metrics_port",
+            &mut args.metrics_port,
+            self.metrics_port.map(Some),
+        );
+        set(
+            matches,
+            "tls_cert",
+            &mut args.tls_cert,
+            self.tls_cert.map(Some),
+        );
+        set(
+            matches,
+            "tls_key",
+            &mut args.tls_key,
+            self.tls_key.map(Some),
+        );
+    }
+}
+
+/// Parses the command line arguments and merges them with the config file, if
+/// one was given.
+fn parse_args(matches: &ArgMatches) -> anyhow::Result<Args> {
+    let mut args = Args::from_arg_matches(matches)?;
+    if let Some(path) = args.config.clone() {
+        ConfigFile::load(&path)
+            .with_context(|| format!("Invalid config file {}", path.display()))?
+            .apply(&mut args, matches);
+    }
+    Ok(args)
+}
+
+struct AppState<B> {
+    blitzi: Arc<B>,
+    /// Replaced on `POST /admin/rotate-token`
+    bearer_token: Arc<BearerToken>,
+    /// Applied to invoices created via `POST /invoice`
+    invoice_limits: InvoiceLimits,
+    /// Only `GET /balance`, `/history` and `/stats` are served, see
+    /// [`read_only_rejected`]
+    read_only: bool,
+    /// Notifies the `webhook_url` of invoices once they are paid, `None` if
+    /// webhooks are disabled
+    webhooks: Option<Arc<Webhooks>>,
+    /// Recent wallet events served on `GET /events`
+    events: Arc<EventLog>,
+    /// Responses replayed to requests repeating an `Idempotency-Key`
+    idempotency: Arc<IdempotencyStore>,
+    /// Served on `GET /metrics`
+    metrics: Arc<Metrics>,
+    /// Data directory of the wallet, reported on `GET /info`
+    datadir: Option<PathBuf>,
+    /// When blitzid started, to report its uptime on `GET /info`
+    started_at: Instant,
+}
+
+impl<B> Clone for AppState<B> {
+    fn clone(&self) -> Self {
+        AppState {
+            blitzi: self.blitzi.clone(),
+            bearer_token: self.bearer_token.clone(),
+            invoice_limits: self.invoice_limits.clone(),
+            read_only: self.read_only,
+            webhooks: self.webhooks.clone(),
+            events: self.events.clone(),
+            idempotency: self.idempotency.clone(),
+            metrics: self.metrics.clone(),
+            datadir: self.datadir.clone(),
+            started_at: self.started_at,
+        }
+    }
 }
 
+/// The bearer token clients authenticate with, which can be rotated while the
+/// server is running.
+struct BearerToken(std::sync::RwLock<String>);
+
+impl BearerToken {
+    fn new(token: String) -> Self {
+        BearerToken(std::sync::RwLock::new(token))
+    }
+
+    /// Compares the SHA256 digests of both tokens in constant time, so the
+    /// response time doesn't reveal how much of a guessed token is correct.
+    fn matches(&self, candidate: &str) -> bool {
+        let token = sha256::Hash::hash(self.0.read().expect("lock poisoned").as_bytes());
+        let candidate = sha256::Hash::hash(candidate.as_bytes());
+        token
+            .as_byte_array()
+            .iter()
+            .zip(candidate.as_byte_array())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    }
+
+    /// Replaces the token with a newly generated one and returns it.
+    fn rotate(&self) -> String {
+        let token = generate_bearer_token();
+        *self.0.write().expect("lock poisoned") = token.clone();
+        token
+    }
+}
+
+/// Header clients can set on `POST /pay` and `POST /invoice` to safely retry
+/// requests, see [`IdempotencyStore`] and [`Blitzi::pay_idempotent`].
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Header SSE clients send when reconnecting to `GET /events`.
+const LAST_EVENT_ID_HEADER: &str = "last-event-id";
+
+type PayResult = Result<Json<PayInvoiceResponse>, Response>;
+
 #[derive(Serialize, Deserialize)]
 struct CreateInvoiceRequest {
-    amount_msats: u64,
+    /// Exactly one of `amount_msats` and `amount_sats` has to be set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    amount_msats: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    amount_sats: Option<u64>,
     description: String,
+    /// URL to POST a notification to once the invoice is paid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    webhook_url: Option<String>,
+    /// Included in the webhook notification as is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<serde_json::Value>,
+    /// Reference id (e.g. an order id) attached to the invoice, echoed in
+    /// status responses
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    external_id: Option<String>,
+}
+
+impl CreateInvoiceRequest {
+    /// Returns the requested amount, given either in msats or sats.
+    fn amount(&self) -> Result<Amount, &'static str> {
+        match (self.amount_msats, self.amount_sats) {
+            (Some(amount_msats), None) => Ok(msats(amount_msats)),
+            (None, Some(amount_sats)) => {
+                checked_sats(amount_sats).ok_or("amount_sats is too large")
+            }
+            _ => Err("exactly one of amount_msats and amount_sats has to be set"),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 struct CreateInvoiceResponse {
     invoice: String,
-    payment_hash: String,
+    payment_hash: PaymentHash,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    external_id: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct PayInvoiceRequest {
     invoice: String,
+    /// Maximum gateway fee, the payment is rejected with `402` if the fee is
+    /// higher
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_fee_msats: Option<u64>,
+    /// Has to match the invoice amount if set, amountless invoices can't be
+    /// paid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    amount_msats: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct PayInvoiceResponse {
-    preimage: String,
+    preimage: Preimage,
+    payment_hash: PaymentHash,
+    amount_msats: u64,
+    fee_msats: u64,
+    /// Whether the invoice was paid within the federation
+    internal: bool,
+    /// Set if the idempotency key was already used for a different invoice
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct QuotePaymentRequest {
+    invoice: String,
+}
+
+/// Error code of [`FeeTooHighResponse`].
+const FEE_TOO_HIGH: &str = "fee_too_high";
+
+/// Returned with `402 PAYMENT REQUIRED` if the gateway fee exceeds the
+/// `max_fee_msats` of `POST /pay`.
+#[derive(Serialize, Deserialize)]
+struct FeeTooHighResponse {
+    error: String,
+    /// Always [`FEE_TOO_HIGH`]
+    code: String,
+    fee_msats: u64,
+    max_fee_msats: u64,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -72,9 +524,196 @@ struct BalanceResponse {
     balance_msats: u64,
 }
 
+#[derive(Serialize, Deserialize)]
+struct GatewaysResponse {
+    gateways: Vec<GatewayInfo>,
+}
+
+/// Git commit blitzid was built from, set by the Nix build.
+const GIT_HASH: Option<&str> = option_env!("BLITZID_GIT_HASH");
+
+/// Returned by `GET /version`.
+#[derive(Serialize, Deserialize)]
+struct VersionResponse {
+    version: String,
+    /// `None` if blitzid wasn't built from a clean git checkout
+    git_hash: Option<String>,
+}
+
+impl VersionResponse {
+    fn current() -> Self {
+        VersionResponse {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_hash: GIT_HASH.filter(|hash| !hash.is_empty()).map(str::to_owned),
+        }
+    }
+}
+
+/// Returned by `GET /info`. Fields that can't be determined are `None`
+/// instead of failing the whole response.
+#[derive(Serialize, Deserialize)]
+struct InfoResponse {
+    #[serde(flatten)]
+    version: VersionResponse,
+    federation_id: String,
+    /// `federation_name` from the federation's meta data
+    federation_name: Option<String>,
+    /// Network, modules and their versions
+    capabilities: Option<FederationCapabilities>,
+    gateways: Vec<GatewayInfo>,
+    /// `None` if the wallet wasn't opened from a data directory
+    datadir: Option<String>,
+    uptime_secs: u64,
+}
+
 #[derive(Serialize, Deserialize)]
 struct InvoiceStatusResponse {
     paid: bool,
+    /// Set if the `timeout_secs` of the request elapsed before the invoice
+    /// was paid or canceled
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    timed_out: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    external_id: Option<String>,
+}
+
+/// Upper bound for the `timeout_secs` of `GET /invoice/:payment_hash`.
+const MAX_LONG_POLL_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Serialize, Deserialize)]
+struct InvoiceStatusQuery {
+    timeout_secs: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum InvoiceState {
+    Pending,
+    Paid,
+    Canceled,
+    Expired,
+}
+
+/// Response of `GET /invoice/:payment_hash/status`, timestamps are unix
+/// seconds.
+#[derive(Serialize, Deserialize)]
+struct InvoiceStateResponse {
+    status: InvoiceState,
+    amount_msats: Option<u64>,
+    created_at: u64,
+    expires_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    external_id: Option<String>,
+}
+
+const DEFAULT_HISTORY_LIMIT: usize = 20;
+const MAX_HISTORY_LIMIT: usize = 100;
+
+#[derive(Serialize, Deserialize)]
+struct HistoryQuery {
+    limit: Option<usize>,
+    before: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HistoryResponse {
+    entries: Vec<HistoryEntry>,
+    next_cursor: Option<OperationCursor>,
+}
+
+const DEFAULT_TRANSACTION_LIMIT: usize = 50;
+const MAX_TRANSACTION_LIMIT: usize = 500;
+
+/// Query of `GET /invoices` and `GET /payments`.
+#[derive(Serialize, Deserialize)]
+struct TransactionsQuery {
+    limit: Option<usize>,
+    cursor: Option<String>,
+    status: Option<TransactionStatus>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum TransactionStatus {
+    Pending,
+    Paid,
+    Failed,
+}
+
+impl From<HistoryEntryStatus> for TransactionStatus {
+    fn from(status: HistoryEntryStatus) -> Self {
+        match status {
+            HistoryEntryStatus::Pending => TransactionStatus::Pending,
+            HistoryEntryStatus::Succeeded => TransactionStatus::Paid,
+            HistoryEntryStatus::Failed => TransactionStatus::Failed,
+        }
+    }
+}
+
+impl From<TransactionStatus> for HistoryEntryStatus {
+    fn from(status: TransactionStatus) -> Self {
+        match status {
+            TransactionStatus::Pending => HistoryEntryStatus::Pending,
+            TransactionStatus::Paid => HistoryEntryStatus::Succeeded,
+            TransactionStatus::Failed => HistoryEntryStatus::Failed,
+        }
+    }
+}
+
+/// An invoice or outgoing payment, timestamps are unix seconds.
+#[derive(Serialize, Deserialize)]
+struct TransactionRecord {
+    payment_hash: Option<PaymentHash>,
+    amount_msats: Option<u64>,
+    /// Only set for outgoing payments
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fee_msats: Option<u64>,
+    description: Option<String>,
+    status: TransactionStatus,
+    created_at: u64,
+    settled_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    external_id: Option<String>,
+}
+
+impl From<HistoryEntry> for TransactionRecord {
+    fn from(entry: HistoryEntry) -> Self {
+        let unix_secs = |time: std::time::SystemTime| {
+            time.duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        };
+        TransactionRecord {
+            payment_hash: entry.payment_hash,
+            amount_msats: entry.amount.map(|amount| amount.msats),
+            fee_msats: entry.fee.map(|fee| fee.msats),
+            description: entry.description,
+            status: entry.status.into(),
+            created_at: unix_secs(entry.timestamp),
+            settled_at: entry.settled_at.map(unix_secs),
+            external_id: entry.external_id,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct TransactionsResponse {
+    transactions: Vec<TransactionRecord>,
+    /// Only set if there are more (older) transactions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<OperationCursor>,
+}
+
+/// Query of `GET /ws/invoice/:payment_hash`, browsers can't set the
+/// `Authorization` header on WebSocket requests.
+#[derive(Serialize, Deserialize)]
+struct InvoiceSocketQuery {
+    token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RotateTokenResponse {
+    bearer_token: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -82,8 +721,8 @@ struct ErrorResponse {
     error: String,
 }
 
-async fn auth_middleware(
-    State(state): State<AppState>,
+async fn auth_middleware<B: LightningBackend>(
+    State(state): State<AppState<B>>,
     request: Request<Body>,
     next: Next,
 ) -> Result<Response, StatusCode> {
@@ -93,33 +732,156 @@ async fn auth_middleware(
         .and_then(|h| h.to_str().ok());
 
     match auth_header {
-        Some(auth) if auth == format!("Bearer {}", state.bearer_token) => {
+        Some(auth)
+            if auth
+                .strip_prefix("Bearer ")
+                .is_some_and(|token| state.bearer_token.matches(token)) =>
+        {
             Ok(next.run(request).await)
         }
         _ => Err(StatusCode::UNAUTHORIZED),
     }
 }
 
-async fn create_invoice(
-    State(state): State<AppState>,
+/// Creates an invoice. If the request carries an `Idempotency-Key` header, the
+/// response is replayed to retries using the same key instead of creating
+/// another invoice.
+async fn create_invoice<B: LightningBackend>(
+    State(state): State<AppState<B>>,
+    headers: HeaderMap,
     Json(payload): Json<CreateInvoiceRequest>,
+) -> Response {
+    match idempotency_key(&headers) {
+        Ok(Some(key)) => {
+            let idempotency = state.idempotency.clone();
+            idempotency
+                .run("/invoice", key, payload, |payload| async move {
+                    issue_invoice(&state, payload).await.into_response()
+                })
+                .await
+        }
+        Ok(None) => issue_invoice(&state, payload).await.into_response(),
+        Err(response) => response,
+    }
+}
+
+async fn issue_invoice<B: LightningBackend>(
+    state: &AppState<B>,
+    payload: CreateInvoiceRequest,
 ) -> Result<Json<CreateInvoiceResponse>, (StatusCode, Json<ErrorResponse>)> {
-    let amount = Amount::from_msats(payload.amount_msats);
+    let amount = payload.amount().map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                error: format!("Invalid amount: {}", e),
+            }),
+        )
+    })?;
+    let description = state
+        .invoice_limits
+        .apply(amount, &payload.description)
+        .map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse {
+                    error: e.to_string(),
+                }),
+            )
+        })?;
 
-    match state
-        .blitzi
-        .lightning_invoice(amount, &payload.description)
-        .await
-    {
+    let webhooks = match (&payload.webhook_url, &state.webhooks) {
+        (None, _) => None,
+        (Some(_), None) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse {
+                    error: "Webhooks are not enabled".to_string(),
+                }),
+            ));
+        }
+        (Some(url), Some(webhooks)) => {
+            Webhooks::validate_url(url).map_err(|e| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    Json(ErrorResponse {
+                        error: format!("Invalid webhook_url: {}", e),
+                    }),
+                )
+            })?;
+            Some(webhooks)
+        }
+    };
+
+    if let Some(external_id) = &payload.external_id {
+        if external_id.is_empty() || external_id.len() > MAX_EXTERNAL_ID_LEN {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse {
+                    error: format!(
+                        "Invalid external_id: must be between 1 and {} bytes long",
+                        MAX_EXTERNAL_ID_LEN
+                    ),
+                }),
+            ));
+        }
+    }
+
+    let invoice = match &payload.external_id {
+        Some(external_id) => {
+            state
+                .blitzi
+                .lightning_invoice_tagged(amount, &description, external_id)
+                .await
+        }
+        None => state.blitzi.lightning_invoice(amount, &description).await,
+    };
+    match invoice {
         Ok(invoice) => {
-            let payment_hash = hex::encode(invoice.payment_hash().to_byte_array());
+            let payment_hash = PaymentHash::from(invoice.payment_hash());
+            if let (Some(webhooks), Some(url)) = (webhooks, payload.webhook_url) {
+                let metadata = payload.metadata.unwrap_or_default();
+                if let Err(e) = webhooks.register(payment_hash, url, amount, metadata).await {
+                    error!(error = %e, %payment_hash, "Failed to register webhook");
+                    return Err((
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(ErrorResponse {
+                            error: format!("Failed to register webhook: {}", e),
+                        }),
+                    ));
+                }
+            }
+            state.metrics.invoice_created();
             Ok(Json(CreateInvoiceResponse {
-                invoice: invoice.to_string(),
                 payment_hash,
+                invoice: invoice.to_string(),
+                external_id: payload.external_id,
             }))
         }
+        Err(e) if e.downcast_ref::<ExternalIdInUse>().is_some() => Err((
+            StatusCode::CONFLICT,
+            Json(ErrorResponse {
+                error: format!("{}", e),
+            }),
+        )),
+        Err(e) if e.downcast_ref::<InvoiceAmountError>().is_some() => Err((
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                error: format!("Invalid amount: {}", e),
+            }),
+        )),
+        Err(e)
+            if e.downcast_ref::<DescriptionTooLong>().is_some()
+                || e.downcast_ref::<InvalidDescription>().is_some() =>
+        {
+            Err((
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse {
+                    error: format!("Invalid description: {}", e),
+                }),
+            ))
+        }
         Err(e) => {
-            error!("Failed to create invoice: {}", e);
+            error!(error = %e, amount_msats = amount.msats, "Failed to create invoice");
             Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(ErrorResponse {
@@ -130,115 +892,757 @@ async fn create_invoice(
     }
 }
 
-async fn pay_invoice(
-    State(state): State<AppState>,
+/// Returns the `Idempotency-Key` header of a request, if any.
+fn idempotency_key(headers: &HeaderMap) -> Result<Option<&str>, Response> {
+    match headers.get(IDEMPOTENCY_KEY_HEADER).map(|key| key.to_str()) {
+        Some(Ok(key)) if !key.is_empty() => Ok(Some(key)),
+        Some(_) => Err((
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                error: "Invalid idempotency key".to_string(),
+            }),
+        )
+            .into_response()),
+        None => Ok(None),
+    }
+}
+
+/// Pays an invoice. If the request carries an `Idempotency-Key` header, the
+/// response is replayed to retries using the same key and the payment is keyed
+/// on it instead of the invoice, so a retry never pays twice.
+async fn pay_invoice<B: LightningBackend>(
+    State(state): State<AppState<B>>,
+    headers: HeaderMap,
     Json(payload): Json<PayInvoiceRequest>,
-) -> Result<Json<PayInvoiceResponse>, (StatusCode, Json<ErrorResponse>)> {
-    let invoice = match payload.invoice.parse() {
+) -> Response {
+    match idempotency_key(&headers) {
+        Ok(Some(key)) => {
+            let idempotency = state.idempotency.clone();
+            idempotency
+                .run("/pay", key, payload, |payload| async move {
+                    pay(&state, payload, Some(key)).await.into_response()
+                })
+                .await
+        }
+        Ok(None) => pay(&state, payload, None).await.into_response(),
+        Err(response) => response,
+    }
+}
+
+async fn pay<B: LightningBackend>(
+    state: &AppState<B>,
+    payload: PayInvoiceRequest,
+    idempotency_key: Option<&str>,
+) -> PayResult {
+    let bad_request =
+        |error: String| (StatusCode::BAD_REQUEST, Json(ErrorResponse { error })).into_response();
+    let invoice = match payload.invoice.parse::<Bolt11Invoice>() {
         Ok(inv) => inv,
-        Err(e) => {
-            return Err((
-                StatusCode::BAD_REQUEST,
-                Json(ErrorResponse {
-                    error: format!("Invalid invoice: {}", e),
-                }),
+        Err(e) => return Err(bad_request(format!("Invalid invoice: {}", e))),
+    };
+    match (invoice.amount_milli_satoshis(), payload.amount_msats) {
+        (None, _) => {
+            return Err(bad_request(
+                "Invalid invoice: amountless invoices aren't supported".to_string(),
             ));
         }
+        (Some(amount), Some(expected)) if amount != expected => {
+            return Err(bad_request(format!(
+                "Invoice amount of {} msat doesn't match amount_msats of {} msat",
+                amount, expected
+            )));
+        }
+        _ => {}
+    }
+    let options = PayOptions {
+        max_fee: payload.max_fee_msats.map(msats),
+        ..PayOptions::default()
     };
 
-    match state.blitzi.pay(&invoice).await {
-        Ok(preimage) => Ok(Json(PayInvoiceResponse {
-            preimage: hex::encode(preimage),
-        })),
+    let started = Instant::now();
+    let result = match idempotency_key {
+        Some(key) => match state.blitzi.pay_idempotent(key, &invoice, options).await {
+            Ok(payment) => state
+                .blitzi
+                .payment_receipt(payment.payment_hash)
+                .await
+                .and_then(|receipt| {
+                    receipt.context("Payment succeeded but its receipt wasn't recorded")
+                })
+                .map(|receipt| (receipt, payment.warning)),
+            Err(e) => Err(e),
+        },
+        None => state
+            .blitzi
+            .pay_detailed(&invoice, options)
+            .await
+            .map(|receipt| (receipt, None)),
+    };
+
+    match result {
+        Ok((receipt, warning)) => {
+            state.metrics.payment_finished(started.elapsed());
+            Ok(Json(PayInvoiceResponse {
+                preimage: receipt.preimage,
+                payment_hash: receipt.payment_hash,
+                amount_msats: receipt.amount.msats,
+                fee_msats: receipt.fee.msats,
+                internal: receipt.internal,
+                warning,
+            }))
+        }
+        Err(e) if e.downcast_ref::<InvalidInvoiceError>().is_some() => {
+            Err(bad_request(format!("Invalid invoice: {}", e)))
+        }
+        Err(e) if e.downcast_ref::<IdempotencyKeyConflict>().is_some() => {
+            Err(idempotency::conflict(e.to_string()))
+        }
+        Err(e) if e.downcast_ref::<UnsupportedByLnv2>().is_some() => Err((
+            StatusCode::NOT_IMPLEMENTED,
+            Json(ErrorResponse {
+                error: e.to_string(),
+            }),
+        )
+            .into_response()),
+        Err(e) if e.downcast_ref::<FeeTooHigh>().is_some() => {
+            let fee_too_high = e.downcast_ref::<FeeTooHigh>().expect("checked above");
+            Err((
+                StatusCode::PAYMENT_REQUIRED,
+                Json(FeeTooHighResponse {
+                    error: e.to_string(),
+                    code: FEE_TOO_HIGH.to_string(),
+                    fee_msats: fee_too_high.fee.msats,
+                    max_fee_msats: fee_too_high.max_fee.msats,
+                }),
+            )
+                .into_response())
+        }
         Err(e) => {
-            error!("Failed to pay invoice: {}", e);
+            error!(error = %e, payment_hash = %invoice.payment_hash(), "Failed to pay invoice");
+            // Started payments are counted as refunded from the event stream
+            if e.downcast_ref::<PaymentRefunded>().is_none() {
+                state.metrics.payment_failed();
+            }
             Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(ErrorResponse {
                     error: format!("Failed to pay invoice: {}", e),
                 }),
-            ))
+            )
+                .into_response())
         }
     }
 }
 
-async fn get_balance(
-    State(state): State<AppState>,
-) -> Result<Json<BalanceResponse>, (StatusCode, Json<ErrorResponse>)> {
-    let balance = state.blitzi.balance().await;
-    Ok(Json(BalanceResponse {
-        balance_msats: balance.msats,
-    }))
-}
-
-/// Checks if an invoice has been paid by waiting for payment.
-///
-/// Note: This endpoint blocks until the invoice is paid or times out, which is
-/// intentional behavior. Clients should use appropriate HTTP timeouts.
-async fn check_invoice(
-    State(state): State<AppState>,
-    Path(payment_hash): Path<String>,
-) -> Result<Json<InvoiceStatusResponse>, (StatusCode, Json<ErrorResponse>)> {
-    let payment_hash_bytes = match hex::decode(&payment_hash) {
-        Ok(bytes) => bytes,
+/// Returns the amount and fee of paying an invoice without paying it.
+async fn quote_payment<B: LightningBackend>(
+    State(state): State<AppState<B>>,
+    Json(payload): Json<QuotePaymentRequest>,
+) -> Result<Json<PaymentQuote>, (StatusCode, Json<ErrorResponse>)> {
+    let invoice = match payload.invoice.parse::<Bolt11Invoice>() {
+        Ok(inv) => inv,
         Err(e) => {
             return Err((
                 StatusCode::BAD_REQUEST,
                 Json(ErrorResponse {
-                    error: format!("Invalid payment hash: {}", e),
+                    error: format!("Invalid invoice: {}", e),
                 }),
             ));
         }
     };
 
-    if payment_hash_bytes.len() != 32 {
-        return Err((
+    match state.blitzi.quote_payment(&invoice).await {
+        Ok(quote) => Ok(Json(quote)),
+        Err(e) if e.downcast_ref::<InvalidInvoiceError>().is_some() => Err((
             StatusCode::BAD_REQUEST,
             Json(ErrorResponse {
-                error: "Payment hash must be 32 bytes".to_string(),
+                error: format!("Invalid invoice: {}", e),
             }),
-        ));
-    }
-
-    let mut hash_array = [0u8; 32];
-    hash_array.copy_from_slice(&payment_hash_bytes);
-    let payment_hash_obj =
-        fedimint_core::bitcoin::hashes::sha256::Hash::from_byte_array(hash_array);
-
-    match state
-        .blitzi
-        .await_incoming_payment_by_hash(&payment_hash_obj)
-        .await
-    {
-        Ok(()) => Ok(Json(InvoiceStatusResponse { paid: true })),
+        )),
+        Err(e) if e.downcast_ref::<UnsupportedByLnv2>().is_some() => Err((
+            StatusCode::NOT_IMPLEMENTED,
+            Json(ErrorResponse {
+                error: e.to_string(),
+            }),
+        )),
         Err(e) => {
-            let error_msg = e.to_string();
-            if error_msg.contains("No operation found") {
-                Err((
-                    StatusCode::NOT_FOUND,
-                    Json(ErrorResponse {
-                        error: "Invoice not found or not issued by this server".to_string(),
-                    }),
-                ))
-            } else if error_msg.contains("canceled") {
-                Ok(Json(InvoiceStatusResponse { paid: false }))
-            } else {
-                error!("Error checking invoice status: {}", e);
-                Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(ErrorResponse {
-                        error: format!("Failed to check invoice status: {}", e),
-                    }),
-                ))
-            }
+            error!(error = %e, payment_hash = %invoice.payment_hash(), "Failed to quote payment");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ErrorResponse {
+                    error: format!("Failed to quote payment: {}", e),
+                }),
+            ))
         }
     }
 }
 
-async fn health_check() -> &'static str {
+async fn get_balance<B: LightningBackend>(
+    State(state): State<AppState<B>>,
+) -> Result<Json<BalanceResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let balance = state.blitzi.balance().await;
+    Ok(Json(BalanceResponse {
+        balance_msats: balance.msats,
+    }))
+}
+
+/// Returns the version of blitzid without contacting the federation.
+async fn get_version() -> Json<VersionResponse> {
+    Json(VersionResponse::current())
+}
+
+/// Describes the daemon and the federation it's connected to, e.g. for deploy
+/// tooling to check that it's configured as expected.
+async fn get_info<B: LightningBackend>(State(state): State<AppState<B>>) -> Json<InfoResponse> {
+    let federation_name = match state.blitzi.meta().await {
+        Ok(mut meta) => meta.remove("federation_name"),
+        Err(e) => {
+            warn!(error = %e, "Failed to read the federation meta data");
+            None
+        }
+    };
+    let capabilities = match state.blitzi.capabilities().await {
+        Ok(capabilities) => Some(capabilities),
+        Err(e) => {
+            warn!(error = %e, "Failed to read the federation capabilities");
+            None
+        }
+    };
+
+    Json(InfoResponse {
+        version: VersionResponse::current(),
+        federation_id: state.blitzi.federation_id().to_string(),
+        federation_name,
+        capabilities,
+        gateways: state.blitzi.list_gateways().await,
+        datadir: state
+            .datadir
+            .as_ref()
+            .map(|datadir| datadir.display().to_string()),
+        uptime_secs: state.started_at.elapsed().as_secs(),
+    })
+}
+
+async fn get_stats<B: LightningBackend>(
+    State(state): State<AppState<B>>,
+) -> Result<Json<WalletStats>, (StatusCode, Json<ErrorResponse>)> {
+    Ok(Json(state.blitzi.wallet_stats().await))
+}
+
+/// Replaces the bearer token with a new one, which is only returned in this
+/// response. Requests using the old token are rejected from now on.
+async fn rotate_token<B: LightningBackend>(
+    State(state): State<AppState<B>>,
+) -> Json<RotateTokenResponse> {
+    let bearer_token = state.bearer_token.rotate();
+    info!("Bearer token rotated, the previous token is no longer accepted");
+    Json(RotateTokenResponse { bearer_token })
+}
+
+/// Lists the Lightning gateways registered with the federation and their fees.
+async fn get_gateways<B: LightningBackend>(
+    State(state): State<AppState<B>>,
+) -> Result<Json<GatewaysResponse>, (StatusCode, Json<ErrorResponse>)> {
+    Ok(Json(GatewaysResponse {
+        gateways: state.blitzi.list_gateways().await,
+    }))
+}
+
+/// Lists the operation history, newest first. Older entries can be fetched by
+/// passing the `next_cursor` of the previous response as `before`.
+async fn get_history<B: LightningBackend>(
+    State(state): State<AppState<B>>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<HistoryResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_HISTORY_LIMIT)
+        .clamp(1, MAX_HISTORY_LIMIT);
+
+    let before = match query.before.map(|cursor| cursor.parse::<OperationCursor>()) {
+        Some(Ok(cursor)) => Some(cursor),
+        Some(Err(e)) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse {
+                    error: format!("Invalid cursor: {}", e),
+                }),
+            ));
+        }
+        None => None,
+    };
+
+    let entries = state.blitzi.list_operations(limit, before).await;
+    let next_cursor = if entries.len() == limit {
+        entries.last().map(|entry| entry.cursor)
+    } else {
+        None
+    };
+
+    Ok(Json(HistoryResponse {
+        entries,
+        next_cursor,
+    }))
+}
+
+/// Lists the invoices created by blitzid, newest first.
+async fn list_invoices<B: LightningBackend>(
+    State(state): State<AppState<B>>,
+    Query(query): Query<TransactionsQuery>,
+) -> Result<Json<TransactionsResponse>, (StatusCode, Json<ErrorResponse>)> {
+    list_transactions(&state, HistoryEntryKind::Receive, query).await
+}
+
+/// Lists the outgoing payments, newest first.
+async fn list_payments<B: LightningBackend>(
+    State(state): State<AppState<B>>,
+    Query(query): Query<TransactionsQuery>,
+) -> Result<Json<TransactionsResponse>, (StatusCode, Json<ErrorResponse>)> {
+    list_transactions(&state, HistoryEntryKind::Pay, query).await
+}
+
+async fn list_transactions<B: LightningBackend>(
+    state: &AppState<B>,
+    kind: HistoryEntryKind,
+    query: TransactionsQuery,
+) -> Result<Json<TransactionsResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_TRANSACTION_LIMIT)
+        .clamp(1, MAX_TRANSACTION_LIMIT);
+
+    let before = match query.cursor.map(|cursor| cursor.parse::<OperationCursor>()) {
+        Some(Ok(cursor)) => Some(cursor),
+        Some(Err(e)) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse {
+                    error: format!("Invalid cursor: {}", e),
+                }),
+            ));
+        }
+        None => None,
+    };
+
+    // Fetch one more entry to know whether there is another page
+    let mut entries = state
+        .blitzi
+        .list_transactions(kind, query.status.map(Into::into), limit + 1, before)
+        .await;
+    let next_cursor = if entries.len() > limit {
+        entries.truncate(limit);
+        entries.last().map(|entry| entry.cursor)
+    } else {
+        None
+    };
+
+    Ok(Json(TransactionsResponse {
+        transactions: entries.into_iter().map(Into::into).collect(),
+        next_cursor,
+    }))
+}
+
+/// Upgrades to a WebSocket streaming the states of the incoming payment for
+/// an invoice as JSON messages, see [`stream_invoice_updates`]. The bearer
+/// token is passed as the `token` query parameter.
+async fn invoice_socket<B: LightningBackend>(
+    State(state): State<AppState<B>>,
+    Path(payment_hash): Path<String>,
+    Query(query): Query<InvoiceSocketQuery>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    if !query
+        .token
+        .is_some_and(|token| state.bearer_token.matches(&token))
+    {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let payment_hash = match payment_hash.parse::<PaymentHash>() {
+        Ok(payment_hash) => payment_hash,
+        Err(e) => {
+            let error = ErrorResponse {
+                error: format!("{:#}", e),
+            };
+            return (StatusCode::BAD_REQUEST, Json(error)).into_response();
+        }
+    };
+
+    upgrade.on_upgrade(move |socket| stream_invoice_updates(state.blitzi, payment_hash, socket))
+}
+
+/// Sends a message for every state of the incoming payment for `payment_hash`
+/// until it reaches a final one, then closes `socket` normally. Invoices not
+/// issued by this server close it with a policy violation.
+async fn stream_invoice_updates<B: LightningBackend>(
+    blitzi: Arc<B>,
+    payment_hash: PaymentHash,
+    mut socket: WebSocket,
+) {
+    let updates = match blitzi.is_invoice_ours(payment_hash).await {
+        Ok(true) => blitzi.subscribe_invoice_updates(payment_hash).await,
+        Ok(false) => Err(anyhow::anyhow!(
+            "Invoice not found or not issued by this server"
+        )),
+        Err(e) => Err(e),
+    };
+    let mut updates = match updates {
+        Ok(updates) => updates,
+        Err(e) => {
+            close(socket, close_code::POLICY, &e.to_string()).await;
+            return;
+        }
+    };
+
+    loop {
+        let update = tokio::select! {
+            update = futures_lite::StreamExt::next(&mut updates) => update,
+            // Messages from the client are ignored, only disconnects matter
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+                Some(Ok(_)) => continue,
+            },
+        };
+        let Some(update) = update else {
+            break;
+        };
+        let message = serde_json::to_string(&update).expect("states can be serialized");
+        if socket.send(Message::Text(message)).await.is_err() {
+            return;
+        }
+        if update.is_final() {
+            break;
+        }
+    }
+    close(socket, close_code::NORMAL, "").await;
+}
+
+/// Closes `socket` with `code`, truncating `reason` to the 123 bytes a close
+/// frame can carry.
+async fn close(mut socket: WebSocket, code: u16, reason: &str) {
+    let mut end = reason.len().min(123);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    let frame = CloseFrame {
+        code,
+        reason: reason[..end].to_owned().into(),
+    };
+    let _ = socket.send(Message::Close(Some(frame))).await;
+}
+
+/// Waits for the invoice to be paid or canceled, at most `timeout_secs` (capped
+/// at [`MAX_LONG_POLL_TIMEOUT`]) if given.
+async fn check_invoice<B: LightningBackend>(
+    State(state): State<AppState<B>>,
+    Path(payment_hash): Path<String>,
+    Query(query): Query<InvoiceStatusQuery>,
+) -> Result<Json<InvoiceStatusResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let payment_hash = match payment_hash.parse::<PaymentHash>() {
+        Ok(payment_hash) => payment_hash,
+        Err(e) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse {
+                    error: format!("{:#}", e),
+                }),
+            ));
+        }
+    };
+
+    let internal_error = |e: anyhow::Error| {
+        error!(error = %e, %payment_hash, "Error checking invoice status");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse {
+                error: format!("Failed to check invoice status: {}", e),
+            }),
+        )
+    };
+
+    if !state
+        .blitzi
+        .is_invoice_ours(payment_hash)
+        .await
+        .map_err(internal_error)?
+    {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: "Invoice not found or not issued by this server".to_string(),
+            }),
+        ));
+    }
+
+    let external_id = state
+        .blitzi
+        .invoice_external_id(payment_hash)
+        .await
+        .map_err(internal_error)?;
+    let timeout = query
+        .timeout_secs
+        .map(|secs| Duration::from_secs(secs).min(MAX_LONG_POLL_TIMEOUT));
+    let result = match timeout {
+        Some(timeout) => {
+            match tokio::time::timeout(timeout, state.blitzi.await_incoming_payment(payment_hash))
+                .await
+            {
+                Ok(result) => result,
+                Err(_) => {
+                    return Ok(Json(InvoiceStatusResponse {
+                        paid: false,
+                        timed_out: true,
+                        external_id,
+                    }));
+                }
+            }
+        }
+        None => state.blitzi.await_incoming_payment(payment_hash).await,
+    };
+    match result {
+        Ok(()) => Ok(Json(InvoiceStatusResponse {
+            paid: true,
+            timed_out: false,
+            external_id,
+        })),
+        Err(e) => match state.blitzi.invoice_status(payment_hash).await {
+            Ok(InvoiceStatus::Canceled) => Ok(Json(InvoiceStatusResponse {
+                paid: false,
+                timed_out: false,
+                external_id,
+            })),
+            _ => Err(internal_error(e)),
+        },
+    }
+}
+
+/// Returns the current status of an invoice without waiting for it to be paid.
+async fn get_invoice_state<B: LightningBackend>(
+    State(state): State<AppState<B>>,
+    Path(payment_hash): Path<String>,
+) -> Result<Json<InvoiceStateResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let payment_hash = match payment_hash.parse::<PaymentHash>() {
+        Ok(payment_hash) => payment_hash,
+        Err(e) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse {
+                    error: format!("{:#}", e),
+                }),
+            ));
+        }
+    };
+
+    let internal_error = |e: anyhow::Error| {
+        error!(error = %e, %payment_hash, "Error checking invoice status");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse {
+                error: format!("Failed to check invoice status: {}", e),
+            }),
+        )
+    };
+
+    let Some(invoice) = state
+        .blitzi
+        .issued_invoice(payment_hash)
+        .await
+        .map_err(internal_error)?
+    else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: "Invoice not found or not issued by this server".to_string(),
+            }),
+        ));
+    };
+
+    let status = match state
+        .blitzi
+        .invoice_status(payment_hash)
+        .await
+        .map_err(internal_error)?
+    {
+        InvoiceStatus::Paid => InvoiceState::Paid,
+        // Expired invoices are canceled by the library once it notices
+        InvoiceStatus::Pending | InvoiceStatus::Canceled if invoice.is_expired() => {
+            InvoiceState::Expired
+        }
+        InvoiceStatus::Pending => InvoiceState::Pending,
+        InvoiceStatus::Canceled => InvoiceState::Canceled,
+    };
+    let external_id = state
+        .blitzi
+        .invoice_external_id(payment_hash)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(InvoiceStateResponse {
+        status,
+        amount_msats: invoice.amount_milli_satoshis(),
+        created_at: invoice.duration_since_epoch().as_secs(),
+        expires_at: invoice.expires_at().map(|expires_at| expires_at.as_secs()),
+        external_id,
+    }))
+}
+
+/// Streams wallet events as Server-Sent Events. Clients reconnecting with a
+/// `Last-Event-ID` header first receive the buffered events they missed.
+async fn get_events<B: LightningBackend>(
+    State(state): State<AppState<B>>,
+    headers: HeaderMap,
+) -> impl axum::response::IntoResponse {
+    let last_event_id = headers
+        .get(LAST_EVENT_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.trim().parse().ok());
+    state.events.subscribe(last_event_id)
+}
+
+/// Returns the metrics in the Prometheus text format.
+async fn get_metrics<B: LightningBackend>(State(state): State<AppState<B>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, metrics::CONTENT_TYPE)],
+        state.metrics.render(state.blitzi.as_ref()).await,
+    )
+}
+
+async fn health_check() -> &'static str {
     "OK"
 }
 
+fn init_logging(format: LogFormat) {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+
+    match format {
+        LogFormat::Text => tracing_subscriber::fmt().with_env_filter(filter).init(),
+        LogFormat::Json => tracing_subscriber::fmt()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_env_filter(filter)
+            .init(),
+    }
+}
+
+/// Creates the tracing span every request is handled in, so all log lines
+/// emitted while handling a request carry its id.
+fn request_span(request: &Request<Body>) -> Span {
+    let request_id = hex::encode(rand::random::<[u8; 8]>());
+    info_span!(
+        "request",
+        %request_id,
+        method = %request.method(),
+        path = %request.uri().path(),
+    )
+}
+
+/// How long browsers may cache the answer to a CORS preflight request.
+const CORS_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Builds the CORS layer allowing browser apps on `origins` to call the API,
+/// returns `None` if no origins are configured.
+fn cors_layer(origins: &[String]) -> anyhow::Result<Option<CorsLayer>> {
+    if origins.is_empty() {
+        return Ok(None);
+    }
+
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        warn!(
+            "CORS allows any origin, every website visited by someone holding the bearer token \
+             can use it to call the API"
+        );
+        AllowOrigin::any()
+    } else {
+        let origins = origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin)
+                    .with_context(|| format!("Invalid CORS origin: {}", origin))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        AllowOrigin::list(origins)
+    };
+
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET, Method::POST])
+            .allow_headers([
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                HeaderName::from_static(IDEMPOTENCY_KEY_HEADER),
+                HeaderName::from_static(LAST_EVENT_ID_HEADER),
+            ])
+            .expose_headers([HeaderName::from_static(idempotency::REPLAYED_HEADER)])
+            .max_age(CORS_MAX_AGE),
+    ))
+}
+
+/// Answers all requests but the allowed ones in read-only mode.
+async fn read_only_rejected() -> (StatusCode, Json<ErrorResponse>) {
+    (
+        StatusCode::FORBIDDEN,
+        Json(ErrorResponse {
+            error: "blitzid is running in read-only mode".to_string(),
+        }),
+    )
+}
+
+fn router<B: LightningBackend>(state: AppState<B>, cors: Option<CorsLayer>) -> Router {
+    let read_only_routes = Router::new()
+        .route("/balance", get(get_balance::<B>))
+        .route("/history", get(get_history::<B>))
+        .route("/stats", get(get_stats::<B>));
+    let protected_routes = if state.read_only {
+        read_only_routes.fallback(read_only_rejected)
+    } else {
+        read_only_routes
+            .route("/events", get(get_events::<B>))
+            .route("/invoices", get(list_invoices::<B>))
+            .route("/payments", get(list_payments::<B>))
+            .route("/metrics", get(get_metrics::<B>))
+            .route("/info", get(get_info::<B>))
+            .route("/version", get(get_version))
+            .route("/admin/rotate-token", post(rotate_token::<B>))
+            .route("/invoice", post(create_invoice::<B>))
+            .route("/invoice/:payment_hash", get(check_invoice::<B>))
+            .route("/invoice/:payment_hash/status", get(get_invoice_state::<B>))
+            .route("/pay", post(pay_invoice::<B>))
+            .route("/pay/quote", post(quote_payment::<B>))
+            .route("/gateways", get(get_gateways::<B>))
+    };
+    let protected_routes = protected_routes.layer(middleware::from_fn_with_state(
+        state.clone(),
+        auth_middleware::<B>,
+    ));
+
+    let mut app = Router::new()
+        .route("/health", get(health_check))
+        .merge(protected_routes);
+    // Authenticates via the query instead of the auth middleware
+    if !state.read_only {
+        app = app.route("/ws/invoice/:payment_hash", get(invoice_socket::<B>));
+    }
+
+    // CORS has to wrap the auth middleware so preflight requests, which don't
+    // carry credentials, are answered without authentication
+    let app = match cors {
+        Some(cors) => app.layer(cors),
+        None => app,
+    };
+
+    app.layer(TraceLayer::new_for_http().make_span_with(request_span))
+        .with_state(state)
+}
+
+/// Router serving only `GET /metrics`, without authentication.
+fn metrics_router<B: LightningBackend>(state: AppState<B>) -> Router {
+    Router::new()
+        .route("/metrics", get(get_metrics::<B>))
+        .with_state(state)
+}
+
 fn generate_bearer_token() -> String {
     use rand::Rng;
     const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
@@ -255,14 +1659,36 @@ fn generate_bearer_token() -> String {
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
-        )
-        .init();
+    let started_at = Instant::now();
+    let args = parse_args(&Args::command().get_matches())?;
+    init_logging(args.log_format);
+
+    let cors = cors_layer(&args.cors_origins)?;
+    let tls = match TlsFiles::new(args.tls_cert.clone(), args.tls_key.clone())? {
+        Some(files) => {
+            let config = RustlsConfig::from_config(files.load()?);
+            Some((files, config))
+        }
+        None => None,
+    };
+
+    anyhow::ensure!(
+        !(args.read_only && args.nwc_relay.is_some()),
+        "Nostr Wallet Connect can't be enabled in read-only mode"
+    );
 
-    let args = Args::parse();
+    if let Some(prefix) = &args.invoice_description_prefix {
+        anyhow::ensure!(
+            prefix.len() <= MAX_DESCRIPTION_LEN,
+            "Invoice description prefix is {} bytes long, the maximum is {} bytes",
+            prefix.len(),
+            MAX_DESCRIPTION_LEN
+        );
+    }
+    let invoice_limits = InvoiceLimits {
+        max_amount: args.max_invoice_msats.map(msats),
+        description_prefix: args.invoice_description_prefix,
+    };
 
     let bearer_token = args.bearer_token.unwrap_or_else(|| {
         let token = generate_bearer_token();
@@ -276,6 +1702,13 @@ async fn main() -> anyhow::Result<()> {
     if let Some(datadir) = args.datadir {
         builder = builder.datadir(datadir);
     }
+    if let Some(backend) = args.db_backend {
+        builder = builder.database_backend(backend.into());
+    }
+    if let Some(interval) = args.config_check_interval {
+        anyhow::ensure!(interval > 0, "Config check interval must be positive");
+        builder = builder.config_check_interval(Duration::from_secs(interval));
+    }
 
     if let Some(federation) = args.federation {
         builder = builder
@@ -289,43 +1722,1525 @@ async fn main() -> anyhow::Result<()> {
         .context("Failed to build Blitzi client")?;
     info!("Blitzi client initialized successfully");
 
+    let nwc = match &args.nwc_relay {
+        Some(relay) => {
+            let datadir = blitzi
+                .datadir()
+                .context("Nostr Wallet Connect needs a data directory")?;
+            let secret = match &args.nwc_secret {
+                Some(secret) => secret.clone(),
+                None => NwcServer::load_or_generate_secret(datadir)?,
+            };
+            let nwc = NwcServer::new(relay, &secret)?;
+            let path = nwc.write_connection_uri(datadir)?;
+            info!(
+                path = %path.display(),
+                "Wrote the NWC connection URI, anyone who can read it can spend the wallet's funds"
+            );
+            Some(nwc)
+        }
+        None => None,
+    };
+
+    let metrics = Arc::new(Metrics::default());
+    let webhook_secret = args.webhook_secret.unwrap_or_else(|| {
+        let secret = Webhooks::generate_secret();
+        info!("Generated webhook secret: {}", secret);
+        secret
+    });
+    let webhooks = Arc::new(Webhooks::load(
+        blitzi
+            .datadir()
+            .context("Webhooks need a data directory")?
+            .join("blitzid-webhooks.json"),
+        webhook_secret,
+        metrics.clone(),
+    )?);
+    let idempotency = Arc::new(IdempotencyStore::load(
+        blitzi
+            .datadir()
+            .context("Idempotency keys need a data directory")?
+            .join("blitzid-idempotency.json"),
+    )?);
+
+    let blitzi = Arc::new(blitzi);
+
+    // Payments interrupted by a crash or restart are followed until they
+    // finish, without paying again
+    let resume_task = {
+        let blitzi = blitzi.clone();
+        tokio::spawn(async move {
+            for (payment_hash, result) in blitzi.resume_pending_payments().await {
+                info!(%payment_hash, ?result, "Resumed interrupted payment");
+            }
+        })
+    };
+
+    let nwc_task = nwc.map(|nwc| {
+        let blitzi = blitzi.clone();
+        let invoice_limits = invoice_limits.clone();
+        tokio::spawn(async move {
+            if let Err(e) = nwc.run(blitzi, invoice_limits).await {
+                error!(error = %e, "NWC server failed");
+            }
+        })
+    });
+
+    let webhook_task = tokio::spawn(webhooks.clone().run(blitzi.clone()));
+    let events = Arc::new(EventLog::default());
+    let events_task = tokio::spawn(events.clone().run(blitzi.clone()));
+    let metrics_task = tokio::spawn(metrics.clone().run(blitzi.clone()));
+
     let state = AppState {
-        blitzi: Arc::new(blitzi),
-        bearer_token: bearer_token.clone(),
-    };
-
-    let protected_routes = Router::new()
-        .route("/invoice", post(create_invoice))
-        .route("/invoice/:payment_hash", get(check_invoice))
-        .route("/pay", post(pay_invoice))
-        .route("/balance", get(get_balance))
-        .layer(middleware::from_fn_with_state(
-            state.clone(),
-            auth_middleware,
-        ));
+        blitzi: blitzi.clone(),
+        bearer_token: Arc::new(BearerToken::new(bearer_token.clone())),
+        invoice_limits,
+        read_only: args.read_only,
+        webhooks: Some(webhooks),
+        events,
+        idempotency,
+        metrics,
+        datadir: blitzi.datadir().map(FsPath::to_path_buf),
+        started_at,
+    };
 
-    let app = Router::new()
-        .route("/health", get(health_check))
-        .merge(protected_routes)
-        .with_state(state);
+    if args.read_only {
+        warn!("Read-only mode: only GET /balance, /history and /stats are served");
+    }
+
+    let metrics_server = match args.metrics_port {
+        Some(port) => {
+            let addr = format!("{}:{}", args.host, port);
+            let listener = tokio::net::TcpListener::bind(&addr)
+                .await
+                .context("Failed to bind metrics address")?;
+            info!(%addr, "Serving GET /metrics without authentication");
+            let app = metrics_router(state.clone());
+            Some(tokio::spawn(async move {
+                if let Err(e) = axum::serve(listener, app).await {
+                    error!(error = %e, "Metrics server failed");
+                }
+            }))
+        }
+        None => None,
+    };
+
+    if cors.is_some() {
+        info!(origins = ?args.cors_origins, "CORS enabled");
+    }
+    let app = router(state, cors);
 
     let addr = format!("{}:{}", args.host, args.port);
-    info!("Starting server on {}", addr);
+    info!(%addr, tls = tls.is_some(), "Starting server");
     info!("Use Authorization header: Bearer {}", bearer_token);
 
     let listener = tokio::net::TcpListener::bind(&addr)
         .await
         .context("Failed to bind to address")?;
 
-    axum::serve(listener, app).await.context("Server error")?;
+    match tls {
+        Some((files, config)) => {
+            #[cfg(unix)]
+            let reload_task = tokio::spawn(files.reload_on_sighup(config.clone()));
+            #[cfg(not(unix))]
+            drop(files);
+            tls::serve(listener, app, config, shutdown_signal())
+                .await
+                .context("Server error")?;
+            #[cfg(unix)]
+            reload_task.abort();
+        }
+        None => {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown_signal())
+                .await
+                .context("Server error")?;
+        }
+    }
+    info!("Server stopped, all in-flight requests finished");
+
+    resume_task.abort();
+    let _ = resume_task.await;
+    // Undelivered notifications are persisted and sent after the next start
+    webhook_task.abort();
+    let _ = webhook_task.await;
+    events_task.abort();
+    let _ = events_task.await;
+    metrics_task.abort();
+    let _ = metrics_task.await;
+    if let Some(metrics_server) = metrics_server {
+        metrics_server.abort();
+        let _ = metrics_server.await;
+    }
+
+    if let Some(nwc_task) = nwc_task {
+        nwc_task.abort();
+        let _ = nwc_task.await;
+        info!("NWC server stopped");
+    }
+
+    info!("Shutting down Blitzi client...");
+    match Arc::try_unwrap(blitzi) {
+        Ok(blitzi) => {
+            blitzi
+                .shutdown()
+                .await
+                .context("Failed to shut down Blitzi client")?;
+            info!("Blitzi client shut down, database flushed");
+        }
+        Err(_) => error!("Blitzi client is still in use, exiting without shutting it down"),
+    }
 
     Ok(())
 }
 
+/// Resolves once SIGINT (Ctrl+C) or, on Unix, SIGTERM is received, which
+/// makes the server stop accepting connections and finish in-flight requests.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!(error = %e, "Failed to listen for SIGINT");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!(error = %e, "Failed to listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => info!("Received SIGINT, shutting down"),
+        () = terminate => info!("Received SIGTERM, shutting down"),
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use blitzi::{FederationId, MockCall, MockIncomingPayment, MockLightning, MockPayment};
+    use futures_lite::StreamExt;
+    use tower::ServiceExt;
+
     use super::*;
 
+    const TEST_TOKEN: &str = "test-token";
+
+    fn test_app() -> (Arc<MockLightning>, Router) {
+        test_app_with_cors(None)
+    }
+
+    fn test_app_with_cors(cors: Option<CorsLayer>) -> (Arc<MockLightning>, Router) {
+        test_app_with_state(cors, None, None)
+    }
+
+    fn test_app_with_state(
+        cors: Option<CorsLayer>,
+        max_invoice_amount: Option<Amount>,
+        invoice_description_prefix: Option<&str>,
+    ) -> (Arc<MockLightning>, Router) {
+        let (mock, state) = test_state();
+        let app = router(
+            AppState {
+                invoice_limits: InvoiceLimits {
+                    max_amount: max_invoice_amount,
+                    description_prefix: invoice_description_prefix.map(str::to_owned),
+                },
+                ..state
+            },
+            cors,
+        );
+        (mock, app)
+    }
+
+    /// State of a writable daemon backed by a fresh mock, tests override
+    /// single fields using struct update syntax.
+    fn test_state() -> (Arc<MockLightning>, AppState<MockLightning>) {
+        let mock = Arc::new(MockLightning::new());
+        let state = AppState {
+            blitzi: mock.clone(),
+            bearer_token: Arc::new(BearerToken::new(TEST_TOKEN.to_string())),
+            invoice_limits: InvoiceLimits::default(),
+            read_only: false,
+            webhooks: None,
+            events: Arc::default(),
+            idempotency: Arc::default(),
+            metrics: Arc::default(),
+            datadir: None,
+            started_at: Instant::now(),
+        };
+        (mock, state)
+    }
+
+    fn preflight(origin: &str) -> Request<Body> {
+        Request::builder()
+            .method("OPTIONS")
+            .uri("/balance")
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn request(
+        app: Router,
+        method: &str,
+        uri: &str,
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, serde_json::Value) {
+        request_with_headers(app, method, uri, &[], body).await
+    }
+
+    async fn request_with_headers(
+        app: Router,
+        method: &str,
+        uri: &str,
+        headers: &[(&str, &str)],
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, serde_json::Value) {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {}", TEST_TOKEN))
+            .header(header::CONTENT_TYPE, "application/json");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let request = match body {
+            Some(body) => request.body(Body::from(body.to_string())),
+            None => request.body(Body::empty()),
+        }
+        .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (
+            status,
+            serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_requires_auth() {
+        let (_, app) = test_app();
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/balance")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_rotate_token() {
+        let (_, app) = test_app();
+        let balance_with = |token: String| {
+            let app = app.clone();
+            async move {
+                app.oneshot(
+                    Request::builder()
+                        .uri("/balance")
+                        .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap()
+                .status()
+            }
+        };
+
+        let (status, body) = request(app.clone(), "POST", "/admin/rotate-token", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let new_token = body["bearer_token"].as_str().unwrap().to_owned();
+        assert_ne!(new_token, TEST_TOKEN);
+
+        assert_eq!(
+            balance_with(TEST_TOKEN.to_owned()).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(balance_with(new_token.clone()).await, StatusCode::OK);
+
+        // Only the current token may rotate it
+        let (status, _) = request(app, "POST", "/admin/rotate-token", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_max_invoice_amount() {
+        let (mock, app) = test_app_with_state(None, Some(msats(10_000)), None);
+
+        let (status, body) = request(
+            app.clone(),
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({ "amount_msats": 10_001, "description": "test" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(
+            body["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("Invalid amount")
+        );
+        // Rejected before the backend is asked for an invoice
+        assert!(mock.calls().is_empty());
+
+        let (status, _) = request(
+            app,
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({ "amount_msats": 10_000, "description": "test" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let events = Arc::new(EventLog::default());
+        let app = router(
+            AppState {
+                events: events.clone(),
+                ..test_state().1
+            },
+            None,
+        );
+        for balance in [1, 2, 3] {
+            events.push(blitzi::BlitziEvent::BalanceChanged {
+                balance: msats(balance),
+            });
+        }
+
+        // A reconnecting client receives the events after its last seen one
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/events")
+                    .header(header::AUTHORIZATION, format!("Bearer {}", TEST_TOKEN))
+                    .header(LAST_EVENT_ID_HEADER, "1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+
+        let mut body = response.into_body().into_data_stream();
+        let mut received = String::new();
+        while !received.contains("id: 3") {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), body.next())
+                .await
+                .expect("events are sent")
+                .unwrap()
+                .unwrap();
+            received.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        assert!(!received.contains("id: 1\n"));
+        assert!(
+            received.contains("id: 2\ndata: {\"type\":\"balance_changed\",\"balance_msats\":2}\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_only() {
+        let (mock, state) = test_state();
+        let app = router(
+            AppState {
+                read_only: true,
+                ..state
+            },
+            None,
+        );
+
+        for uri in ["/balance", "/history", "/stats"] {
+            let (status, _) = request(app.clone(), "GET", uri, None).await;
+            assert_eq!(status, StatusCode::OK, "{uri}");
+        }
+        let calls = mock.calls().len();
+
+        let (status, body) = request(
+            app.clone(),
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({ "amount_msats": 1_000, "description": "test" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"], "blitzid is running in read-only mode");
+        let (status, _) = request(
+            app.clone(),
+            "POST",
+            "/pay",
+            Some(serde_json::json!({ "invoice": "lnbc1" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        for uri in [
+            "/gateways",
+            "/events",
+            "/invoices",
+            "/payments",
+            "/metrics",
+            "/info",
+            "/version",
+            "/unknown",
+        ] {
+            let (status, _) = request(app.clone(), "GET", uri, None).await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{uri}");
+        }
+        let (status, _) = request(app.clone(), "POST", "/admin/rotate-token", None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        // The token wasn't rotated
+        let (status, _) = request(app.clone(), "GET", "/balance", None).await;
+        assert_eq!(status, StatusCode::OK);
+        // The rejected requests didn't reach the backend
+        assert_eq!(mock.calls().len(), calls);
+
+        // Rejected routes still require authentication
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/pay")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_payment_outcomes() {
+        let (mock, state) = test_state();
+        let metrics = state.metrics.clone();
+        tokio::spawn(metrics.clone().run(mock.clone()));
+        // Lets the task subscribe to the events
+        tokio::task::yield_now().await;
+        let app = router(state, None);
+        let invoice = mock.lightning_invoice(msats(1000), "test").await.unwrap();
+        let pay = || {
+            request(
+                app.clone(),
+                "POST",
+                "/pay",
+                Some(serde_json::json!({ "invoice": invoice.to_string() })),
+            )
+        };
+
+        // Rejected before a payment is started
+        let (status, _) = pay().await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+
+        // Started and refunded
+        mock.set_balance(msats(10_000));
+        mock.script_payment(
+            invoice.payment_hash(),
+            MockPayment::Failure("no route".to_string()),
+        );
+        let (status, _) = pay().await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+
+        let out = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let out = metrics.render(mock.as_ref()).await;
+                if out.contains("blitzid_payments_total{outcome=\"refunded\"} 1\n") {
+                    return out;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the refund is counted");
+        assert!(out.contains("blitzid_payments_total{outcome=\"failed\"} 1\n"));
+        assert!(out.contains("blitzid_payments_total{outcome=\"succeeded\"} 0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let (_, app) = test_app();
+        let (status, _) = request(
+            app.clone(),
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({ "amount_msats": 1_000, "description": "test" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .header(header::AUTHORIZATION, format!("Bearer {}", TEST_TOKEN))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            metrics::CONTENT_TYPE
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("blitzid_invoices_created_total 1\n"));
+        assert!(body.contains("# TYPE blitzid_balance_msats gauge\n"));
+
+        // Only the separate metrics port serves it without authentication
+        let unauthenticated = || {
+            Request::builder()
+                .uri("/metrics")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = app.oneshot(unauthenticated()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let (_, state) = test_state();
+        let response = metrics_router(state)
+            .oneshot(unauthenticated())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_info() {
+        let (mock, app) = test_app();
+        mock.set_meta([("federation_name".to_string(), "Test Federation".to_string())].into());
+
+        let (status, body) = request(app.clone(), "GET", "/info", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(body["federation_id"], FederationId::dummy().to_string());
+        assert_eq!(body["federation_name"], "Test Federation");
+        assert_eq!(body["capabilities"]["network"], "regtest");
+        assert_eq!(body["gateways"], serde_json::json!([]));
+        assert_eq!(body["datadir"], serde_json::Value::Null);
+        assert!(body["uptime_secs"].is_u64());
+
+        // Unavailable fields are null instead of failing the response
+        mock.set_capabilities(None);
+        let (status, body) = request(app.clone(), "GET", "/info", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["capabilities"], serde_json::Value::Null);
+        assert_eq!(body["federation_id"], FederationId::dummy().to_string());
+
+        let calls = mock.calls().len();
+        let (status, body) = request(app, "GET", "/version", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        assert!(body.get("git_hash").is_some());
+        // Answered without the backend
+        assert_eq!(mock.calls().len(), calls);
+    }
+
+    #[tokio::test]
+    async fn test_create_invoice_amount_sats() {
+        let (mock, app) = test_app();
+
+        let (status, _) = request(
+            app.clone(),
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({ "amount_sats": 21, "description": "test" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            mock.calls(),
+            vec![MockCall::LightningInvoice {
+                amount: msats(21_000),
+                description: "test".to_string(),
+            }]
+        );
+
+        for body in [
+            serde_json::json!({ "amount_sats": 21, "amount_msats": 21_000, "description": "test" }),
+            serde_json::json!({ "description": "test" }),
+            serde_json::json!({ "amount_sats": u64::MAX, "description": "test" }),
+        ] {
+            let (status, body) = request(app.clone(), "POST", "/invoice", Some(body)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert!(
+                body["error"]
+                    .as_str()
+                    .unwrap()
+                    .starts_with("Invalid amount")
+            );
+        }
+        assert_eq!(mock.calls().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_create_invoice_webhook() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("webhooks.json");
+        let (mock, state) = test_state();
+        let state = AppState {
+            webhooks: Some(Arc::new(
+                Webhooks::load(path.clone(), "secret".to_string(), Arc::default()).unwrap(),
+            )),
+            ..state
+        };
+        let app = router(state.clone(), None);
+
+        let (status, body) = request(
+            app.clone(),
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({
+                "amount_msats": 1000,
+                "description": "order",
+                "webhook_url": "ftp://shop.example.com/paid",
+            })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(
+            body["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("Invalid webhook_url")
+        );
+        assert!(mock.calls().is_empty());
+
+        let (status, body) = request(
+            app.clone(),
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({
+                "amount_msats": 1000,
+                "description": "order",
+                "webhook_url": "https://shop.example.com/paid",
+                "metadata": { "order": 1 },
+            })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let queue: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        let registration = &queue["registrations"][body["payment_hash"].as_str().unwrap()];
+        assert_eq!(registration["url"], "https://shop.example.com/paid");
+        assert_eq!(registration["metadata"], serde_json::json!({ "order": 1 }));
+
+        // Rejected if webhooks are disabled
+        let app = router(
+            AppState {
+                webhooks: None,
+                ..state
+            },
+            None,
+        );
+        let (status, _) = request(
+            app,
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({
+                "amount_msats": 1000,
+                "description": "order",
+                "webhook_url": "https://shop.example.com/paid",
+            })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_invoice_description_prefix() {
+        let (mock, app) = test_app_with_state(None, None, Some("Shop: "));
+
+        let (status, _) = request(
+            app.clone(),
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({ "amount_msats": 1000, "description": "coffee" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            mock.calls(),
+            vec![MockCall::LightningInvoice {
+                amount: msats(1000),
+                description: "Shop: coffee".to_string(),
+            }]
+        );
+
+        // The limit reported excludes the prefix
+        let (status, body) = request(
+            app,
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({ "amount_msats": 1000, "description": "a".repeat(635) })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(
+            body["error"]
+                .as_str()
+                .unwrap()
+                .contains("635 bytes long, the maximum is 633 bytes")
+        );
+        assert_eq!(mock.calls().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_invoice_flow() {
+        let (mock, app) = test_app();
+        mock.set_default_incoming(MockIncomingPayment::PaidAfter(Duration::from_millis(10)));
+
+        let (status, body) = request(
+            app.clone(),
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({ "amount_msats": 1000, "description": "test" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let payment_hash = body["payment_hash"].as_str().unwrap().to_string();
+
+        let (status, body) = request(
+            app.clone(),
+            "GET",
+            &format!("/invoice/{}", payment_hash),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["paid"], true);
+
+        let (status, body) = request(app, "GET", "/balance", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["balance_msats"], 1000);
+    }
+
+    #[tokio::test]
+    async fn test_check_invoice_not_ours_or_canceled() {
+        let (mock, app) = test_app();
+
+        let (status, body) = request(
+            app.clone(),
+            "GET",
+            &format!("/invoice/{}", "00".repeat(32)),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            body["error"],
+            "Invoice not found or not issued by this server"
+        );
+
+        mock.set_default_incoming(MockIncomingPayment::Canceled);
+        let (_, body) = request(
+            app.clone(),
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({ "amount_msats": 1000, "description": "test" })),
+        )
+        .await;
+        let payment_hash = body["payment_hash"].as_str().unwrap().to_string();
+        let (status, body) = request(app, "GET", &format!("/invoice/{}", payment_hash), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["paid"], false);
+    }
+
+    #[tokio::test]
+    async fn test_invoice_status_endpoint() {
+        let (mock, app) = test_app();
+
+        let (status, _) = request(
+            app.clone(),
+            "GET",
+            &format!("/invoice/{}/status", "00".repeat(32)),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        mock.set_default_incoming(MockIncomingPayment::Never);
+        let (_, created) = request(
+            app.clone(),
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({ "amount_msats": 1000, "description": "test" })),
+        )
+        .await;
+        let payment_hash = created["payment_hash"].as_str().unwrap().to_string();
+
+        let (status, body) = request(
+            app.clone(),
+            "GET",
+            &format!("/invoice/{}/status", payment_hash),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "pending");
+        assert_eq!(body["amount_msats"], 1000);
+        assert!(body["created_at"].as_u64().unwrap() > 0);
+
+        // The long poll gives up instead of waiting for a payment forever
+        let (status, body) = request(
+            app.clone(),
+            "GET",
+            &format!("/invoice/{}?timeout_secs=0", payment_hash),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            serde_json::json!({ "paid": false, "timed_out": true })
+        );
+
+        mock.set_default_incoming(MockIncomingPayment::PaidAfter(Duration::ZERO));
+        let (_, created) = request(
+            app.clone(),
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({ "amount_msats": 2000, "description": "test" })),
+        )
+        .await;
+        let payment_hash = created["payment_hash"].as_str().unwrap().to_string();
+
+        let (status, body) = request(
+            app.clone(),
+            "GET",
+            &format!("/invoice/{}?timeout_secs=10", payment_hash),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({ "paid": true }));
+
+        let (status, body) = request(
+            app,
+            "GET",
+            &format!("/invoice/{}/status", payment_hash),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "paid");
+        assert_eq!(body["amount_msats"], 2000);
+    }
+
+    #[tokio::test]
+    async fn test_invoice_external_id() {
+        let (mock, app) = test_app();
+        mock.set_default_incoming(MockIncomingPayment::PaidAfter(Duration::ZERO));
+        let body = serde_json::json!({
+            "amount_msats": 1000,
+            "description": "test",
+            "external_id": "order-1",
+        });
+
+        let (status, created) = request(app.clone(), "POST", "/invoice", Some(body.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(created["external_id"], "order-1");
+
+        let uri = format!("/invoice/{}", created["payment_hash"].as_str().unwrap());
+        let (status, body_status) = request(app.clone(), "GET", &uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body_status,
+            serde_json::json!({ "paid": true, "external_id": "order-1" })
+        );
+
+        // Ids can only be attached once
+        let (status, _) = request(app.clone(), "POST", "/invoice", Some(body)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (status, _) = request(
+            app,
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({
+                "amount_msats": 1000,
+                "description": "test",
+                "external_id": "",
+            })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_invoice_socket() {
+        use tokio_tungstenite::tungstenite::Message;
+        use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+
+        let (mock, app) = test_app();
+        let (_, body) = request(
+            app.clone(),
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({ "amount_msats": 1000, "description": "test" })),
+        )
+        .await;
+        let payment_hash = body["payment_hash"].as_str().unwrap().to_string();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let url = |payment_hash: &str, token: &str| {
+            format!("ws://{}/ws/invoice/{}?token={}", addr, payment_hash, token)
+        };
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(url(&payment_hash, TEST_TOKEN))
+            .await
+            .unwrap();
+        let message = socket.next().await.unwrap().unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(message.to_text().unwrap()).unwrap(),
+            serde_json::json!({ "state": "awaiting_payment" })
+        );
+
+        mock.script_incoming(
+            payment_hash.parse::<PaymentHash>().unwrap(),
+            MockIncomingPayment::PaidAfter(Duration::ZERO),
+        );
+        let message = socket.next().await.unwrap().unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(message.to_text().unwrap()).unwrap(),
+            serde_json::json!({ "state": "claimed" })
+        );
+        let Message::Close(Some(frame)) = socket.next().await.unwrap().unwrap() else {
+            panic!("Expected a close frame");
+        };
+        assert_eq!(frame.code, CloseCode::Normal);
+
+        // Unknown invoices close the socket with a policy violation
+        let (mut socket, _) = tokio_tungstenite::connect_async(url(&"00".repeat(32), TEST_TOKEN))
+            .await
+            .unwrap();
+        let Message::Close(Some(frame)) = socket.next().await.unwrap().unwrap() else {
+            panic!("Expected a close frame");
+        };
+        assert_eq!(frame.code, CloseCode::Policy);
+        assert_eq!(
+            frame.reason,
+            "Invoice not found or not issued by this server"
+        );
+
+        assert!(
+            tokio_tungstenite::connect_async(url(&payment_hash, "wrong"))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_transactions() {
+        let (mock, app) = test_app();
+        let entry = |n: u64, kind: &str, status: &str| -> HistoryEntry {
+            serde_json::from_value(serde_json::json!({
+                "cursor": format!("{}_{:064x}", n * 1_000_000, n),
+                "operation_id": format!("{:064x}", n),
+                "timestamp": n,
+                "kind": kind,
+                "amount_msats": n * 1000,
+                "fee_msats": if kind == "pay" { Some(10) } else { None },
+                "status": status,
+                "payment_hash": format!("{:064x}", n),
+                "description": format!("invoice {n}"),
+            }))
+            .unwrap()
+        };
+        let mut history = vec![entry(7, "pay", "failed")];
+        for n in (1..=6).rev() {
+            let status = if n % 2 == 0 { "succeeded" } else { "pending" };
+            history.push(entry(n, "receive", status));
+        }
+        mock.set_history(history);
+
+        let (status, body) = request(app.clone(), "GET", "/invoices?limit=4", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let transactions = body["transactions"].as_array().unwrap();
+        assert_eq!(transactions.len(), 4);
+        assert_eq!(
+            transactions[0],
+            serde_json::json!({
+                "payment_hash": format!("{:064x}", 6),
+                "amount_msats": 6000,
+                "description": "invoice 6",
+                "status": "paid",
+                "created_at": 6,
+                "settled_at": null,
+            })
+        );
+
+        let cursor = body["next_cursor"].as_str().unwrap();
+        let (status, body) = request(
+            app.clone(),
+            "GET",
+            &format!("/invoices?limit=4&cursor={cursor}"),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let amounts = body["transactions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|transaction| transaction["amount_msats"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(amounts, vec![2000, 1000]);
+        assert!(body.get("next_cursor").is_none());
+
+        let (_, body) = request(app.clone(), "GET", "/invoices?status=pending", None).await;
+        let statuses = body["transactions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|transaction| transaction["status"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(statuses, vec!["pending"; 3]);
+
+        let (_, body) = request(app.clone(), "GET", "/payments", None).await;
+        assert_eq!(body["transactions"][0]["fee_msats"], 10);
+        assert_eq!(body["transactions"][0]["status"], "failed");
+        assert_eq!(body["transactions"].as_array().unwrap().len(), 1);
+
+        let (status, _) = request(app, "GET", "/invoices?cursor=invalid", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_list_gateways() {
+        let (mock, app) = test_app();
+        let secp = blitzi::secp256k1::Secp256k1::new();
+        let key = blitzi::secp256k1::SecretKey::from_slice(&[1; 32])
+            .unwrap()
+            .public_key(&secp);
+        mock.set_gateways(vec![GatewayInfo {
+            gateway_id: key,
+            node_pub_key: key,
+            lightning_alias: "gateway".to_string(),
+            api: "https://gateway.example.com/".to_string(),
+            base_fee: msats(1000),
+            proportional_fee_ppm: 100,
+            vetted: true,
+            ttl: Duration::from_secs(600),
+        }]);
+
+        let (status, body) = request(app, "GET", "/gateways", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["gateways"][0]["gateway_id"], key.to_string());
+        assert_eq!(body["gateways"][0]["base_fee_msats"], 1000);
+        assert_eq!(body["gateways"][0]["proportional_fee_ppm"], 100);
+    }
+
+    #[tokio::test]
+    async fn test_create_invoice_zero_amount() {
+        let (_, app) = test_app();
+        let (status, _) = request(
+            app,
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({ "amount_msats": 0, "description": "test" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_create_invoice_invalid_description() {
+        let (_, app) = test_app();
+        let (status, body) = request(
+            app,
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({ "amount_msats": 1000, "description": "a".repeat(640) })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().contains("640 bytes"));
+    }
+
+    async fn pay_with_key(
+        app: Router,
+        key: &str,
+        invoice: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        request_with_headers(
+            app,
+            "POST",
+            "/pay",
+            &[(IDEMPOTENCY_KEY_HEADER, key)],
+            Some(serde_json::json!({ "invoice": invoice })),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_pay_idempotency_key() {
+        let (mock, app) = test_app();
+        mock.set_balance(msats(10_000));
+
+        let (_, body) = request(
+            app.clone(),
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({ "amount_msats": 1000, "description": "test" })),
+        )
+        .await;
+        let invoice = body["invoice"].as_str().unwrap().to_string();
+
+        let (status, first) = pay_with_key(app.clone(), "key-1", &invoice).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, second) = pay_with_key(app.clone(), "key-1", &invoice).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(first, second);
+
+        assert!(
+            mock.calls()
+                .iter()
+                .any(|call| matches!(call, MockCall::PayIdempotent { key, .. } if key == "key-1"))
+        );
+        assert_eq!(mock.balance().await, msats(9_000));
+
+        let (_, body) = request(
+            app.clone(),
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({ "amount_msats": 1000, "description": "other" })),
+        )
+        .await;
+        let other_invoice = body["invoice"].as_str().unwrap().to_string();
+        // Reusing the key for a different invoice is rejected
+        let (status, body) = pay_with_key(app.clone(), "key-1", &other_invoice).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], idempotency::CONFLICT_CODE);
+        assert_eq!(mock.balance().await, msats(9_000));
+
+        // The key isn't recorded for failed requests, so they can be corrected
+        let (status, _) = pay_with_key(app.clone(), "key-2", "lnbc1invalid").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = pay_with_key(app, "key-2", &other_invoice).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(mock.balance().await, msats(8_000));
+    }
+
+    #[tokio::test]
+    async fn test_pay_concurrent_idempotency_key() {
+        let (mock, app) = test_app();
+        mock.set_balance(msats(10_000));
+
+        let (_, body) = request(
+            app.clone(),
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({ "amount_msats": 1000, "description": "test" })),
+        )
+        .await;
+        let invoice = body["invoice"].as_str().unwrap().to_string();
+
+        let requests = (0..4)
+            .map(|_| {
+                let (app, invoice) = (app.clone(), invoice.clone());
+                tokio::spawn(async move { pay_with_key(app, "key-1", &invoice).await })
+            })
+            .collect::<Vec<_>>();
+        let mut responses = vec![];
+        for request in requests {
+            let (status, body) = request.await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            responses.push(body);
+        }
+        assert!(responses.iter().all(|body| *body == responses[0]));
+
+        // Only the first request reached the backend, the others were replayed
+        let payments = mock
+            .calls()
+            .iter()
+            .filter(|call| matches!(call, MockCall::PayIdempotent { .. }))
+            .count();
+        assert_eq!(payments, 1);
+        assert_eq!(mock.balance().await, msats(9_000));
+    }
+
+    #[tokio::test]
+    async fn test_create_invoice_idempotency_key() {
+        let (mock, app) = test_app();
+        let create = |description: &'static str| {
+            request_with_headers(
+                app.clone(),
+                "POST",
+                "/invoice",
+                &[(IDEMPOTENCY_KEY_HEADER, "order-1")],
+                Some(serde_json::json!({ "amount_msats": 1000, "description": description })),
+            )
+        };
+
+        let (status, first) = create("order").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, second) = create("order").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(first, second);
+        let invoices = mock
+            .calls()
+            .iter()
+            .filter(|call| matches!(call, MockCall::LightningInvoice { .. }))
+            .count();
+        assert_eq!(invoices, 1);
+
+        let (status, body) = create("other order").await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], idempotency::CONFLICT_CODE);
+    }
+
+    #[tokio::test]
+    async fn test_pay_fee_cap() {
+        let (mock, app) = test_app();
+        mock.set_balance(msats(10_000));
+        mock.set_default_payment(MockPayment::Success { fee: msats(20) });
+
+        let (_, body) = request(
+            app.clone(),
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({ "amount_msats": 1000, "description": "test" })),
+        )
+        .await;
+        let invoice = body["invoice"].as_str().unwrap().to_string();
+
+        let (status, quote) = request(
+            app.clone(),
+            "POST",
+            "/pay/quote",
+            Some(serde_json::json!({ "invoice": invoice })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(quote["amount_msats"], 1000);
+        assert_eq!(quote["fee_msats"], 20);
+
+        let (status, body) = request(
+            app.clone(),
+            "POST",
+            "/pay",
+            Some(serde_json::json!({ "invoice": invoice, "max_fee_msats": 10 })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(body["code"], FEE_TOO_HIGH);
+        assert_eq!(body["fee_msats"], 20);
+        assert_eq!(mock.balance().await, msats(10_000));
+
+        let (status, _) = request(
+            app.clone(),
+            "POST",
+            "/pay",
+            Some(serde_json::json!({ "invoice": invoice, "amount_msats": 2000 })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = request(
+            app,
+            "POST",
+            "/pay",
+            Some(serde_json::json!({ "invoice": invoice, "max_fee_msats": 20 })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["amount_msats"], 1000);
+        assert_eq!(body["fee_msats"], 20);
+        assert_eq!(body["internal"], true);
+        assert_eq!(body["payment_hash"], quote["payment_hash"]);
+        assert_eq!(mock.balance().await, msats(8_980));
+    }
+
+    #[tokio::test]
+    async fn test_pay_fee_cap_unsupported_by_lnv2() {
+        let (mock, app) = test_app();
+        mock.set_balance(msats(10_000));
+        let mut capabilities = mock.capabilities().await.unwrap();
+        capabilities.modules = vec!["lnv2".to_string(), "mint".to_string()];
+        mock.set_capabilities(Some(capabilities));
+
+        let (_, body) = request(
+            app.clone(),
+            "POST",
+            "/invoice",
+            Some(serde_json::json!({ "amount_msats": 1000, "description": "test" })),
+        )
+        .await;
+        let invoice = body["invoice"].as_str().unwrap().to_string();
+
+        let (status, body) = request(
+            app.clone(),
+            "POST",
+            "/pay/quote",
+            Some(serde_json::json!({ "invoice": invoice })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(
+            body["error"],
+            "Quoting payments isn't supported by the lnv2 module"
+        );
+
+        let (status, body) = request(
+            app,
+            "POST",
+            "/pay",
+            Some(serde_json::json!({ "invoice": invoice, "max_fee_msats": 10 })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(
+            body["error"],
+            "Limiting the fee isn't supported by the lnv2 module"
+        );
+        assert_eq!(mock.balance().await, msats(10_000));
+    }
+
+    #[tokio::test]
+    async fn test_cors_disabled_by_default() {
+        let (_, app) = test_app();
+        let response = app.oneshot(preflight("https://example.com")).await.unwrap();
+        assert!(
+            !response
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cors_allowed_origin() {
+        let cors = cors_layer(&["https://example.com".to_string()]).unwrap();
+        let (_, app) = test_app_with_cors(cors);
+
+        let response = app
+            .clone()
+            .oneshot(preflight("https://example.com"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.com"
+        );
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_METHODS],
+            "GET,POST"
+        );
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_MAX_AGE],
+            CORS_MAX_AGE.as_secs().to_string()
+        );
+
+        // Retried payments send an idempotency key, which needs a preflight too
+        let request = Request::builder()
+            .method("OPTIONS")
+            .uri("/pay")
+            .header(header::ORIGIN, "https://example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(
+                header::ACCESS_CONTROL_REQUEST_HEADERS,
+                "authorization,content-type,idempotency-key",
+            )
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let allowed = response.headers()[header::ACCESS_CONTROL_ALLOW_HEADERS]
+            .to_str()
+            .unwrap();
+        for name in ["authorization", "content-type", IDEMPOTENCY_KEY_HEADER] {
+            assert!(allowed.contains(name), "{} not allowed", name);
+        }
+
+        let response = app.oneshot(preflight("https://evil.com")).await.unwrap();
+        assert!(
+            !response
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cors_any_origin() {
+        let cors = cors_layer(&["*".to_string()]).unwrap();
+        let (_, app) = test_app_with_cors(cors);
+
+        let response = app.oneshot(preflight("https://example.com")).await.unwrap();
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    }
+
+    #[test]
+    fn test_cors_layer() {
+        assert!(cors_layer(&[]).unwrap().is_none());
+        assert!(cors_layer(&["invalid\norigin".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_config_file() {
+        let config: ConfigFile = toml::from_str(
+            r#"
+            datadir = "/data"
+            port = 8080
+            host = "0.0.0.0"
+            log_format = "json"
+            cors_origins = ["https://example.com"]
+            nwc_relay = "wss://relay.example.com"
+            max_invoice_msats = 100000
+            config_check_interval = 3600
+            "#,
+        )
+        .unwrap();
+
+        let matches = Args::command()
+            .try_get_matches_from(["blitzid", "--port", "9000"])
+            .unwrap();
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        config.apply(&mut args, &matches);
+
+        assert_eq!(args.datadir.as_deref(), Some("/data"));
+        assert_eq!(args.host, "0.0.0.0");
+        assert_eq!(args.log_format, LogFormat::Json);
+        assert_eq!(args.cors_origins, vec!["https://example.com".to_string()]);
+        assert_eq!(args.nwc_relay.as_deref(), Some("wss://relay.example.com"));
+        assert_eq!(args.max_invoice_msats, Some(100_000));
+        assert_eq!(args.config_check_interval, Some(3600));
+        // Command line arguments take precedence
+        assert_eq!(args.port, 9000);
+        // Values not set in either place keep their defaults
+        assert_eq!(args.federation, None);
+
+        assert!(toml::from_str::<ConfigFile>("unknown = 1").is_err());
+    }
+
     #[test]
     fn test_generate_bearer_token() {
         let token = generate_bearer_token();
@@ -339,4 +3254,13 @@ mod tests {
         let token2 = generate_bearer_token();
         assert_ne!(token1, token2, "Generated tokens should be unique");
     }
+
+    #[test]
+    fn test_bearer_token_matches() {
+        let token = BearerToken::new("secret".to_string());
+        assert!(token.matches("secret"));
+        assert!(!token.matches("secreT"));
+        assert!(!token.matches("secret2"));
+        assert!(!token.matches(""));
+    }
 }
@@ -0,0 +1,345 @@
+//! Replays the responses of `POST /pay` and `POST /invoice` to requests
+//! repeating an `Idempotency-Key` header.
+//!
+//! Successful responses are kept in a JSON file in the data directory for
+//! [`RETENTION`], so retries after a restart are answered too. Error responses
+//! aren't recorded, so a failed request can be retried with the same key,
+//! e.g. with a higher `max_fee_msats`.
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use axum::Json;
+use axum::body::Body;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use blitzi::bitcoin::hashes::{Hash, sha256};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::error;
+
+/// How long responses are replayed for.
+pub const RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Error code returned if a key is reused with a different request body.
+pub const CONFLICT_CODE: &str = "idempotency_conflict";
+
+/// Header set on replayed responses.
+pub const REPLAYED_HEADER: &str = "idempotent-replayed";
+
+/// Returned with `409 CONFLICT` if a key is reused with a different request
+/// body.
+#[derive(Serialize, Deserialize)]
+struct ConflictResponse {
+    error: String,
+    /// Always [`CONFLICT_CODE`]
+    code: String,
+}
+
+/// Successful response recorded for a key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Record {
+    /// Hex encoded SHA256 of the request body the response belongs to
+    request_hash: String,
+    status: u16,
+    body: serde_json::Value,
+    /// Unix time in seconds the response was recorded at
+    created_at: u64,
+}
+
+impl Record {
+    fn is_expired(&self, now: u64) -> bool {
+        now.saturating_sub(self.created_at) >= RETENTION.as_secs()
+    }
+}
+
+/// Responses recorded per idempotency key. The default store keeps them in
+/// memory only.
+#[derive(Default)]
+pub struct IdempotencyStore {
+    /// File the records are persisted to, `None` to keep them in memory
+    path: Option<PathBuf>,
+    /// Keyed by the route followed by the idempotency key
+    records: Mutex<HashMap<String, Record>>,
+    /// Locked while a request with the key is processed, so concurrent
+    /// duplicates wait for the first one's response
+    in_flight: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl IdempotencyStore {
+    /// Loads the records persisted at `path`, starting without records if the
+    /// file doesn't exist yet. Expired records are dropped.
+    pub fn load(path: PathBuf) -> anyhow::Result<Self> {
+        let mut records: HashMap<String, Record> = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("Invalid idempotency store {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("Failed to read idempotency store {}", path.display())
+                });
+            }
+        };
+        let now = unix_now();
+        records.retain(|_, record| !record.is_expired(now));
+        Ok(IdempotencyStore {
+            path: Some(path),
+            records: Mutex::new(records),
+            in_flight: Default::default(),
+        })
+    }
+
+    /// Answers the request to `route` with the body `request` using `handler`,
+    /// unless a response was already recorded for `key`. Then the recorded
+    /// response is replayed if the request body matches, and a `409 CONFLICT`
+    /// with the code [`CONFLICT_CODE`] is returned otherwise. Requests with the
+    /// same key are processed one after the other.
+    pub async fn run<R: Serialize, F: Future<Output = Response>>(
+        &self,
+        route: &str,
+        key: &str,
+        request: R,
+        handler: impl FnOnce(R) -> F,
+    ) -> Response {
+        let request_hash = match serde_json::to_vec(&request) {
+            Ok(bytes) => sha256::Hash::hash(&bytes).to_string(),
+            Err(e) => return internal_error(e.into()),
+        };
+        let key = format!("{} {}", route, key);
+        let lock = self
+            .in_flight
+            .lock()
+            .expect("lock poisoned")
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let guard = lock.lock().await;
+
+        let response = match self.recorded(&key).await {
+            Some(record) if record.request_hash == request_hash => replay(record),
+            Some(_) => {
+                conflict("Idempotency key was already used for a different request".to_string())
+            }
+            None => {
+                let response = handler(request).await;
+                self.record(key.clone(), request_hash, response).await
+            }
+        };
+
+        drop(guard);
+        let mut in_flight = self.in_flight.lock().expect("lock poisoned");
+        // Only the map and this request hold the lock if nobody else waits
+        if Arc::strong_count(&lock) == 2 {
+            in_flight.remove(&key);
+        }
+        response
+    }
+
+    /// Returns the unexpired response recorded for `key`.
+    async fn recorded(&self, key: &str) -> Option<Record> {
+        let records = self.records.lock().await;
+        records
+            .get(key)
+            .filter(|record| !record.is_expired(unix_now()))
+            .cloned()
+    }
+
+    /// Records `response` for `key` if it's successful and returns it.
+    async fn record(&self, key: String, request_hash: String, response: Response) -> Response {
+        if !response.status().is_success() {
+            return response;
+        }
+        let (parts, body) = response.into_parts();
+        let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+            Ok(bytes) => bytes,
+            Err(e) => return internal_error(e.into()),
+        };
+        let record = match serde_json::from_slice(&bytes) {
+            Ok(body) => Record {
+                request_hash,
+                status: parts.status.as_u16(),
+                body,
+                created_at: unix_now(),
+            },
+            Err(e) => return internal_error(e.into()),
+        };
+
+        let mut records = self.records.lock().await;
+        let now = unix_now();
+        records.retain(|_, record| !record.is_expired(now));
+        records.insert(key, record);
+        if let Err(e) = self.save(&records).await {
+            // The request succeeded, retries are still answered until a restart
+            error!(error = %e, "Failed to persist idempotency store");
+        }
+        Response::from_parts(parts, Body::from(bytes))
+    }
+
+    /// Writes the records to a temporary file first, so a crash can't leave a
+    /// truncated store behind.
+    async fn save(&self, records: &HashMap<String, Record>) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let tmp = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(records)?).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+}
+
+/// Returns a `409 CONFLICT` with the code [`CONFLICT_CODE`] and the message
+/// `error`.
+pub fn conflict(error: String) -> Response {
+    (
+        StatusCode::CONFLICT,
+        Json(ConflictResponse {
+            error,
+            code: CONFLICT_CODE.to_string(),
+        }),
+    )
+        .into_response()
+}
+
+fn replay(record: Record) -> Response {
+    let status = StatusCode::from_u16(record.status).unwrap_or(StatusCode::OK);
+    let mut response = (status, Json(record.body)).into_response();
+    response.headers_mut().insert(
+        REPLAYED_HEADER,
+        axum::http::HeaderValue::from_static("true"),
+    );
+    response
+}
+
+fn internal_error(e: anyhow::Error) -> Response {
+    error!(error = %e, "Failed to process idempotent request");
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(serde_json::json!({ "error": format!("Internal error: {}", e) })),
+    )
+        .into_response()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn body(response: Response) -> serde_json::Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    fn ok(value: u64) -> Response {
+        Json(serde_json::json!({ "value": value })).into_response()
+    }
+
+    #[tokio::test]
+    async fn test_replay_and_conflict() {
+        let store = IdempotencyStore::default();
+        let request = serde_json::json!({ "invoice": "a" });
+
+        let response = store
+            .run("/pay", "key", request.clone(), |_| async { ok(1) })
+            .await;
+        assert!(!response.headers().contains_key(REPLAYED_HEADER));
+        assert_eq!(body(response).await["value"], 1);
+
+        let response = store
+            .run("/pay", "key", request.clone(), |_| async { ok(2) })
+            .await;
+        assert_eq!(response.headers()[REPLAYED_HEADER], "true");
+        assert_eq!(body(response).await["value"], 1);
+
+        // Keys are scoped by route
+        let response = store
+            .run("/invoice", "key", request.clone(), |_| async { ok(3) })
+            .await;
+        assert_eq!(body(response).await["value"], 3);
+
+        let other = serde_json::json!({ "invoice": "b" });
+        let response = store.run("/pay", "key", other, |_| async { ok(4) }).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(body(response).await["code"], CONFLICT_CODE);
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_not_recorded() {
+        let store = IdempotencyStore::default();
+        let request = serde_json::json!({ "invoice": "a" });
+
+        let response = store
+            .run("/pay", "key", request.clone(), |_| async {
+                StatusCode::PAYMENT_REQUIRED.into_response()
+            })
+            .await;
+        assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+
+        // A corrected request may reuse the key
+        let other = serde_json::json!({ "invoice": "b" });
+        let response = store.run("/pay", "key", other, |_| async { ok(1) }).await;
+        assert_eq!(body(response).await["value"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_duplicates_wait() {
+        let store = Arc::new(IdempotencyStore::default());
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let request = serde_json::json!({ "invoice": "a" });
+
+        let requests = (0..4).map(|_| {
+            let (store, calls, request) = (store.clone(), calls.clone(), request.clone());
+            tokio::spawn(async move {
+                let response = store
+                    .run("/pay", "key", request, |_| async move {
+                        let call = calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        ok(call as u64)
+                    })
+                    .await;
+                body(response).await
+            })
+        });
+        for request in requests.collect::<Vec<_>>() {
+            assert_eq!(request.await.unwrap()["value"], 0);
+        }
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert!(store.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_records_are_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("idempotency.json");
+        let request = serde_json::json!({ "invoice": "a" });
+        let store = IdempotencyStore::load(path.clone()).unwrap();
+        store
+            .run("/pay", "key", request.clone(), |_| async { ok(1) })
+            .await;
+
+        let reloaded = IdempotencyStore::load(path.clone()).unwrap();
+        let response = reloaded
+            .run("/pay", "key", request, |_| async { ok(2) })
+            .await;
+        assert_eq!(body(response).await["value"], 1);
+
+        // Expired records are dropped when loading
+        let mut records = reloaded.records.lock().await;
+        records.get_mut("/pay key").unwrap().created_at -= RETENTION.as_secs();
+        reloaded.save(&records).await.unwrap();
+        drop(records);
+        let reloaded = IdempotencyStore::load(path.clone()).unwrap();
+        assert!(reloaded.records.lock().await.is_empty());
+    }
+}
@@ -0,0 +1,100 @@
+//! Limits on the invoices blitzid issues on request, applied the same way to
+//! `POST /invoice` and NWC `make_invoice`.
+use std::fmt;
+
+use blitzi::{Amount, DescriptionTooLong, InvoiceAmountError, MAX_DESCRIPTION_LEN};
+
+/// Limits set by `--max-invoice-msats` and `--invoice-description-prefix`.
+#[derive(Debug, Clone, Default)]
+pub struct InvoiceLimits {
+    /// Invoice requests above this amount are rejected before reaching the
+    /// backend
+    pub max_amount: Option<Amount>,
+    /// Prepended to the description of every invoice
+    pub description_prefix: Option<String>,
+}
+
+impl InvoiceLimits {
+    /// Checks a request for an invoice of `amount` and returns the
+    /// description to issue it with, `description` behind the prefix.
+    pub fn apply(&self, amount: Amount, description: &str) -> Result<String, InvoiceRejected> {
+        if let Some(max) = self.max_amount {
+            if amount > max {
+                return Err(InvoiceRejected::Amount(InvoiceAmountError::TooLarge {
+                    amount,
+                    max,
+                }));
+            }
+        }
+
+        let Some(prefix) = &self.description_prefix else {
+            return Ok(description.to_string());
+        };
+        // Report the length limit of the description the client controls
+        let max = MAX_DESCRIPTION_LEN - prefix.len();
+        if description.len() > max {
+            return Err(InvoiceRejected::Description(DescriptionTooLong {
+                max,
+                got: description.len(),
+            }));
+        }
+        Ok(format!("{}{}", prefix, description))
+    }
+}
+
+/// An invoice request exceeds the [`InvoiceLimits`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvoiceRejected {
+    Amount(InvoiceAmountError),
+    Description(DescriptionTooLong),
+}
+
+impl fmt::Display for InvoiceRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvoiceRejected::Amount(e) => write!(f, "Invalid amount: {}", e),
+            InvoiceRejected::Description(e) => write!(f, "Invalid description: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for InvoiceRejected {}
+
+#[cfg(test)]
+mod tests {
+    use blitzi::msats;
+
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        assert_eq!(
+            InvoiceLimits::default().apply(msats(1000), "test"),
+            Ok("test".to_string())
+        );
+
+        let limits = InvoiceLimits {
+            max_amount: Some(msats(1000)),
+            description_prefix: Some("shop: ".to_string()),
+        };
+        assert_eq!(
+            limits.apply(msats(1000), "test"),
+            Ok("shop: test".to_string())
+        );
+        assert_eq!(
+            limits.apply(msats(1001), "test"),
+            Err(InvoiceRejected::Amount(InvoiceAmountError::TooLarge {
+                amount: msats(1001),
+                max: msats(1000),
+            }))
+        );
+        let max = MAX_DESCRIPTION_LEN - "shop: ".len();
+        assert_eq!(
+            limits.apply(msats(1000), &"a".repeat(max + 1)),
+            Err(InvoiceRejected::Description(DescriptionTooLong {
+                max,
+                got: max + 1,
+            }))
+        );
+    }
+}
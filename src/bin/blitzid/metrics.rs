@@ -0,0 +1,376 @@
+//! Prometheus metrics served on `GET /metrics`, optionally on a separate port
+//! via `--metrics-port`.
+//!
+//! Counters and histograms are updated by the request handlers and the wallet's
+//! event stream, the gauges are read from the backend's
+//! [`status`](LightningBackend::status) on every scrape, which doesn't read the
+//! operation log. Labels are kept low-cardinality, no payment hashes are
+//! exported.
+//!
+//! Every outgoing payment is counted under exactly one outcome: `succeeded`
+//! and `refunded` for payments that were started, from the
+//! [`BlitziEvent::PaymentSent`] and [`BlitziEvent::PaymentFailed`] events, and
+//! `failed` for `POST /pay` requests that failed before a payment was started,
+//! e.g. because no gateway was available.
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use blitzi::{BlitziEvent, LightningBackend};
+use futures_lite::StreamExt;
+use tracing::warn;
+
+/// Content type of the Prometheus text exposition format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Bucket bounds of the payment latency in seconds.
+const PAYMENT_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+/// Bucket bounds of the time from creating an invoice until it's paid in
+/// seconds.
+const TIME_TO_PAID_BUCKETS: &[f64] = &[
+    1.0, 5.0, 15.0, 30.0, 60.0, 300.0, 900.0, 3600.0, 21600.0, 86400.0,
+];
+
+/// Counters and histograms of the daemon, since its start.
+pub struct Metrics {
+    invoices_created: AtomicU64,
+    invoices_paid: AtomicU64,
+    payments_succeeded: AtomicU64,
+    payments_failed: AtomicU64,
+    payments_refunded: AtomicU64,
+    webhook_attempts: AtomicU64,
+    webhook_failures: AtomicU64,
+    payment_duration: Histogram,
+    invoice_time_to_paid: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            invoices_created: AtomicU64::new(0),
+            invoices_paid: AtomicU64::new(0),
+            payments_succeeded: AtomicU64::new(0),
+            payments_failed: AtomicU64::new(0),
+            payments_refunded: AtomicU64::new(0),
+            webhook_attempts: AtomicU64::new(0),
+            webhook_failures: AtomicU64::new(0),
+            payment_duration: Histogram::new(PAYMENT_BUCKETS),
+            invoice_time_to_paid: Histogram::new(TIME_TO_PAID_BUCKETS),
+        }
+    }
+}
+
+impl Metrics {
+    /// Counts an invoice created via `POST /invoice`.
+    pub fn invoice_created(&self) {
+        self.invoices_created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records how long a successful `POST /pay` took.
+    pub fn payment_finished(&self, duration: Duration) {
+        self.payment_duration.observe(duration.as_secs_f64());
+    }
+
+    /// Counts a `POST /pay` request failing before a payment was started, for
+    /// a reason other than a bad request. Payments that were started are
+    /// counted from the event stream instead, see [`Self::run`].
+    pub fn payment_failed(&self) {
+        self.payments_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a webhook delivery attempt and whether it failed.
+    pub fn webhook_attempt(&self, failed: bool) {
+        self.webhook_attempts.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.webhook_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts the paid invoices and finished payments of `blitzi` until its
+    /// event stream ends. This covers payments made via NWC too.
+    pub async fn run<B: LightningBackend>(self: Arc<Self>, blitzi: Arc<B>) {
+        let mut events = blitzi.subscribe_events().await;
+        while let Some(event) = events.next().await {
+            match event {
+                BlitziEvent::InvoicePaid { payment_hash, .. } => {
+                    self.invoices_paid.fetch_add(1, Ordering::Relaxed);
+                    let Some(payment_hash) = payment_hash else {
+                        continue;
+                    };
+                    match blitzi.issued_invoice(payment_hash).await {
+                        Ok(Some(invoice)) => {
+                            let created_at =
+                                SystemTime::UNIX_EPOCH + invoice.duration_since_epoch();
+                            let time_to_paid = SystemTime::now()
+                                .duration_since(created_at)
+                                .unwrap_or_default();
+                            self.invoice_time_to_paid
+                                .observe(time_to_paid.as_secs_f64());
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            warn!(%payment_hash, error = %e, "Failed to look up paid invoice");
+                        }
+                    }
+                }
+                BlitziEvent::PaymentSent { .. } => {
+                    self.payments_succeeded.fetch_add(1, Ordering::Relaxed);
+                }
+                BlitziEvent::PaymentFailed { .. } => {
+                    self.payments_refunded.fetch_add(1, Ordering::Relaxed);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text format, reading the gauges
+    /// from the [status](LightningBackend::status) of `blitzi`.
+    pub async fn render<B: LightningBackend>(&self, blitzi: &B) -> String {
+        let status = blitzi.status().await;
+
+        let mut out = String::new();
+        counter(
+            &mut out,
+            "blitzid_invoices_created_total",
+            "Invoices created via POST /invoice",
+            &self.invoices_created,
+        );
+        counter(
+            &mut out,
+            "blitzid_invoices_paid_total",
+            "Invoices whose payment was claimed",
+            &self.invoices_paid,
+        );
+        let _ = writeln!(
+            out,
+            "# HELP blitzid_payments_total Outgoing payments by outcome, failed payments weren't \
+             started and refunded ones failed after the funds were locked"
+        );
+        let _ = writeln!(out, "# TYPE blitzid_payments_total counter");
+        for (outcome, value) in [
+            ("succeeded", &self.payments_succeeded),
+            ("failed", &self.payments_failed),
+            ("refunded", &self.payments_refunded),
+        ] {
+            let _ = writeln!(
+                out,
+                "blitzid_payments_total{{outcome=\"{}\"}} {}",
+                outcome,
+                value.load(Ordering::Relaxed)
+            );
+        }
+        counter(
+            &mut out,
+            "blitzid_webhook_attempts_total",
+            "Webhook delivery attempts",
+            &self.webhook_attempts,
+        );
+        counter(
+            &mut out,
+            "blitzid_webhook_failures_total",
+            "Failed webhook delivery attempts",
+            &self.webhook_failures,
+        );
+        self.payment_duration.render(
+            &mut out,
+            "blitzid_payment_duration_seconds",
+            "Time it took to pay an invoice via POST /pay",
+        );
+        self.invoice_time_to_paid.render(
+            &mut out,
+            "blitzid_invoice_time_to_paid_seconds",
+            "Time from creating an invoice until its payment was claimed",
+        );
+        gauge(
+            &mut out,
+            "blitzid_balance_msats",
+            "Balance of the wallet",
+            status.balance.msats,
+        );
+        gauge(
+            &mut out,
+            "blitzid_pending_invoices",
+            "Invoices that are neither paid nor expired yet",
+            status.pending_invoices as u64,
+        );
+        gauge(
+            &mut out,
+            "blitzid_guardians",
+            "Guardians of the federation",
+            status.guardians as u64,
+        );
+        gauge(
+            &mut out,
+            "blitzid_reachable_guardians",
+            "Guardians of the federation that answered the last scrape",
+            status.reachable_guardians as u64,
+        );
+        gauge(
+            &mut out,
+            "blitzid_gateways",
+            "Lightning gateways registered with the federation",
+            status.gateways as u64,
+        );
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Histogram with fixed bucket bounds.
+struct Histogram {
+    bounds: &'static [f64],
+    state: Mutex<HistogramState>,
+}
+
+#[derive(Default)]
+struct HistogramState {
+    /// Observations per bucket, not cumulative, the last one counts the
+    /// observations above all bounds
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            state: Mutex::new(HistogramState {
+                buckets: vec![0; bounds.len() + 1],
+                ..HistogramState::default()
+            }),
+        }
+    }
+
+    fn observe(&self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        let mut state = self.state.lock().expect("Histogram lock poisoned");
+        state.buckets[bucket] += 1;
+        state.sum += value;
+        state.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let state = self.state.lock().expect("Histogram lock poisoned");
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&state.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, state.count);
+        let _ = writeln!(out, "{}_sum {}", name, state.sum);
+        let _ = writeln!(out, "{}_count {}", name, state.count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use blitzi::{MockCall, MockLightning, MockPayment, msats};
+
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::new(&[1.0, 5.0]);
+        histogram.observe(0.5);
+        histogram.observe(1.0);
+        histogram.observe(3.0);
+        histogram.observe(10.0);
+
+        let mut out = String::new();
+        histogram.render(&mut out, "test_seconds", "Test");
+        assert_eq!(
+            out,
+            "# HELP test_seconds Test\n\
+             # TYPE test_seconds histogram\n\
+             test_seconds_bucket{le=\"1\"} 2\n\
+             test_seconds_bucket{le=\"5\"} 3\n\
+             test_seconds_bucket{le=\"+Inf\"} 4\n\
+             test_seconds_sum 14.5\n\
+             test_seconds_count 4\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_render() {
+        let mock = MockLightning::new();
+        mock.set_balance(msats(1234));
+        mock.set_guardians(4, 3);
+        mock.lightning_invoice(msats(1000), "pending")
+            .await
+            .unwrap();
+        let metrics = Metrics::default();
+        metrics.invoice_created();
+        metrics.payment_failed();
+        metrics.webhook_attempt(true);
+        metrics.payment_finished(Duration::from_millis(300));
+
+        let out = metrics.render(&mock).await;
+        assert!(out.contains("blitzid_invoices_created_total 1\n"));
+        assert!(out.contains("blitzid_payments_total{outcome=\"failed\"} 1\n"));
+        assert!(out.contains("blitzid_payments_total{outcome=\"succeeded\"} 0\n"));
+        assert!(out.contains("blitzid_webhook_failures_total 1\n"));
+        assert!(out.contains("blitzid_payment_duration_seconds_bucket{le=\"0.5\"} 1\n"));
+        assert!(out.contains("blitzid_balance_msats 1234\n"));
+        assert!(out.contains("blitzid_pending_invoices 1\n"));
+        assert!(out.contains("blitzid_guardians 4\n"));
+        assert!(out.contains("blitzid_reachable_guardians 3\n"));
+        assert!(out.contains("blitzid_gateways 0\n"));
+        // The gauges don't read the operation log
+        assert!(!mock.calls().contains(&MockCall::WalletStats));
+    }
+
+    #[tokio::test]
+    async fn test_run_counts_payment_outcomes() {
+        let mock = Arc::new(MockLightning::new());
+        mock.set_balance(msats(10_000));
+        let metrics = Arc::new(Metrics::default());
+        tokio::spawn(metrics.clone().run(mock.clone()));
+        // Lets the task subscribe to the events
+        tokio::task::yield_now().await;
+
+        let paid = mock.lightning_invoice(msats(1000), "paid").await.unwrap();
+        mock.pay(&paid).await.unwrap();
+        let failed = mock.lightning_invoice(msats(1000), "failed").await.unwrap();
+        mock.script_payment(
+            failed.payment_hash(),
+            MockPayment::Failure("no route".to_string()),
+        );
+        assert!(mock.pay(&failed).await.is_err());
+
+        let out = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let out = metrics.render(mock.as_ref()).await;
+                if out.contains("blitzid_payments_total{outcome=\"refunded\"} 1\n") {
+                    return out;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("payments are counted");
+        assert!(out.contains("blitzid_payments_total{outcome=\"succeeded\"} 1\n"));
+        // Failed requests are counted by the handler, not from events
+        assert!(out.contains("blitzid_payments_total{outcome=\"failed\"} 0\n"));
+    }
+}
@@ -0,0 +1,690 @@
+//! Nostr Wallet Connect (NIP-47) server, lets Nostr apps control the wallet by
+//! sending encrypted requests through a relay.
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+use blitzi::bitcoin::hashes::{Hash, sha256};
+use blitzi::lightning_invoice::Bolt11InvoiceDescriptionRef;
+use blitzi::{
+    Bolt11Invoice, DescriptionTooLong, InvalidDescription, InvalidInvoiceError, InvoiceAmountError,
+    InvoiceStatus, LightningBackend, PaymentHash, PolicyDenied, SpendLimitExceeded, msats,
+};
+use nostr_sdk::nips::{nip04, nip44};
+use nostr_sdk::{
+    Client, Event, EventBuilder, Filter, Keys, Kind, PublicKey, RelayPoolNotification, RelayUrl,
+    SecretKey, Tag, TagKind, Timestamp,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+use crate::limits::InvoiceLimits;
+
+/// Methods announced in the wallet's info event
+const SUPPORTED_METHODS: &str = "pay_invoice make_invoice get_balance lookup_invoice";
+
+/// Encryption schemes announced in the wallet's info event
+const SUPPORTED_ENCRYPTION: &str = "nip44_v2 nip04";
+
+/// File in the data directory the generated wallet service secret is kept in
+const SECRET_FILE: &str = "blitzid-nwc-secret";
+
+/// File in the data directory the connection URI is written to
+const URI_FILE: &str = "blitzid-nwc-uri";
+
+/// Server answering NWC requests of a single app, which authenticates using
+/// the secret contained in the [connection URI](Self::connection_uri).
+pub struct NwcServer {
+    relay: RelayUrl,
+    /// Keys of the wallet service, requests are addressed to its public key
+    wallet_keys: Keys,
+    /// Keys of the connected app, derived from the wallet's secret so the
+    /// connection URI stays the same across restarts
+    app_keys: Keys,
+}
+
+impl NwcServer {
+    /// Creates a server listening on `relay` using the wallet service secret
+    /// key `secret` (hex or `nsec`).
+    pub fn new(relay: &str, secret: &str) -> anyhow::Result<Self> {
+        let relay = RelayUrl::parse(relay).context("Invalid NWC relay URL")?;
+        let wallet_keys = Keys::parse(secret).context("Invalid NWC secret")?;
+
+        let app_secret = sha256::Hash::hash(
+            &[
+                b"blitzid/nwc/app".as_slice(),
+                wallet_keys.secret_key().as_secret_bytes(),
+            ]
+            .concat(),
+        );
+        let app_secret = SecretKey::from_slice(app_secret.as_byte_array())?;
+
+        Ok(NwcServer {
+            relay,
+            wallet_keys,
+            app_keys: Keys::new(app_secret),
+        })
+    }
+
+    /// Generates a random wallet service secret key, hex encoded.
+    pub fn generate_secret() -> String {
+        Keys::generate().secret_key().to_secret_hex()
+    }
+
+    /// Returns the wallet service secret kept in `datadir`, generating and
+    /// storing one on the first start so paired apps stay connected across
+    /// restarts.
+    pub fn load_or_generate_secret(datadir: &Path) -> anyhow::Result<String> {
+        let path = datadir.join(SECRET_FILE);
+        match std::fs::read_to_string(&path) {
+            Ok(secret) => return Ok(secret.trim().to_string()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", path.display()));
+            }
+        }
+
+        let secret = Self::generate_secret();
+        write_private(&path, &secret)?;
+        info!(path = %path.display(), "Generated NWC secret");
+        Ok(secret)
+    }
+
+    /// Returns the `nostr+walletconnect://` URI apps use to connect to the
+    /// wallet. Anyone knowing it can spend the wallet's funds.
+    pub fn connection_uri(&self) -> String {
+        format!(
+            "nostr+walletconnect://{}?relay={}&secret={}",
+            self.wallet_keys.public_key().to_hex(),
+            percent_encode(self.relay.as_str()),
+            self.app_keys.secret_key().to_secret_hex(),
+        )
+    }
+
+    /// Writes the [connection URI](Self::connection_uri) to a file in
+    /// `datadir` only its owner can read and returns the file's path.
+    pub fn write_connection_uri(&self, datadir: &Path) -> anyhow::Result<PathBuf> {
+        let path = datadir.join(URI_FILE);
+        write_private(&path, &self.connection_uri())?;
+        Ok(path)
+    }
+
+    /// Connects to the relay, publishes the wallet's info event and answers
+    /// requests until the connection to the relay is closed. Invoices are
+    /// issued within `limits`.
+    pub async fn run<B: LightningBackend>(
+        self,
+        blitzi: Arc<B>,
+        limits: InvoiceLimits,
+    ) -> anyhow::Result<()> {
+        let client = Client::new(self.wallet_keys.clone());
+        client.add_relay(self.relay.clone()).await?;
+        client.connect().await;
+
+        let info = EventBuilder::new(Kind::WalletConnectInfo, SUPPORTED_METHODS).tag(Tag::custom(
+            TagKind::custom("encryption"),
+            [SUPPORTED_ENCRYPTION],
+        ));
+        client
+            .send_event_builder(info)
+            .await
+            .context("Failed to publish NWC info event")?;
+
+        let filter = Filter::new()
+            .kind(Kind::WalletConnectRequest)
+            .author(self.app_keys.public_key())
+            .pubkey(self.wallet_keys.public_key())
+            .since(Timestamp::now());
+        client
+            .subscribe(filter, None)
+            .await
+            .context("Failed to subscribe to NWC requests")?;
+        info!(relay = %self.relay, "Listening for NWC requests");
+
+        let limits = Arc::new(limits);
+        let mut notifications = client.notifications();
+        loop {
+            let event = match notifications.recv().await {
+                Ok(RelayPoolNotification::Event { event, .. }) => event,
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "Missed NWC relay notifications");
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            if event.kind != Kind::WalletConnectRequest
+                || event.pubkey != self.app_keys.public_key()
+            {
+                continue;
+            }
+
+            // Payments can take a while, don't hold up other requests meanwhile
+            let client = client.clone();
+            let wallet_keys = self.wallet_keys.clone();
+            let blitzi = blitzi.clone();
+            let limits = limits.clone();
+            tokio::spawn(async move {
+                let answered = answer(&client, &wallet_keys, blitzi.as_ref(), &limits, &event);
+                if let Err(e) = answered.await {
+                    warn!(error = %e, event_id = %event.id, "Failed to answer NWC request");
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Decrypts a request event, handles it and publishes the encrypted response.
+async fn answer<B: LightningBackend>(
+    client: &Client,
+    wallet_keys: &Keys,
+    blitzi: &B,
+    limits: &InvoiceLimits,
+    event: &Event,
+) -> anyhow::Result<()> {
+    let encryption = Encryption::of(event);
+    let content = encryption.decrypt(wallet_keys.secret_key(), &event.pubkey, &event.content)?;
+    let request: NwcRequest = serde_json::from_str(&content).context("Invalid NWC request")?;
+
+    info!(method = %request.method, event_id = %event.id, "Handling NWC request");
+    let response = handle_request(blitzi, limits, request).await;
+
+    let content = encryption.encrypt(
+        wallet_keys.secret_key(),
+        &event.pubkey,
+        &serde_json::to_string(&response)?,
+    )?;
+    let mut builder = EventBuilder::new(Kind::WalletConnectResponse, content)
+        .tag(Tag::public_key(event.pubkey))
+        .tag(Tag::event(event.id));
+    if encryption == Encryption::Nip44 {
+        builder = builder.tag(Tag::custom(TagKind::custom("encryption"), ["nip44_v2"]));
+    }
+    client.send_event_builder(builder).await?;
+
+    Ok(())
+}
+
+/// Encryption scheme of a request, responses use the same one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encryption {
+    Nip04,
+    Nip44,
+}
+
+impl Encryption {
+    /// Requests are NIP-04 encrypted unless they carry an `encryption` tag
+    /// asking for NIP-44.
+    fn of(event: &Event) -> Self {
+        let is_nip44 = event.tags.iter().any(|tag| {
+            matches!(tag.as_slice(), [kind, value, ..] if kind == "encryption" && value == "nip44_v2")
+        });
+        if is_nip44 {
+            Encryption::Nip44
+        } else {
+            Encryption::Nip04
+        }
+    }
+
+    fn decrypt(
+        self,
+        secret_key: &SecretKey,
+        public_key: &PublicKey,
+        content: &str,
+    ) -> anyhow::Result<String> {
+        Ok(match self {
+            Encryption::Nip04 => nip04::decrypt(secret_key, public_key, content)?,
+            Encryption::Nip44 => nip44::decrypt(secret_key, public_key, content)?,
+        })
+    }
+
+    fn encrypt(
+        self,
+        secret_key: &SecretKey,
+        public_key: &PublicKey,
+        content: &str,
+    ) -> anyhow::Result<String> {
+        Ok(match self {
+            Encryption::Nip04 => nip04::encrypt(secret_key, public_key, content)?,
+            Encryption::Nip44 => {
+                nip44::encrypt(secret_key, public_key, content, nip44::Version::V2)?
+            }
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct NwcRequest {
+    method: String,
+    #[serde(default)]
+    params: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct PayInvoiceParams {
+    invoice: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MakeInvoiceParams {
+    /// Amount in millisatoshis
+    amount: u64,
+    #[serde(default)]
+    description: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LookupInvoiceParams {
+    #[serde(default)]
+    payment_hash: Option<PaymentHash>,
+    #[serde(default)]
+    invoice: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct NwcResponse {
+    result_type: String,
+    error: Option<NwcError>,
+    result: Option<serde_json::Value>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct NwcError {
+    code: ErrorCode,
+    message: String,
+}
+
+impl NwcError {
+    fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        NwcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Error codes defined by NIP-47
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum ErrorCode {
+    PaymentFailed,
+    NotFound,
+    QuotaExceeded,
+    Restricted,
+    NotImplemented,
+    Internal,
+    Other,
+}
+
+async fn handle_request<B: LightningBackend>(
+    blitzi: &B,
+    limits: &InvoiceLimits,
+    request: NwcRequest,
+) -> NwcResponse {
+    let result = match request.method.as_str() {
+        "pay_invoice" => pay_invoice(blitzi, request.params).await,
+        "make_invoice" => make_invoice(blitzi, limits, request.params).await,
+        "get_balance" => Ok(json!({ "balance": blitzi.balance().await.msats })),
+        "lookup_invoice" => lookup_invoice(blitzi, request.params).await,
+        method => Err(NwcError::new(
+            ErrorCode::NotImplemented,
+            format!("Method {} is not supported", method),
+        )),
+    };
+
+    match result {
+        Ok(result) => NwcResponse {
+            result_type: request.method,
+            error: None,
+            result: Some(result),
+        },
+        Err(error) => NwcResponse {
+            result_type: request.method,
+            error: Some(error),
+            result: None,
+        },
+    }
+}
+
+async fn pay_invoice<B: LightningBackend>(
+    blitzi: &B,
+    params: serde_json::Value,
+) -> Result<serde_json::Value, NwcError> {
+    let params: PayInvoiceParams = parse_params(params)?;
+    let invoice = parse_invoice(&params.invoice)?;
+
+    match blitzi.pay(&invoice).await {
+        Ok(preimage) => Ok(json!({ "preimage": preimage })),
+        Err(e) => {
+            let code = if e.downcast_ref::<SpendLimitExceeded>().is_some() {
+                ErrorCode::QuotaExceeded
+            } else if e.downcast_ref::<PolicyDenied>().is_some() {
+                ErrorCode::Restricted
+            } else if e.downcast_ref::<InvalidInvoiceError>().is_some() {
+                ErrorCode::Other
+            } else {
+                warn!(error = %e, payment_hash = %invoice.payment_hash(), "NWC payment failed");
+                ErrorCode::PaymentFailed
+            };
+            Err(NwcError::new(code, e.to_string()))
+        }
+    }
+}
+
+async fn make_invoice<B: LightningBackend>(
+    blitzi: &B,
+    limits: &InvoiceLimits,
+    params: serde_json::Value,
+) -> Result<serde_json::Value, NwcError> {
+    let params: MakeInvoiceParams = parse_params(params)?;
+    let amount = msats(params.amount);
+    let description = limits
+        .apply(amount, params.description.as_deref().unwrap_or_default())
+        .map_err(|e| NwcError::new(ErrorCode::Other, e.to_string()))?;
+
+    match blitzi.lightning_invoice(amount, &description).await {
+        Ok(invoice) => Ok(transaction(&invoice, InvoiceStatus::Pending)),
+        Err(e) if e.downcast_ref::<InvoiceAmountError>().is_some() => Err(NwcError::new(
+            ErrorCode::Other,
+            format!("Invalid amount: {}", e),
+        )),
+        Err(e)
+            if e.downcast_ref::<DescriptionTooLong>().is_some()
+                || e.downcast_ref::<InvalidDescription>().is_some() =>
+        {
+            Err(NwcError::new(
+                ErrorCode::Other,
+                format!("Invalid description: {}", e),
+            ))
+        }
+        Err(e) => Err(NwcError::new(
+            ErrorCode::Internal,
+            format!("Failed to create invoice: {}", e),
+        )),
+    }
+}
+
+/// Looks up an invoice issued by the wallet. Only the status is known for
+/// invoices looked up by payment hash, requests passing the invoice also get
+/// its amount and description.
+async fn lookup_invoice<B: LightningBackend>(
+    blitzi: &B,
+    params: serde_json::Value,
+) -> Result<serde_json::Value, NwcError> {
+    let params: LookupInvoiceParams = parse_params(params)?;
+    let invoice = params.invoice.as_deref().map(parse_invoice).transpose()?;
+    let payment_hash = match (params.payment_hash, &invoice) {
+        (Some(payment_hash), _) => payment_hash,
+        (None, Some(invoice)) => invoice.payment_hash().into(),
+        (None, None) => {
+            return Err(NwcError::new(
+                ErrorCode::Other,
+                "Either payment_hash or invoice is required",
+            ));
+        }
+    };
+
+    let ours = blitzi
+        .is_invoice_ours(payment_hash)
+        .await
+        .map_err(|e| NwcError::new(ErrorCode::Internal, e.to_string()))?;
+    if !ours {
+        return Err(NwcError::new(
+            ErrorCode::NotFound,
+            "Invoice not issued by this wallet",
+        ));
+    }
+    let status = blitzi
+        .invoice_status(payment_hash)
+        .await
+        .map_err(|e| NwcError::new(ErrorCode::Internal, e.to_string()))?;
+
+    Ok(match invoice {
+        Some(invoice) => transaction(&invoice, status),
+        None => json!({
+            "type": "incoming",
+            "state": state(status),
+            "payment_hash": payment_hash,
+        }),
+    })
+}
+
+/// Describes an incoming payment in the transaction format of NIP-47.
+fn transaction(invoice: &Bolt11Invoice, status: InvoiceStatus) -> serde_json::Value {
+    let description = match invoice.description() {
+        Bolt11InvoiceDescriptionRef::Direct(description) => Some(description.to_string()),
+        Bolt11InvoiceDescriptionRef::Hash(_) => None,
+    };
+
+    json!({
+        "type": "incoming",
+        "state": state(status),
+        "invoice": invoice.to_string(),
+        "description": description,
+        "payment_hash": PaymentHash::from(invoice.payment_hash()),
+        "amount": invoice.amount_milli_satoshis(),
+        "created_at": invoice.duration_since_epoch().as_secs(),
+        "expires_at": invoice.expires_at().map(|expires_at| expires_at.as_secs()),
+    })
+}
+
+fn state(status: InvoiceStatus) -> &'static str {
+    match status {
+        InvoiceStatus::Pending => "pending",
+        InvoiceStatus::Paid => "settled",
+        InvoiceStatus::Canceled => "expired",
+    }
+}
+
+fn parse_params<T: DeserializeOwned>(params: serde_json::Value) -> Result<T, NwcError> {
+    serde_json::from_value(params)
+        .map_err(|e| NwcError::new(ErrorCode::Other, format!("Invalid params: {}", e)))
+}
+
+fn parse_invoice(invoice: &str) -> Result<Bolt11Invoice, NwcError> {
+    invoice
+        .parse()
+        .map_err(|e| NwcError::new(ErrorCode::Other, format!("Invalid invoice: {}", e)))
+}
+
+/// Writes `contents` to `path`, readable and writable only by its owner.
+fn write_private(path: &Path, contents: &str) -> anyhow::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let write = || -> std::io::Result<()> {
+        let mut file = options.open(path)?;
+        // The mode only applies to new files
+        #[cfg(unix)]
+        file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        file.write_all(contents.as_bytes())
+    };
+    write().with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Percent-encodes everything but unreserved characters, for use in a URI
+/// query.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| {
+            if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                (byte as char).to_string()
+            } else {
+                format!("%{:02X}", byte)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use blitzi::{MockIncomingPayment, MockLightning};
+
+    use super::*;
+
+    async fn request(mock: &MockLightning, request: serde_json::Value) -> serde_json::Value {
+        request_with_limits(mock, &InvoiceLimits::default(), request).await
+    }
+
+    async fn request_with_limits(
+        mock: &MockLightning,
+        limits: &InvoiceLimits,
+        request: serde_json::Value,
+    ) -> serde_json::Value {
+        let request = serde_json::from_value(request).unwrap();
+        serde_json::to_value(handle_request(mock, limits, request).await).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_invoice_requests() {
+        let mock = MockLightning::new();
+        mock.set_default_incoming(MockIncomingPayment::Never);
+
+        let response = request(
+            &mock,
+            json!({ "method": "make_invoice", "params": { "amount": 1000, "description": "nwc" } }),
+        )
+        .await;
+        assert_eq!(response["result_type"], "make_invoice");
+        assert_eq!(response["error"], serde_json::Value::Null);
+        assert_eq!(response["result"]["amount"], 1000);
+        assert_eq!(response["result"]["description"], "nwc");
+        let invoice = response["result"]["invoice"].clone();
+
+        let response = request(
+            &mock,
+            json!({ "method": "lookup_invoice", "params": { "invoice": invoice } }),
+        )
+        .await;
+        assert_eq!(response["result"]["state"], "pending");
+        assert_eq!(response["result"]["amount"], 1000);
+
+        let response = request(
+            &mock,
+            json!({ "method": "lookup_invoice", "params": { "payment_hash": "00".repeat(32) } }),
+        )
+        .await;
+        assert_eq!(response["error"]["code"], "NOT_FOUND");
+    }
+
+    #[tokio::test]
+    async fn test_make_invoice_limits() {
+        let mock = MockLightning::new();
+        let limits = InvoiceLimits {
+            max_amount: Some(msats(1000)),
+            description_prefix: Some("shop: ".to_string()),
+        };
+
+        let response = request_with_limits(
+            &mock,
+            &limits,
+            json!({ "method": "make_invoice", "params": { "amount": 1000, "description": "nwc" } }),
+        )
+        .await;
+        assert_eq!(response["result"]["description"], "shop: nwc");
+
+        let calls = mock.calls().len();
+        let response = request_with_limits(
+            &mock,
+            &limits,
+            json!({ "method": "make_invoice", "params": { "amount": 1001 } }),
+        )
+        .await;
+        assert_eq!(response["error"]["code"], "OTHER");
+        assert!(
+            response["error"]["message"]
+                .as_str()
+                .unwrap()
+                .starts_with("Invalid amount")
+        );
+        // The rejected request didn't reach the backend
+        assert_eq!(mock.calls().len(), calls);
+    }
+
+    #[tokio::test]
+    async fn test_pay_and_balance() {
+        let mock = MockLightning::new();
+        mock.set_balance(msats(10_000));
+        let invoice = mock.lightning_invoice(msats(1000), "test").await.unwrap();
+
+        let response = request(
+            &mock,
+            json!({ "method": "pay_invoice", "params": { "invoice": invoice.to_string() } }),
+        )
+        .await;
+        assert!(response["result"]["preimage"].is_string());
+
+        let response = request(&mock, json!({ "method": "get_balance", "params": {} })).await;
+        assert_eq!(response["result"], json!({ "balance": 9000 }));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_requests() {
+        let mock = MockLightning::new();
+
+        let response = request(&mock, json!({ "method": "pay_keysend", "params": {} })).await;
+        assert_eq!(response["result_type"], "pay_keysend");
+        assert_eq!(response["error"]["code"], "NOT_IMPLEMENTED");
+        assert_eq!(response["result"], serde_json::Value::Null);
+
+        let response = request(
+            &mock,
+            json!({ "method": "pay_invoice", "params": { "invoice": "lnbc1invalid" } }),
+        )
+        .await;
+        assert_eq!(response["error"]["code"], "OTHER");
+    }
+
+    #[test]
+    fn test_connection_uri() {
+        let secret = NwcServer::generate_secret();
+        let server = NwcServer::new("wss://relay.example.com", &secret).unwrap();
+        let uri = server.connection_uri();
+
+        assert!(uri.starts_with(&format!(
+            "nostr+walletconnect://{}?relay=wss%3A%2F%2Frelay.example.com",
+            server.wallet_keys.public_key().to_hex()
+        )));
+        assert_ne!(
+            server.app_keys.public_key(),
+            server.wallet_keys.public_key()
+        );
+        // The URI stays the same across restarts
+        let restarted = NwcServer::new("wss://relay.example.com", &secret).unwrap();
+        assert_eq!(restarted.connection_uri(), uri);
+
+        assert!(NwcServer::new("not a url", &secret).is_err());
+        assert!(NwcServer::new("wss://relay.example.com", "invalid").is_err());
+    }
+
+    #[test]
+    fn test_persisted_secret() {
+        let dir = tempfile::tempdir().unwrap();
+        let datadir = dir.path();
+
+        let secret = NwcServer::load_or_generate_secret(datadir).unwrap();
+        // Later starts use the same secret
+        assert_eq!(NwcServer::load_or_generate_secret(datadir).unwrap(), secret);
+
+        let server = NwcServer::new("wss://relay.example.com", &secret).unwrap();
+        let path = server.write_connection_uri(datadir).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            server.connection_uri()
+        );
+        #[cfg(unix)]
+        for file in [path, datadir.join(SECRET_FILE)] {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600, "{}", file.display());
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Server-Sent Events stream of wallet events served on `GET /events`.
+//!
+//! Events are numbered and the most recent ones are buffered, so clients
+//! reconnecting with a `Last-Event-ID` header receive the events they missed.
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::response::sse::{Event, KeepAlive, Sse};
+use blitzi::{BlitziEvent, LightningBackend};
+use futures_lite::{Stream, StreamExt};
+use tokio::sync::broadcast;
+use tracing::warn;
+
+/// Number of recent events kept for reconnecting clients.
+const BUFFERED_EVENTS: usize = 1024;
+
+/// Interval of the heartbeat comments keeping proxies from closing idle
+/// connections.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Recent wallet events with monotonically increasing ids, starting at 1 on
+/// every start of the daemon.
+pub struct EventLog {
+    recent: Mutex<Recent>,
+    sender: broadcast::Sender<(u64, BlitziEvent)>,
+}
+
+struct Recent {
+    events: VecDeque<(u64, BlitziEvent)>,
+    next_id: u64,
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        EventLog {
+            recent: Mutex::new(Recent {
+                events: VecDeque::with_capacity(BUFFERED_EVENTS),
+                next_id: 1,
+            }),
+            sender: broadcast::channel(BUFFERED_EVENTS).0,
+        }
+    }
+}
+
+impl EventLog {
+    /// Records the events of `blitzi` until its event stream ends.
+    pub async fn run<B: LightningBackend>(self: Arc<Self>, blitzi: Arc<B>) {
+        let mut events = blitzi.subscribe_events().await;
+        while let Some(event) = events.next().await {
+            self.push(event);
+        }
+    }
+
+    /// Assigns the next id to `event`, buffers it and sends it to all
+    /// connected clients.
+    pub fn push(&self, event: BlitziEvent) {
+        let mut recent = self.recent.lock().expect("Event log lock poisoned");
+        let id = recent.next_id;
+        recent.next_id += 1;
+        if recent.events.len() == BUFFERED_EVENTS {
+            recent.events.pop_front();
+        }
+        recent.events.push_back((id, event.clone()));
+        // Sent while holding the lock, so subscribers see the ids in order
+        let _ = self.sender.send((id, event));
+    }
+
+    /// Returns the SSE stream of all events after `last_event_id`, starting
+    /// with the buffered ones. Ids from before a restart of the daemon (higher
+    /// than any id issued since) replay all buffered events.
+    pub fn subscribe(
+        &self,
+        last_event_id: Option<u64>,
+    ) -> Sse<impl Stream<Item = Result<Event, Infallible>> + use<>> {
+        let (missed, receiver) = {
+            let recent = self.recent.lock().expect("Event log lock poisoned");
+            let after = match last_event_id {
+                Some(id) if id < recent.next_id => id,
+                // From before a restart
+                Some(_) => 0,
+                None => recent.next_id - 1,
+            };
+            let missed = recent
+                .events
+                .iter()
+                .filter(|(id, _)| *id > after)
+                .cloned()
+                .collect::<Vec<_>>();
+            (missed, self.sender.subscribe())
+        };
+
+        let live = futures_lite::stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, receiver)),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!(missed, "SSE client fell behind, skipping events");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        });
+        let events = futures_lite::stream::iter(missed)
+            .chain(live)
+            .map(|(id, event)| Ok(sse_event(id, &event)));
+
+        Sse::new(events).keep_alive(
+            KeepAlive::new()
+                .interval(HEARTBEAT_INTERVAL)
+                .text("heartbeat"),
+        )
+    }
+}
+
+/// Converts `event` to an SSE event whose data is the JSON serialized event.
+fn sse_event(id: u64, event: &BlitziEvent) -> Event {
+    Event::default()
+        .id(id.to_string())
+        .data(serde_json::to_string(event).expect("events can be serialized"))
+}
+
+#[cfg(test)]
+mod tests {
+    use blitzi::msats;
+
+    use super::*;
+
+    #[test]
+    fn test_buffer_is_bounded() {
+        let log = EventLog::default();
+        for i in 0..BUFFERED_EVENTS as u64 + 10 {
+            log.push(BlitziEvent::BalanceChanged { balance: msats(i) });
+        }
+        let recent = log.recent.lock().unwrap();
+        assert_eq!(recent.events.len(), BUFFERED_EVENTS);
+        assert_eq!(recent.events.front().unwrap().0, 11);
+        assert_eq!(recent.next_id, BUFFERED_EVENTS as u64 + 11);
+    }
+}
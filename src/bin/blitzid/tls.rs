@@ -0,0 +1,236 @@
+//! HTTPS for the API, enabled by passing `--tls-cert` and `--tls-key`.
+//!
+//! The certificate is read again when blitzid receives SIGHUP, so renewed
+//! certificates (e.g. from Let's Encrypt) are picked up without a restart.
+//! Connections that are already open keep using the previous certificate.
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Context, ensure};
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::ServerConfig;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tracing::{error, info};
+
+/// Certificate and key files the TLS config was loaded from.
+pub struct TlsFiles {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl TlsFiles {
+    /// Returns the files if both a certificate and a key were configured,
+    /// `None` if neither was.
+    ///
+    /// # Errors
+    /// Returns an error if only one of them was configured.
+    pub fn new(cert: Option<PathBuf>, key: Option<PathBuf>) -> anyhow::Result<Option<Self>> {
+        match (cert, key) {
+            (Some(cert), Some(key)) => Ok(Some(TlsFiles { cert, key })),
+            (None, None) => Ok(None),
+            (Some(_), None) => anyhow::bail!("--tls-cert requires --tls-key to be set as well"),
+            (None, Some(_)) => anyhow::bail!("--tls-key requires --tls-cert to be set as well"),
+        }
+    }
+
+    /// Reads the certificate chain and private key, both PEM encoded.
+    pub fn load(&self) -> anyhow::Result<Arc<ServerConfig>> {
+        let certs = CertificateDer::pem_file_iter(&self.cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("Failed to read TLS certificate {}", self.cert.display()))?;
+        ensure!(
+            !certs.is_empty(),
+            "TLS certificate file {} doesn't contain a certificate",
+            self.cert.display()
+        );
+        let key = PrivateKeyDer::from_pem_file(&self.key)
+            .with_context(|| format!("Failed to read TLS key {}", self.key.display()))?;
+
+        // Chosen explicitly, several crypto providers are linked into blitzid
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .context("TLS certificate and key don't match")?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+
+    /// Loads the certificate again on every SIGHUP. If that fails the error is
+    /// logged and the previous certificate is kept.
+    #[cfg(unix)]
+    pub async fn reload_on_sighup(self, config: RustlsConfig) {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!(error = %e, "Failed to listen for SIGHUP, TLS certificates won't be reloaded");
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            match self.load() {
+                Ok(server_config) => {
+                    config.reload_from_config(server_config);
+                    info!(cert = %self.cert.display(), "Reloaded TLS certificate");
+                }
+                Err(e) => {
+                    error!(error = %e, "Failed to reload TLS certificate, keeping the previous one");
+                }
+            }
+        }
+    }
+}
+
+/// Serves `app` over HTTPS until `shutdown` resolves, then waits for in-flight
+/// requests to finish. Connections failing the TLS handshake, e.g. plain HTTP
+/// requests, are closed without affecting others.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    config: RustlsConfig,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()> {
+    let handle = axum_server::Handle::new();
+    let shutdown_handle = handle.clone();
+    tokio::spawn(async move {
+        shutdown.await;
+        shutdown_handle.graceful_shutdown(None);
+    });
+
+    axum_server::from_tcp_rustls(listener.into_std()?, config)
+        .handle(handle)
+        .serve(app.into_make_service())
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::path::Path;
+
+    use axum::routing::get;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+
+    use super::*;
+
+    /// Writes a certificate for `localhost` signed by a new CA and its key to
+    /// `dir`, returns them and the PEM encoded CA certificate.
+    fn generate_cert(dir: &Path, name: &str) -> (TlsFiles, String) {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&key, &ca, &ca_key)
+            .unwrap();
+
+        let files = TlsFiles {
+            cert: dir.join(format!("{}.crt", name)),
+            key: dir.join(format!("{}.key", name)),
+        };
+        std::fs::write(&files.cert, cert.pem()).unwrap();
+        std::fs::write(&files.key, key.serialize_pem()).unwrap();
+        (files, ca.pem())
+    }
+
+    fn client(addr: SocketAddr, ca: &str) -> reqwest::Client {
+        reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(ca.as_bytes()).unwrap())
+            .resolve("localhost", addr)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_requires_cert_and_key() {
+        let path = PathBuf::from("/tls");
+        assert!(TlsFiles::new(None, None).unwrap().is_none());
+        assert!(
+            TlsFiles::new(Some(path.clone()), Some(path.clone()))
+                .unwrap()
+                .is_some()
+        );
+
+        let error = TlsFiles::new(Some(path.clone()), None).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "--tls-cert requires --tls-key to be set as well"
+        );
+        let error = TlsFiles::new(None, Some(path)).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "--tls-key requires --tls-cert to be set as well"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let dir = tempfile::tempdir().unwrap();
+        let (files, ca) = generate_cert(dir.path(), "serve");
+        let config = RustlsConfig::from_config(files.load().unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/health", get(|| async { "OK" }));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, app, config.clone(), async move {
+            let _ = stopped.await;
+        }));
+
+        let url = format!("https://localhost:{}/health", addr.port());
+        let response = client(addr, &ca).get(&url).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), "OK");
+
+        // Plain HTTP fails the handshake without taking the server down
+        let plain = reqwest::Client::new()
+            .get(format!("http://127.0.0.1:{}/health", addr.port()))
+            .send()
+            .await;
+        assert!(plain.is_err());
+        // Clients not trusting the CA are rejected
+        let untrusted = reqwest::Client::builder()
+            .resolve("localhost", addr)
+            .build()
+            .unwrap();
+        assert!(untrusted.get(&url).send().await.is_err());
+
+        // A reloaded certificate is used for new connections
+        let (renewed, renewed_ca) = generate_cert(dir.path(), "renewed");
+        config.reload_from_config(renewed.load().unwrap());
+        let response = client(addr, &renewed_ca).get(&url).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_load_invalid_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (files, _) = generate_cert(dir.path(), "invalid");
+        // The certificate file doesn't contain a key
+        let swapped = TlsFiles {
+            cert: files.key.clone(),
+            key: files.cert.clone(),
+        };
+        assert!(swapped.load().is_err());
+
+        let missing = TlsFiles {
+            cert: PathBuf::from("/nonexistent/blitzid.crt"),
+            key: files.key.clone(),
+        };
+        let error = missing.load().err().unwrap();
+        assert!(error.to_string().contains("/nonexistent/blitzid.crt"));
+    }
+}
@@ -0,0 +1,436 @@
+//! Webhook notifications for paid invoices, registered via the `webhook_url`
+//! of `POST /invoice`.
+//!
+//! Registrations and undelivered notifications are kept in a JSON file in the
+//! data directory, so notifications for invoices paid while the daemon was
+//! down are sent after the next start.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, ensure};
+use blitzi::bitcoin::hashes::{Hash, HashEngine, Hmac, HmacEngine, sha256};
+use blitzi::{Amount, BlitziEvent, InvoiceStatus, LightningBackend, PaymentHash};
+use futures_lite::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use crate::metrics::Metrics;
+
+/// Header carrying the hex encoded HMAC-SHA256 of the request body, keyed by
+/// the webhook secret.
+pub const SIGNATURE_HEADER: &str = "x-blitzid-signature";
+
+/// Number of delivery attempts before a notification is dropped.
+const MAX_ATTEMPTS: u32 = 10;
+
+/// Delay before the first retry, doubled after every further attempt. Ten
+/// attempts span about eight and a half minutes.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How long a single delivery attempt may take.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Body POSTed to the webhook URL once an invoice was paid.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebhookPayload {
+    pub payment_hash: PaymentHash,
+    pub amount_msats: u64,
+    /// Unix time in seconds the payment was claimed at
+    pub paid_at: u64,
+    /// The `metadata` passed when creating the invoice, `null` if none
+    pub metadata: serde_json::Value,
+}
+
+/// Webhook registered for an invoice that wasn't paid yet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Registration {
+    url: String,
+    #[serde(rename = "amount_msats")]
+    amount: Amount,
+    metadata: serde_json::Value,
+}
+
+/// Notification that wasn't delivered yet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Delivery {
+    url: String,
+    payload: WebhookPayload,
+    /// Failed attempts so far
+    attempts: u32,
+}
+
+/// Contents of the queue file, both maps are keyed by the hex payment hash.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Queue {
+    registrations: HashMap<String, Registration>,
+    deliveries: HashMap<String, Delivery>,
+}
+
+/// Sends signed notifications to the webhook URLs registered for invoices
+/// once they are paid.
+pub struct Webhooks {
+    path: PathBuf,
+    secret: String,
+    queue: Mutex<Queue>,
+    http: reqwest::Client,
+    /// Counts the delivery attempts
+    metrics: Arc<Metrics>,
+}
+
+impl Webhooks {
+    /// Loads the queue persisted at `path`, starting with an empty one if the
+    /// file doesn't exist yet.
+    pub fn load(path: PathBuf, secret: String, metrics: Arc<Metrics>) -> anyhow::Result<Self> {
+        let queue = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("Invalid webhook queue {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Queue::default(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read webhook queue {}", path.display()));
+            }
+        };
+        Ok(Webhooks {
+            path,
+            secret,
+            queue: Mutex::new(queue),
+            http: reqwest::Client::new(),
+            metrics,
+        })
+    }
+
+    /// Generates a random secret to sign notifications with, hex encoded.
+    pub fn generate_secret() -> String {
+        hex::encode(rand::random::<[u8; 32]>())
+    }
+
+    /// Checks that `url` can be used as webhook URL.
+    pub fn validate_url(url: &str) -> anyhow::Result<()> {
+        let url = reqwest::Url::parse(url)?;
+        ensure!(
+            matches!(url.scheme(), "http" | "https"),
+            "unsupported scheme {}",
+            url.scheme()
+        );
+        Ok(())
+    }
+
+    /// Registers `url` to be notified once the invoice for `amount` with
+    /// `payment_hash` is paid.
+    pub async fn register(
+        &self,
+        payment_hash: PaymentHash,
+        url: String,
+        amount: Amount,
+        metadata: serde_json::Value,
+    ) -> anyhow::Result<()> {
+        let mut queue = self.queue.lock().await;
+        queue.registrations.insert(
+            payment_hash.to_string(),
+            Registration {
+                url,
+                amount,
+                metadata,
+            },
+        );
+        self.save(&queue).await
+    }
+
+    /// Sends the notifications left over from the last run, catches up on
+    /// invoices paid while the daemon was down and then sends notifications
+    /// for incoming payments as they are claimed. Runs until the backend's
+    /// event stream ends.
+    pub async fn run<B: LightningBackend>(self: Arc<Self>, blitzi: Arc<B>) {
+        // Subscribe first so no payment is missed between catching up and
+        // following the stream
+        let mut events = blitzi.subscribe_events().await;
+
+        let (deliveries, registrations) = {
+            let queue = self.queue.lock().await;
+            (
+                queue.deliveries.keys().cloned().collect::<Vec<_>>(),
+                queue.registrations.keys().cloned().collect::<Vec<_>>(),
+            )
+        };
+        for key in deliveries {
+            tokio::spawn(self.clone().deliver(key));
+        }
+        for key in registrations {
+            let Ok(payment_hash) = key.parse::<PaymentHash>() else {
+                continue;
+            };
+            match blitzi.invoice_status(payment_hash).await {
+                Ok(InvoiceStatus::Paid) => self.paid(&*blitzi, payment_hash).await,
+                Ok(InvoiceStatus::Canceled) => self.unregister(payment_hash).await,
+                Ok(InvoiceStatus::Pending) => {}
+                Err(e) => warn!(%payment_hash, error = %e, "Failed to check invoice of webhook"),
+            }
+        }
+
+        while let Some(event) = events.next().await {
+            if let BlitziEvent::InvoicePaid {
+                payment_hash: Some(payment_hash),
+                ..
+            } = event
+            {
+                self.paid(&*blitzi, payment_hash).await;
+            }
+        }
+    }
+
+    /// Turns the registration for `payment_hash`, if any, into a notification
+    /// stamped with the time the payment was claimed and starts delivering it.
+    async fn paid<B: LightningBackend>(self: &Arc<Self>, blitzi: &B, payment_hash: PaymentHash) {
+        let key = payment_hash.to_string();
+        if !self.queue.lock().await.registrations.contains_key(&key) {
+            return;
+        }
+        let claimed_at = match blitzi.await_incoming_payment_details(payment_hash).await {
+            Ok(payment) => payment.claimed_at,
+            Err(e) => {
+                warn!(%payment_hash, error = %e, "Failed to look up when the invoice was paid");
+                SystemTime::now()
+            }
+        };
+        let paid_at = claimed_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        {
+            let mut queue = self.queue.lock().await;
+            let Some(registration) = queue.registrations.remove(&key) else {
+                return;
+            };
+            queue.deliveries.insert(
+                key.clone(),
+                Delivery {
+                    url: registration.url,
+                    payload: WebhookPayload {
+                        payment_hash,
+                        amount_msats: registration.amount.msats,
+                        paid_at,
+                        metadata: registration.metadata,
+                    },
+                    attempts: 0,
+                },
+            );
+            if let Err(e) = self.save(&queue).await {
+                error!(%payment_hash, error = %e, "Failed to persist webhook notification");
+            }
+        }
+        tokio::spawn(self.clone().deliver(key));
+    }
+
+    /// Drops the registration for `payment_hash`, e.g. because the invoice was
+    /// canceled.
+    async fn unregister(&self, payment_hash: PaymentHash) {
+        let mut queue = self.queue.lock().await;
+        if queue
+            .registrations
+            .remove(&payment_hash.to_string())
+            .is_some()
+            && let Err(e) = self.save(&queue).await
+        {
+            error!(%payment_hash, error = %e, "Failed to persist webhook queue");
+        }
+    }
+
+    /// Delivers the notification stored under `key`, retrying with
+    /// exponential backoff until it's accepted or [`MAX_ATTEMPTS`] failed.
+    async fn deliver(self: Arc<Self>, key: String) {
+        loop {
+            let Some(delivery) = self.queue.lock().await.deliveries.get(&key).cloned() else {
+                return;
+            };
+            if delivery.attempts > 0 {
+                tokio::time::sleep(INITIAL_BACKOFF * 2u32.pow(delivery.attempts - 1)).await;
+            }
+
+            let result = self.send(&delivery).await;
+            self.metrics.webhook_attempt(result.is_err());
+            let mut queue = self.queue.lock().await;
+            match result {
+                Ok(()) => {
+                    info!(payment_hash = %key, url = %delivery.url, "Delivered webhook");
+                    queue.deliveries.remove(&key);
+                }
+                Err(e) if delivery.attempts + 1 >= MAX_ATTEMPTS => {
+                    error!(
+                        payment_hash = %key,
+                        url = %delivery.url,
+                        error = %e,
+                        "Giving up on webhook after {} attempts",
+                        MAX_ATTEMPTS
+                    );
+                    queue.deliveries.remove(&key);
+                }
+                Err(e) => {
+                    warn!(payment_hash = %key, url = %delivery.url, error = %e, "Webhook failed, retrying");
+                    if let Some(delivery) = queue.deliveries.get_mut(&key) {
+                        delivery.attempts += 1;
+                    }
+                }
+            }
+            if let Err(e) = self.save(&queue).await {
+                error!(payment_hash = %key, error = %e, "Failed to persist webhook queue");
+            }
+            if !queue.deliveries.contains_key(&key) {
+                return;
+            }
+        }
+    }
+
+    /// Makes a single delivery attempt.
+    async fn send(&self, delivery: &Delivery) -> anyhow::Result<()> {
+        let body = serde_json::to_vec(&delivery.payload)?;
+        self.http
+            .post(&delivery.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, sign(&self.secret, &body))
+            .timeout(DELIVERY_TIMEOUT)
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Writes the queue to a temporary file first, so a crash can't leave a
+    /// truncated queue behind.
+    async fn save(&self, queue: &Queue) -> anyhow::Result<()> {
+        let tmp = self.path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(queue)?).await?;
+        tokio::fs::rename(&tmp, &self.path).await?;
+        Ok(())
+    }
+}
+
+/// Returns the hex encoded HMAC-SHA256 of `body` keyed by `secret`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut engine = HmacEngine::<sha256::Hash>::new(secret.as_bytes());
+    engine.input(body);
+    hex::encode(Hmac::<sha256::Hash>::from_engine(engine).to_byte_array())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::Router;
+    use axum::body::Bytes;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use blitzi::{MockIncomingPayment, MockLightning, msats};
+
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        // Test case 2 of RFC 4231
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_validate_url() {
+        assert!(Webhooks::validate_url("https://shop.example.com/paid").is_ok());
+        assert!(Webhooks::validate_url("http://127.0.0.1:8080/").is_ok());
+        assert!(Webhooks::validate_url("ftp://example.com/").is_err());
+        assert!(Webhooks::validate_url("not a url").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_registrations_are_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("webhooks.json");
+        let webhooks = Webhooks::load(path.clone(), "secret".to_string(), Arc::default()).unwrap();
+        let payment_hash = PaymentHash::from([1; 32]);
+        webhooks
+            .register(
+                payment_hash,
+                "https://shop.example.com/paid".to_string(),
+                msats(1000),
+                serde_json::json!({ "order": 1 }),
+            )
+            .await
+            .unwrap();
+
+        let reloaded = Webhooks::load(path.clone(), "secret".to_string(), Arc::default()).unwrap();
+        let queue = reloaded.queue.lock().await;
+        let registration = &queue.registrations[&payment_hash.to_string()];
+        assert_eq!(registration.url, "https://shop.example.com/paid");
+        assert_eq!(registration.amount, msats(1000));
+    }
+
+    #[tokio::test]
+    async fn test_delivery() {
+        // Receiver failing the first attempt and recording the second
+        let (sender, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let attempts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let receiver = Router::new().route(
+            "/paid",
+            post(move |headers: HeaderMap, body: Bytes| {
+                let sender = sender.clone();
+                let attempts = attempts.clone();
+                async move {
+                    if attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                        return StatusCode::INTERNAL_SERVER_ERROR;
+                    }
+                    let signature = headers[SIGNATURE_HEADER].to_str().unwrap().to_owned();
+                    sender.send((signature, body)).unwrap();
+                    StatusCode::OK
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/paid", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, receiver).await });
+
+        let mock = Arc::new(MockLightning::new());
+        mock.set_default_incoming(MockIncomingPayment::PaidAfter(Duration::ZERO));
+        let invoice = mock.lightning_invoice(msats(1000), "order").await.unwrap();
+        let payment_hash = PaymentHash::from(invoice.payment_hash());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("webhooks.json");
+        let webhooks =
+            Arc::new(Webhooks::load(path.clone(), "secret".to_string(), Arc::default()).unwrap());
+        webhooks
+            .register(
+                payment_hash,
+                url,
+                msats(1000),
+                serde_json::json!({ "order": 1 }),
+            )
+            .await
+            .unwrap();
+        tokio::spawn(webhooks.clone().run(mock.clone()));
+        // Claims the payment
+        mock.invoice_status(payment_hash).await.unwrap();
+
+        let (signature, body) = received.recv().await.unwrap();
+        assert_eq!(signature, sign("secret", &body));
+        let payload: WebhookPayload = serde_json::from_slice(&body).unwrap();
+        assert_eq!(payload.payment_hash, payment_hash);
+        assert_eq!(payload.amount_msats, 1000);
+        assert_eq!(payload.metadata, serde_json::json!({ "order": 1 }));
+        let claimed_at = mock
+            .await_incoming_payment_details(payment_hash)
+            .await
+            .unwrap()
+            .claimed_at;
+        assert_eq!(
+            payload.paid_at,
+            claimed_at.duration_since(UNIX_EPOCH).unwrap().as_secs()
+        );
+
+        // Delivered notifications are removed from the queue
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let queue = webhooks.queue.lock().await;
+        assert!(queue.registrations.is_empty());
+        assert!(queue.deliveries.is_empty());
+    }
+}
@@ -0,0 +1,5 @@
+//! Generates the Kotlin and Swift bindings of the `uniffi` feature, see
+//! `bindings/README.md`.
+fn main() {
+    uniffi::uniffi_bindgen_main()
+}
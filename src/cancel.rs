@@ -0,0 +1,87 @@
+//! Invoices canceled using
+//! [`Blitzi::cancel_invoice`](crate::Blitzi::cancel_invoice). Lightning
+//! invoices can't be revoked and Fedimint has no way to abort a receive
+//! operation, so cancellations are recorded in the client database and applied
+//! when reporting the status of the invoice.
+use std::time::Duration;
+
+use fedimint_core::core::OperationId;
+use fedimint_core::db::{Database, IDatabaseTransactionOpsCore};
+use futures_lite::{Stream, StreamExt};
+
+/// Reason reported for invoices canceled using
+/// [`Blitzi::cancel_invoice`](crate::Blitzi::cancel_invoice).
+pub(crate) const CANCELED_BY_USER: &str = "canceled by user";
+
+/// Prefix of the canceled receive operations, keyed by operation id. In the
+/// key range Fedimint reserves for external use (`0xb1..=0xcf`).
+const CANCELED_PREFIX: &[u8] = b"\xb1blitzi/canceled/";
+
+/// How long to wait for further state updates of an operation before assuming
+/// the last one received is its current state.
+const STATE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Records the receive operation `operation_id` as canceled.
+pub(crate) async fn record(db: &Database, operation_id: OperationId) -> anyhow::Result<()> {
+    let mut dbtx = db.begin_transaction().await;
+    dbtx.raw_insert_bytes(&key(operation_id), &serde_json::to_vec(&true)?)
+        .await?;
+    dbtx.commit_tx_result().await?;
+    Ok(())
+}
+
+/// Returns `true` if the receive operation `operation_id` was canceled.
+pub(crate) async fn is_canceled(db: &Database, operation_id: OperationId) -> anyhow::Result<bool> {
+    let mut dbtx = db.begin_transaction_nc().await;
+    Ok(dbtx.raw_get_bytes(&key(operation_id)).await?.is_some())
+}
+
+/// Returns the current state of an operation from its stream of state updates,
+/// which starts with the states the operation already went through.
+pub(crate) async fn current_state<S>(mut updates: impl Stream<Item = S> + Unpin) -> Option<S> {
+    let mut state = None;
+    while let Ok(Some(update)) =
+        fedimint_core::runtime::timeout(STATE_TIMEOUT, updates.next()).await
+    {
+        state = Some(update);
+    }
+    state
+}
+
+fn key(operation_id: OperationId) -> Vec<u8> {
+    [CANCELED_PREFIX, &operation_id.0].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use fedimint_core::db::IRawDatabaseExt;
+    use fedimint_core::db::mem_impl::MemDatabase;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record_cancellation() {
+        let db = MemDatabase::new().into_database();
+        let operation_id = OperationId([1; 32]);
+
+        assert!(!is_canceled(&db, operation_id).await.unwrap());
+        record(&db, operation_id).await.unwrap();
+        assert!(is_canceled(&db, operation_id).await.unwrap());
+        assert!(!is_canceled(&db, OperationId([2; 32])).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_current_state() {
+        let updates = futures_lite::stream::iter([1, 2, 3]);
+        assert_eq!(current_state(updates).await, Some(3));
+
+        // A stream that doesn't end yields the last state before the timeout
+        let updates = futures_lite::stream::iter([1, 2]).chain(futures_lite::stream::pending());
+        assert_eq!(current_state(updates).await, Some(2));
+
+        assert_eq!(
+            current_state(futures_lite::stream::empty::<u8>()).await,
+            None
+        );
+    }
+}
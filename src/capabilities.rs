@@ -0,0 +1,198 @@
+//! Modules and settings of the joined federation returned by
+//! [`Blitzi::capabilities`](crate::Blitzi::capabilities).
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::Context;
+use fedimint_client::Client;
+use fedimint_core::Amount;
+use fedimint_core::bitcoin::Network;
+use fedimint_core::config::ClientConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::error::NoLightningModule;
+
+/// Meta field some federations use to announce the maximum balance a client
+/// should hold, in millisatoshi.
+const MAX_BALANCE_META_FIELD: &str = "max_balance_msats";
+
+/// Meta field a federation can use to override the default expiry of invoices
+/// in seconds.
+const INVOICE_EXPIRY_META_FIELD: &str = "default_invoice_expiry_secs";
+
+/// Consensus version of a federation.
+///
+/// Serialized as `{"major": 2, "minor": 1}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ConsensusVersion {
+    /// Incremented for incompatible changes
+    pub major: u32,
+    /// Incremented for backwards compatible changes
+    pub minor: u32,
+}
+
+impl fmt::Display for ConsensusVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// What the joined federation supports, e.g. to refuse running a mainnet UI
+/// against a signet federation.
+///
+/// Serialized with the maximum balance as `max_balance_msats`, the network as
+/// its lowercase name (`"bitcoin"`, `"signet"`, `"regtest"`, ...) and the
+/// module versions as an object keyed by module kind.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FederationCapabilities {
+    /// Kinds of the modules the federation runs (e.g. `mint`, `ln`, `lnv2`,
+    /// `wallet` and `meta`), sorted and without duplicates
+    pub modules: Vec<String>,
+    /// Consensus version of the federation
+    pub consensus_version: ConsensusVersion,
+    /// Consensus version of each module kind the federation runs. If it runs
+    /// several modules of a kind, the highest version is listed.
+    pub module_versions: BTreeMap<String, ConsensusVersion>,
+    /// Maximum balance a client should hold, if the federation announces one
+    /// in its meta data
+    #[serde(rename = "max_balance_msats")]
+    pub max_balance: Option<Amount>,
+    /// Bitcoin network the federation operates on
+    pub network: Network,
+}
+
+impl FederationCapabilities {
+    /// Returns `true` if the federation runs a module of the given kind, e.g.
+    /// `"lnv2"`.
+    pub fn supports(&self, module_kind: &str) -> bool {
+        self.modules.iter().any(|kind| kind == module_kind)
+    }
+
+    /// Reads the capabilities from the federation's client config. The meta
+    /// data isn't consulted, so [`Self::max_balance`] is always `None`.
+    ///
+    /// # Errors
+    /// Returns a [`NoLightningModule`] error if the network can't be
+    /// determined because the federation runs neither Lightning module.
+    pub(crate) fn from_config(config: &ClientConfig) -> anyhow::Result<Self> {
+        let mut modules = config
+            .modules
+            .values()
+            .map(|module| module.kind.as_str().to_string())
+            .collect::<Vec<_>>();
+        modules.sort();
+        modules.dedup();
+
+        let mut module_versions = BTreeMap::new();
+        for module in config.modules.values() {
+            let version = ConsensusVersion {
+                major: module.version.major,
+                minor: module.version.minor,
+            };
+            module_versions
+                .entry(module.kind.as_str().to_string())
+                .and_modify(|highest: &mut ConsensusVersion| *highest = (*highest).max(version))
+                .or_insert(version);
+        }
+
+        let ln_network = config
+            .modules
+            .values()
+            .find_map(|module| match module.kind.as_str() {
+                "ln" => module
+                    .cast::<fedimint_ln_common::config::LightningClientConfig>()
+                    .ok()
+                    .map(|cfg| cfg.network.0),
+                crate::lnv2::KIND => module
+                    .cast::<fedimint_lnv2_common::config::LightningClientConfig>()
+                    .ok()
+                    .map(|cfg| cfg.network),
+                _ => None,
+            });
+
+        Ok(FederationCapabilities {
+            modules,
+            consensus_version: ConsensusVersion {
+                major: config.global.consensus_version.major,
+                minor: config.global.consensus_version.minor,
+            },
+            module_versions,
+            max_balance: None,
+            network: ln_network.ok_or(NoLightningModule)?,
+        })
+    }
+}
+
+/// Returns the capabilities of the federation `client` joined, including the
+/// maximum balance announced in its meta data.
+pub(crate) async fn capabilities(client: &Client) -> anyhow::Result<FederationCapabilities> {
+    let mut capabilities = FederationCapabilities::from_config(&client.config().await)
+        .context("Failed to read the federation config")?;
+    capabilities.max_balance = announced_max_balance(client).await;
+    Ok(capabilities)
+}
+
+/// Returns the maximum balance the federation `client` joined announces in its
+/// meta data, if any.
+pub(crate) async fn announced_max_balance(client: &Client) -> Option<Amount> {
+    client
+        .meta_service()
+        .get_field::<u64>(client.db(), MAX_BALANCE_META_FIELD)
+        .await
+        .and_then(|field| field.value)
+        .map(Amount::from_msats)
+}
+
+/// Returns the default invoice expiry the federation `client` joined announces
+/// in its meta data, if it announces a positive one.
+pub(crate) async fn announced_invoice_expiry(client: &Client) -> Option<Duration> {
+    client
+        .meta_service()
+        .get_field::<u64>(client.db(), INVOICE_EXPIRY_META_FIELD)
+        .await
+        .and_then(|field| field.value)
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities_serde() {
+        let capabilities = FederationCapabilities {
+            modules: vec!["ln".to_string(), "mint".to_string()],
+            consensus_version: ConsensusVersion { major: 2, minor: 1 },
+            module_versions: BTreeMap::from([
+                ("ln".to_string(), ConsensusVersion { major: 0, minor: 0 }),
+                ("mint".to_string(), ConsensusVersion { major: 0, minor: 1 }),
+            ]),
+            max_balance: Some(Amount::from_msats(1_000_000)),
+            network: Network::Signet,
+        };
+        assert!(capabilities.supports("mint"));
+        assert!(!capabilities.supports("lnv2"));
+
+        let json = serde_json::to_value(&capabilities).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "modules": ["ln", "mint"],
+                "consensus_version": { "major": 2, "minor": 1 },
+                "module_versions": {
+                    "ln": { "major": 0, "minor": 0 },
+                    "mint": { "major": 0, "minor": 1 },
+                },
+                "max_balance_msats": 1_000_000,
+                "network": "signet",
+            })
+        );
+        assert_eq!(
+            serde_json::from_value::<FederationCapabilities>(json).unwrap(),
+            capabilities
+        );
+        assert_eq!(capabilities.consensus_version.to_string(), "2.1");
+    }
+}
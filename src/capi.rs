@@ -0,0 +1,443 @@
+//! C interface for embedding Blitzi in programs written in other languages,
+//! enabled by the `capi` feature. See `capi/README.md` for building the shared
+//! library and generating its header `capi/blitzi.h`.
+//!
+//! Every function returns a [`BlitziStatus`], outputs are written to the
+//! `out_*` pointers only on success. Panics are caught and returned as
+//! [`BlitziStatus::Panicked`] instead of unwinding into the caller. The
+//! message of the last error on the calling thread is returned by
+//! [`blitzi_last_error_message`]. Strings returned by Blitzi are owned by the
+//! caller and have to be released using [`blitzi_string_free`].
+//!
+//! A [`BlitziHandle`] owns a Tokio runtime that runs the client's background
+//! tasks. Its functions block the calling thread until they complete and may
+//! be called from several threads at once, but not from within an async
+//! runtime.
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char};
+use std::panic::AssertUnwindSafe;
+use std::ptr;
+use std::str::FromStr;
+
+use anyhow::{Context, anyhow};
+
+use crate::error::{
+    AlreadyPaid, BalanceCapExceeded, DatadirLocked, DescriptionTooLong, GatewayUnavailable,
+    InvalidDescription, InvalidInvoiceError, InvoiceAmountError, PassphraseRequired, PolicyDenied,
+    SpendLimitExceeded, WrongPassphrase,
+};
+#[cfg(feature = "test-util")]
+use crate::mock::MockLightning;
+use crate::{Blitzi, Bolt11Invoice, LightningBackend, msats};
+
+#[cfg(not(any(feature = "native", feature = "db-redb")))]
+compile_error!("The `capi` feature requires the `native` or `db-redb` feature");
+
+/// Result of a call to the C interface.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlitziStatus {
+    /// The call succeeded
+    Ok = 0,
+    /// A required pointer was null or a string wasn't valid UTF-8
+    InvalidArgument = 1,
+    /// The invoice couldn't be parsed or paid, e.g. because it expired
+    InvalidInvoice = 2,
+    /// The invoice amount is zero or too large
+    InvalidAmount = 3,
+    /// The invoice description is too long or contains control characters
+    InvalidDescription = 4,
+    /// No Lightning gateway is available
+    GatewayUnavailable = 5,
+    /// Receiving the amount would exceed the configured balance cap
+    BalanceCapExceeded = 6,
+    /// The payment would exceed the configured spend limit
+    SpendLimitExceeded = 7,
+    /// The configured payment policy rejected the payment
+    PolicyDenied = 8,
+    /// The invoice was already paid
+    AlreadyPaid = 9,
+    /// The data directory is in use by another process
+    DatadirLocked = 10,
+    /// The wallet's seed is encrypted and no passphrase was given
+    PassphraseRequired = 11,
+    /// The passphrase doesn't decrypt the wallet's seed
+    WrongPassphrase = 12,
+    /// Blitzi panicked, see [`blitzi_last_error_message`]. The handle may be in
+    /// an inconsistent state and should be freed.
+    Panicked = 13,
+    /// Any other error, see [`blitzi_last_error_message`]
+    Other = 99,
+}
+
+impl BlitziStatus {
+    fn of(error: &anyhow::Error) -> Self {
+        if error.is::<InvalidInvoiceError>() {
+            BlitziStatus::InvalidInvoice
+        } else if error.is::<InvoiceAmountError>() {
+            BlitziStatus::InvalidAmount
+        } else if error.is::<DescriptionTooLong>() || error.is::<InvalidDescription>() {
+            BlitziStatus::InvalidDescription
+        } else if error.is::<GatewayUnavailable>() {
+            BlitziStatus::GatewayUnavailable
+        } else if error.is::<BalanceCapExceeded>() {
+            BlitziStatus::BalanceCapExceeded
+        } else if error.is::<SpendLimitExceeded>() {
+            BlitziStatus::SpendLimitExceeded
+        } else if error.is::<PolicyDenied>() {
+            BlitziStatus::PolicyDenied
+        } else if error.is::<AlreadyPaid>() {
+            BlitziStatus::AlreadyPaid
+        } else if error.is::<DatadirLocked>() {
+            BlitziStatus::DatadirLocked
+        } else if error.is::<PassphraseRequired>() {
+            BlitziStatus::PassphraseRequired
+        } else if error.is::<WrongPassphrase>() {
+            BlitziStatus::WrongPassphrase
+        } else {
+            BlitziStatus::Other
+        }
+    }
+}
+
+/// Error that is returned as [`BlitziStatus::InvalidArgument`].
+#[derive(Debug)]
+struct InvalidArgument(&'static str);
+
+impl std::fmt::Display for InvalidArgument {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid argument: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidArgument {}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Opaque handle of a Blitzi client created using [`blitzi_new`].
+pub struct BlitziHandle {
+    runtime: tokio::runtime::Runtime,
+    backend: Backend,
+}
+
+enum Backend {
+    Blitzi(Blitzi),
+    #[cfg(feature = "test-util")]
+    Mock(MockLightning),
+}
+
+/// Calls `f` with the [`LightningBackend`] of `handle` within its runtime.
+macro_rules! with_backend {
+    ($handle:expr, |$backend:ident| $body:expr) => {
+        match &$handle.backend {
+            Backend::Blitzi($backend) => $handle.runtime.block_on($body),
+            #[cfg(feature = "test-util")]
+            Backend::Mock($backend) => $handle.runtime.block_on($body),
+        }
+    };
+}
+
+/// Opens the wallet in `datadir`, joining the federation with the invite code
+/// `invite` (or the default federation if `invite` is null) if the directory
+/// doesn't contain one yet, and writes its handle to `out_handle`.
+///
+/// # Safety
+/// `datadir` and `invite` (if not null) must be valid NUL-terminated strings
+/// and `out_handle` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn blitzi_new(
+    datadir: *const c_char,
+    invite: *const c_char,
+    out_handle: *mut *mut BlitziHandle,
+) -> BlitziStatus {
+    ffi_call(|| {
+        let datadir = unsafe { str_arg(datadir, "datadir") }?;
+        let invite = unsafe { opt_str_arg(invite, "invite") }?;
+        let out_handle = unsafe { out_arg(out_handle, "out_handle") }?;
+
+        let runtime = runtime()?;
+        let blitzi = runtime.block_on(async {
+            let mut builder = Blitzi::builder().datadir(datadir);
+            if let Some(invite) = invite {
+                builder = builder.federation(invite)?;
+            }
+            builder.build().await
+        })?;
+        *out_handle = Box::into_raw(Box::new(BlitziHandle {
+            runtime,
+            backend: Backend::Blitzi(blitzi),
+        }));
+        Ok(())
+    })
+}
+
+/// Creates a handle backed by an in-memory mock instead of a federation,
+/// whose invoices are paid right away, for testing programs using the C
+/// interface. Requires the `test-util` feature.
+///
+/// # Safety
+/// `out_handle` must be valid for writes.
+#[cfg(feature = "test-util")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn blitzi_new_mock(out_handle: *mut *mut BlitziHandle) -> BlitziStatus {
+    ffi_call(|| {
+        let out_handle = unsafe { out_arg(out_handle, "out_handle") }?;
+        let mock = MockLightning::new();
+        mock.set_default_incoming(crate::MockIncomingPayment::PaidAfter(
+            std::time::Duration::ZERO,
+        ));
+        *out_handle = Box::into_raw(Box::new(BlitziHandle {
+            runtime: runtime()?,
+            backend: Backend::Mock(mock),
+        }));
+        Ok(())
+    })
+}
+
+/// Creates a Lightning invoice for `amount_msats` and writes it, BOLT11
+/// encoded, to `out_bolt11`.
+///
+/// # Safety
+/// `handle` must have been returned by [`blitzi_new`] and not been freed,
+/// `description` must be a valid NUL-terminated string and `out_bolt11` must
+/// be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn blitzi_invoice(
+    handle: *const BlitziHandle,
+    amount_msats: u64,
+    description: *const c_char,
+    out_bolt11: *mut *mut c_char,
+) -> BlitziStatus {
+    ffi_call(|| {
+        let handle = unsafe { handle_arg(handle) }?;
+        let description = unsafe { str_arg(description, "description") }?;
+        let out_bolt11 = unsafe { out_arg(out_bolt11, "out_bolt11") }?;
+
+        let invoice = with_backend!(handle, |backend| backend
+            .lightning_invoice(msats(amount_msats), description))?;
+        *out_bolt11 = into_c_string(invoice.to_string());
+        Ok(())
+    })
+}
+
+/// Pays the BOLT11 invoice `bolt11` and writes the hex encoded preimage to
+/// `out_preimage_hex`.
+///
+/// # Safety
+/// `handle` must have been returned by [`blitzi_new`] and not been freed,
+/// `bolt11` must be a valid NUL-terminated string and `out_preimage_hex` must
+/// be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn blitzi_pay(
+    handle: *const BlitziHandle,
+    bolt11: *const c_char,
+    out_preimage_hex: *mut *mut c_char,
+) -> BlitziStatus {
+    ffi_call(|| {
+        let handle = unsafe { handle_arg(handle) }?;
+        let bolt11 = unsafe { str_arg(bolt11, "bolt11") }?;
+        let out_preimage_hex = unsafe { out_arg(out_preimage_hex, "out_preimage_hex") }?;
+
+        let invoice = Bolt11Invoice::from_str(bolt11.trim())
+            .map_err(|e| anyhow!("Invalid invoice: {}", e))?;
+        let preimage = with_backend!(handle, |backend| backend.pay(&invoice))?;
+        *out_preimage_hex = into_c_string(preimage.to_string());
+        Ok(())
+    })
+}
+
+/// Writes the balance in millisatoshi to `out_msats`.
+///
+/// # Safety
+/// `handle` must have been returned by [`blitzi_new`] and not been freed and
+/// `out_msats` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn blitzi_balance(
+    handle: *const BlitziHandle,
+    out_msats: *mut u64,
+) -> BlitziStatus {
+    ffi_call(|| {
+        let handle = unsafe { handle_arg(handle) }?;
+        let out_msats = unsafe { out_arg(out_msats, "out_msats") }?;
+
+        *out_msats = with_backend!(handle, |backend| backend.balance()).msats;
+        Ok(())
+    })
+}
+
+/// Shuts down the client and frees `handle`. Does nothing if `handle` is
+/// null.
+///
+/// # Safety
+/// `handle` must have been returned by [`blitzi_new`], must not be used by
+/// other threads anymore and must not be used after this call.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn blitzi_free(handle: *mut BlitziHandle) {
+    if handle.is_null() {
+        return;
+    }
+    let handle = unsafe { Box::from_raw(handle) };
+    let shutdown = std::panic::catch_unwind(AssertUnwindSafe(|| match handle.backend {
+        Backend::Blitzi(blitzi) => {
+            if let Err(e) = handle.runtime.block_on(blitzi.shutdown()) {
+                tracing::warn!(error = %e, "Failed to shut down the client");
+            }
+        }
+        #[cfg(feature = "test-util")]
+        Backend::Mock(_) => {}
+    }));
+    if let Err(panic) = shutdown {
+        tracing::warn!(
+            panic = panic_message(panic.as_ref()),
+            "Panicked while shutting down the client"
+        );
+    }
+}
+
+/// Frees a string returned by Blitzi. Does nothing if `s` is null.
+///
+/// # Safety
+/// `s` must have been returned by Blitzi and must not be used after this
+/// call.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn blitzi_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(unsafe { CString::from_raw(s) });
+    }
+}
+
+/// Returns the message of the last error returned on the calling thread, or
+/// null if there was none. The message is owned by Blitzi and stays valid
+/// until the next call on the same thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn blitzi_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Runs `f`, recording its error as the last error of the calling thread. A
+/// panic is caught, as unwinding into the caller would abort the process.
+fn ffi_call(f: impl FnOnce() -> anyhow::Result<()>) -> BlitziStatus {
+    let (status, message) = match std::panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => (BlitziStatus::Ok, None),
+        Ok(Err(e)) if e.is::<InvalidArgument>() => {
+            (BlitziStatus::InvalidArgument, Some(format!("{:#}", e)))
+        }
+        Ok(Err(e)) => (BlitziStatus::of(&e), Some(format!("{:#}", e))),
+        Err(panic) => (
+            BlitziStatus::Panicked,
+            Some(format!(
+                "Blitzi panicked: {}",
+                panic_message(panic.as_ref())
+            )),
+        ),
+    };
+    LAST_ERROR.with(|last| {
+        *last.borrow_mut() = message.map(into_c_string_lossy);
+    });
+    status
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown cause"
+    }
+}
+
+fn runtime() -> anyhow::Result<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .thread_name("blitzi")
+        .build()
+        .context("Failed to start the Tokio runtime")
+}
+
+unsafe fn handle_arg<'a>(handle: *const BlitziHandle) -> anyhow::Result<&'a BlitziHandle> {
+    Ok(unsafe { handle.as_ref() }.ok_or(InvalidArgument("handle is null"))?)
+}
+
+unsafe fn str_arg<'a>(s: *const c_char, name: &'static str) -> anyhow::Result<&'a str> {
+    Ok(unsafe { opt_str_arg(s, name) }?.ok_or(InvalidArgument(name))?)
+}
+
+unsafe fn opt_str_arg<'a>(s: *const c_char, name: &'static str) -> anyhow::Result<Option<&'a str>> {
+    if s.is_null() {
+        return Ok(None);
+    }
+    let s = unsafe { CStr::from_ptr(s) };
+    Ok(Some(s.to_str().map_err(|_| InvalidArgument(name))?))
+}
+
+unsafe fn out_arg<'a, T>(out: *mut T, name: &'static str) -> anyhow::Result<&'a mut T> {
+    Ok(unsafe { out.as_mut() }.ok_or(InvalidArgument(name))?)
+}
+
+fn into_c_string(s: String) -> *mut c_char {
+    // Invoices and hex strings never contain NUL bytes
+    CString::new(s)
+        .expect("no NUL bytes in the string")
+        .into_raw()
+}
+
+fn into_c_string_lossy(s: String) -> CString {
+    CString::new(s.replace('\0', "")).expect("NUL bytes were removed")
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_handle() {
+        let mut handle = ptr::null_mut();
+        assert_eq!(unsafe { blitzi_new_mock(&mut handle) }, BlitziStatus::Ok);
+
+        let mut bolt11 = ptr::null_mut();
+        let description = CString::new("test").unwrap();
+        let status = unsafe { blitzi_invoice(handle, 1_000, description.as_ptr(), &mut bolt11) };
+        assert_eq!(status, BlitziStatus::Ok);
+        let invoice = unsafe { CStr::from_ptr(bolt11) }.to_str().unwrap();
+        assert_eq!(
+            Bolt11Invoice::from_str(invoice)
+                .unwrap()
+                .amount_milli_satoshis(),
+            Some(1_000)
+        );
+        unsafe { blitzi_string_free(bolt11) };
+
+        let status = unsafe { blitzi_invoice(handle, 0, description.as_ptr(), &mut bolt11) };
+        assert_eq!(status, BlitziStatus::InvalidAmount);
+        let message = unsafe { CStr::from_ptr(blitzi_last_error_message()) };
+        assert!(!message.to_str().unwrap().is_empty());
+
+        let status = unsafe { blitzi_invoice(handle, 1_000, ptr::null(), &mut bolt11) };
+        assert_eq!(status, BlitziStatus::InvalidArgument);
+
+        let mut balance = 0;
+        assert_eq!(
+            unsafe { blitzi_balance(handle, &mut balance) },
+            BlitziStatus::Ok
+        );
+        unsafe { blitzi_free(handle) };
+    }
+
+    #[test]
+    fn test_panic_is_caught() {
+        let status = ffi_call(|| panic!("boom"));
+        assert_eq!(status, BlitziStatus::Panicked);
+        let message = unsafe { CStr::from_ptr(blitzi_last_error_message()) };
+        assert_eq!(message.to_str().unwrap(), "Blitzi panicked: boom");
+
+        assert_eq!(ffi_call(|| Ok(())), BlitziStatus::Ok);
+        assert!(blitzi_last_error_message().is_null());
+    }
+}
@@ -0,0 +1,48 @@
+//! Checking whether the federation's config changed, see
+//! [`Blitzi::check_federation_config`](crate::Blitzi::check_federation_config).
+use anyhow::{Context, anyhow};
+use fedimint_client::Client;
+use fedimint_core::config::ClientConfig;
+use fedimint_core::encoding::Encodable;
+use tracing::debug;
+
+use crate::FederationIdMismatch;
+
+/// Downloads the latest config of the federation `client` joined, trying its
+/// guardians one after another until one responds.
+///
+/// # Errors
+/// Returns the error of the last guardian if none of them responds, and a
+/// [`FederationIdMismatch`] error if a guardian serves the config of another
+/// federation.
+pub(crate) async fn fetch_latest_config(client: &Client) -> anyhow::Result<ClientConfig> {
+    let current = client.config().await;
+    let expected = current.global.calculate_federation_id();
+
+    let mut last_error = anyhow!("The federation config doesn't list any guardian");
+    for peer in current.global.api_endpoints.keys().copied() {
+        let Some(invite) = client.invite_code(peer).await else {
+            continue;
+        };
+        match crate::client_builder().await?.preview(&invite).await {
+            Ok(preview) => {
+                let latest = preview.config().clone();
+                let got = latest.global.calculate_federation_id();
+                if got != expected {
+                    return Err(FederationIdMismatch { expected, got }.into());
+                }
+                return Ok(latest);
+            }
+            Err(e) => {
+                debug!(%peer, error = %e, "Failed to fetch the federation config");
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error).context("Failed to fetch the federation config from any guardian")
+}
+
+/// Returns `true` if `latest` differs from the `current` config.
+pub(crate) fn has_changed(current: &ClientConfig, latest: &ClientConfig) -> bool {
+    current.consensus_encode_to_vec() != latest.consensus_encode_to_vec()
+}
@@ -0,0 +1,310 @@
+//! Databases stored in a data directory, see
+//! [`BlitziBuilder::database_backend`](crate::BlitziBuilder::database_backend).
+//!
+//! RocksDB stores its files directly in the data directory, while redb stores
+//! a single file named [`REDB_FILE`] in it. The backend a directory uses is
+//! detected from these files, so a directory is never opened with the wrong
+//! backend, which would look like an empty wallet.
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{Context, ensure};
+use fedimint_core::db::{Database, IDatabaseTransactionOpsCore};
+use futures_lite::StreamExt;
+
+use crate::error::DatabaseBackendMismatch;
+
+/// File RocksDB always creates in its directory.
+const ROCKSDB_MARKER: &str = "CURRENT";
+
+/// Name of the redb database file within the data directory.
+const REDB_FILE: &str = "blitzi.redb";
+
+/// Database used to store the wallet in its data directory, see
+/// [`BlitziBuilder::database_backend`](crate::BlitziBuilder::database_backend).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DatabaseBackend {
+    /// RocksDB, requires the `native` feature. The default if it's enabled.
+    RocksDb,
+    /// [redb](https://www.redb.org), an embedded database written in pure
+    /// Rust, which is faster to build and easier to cross-compile than
+    /// RocksDB. Requires the `db-redb` feature, the default if the `native`
+    /// feature is disabled.
+    Redb,
+}
+
+impl Default for DatabaseBackend {
+    fn default() -> Self {
+        if cfg!(feature = "native") {
+            DatabaseBackend::RocksDb
+        } else {
+            DatabaseBackend::Redb
+        }
+    }
+}
+
+impl fmt::Display for DatabaseBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatabaseBackend::RocksDb => write!(f, "RocksDB"),
+            DatabaseBackend::Redb => write!(f, "redb"),
+        }
+    }
+}
+
+/// Returns the backend of the database in `datadir`, `None` if it doesn't
+/// contain one.
+pub(crate) fn detect(datadir: &Path) -> Option<DatabaseBackend> {
+    if datadir.join(REDB_FILE).exists() {
+        Some(DatabaseBackend::Redb)
+    } else if datadir.join(ROCKSDB_MARKER).exists() {
+        Some(DatabaseBackend::RocksDb)
+    } else {
+        None
+    }
+}
+
+/// Opens the database in `datadir` using `backend`, creating it if the
+/// directory doesn't contain one yet.
+///
+/// # Errors
+/// Returns a [`DatabaseBackendMismatch`] error if `datadir` contains a
+/// database of a different backend.
+pub(crate) async fn open(datadir: &Path, backend: DatabaseBackend) -> anyhow::Result<Database> {
+    if let Some(found) = detect(datadir)
+        && found != backend
+    {
+        return Err(DatabaseBackendMismatch {
+            path: datadir.to_owned(),
+            expected: backend,
+            found,
+        }
+        .into());
+    }
+
+    match backend {
+        DatabaseBackend::RocksDb => open_rocksdb(datadir).await,
+        DatabaseBackend::Redb => open_redb(datadir).await,
+    }
+}
+
+#[cfg(feature = "native")]
+async fn open_rocksdb(datadir: &Path) -> anyhow::Result<Database> {
+    use fedimint_core::db::IRawDatabaseExt;
+
+    Ok(fedimint_rocksdb::RocksDb::open(datadir)
+        .await?
+        .into_database())
+}
+
+#[cfg(not(feature = "native"))]
+async fn open_rocksdb(_datadir: &Path) -> anyhow::Result<Database> {
+    Err(anyhow::anyhow!(
+        "The RocksDB database backend requires the `native` feature"
+    ))
+}
+
+#[cfg(feature = "db-redb")]
+async fn open_redb(datadir: &Path) -> anyhow::Result<Database> {
+    use fedimint_core::db::IRawDatabaseExt;
+
+    std::fs::create_dir_all(datadir)
+        .with_context(|| format!("Failed to create data directory {}", datadir.display()))?;
+    Ok(
+        fedimint_cursed_redb::MemAndRedb::new(datadir.join(REDB_FILE))
+            .await?
+            .into_database(),
+    )
+}
+
+#[cfg(not(feature = "db-redb"))]
+async fn open_redb(_datadir: &Path) -> anyhow::Result<Database> {
+    Err(anyhow::anyhow!(
+        "The redb database backend requires the `db-redb` feature"
+    ))
+}
+
+/// Copies the wallet database in `from_dir` to a new database in `to_dir`
+/// using `backend`, e.g. to switch an existing wallet from RocksDB to redb.
+/// The backend of the source database is detected automatically. Returns the
+/// number of key/value pairs copied.
+///
+/// Neither database may be in use while migrating. The source database is left
+/// untouched, delete it once the wallet was opened successfully from `to_dir`
+/// (using [`BlitziBuilder::database_backend`](crate::BlitziBuilder::database_backend)
+/// with `backend`).
+///
+/// # Errors
+/// Returns an error if `from_dir` doesn't contain a database, if `to_dir`
+/// already contains one, or if either can't be opened.
+pub async fn migrate_database(
+    from_dir: impl AsRef<Path>,
+    to_dir: impl AsRef<Path>,
+    backend: DatabaseBackend,
+) -> anyhow::Result<u64> {
+    let (from_dir, to_dir) = (from_dir.as_ref(), to_dir.as_ref());
+    let from_backend = detect(from_dir)
+        .with_context(|| format!("{} doesn't contain a database", from_dir.display()))?;
+    ensure!(
+        detect(to_dir).is_none(),
+        "{} already contains a database",
+        to_dir.display()
+    );
+
+    let from = open(from_dir, from_backend).await?;
+    let to = open(to_dir, backend).await?;
+    copy(&from, &to).await
+}
+
+/// Copies all key/value pairs from `from` to `to` and returns their number.
+async fn copy(from: &Database, to: &Database) -> anyhow::Result<u64> {
+    let mut copied = 0;
+    // Copied one key prefix at a time to keep transactions small
+    for prefix in 0..=u8::MAX {
+        let entries = {
+            let mut dbtx = from.begin_transaction_nc().await;
+            dbtx.raw_find_by_prefix(&[prefix])
+                .await?
+                .collect::<Vec<_>>()
+                .await
+        };
+        if entries.is_empty() {
+            continue;
+        }
+
+        let mut dbtx = to.begin_transaction().await;
+        for (key, value) in &entries {
+            dbtx.raw_insert_bytes(key, value).await?;
+        }
+        dbtx.commit_tx_result().await?;
+        copied += entries.len() as u64;
+    }
+    Ok(copied)
+}
+
+/// Returns all keys of `db`, to later remove the ones added since using
+/// [`remove_keys_except`].
+pub(crate) async fn keys(db: &Database) -> anyhow::Result<BTreeSet<Vec<u8>>> {
+    let mut keys = BTreeSet::new();
+    // Read one key prefix at a time to keep transactions small
+    for prefix in 0..=u8::MAX {
+        let mut dbtx = db.begin_transaction_nc().await;
+        let entries = dbtx
+            .raw_find_by_prefix(&[prefix])
+            .await?
+            .collect::<Vec<_>>()
+            .await;
+        keys.extend(entries.into_iter().map(|(key, _)| key));
+    }
+    Ok(keys)
+}
+
+/// Removes all keys from `db` that aren't in `keep`, e.g. the partial state
+/// of a join that didn't finish. Keys in `keep` are left untouched, so data
+/// that was in the database before, e.g. of the application that provided it,
+/// is never removed.
+pub(crate) async fn remove_keys_except(
+    db: &Database,
+    keep: &BTreeSet<Vec<u8>>,
+) -> anyhow::Result<()> {
+    for key in keys(db).await?.difference(keep) {
+        let mut dbtx = db.begin_transaction().await;
+        dbtx.raw_remove_entry(key).await?;
+        dbtx.commit_tx_result().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use fedimint_core::db::IRawDatabaseExt;
+    use fedimint_core::db::mem_impl::MemDatabase;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_copy() {
+        let from = MemDatabase::new().into_database();
+        let entries = [
+            (vec![0x00], vec![1]),
+            (vec![0x2f, 1, 2], vec![]),
+            (b"\xb1blitzi/key".to_vec(), b"value".to_vec()),
+            (vec![0xff, 0xff], vec![2; 100]),
+        ];
+        let mut dbtx = from.begin_transaction().await;
+        for (key, value) in &entries {
+            dbtx.raw_insert_bytes(key, value).await.unwrap();
+        }
+        dbtx.commit_tx_result().await.unwrap();
+
+        let to = MemDatabase::new().into_database();
+        assert_eq!(copy(&from, &to).await.unwrap(), entries.len() as u64);
+
+        let mut dbtx = to.begin_transaction_nc().await;
+        for (key, value) in &entries {
+            assert_eq!(dbtx.raw_get_bytes(key).await.unwrap().as_ref(), Some(value));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remove_keys_except() {
+        let db = MemDatabase::new().into_database();
+        let insert = |keys: Vec<Vec<u8>>| {
+            let db = db.clone();
+            async move {
+                let mut dbtx = db.begin_transaction().await;
+                for key in keys {
+                    dbtx.raw_insert_bytes(&key, &[1]).await.unwrap();
+                }
+                dbtx.commit_tx_result().await.unwrap();
+            }
+        };
+        let existing = vec![vec![0x00], b"app/key".to_vec()];
+        insert(existing.clone()).await;
+        let keep = keys(&db).await.unwrap();
+
+        insert(vec![
+            vec![0x00, 1],
+            vec![0x2f, 1],
+            b"\xb1blitzi/key".to_vec(),
+            vec![0xff],
+        ])
+        .await;
+        remove_keys_except(&db, &keep).await.unwrap();
+        assert_eq!(keys(&db).await.unwrap(), existing.into_iter().collect());
+    }
+
+    #[test]
+    fn test_detect() {
+        let dir = tempfile::tempdir().unwrap();
+        let datadir = dir.path();
+        assert_eq!(detect(datadir), None);
+
+        std::fs::write(datadir.join(ROCKSDB_MARKER), "MANIFEST-000001\n").unwrap();
+        assert_eq!(detect(datadir), Some(DatabaseBackend::RocksDb));
+
+        std::fs::remove_file(datadir.join(ROCKSDB_MARKER)).unwrap();
+        std::fs::write(datadir.join(REDB_FILE), []).unwrap();
+        assert_eq!(detect(datadir), Some(DatabaseBackend::Redb));
+    }
+
+    #[tokio::test]
+    async fn test_open_wrong_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let datadir = dir.path();
+        std::fs::write(datadir.join(ROCKSDB_MARKER), "MANIFEST-000001\n").unwrap();
+
+        let error = open(datadir, DatabaseBackend::Redb).await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<DatabaseBackendMismatch>(),
+            Some(&DatabaseBackendMismatch {
+                path: datadir.to_path_buf(),
+                expected: DatabaseBackend::Redb,
+                found: DatabaseBackend::RocksDb,
+            })
+        );
+        // Nothing was created next to the RocksDB files
+        assert!(!datadir.join(REDB_FILE).exists());
+    }
+}
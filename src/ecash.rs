@@ -0,0 +1,103 @@
+//! Ecash notes spent by
+//! [`Blitzi::spend_ecash_with_timeout`](crate::Blitzi::spend_ecash_with_timeout)
+//! and transferred using
+//! [`Blitzi::transfer_ecash`](crate::Blitzi::transfer_ecash).
+use std::time::SystemTime;
+
+use fedimint_core::Amount;
+use fedimint_core::core::OperationId;
+use serde::{Deserialize, Serialize};
+
+use crate::serde_util::{operation_id_hex, unix_secs};
+
+/// Ecash notes taken out of the wallet to be handed to someone else, e.g. as
+/// a string in a chat message. The recipient can redeem them with any Fedimint
+/// client, the notes contain the invite code of the federation.
+///
+/// Serialized with the operation id as hex and the amount as `amount_msats`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpentEcash {
+    /// Id of the spend operation, pass it to
+    /// [`Blitzi::reclaim_ecash`](crate::Blitzi::reclaim_ecash) to reclaim the
+    /// notes
+    #[serde(with = "operation_id_hex")]
+    pub operation_id: OperationId,
+    /// Total amount of the notes, equals the requested amount unless change
+    /// couldn't be made, in which case it slightly exceeds it
+    #[serde(rename = "amount_msats")]
+    pub amount: Amount,
+    /// The notes encoded as a string
+    pub notes: String,
+}
+
+/// Ecash transferred to another Blitzi client of the same federation, created
+/// by [`Blitzi::transfer_ecash`](crate::Blitzi::transfer_ecash). The
+/// recipient redeems it using
+/// [`Blitzi::redeem_transfer`](crate::Blitzi::redeem_transfer) before it
+/// expires, afterwards the sender gets the funds back.
+///
+/// Anyone holding the token can redeem it, so only pass it over channels you
+/// trust. Serialized with the operation id as hex, the amount as
+/// `amount_msats` and `expires_at` as unix seconds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferToken {
+    /// Id of the sender's spend operation
+    #[serde(with = "operation_id_hex")]
+    pub operation_id: OperationId,
+    /// Amount transferred, may slightly exceed the requested amount if it
+    /// can't be represented exactly by the notes held
+    #[serde(rename = "amount_msats")]
+    pub amount: Amount,
+    /// Time after which the token can't be redeemed anymore and the sender
+    /// reclaims the funds
+    #[serde(with = "unix_secs")]
+    pub expires_at: SystemTime,
+    /// The ecash notes encoded as a string
+    pub notes: String,
+}
+
+impl TransferToken {
+    /// Returns whether the token expired.
+    pub fn is_expired(&self) -> bool {
+        fedimint_core::time::now() >= self.expires_at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    #[test]
+    fn test_transfer_token() {
+        let token = TransferToken {
+            operation_id: OperationId([1; 32]),
+            amount: Amount::from_msats(1000),
+            expires_at: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            notes: "notes".to_string(),
+        };
+        assert!(token.is_expired());
+
+        let json = serde_json::to_value(&token).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "operation_id": "01".repeat(32),
+                "amount_msats": 1000,
+                "expires_at": 1_700_000_000,
+                "notes": "notes",
+            })
+        );
+        assert_eq!(
+            serde_json::from_value::<TransferToken>(json).unwrap(),
+            token
+        );
+
+        let token = TransferToken {
+            expires_at: fedimint_core::time::now() + Duration::from_secs(60),
+            ..token
+        };
+        assert!(!token.is_expired());
+    }
+}
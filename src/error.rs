@@ -0,0 +1,834 @@
+//! Typed errors returned by Blitzi for conditions callers may want to handle
+//! specifically. They are returned wrapped in [`anyhow::Error`] and can be
+//! recovered using [`anyhow::Error::downcast_ref`].
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fedimint_core::Amount;
+use fedimint_core::bitcoin::Network;
+use fedimint_core::config::FederationId;
+use fedimint_core::core::OperationId;
+use fedimint_core::secp256k1::PublicKey;
+use lightning_invoice::Currency;
+
+use crate::{DatabaseBackend, PaymentHash};
+
+/// The amount requested for an invoice is outside the accepted bounds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvoiceAmountError {
+    /// Invoices for zero millisatoshi are not supported.
+    Zero,
+    /// The amount exceeds the configured maximum invoice amount, see
+    /// [`BlitziBuilder::max_invoice_amount`](crate::BlitziBuilder::max_invoice_amount).
+    TooLarge {
+        /// The requested amount
+        amount: Amount,
+        /// The configured maximum
+        max: Amount,
+    },
+}
+
+impl fmt::Display for InvoiceAmountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvoiceAmountError::Zero => write!(f, "Invoice amount must be greater than zero"),
+            InvoiceAmountError::TooLarge { amount, max } => write!(
+                f,
+                "Invoice amount of {} msat exceeds the maximum of {} msat",
+                amount.msats, max.msats
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InvoiceAmountError {}
+
+/// An invoice description is longer than a BOLT11 invoice can hold, see
+/// [`BlitziBuilder::truncate_description`](crate::BlitziBuilder::truncate_description)
+/// to shorten such descriptions automatically instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DescriptionTooLong {
+    /// Maximum length in bytes (UTF-8)
+    pub max: usize,
+    /// Length of the rejected description in bytes (UTF-8)
+    pub got: usize,
+}
+
+impl fmt::Display for DescriptionTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invoice description is {} bytes long, the maximum is {} bytes",
+            self.got, self.max
+        )
+    }
+}
+
+impl std::error::Error for DescriptionTooLong {}
+
+/// An invoice description contains a character that isn't allowed in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidDescription {
+    /// The offending control character
+    pub character: char,
+    /// Byte offset of the character in the description
+    pub position: usize,
+}
+
+impl fmt::Display for InvalidDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invoice description contains control character {:?} at byte {}",
+            self.character, self.position
+        )
+    }
+}
+
+impl std::error::Error for InvalidDescription {}
+
+/// An invoice can't be paid by this client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidInvoiceError {
+    /// The invoice has expired.
+    Expired,
+    /// The invoice is for a different network than the federation.
+    NetworkMismatch {
+        /// Network the invoice is for
+        invoice: Currency,
+        /// Network of the federation
+        federation: Network,
+    },
+}
+
+impl fmt::Display for InvalidInvoiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidInvoiceError::Expired => write!(f, "Invoice has expired"),
+            InvalidInvoiceError::NetworkMismatch {
+                invoice,
+                federation,
+            } => write!(
+                f,
+                "Invoice is for network {:?}, but the federation uses {}",
+                invoice, federation
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InvalidInvoiceError {}
+
+/// A route hint passed to
+/// [`Blitzi::lightning_invoice_with_hints`](crate::Blitzi::lightning_invoice_with_hints)
+/// can't be embedded in an invoice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidRouteHint {
+    /// The hint contains no hops
+    Empty {
+        /// Position of the hint in the list of hints
+        index: usize,
+    },
+    /// The hint has more hops than fit into an invoice
+    TooManyHops {
+        /// Position of the hint in the list of hints
+        index: usize,
+        /// Number of hops of the hint
+        hops: usize,
+        /// Maximum number of hops per hint
+        max: usize,
+    },
+    /// A hop of the hint has a minimum HTLC amount above its maximum
+    InvalidHtlcRange {
+        /// Position of the hint in the list of hints
+        index: usize,
+    },
+}
+
+impl fmt::Display for InvalidRouteHint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidRouteHint::Empty { index } => write!(f, "Route hint {} has no hops", index),
+            InvalidRouteHint::TooManyHops { index, hops, max } => write!(
+                f,
+                "Route hint {} has {} hops, but at most {} fit into an invoice",
+                index, hops, max
+            ),
+            InvalidRouteHint::InvalidHtlcRange { index } => write!(
+                f,
+                "Route hint {} has a hop whose minimum HTLC amount exceeds its maximum",
+                index
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InvalidRouteHint {}
+
+/// No LN gateway is available to create an invoice with, or the selected
+/// gateway isn't registered with the federation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GatewayUnavailable {
+    /// The selected gateway, `None` if any gateway would have been accepted
+    pub gateway_id: Option<PublicKey>,
+}
+
+impl fmt::Display for GatewayUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.gateway_id {
+            Some(gateway_id) => write!(
+                f,
+                "LN gateway {} is not registered with the federation",
+                gateway_id
+            ),
+            None => write!(f, "No LN gateway available"),
+        }
+    }
+}
+
+impl std::error::Error for GatewayUnavailable {}
+
+/// The federation behind an invite code isn't the expected one, see
+/// [`BlitziBuilder::expect_federation_id`](crate::BlitziBuilder::expect_federation_id)
+/// and [`verify_invite`](crate::verify_invite).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FederationIdMismatch {
+    /// The expected federation
+    pub expected: FederationId,
+    /// The federation the invite code leads to
+    pub got: FederationId,
+}
+
+impl fmt::Display for FederationIdMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Expected federation {} but the invite code is for federation {}",
+            self.expected, self.got
+        )
+    }
+}
+
+impl std::error::Error for FederationIdMismatch {}
+
+/// The data directory (or provided database) holds a wallet of another
+/// federation than the one set via
+/// [`BlitziBuilder::federation`](crate::BlitziBuilder::federation).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FederationMismatch {
+    /// The federation of the existing wallet
+    pub stored: FederationId,
+    /// The federation of the invite code that was set
+    pub requested: FederationId,
+}
+
+impl fmt::Display for FederationMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The wallet in the data directory belongs to federation {} but the invite code is \
+             for federation {}, use a separate data directory per federation",
+            self.stored, self.requested
+        )
+    }
+}
+
+impl std::error::Error for FederationMismatch {}
+
+/// The federation supports neither the `ln` nor the `lnv2` Lightning module,
+/// so Blitzi can't send or receive Lightning payments through it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoLightningModule;
+
+impl fmt::Display for NoLightningModule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The federation supports neither the ln nor the lnv2 Lightning module"
+        )
+    }
+}
+
+impl std::error::Error for NoLightningModule {}
+
+/// No federation to join was set via
+/// [`BlitziBuilder::federation`](crate::BlitziBuilder::federation) and there is
+/// no default one because the `default-federation` feature is disabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoFederationConfigured;
+
+impl fmt::Display for NoFederationConfigured {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "No federation configured, set one using BlitziBuilder::federation"
+        )
+    }
+}
+
+impl std::error::Error for NoFederationConfigured {}
+
+/// The data directory is locked by another process, e.g. a second instance of
+/// blitzid or another application using the same directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatadirLocked {
+    /// The locked data directory
+    pub path: PathBuf,
+}
+
+impl fmt::Display for DatadirLocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Data directory {} is locked, is another process using it?",
+            self.path.display()
+        )
+    }
+}
+
+impl std::error::Error for DatadirLocked {}
+
+/// The data directory contains a database of a different backend than the one
+/// configured via
+/// [`BlitziBuilder::database_backend`](crate::BlitziBuilder::database_backend).
+/// Use [`migrate_database`](crate::migrate_database) to switch backends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatabaseBackendMismatch {
+    /// The data directory
+    pub path: PathBuf,
+    /// The configured backend
+    pub expected: DatabaseBackend,
+    /// The backend of the database found in the data directory
+    pub found: DatabaseBackend,
+}
+
+impl fmt::Display for DatabaseBackendMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Data directory {} contains a {} database but the {} backend is configured, use \
+             migrate_database to switch backends",
+            self.path.display(),
+            self.found,
+            self.expected
+        )
+    }
+}
+
+impl std::error::Error for DatabaseBackendMismatch {}
+
+/// The balance didn't reach the target before the timeout, see
+/// [`Blitzi::await_balance_at_least`](crate::Blitzi::await_balance_at_least).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BalanceTimeout {
+    /// The balance that was waited for
+    pub target: Amount,
+    /// The balance when the timeout was reached
+    pub balance: Amount,
+}
+
+impl fmt::Display for BalanceTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Timed out waiting for a balance of {} msat, current balance is {} msat",
+            self.target.msats, self.balance.msats
+        )
+    }
+}
+
+impl std::error::Error for BalanceTimeout {}
+
+/// No incoming payment was claimed before the timeout, see
+/// [`Blitzi::await_any_incoming_payment`](crate::Blitzi::await_any_incoming_payment).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IncomingPaymentTimedOut {
+    /// The timeout that passed
+    pub timeout: Duration,
+}
+
+impl fmt::Display for IncomingPaymentTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No incoming payment received within {:?}", self.timeout)
+    }
+}
+
+impl std::error::Error for IncomingPaymentTimedOut {}
+
+/// Leaving the federation was refused because funds would be lost, see
+/// [`Blitzi::leave_federation`](crate::Blitzi::leave_federation).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeaveFederationError {
+    /// The wallet still holds funds.
+    NonZeroBalance {
+        /// The current balance
+        balance: Amount,
+    },
+    /// Some operations haven't finished yet, e.g. invoices that could still
+    /// be paid.
+    PendingOperations {
+        /// Number of pending operations
+        count: usize,
+    },
+}
+
+impl fmt::Display for LeaveFederationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LeaveFederationError::NonZeroBalance { balance } => write!(
+                f,
+                "Refusing to leave the federation with a balance of {} msat",
+                balance.msats
+            ),
+            LeaveFederationError::PendingOperations { count } => write!(
+                f,
+                "Refusing to leave the federation with {} pending operations",
+                count
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LeaveFederationError {}
+
+/// The database was written by a newer version of Blitzi that uses a schema
+/// this version doesn't understand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IncompatibleDatabase {
+    /// Schema version found in the database
+    pub found: u64,
+    /// Newest schema version supported by this version of Blitzi
+    pub supported: u64,
+}
+
+impl fmt::Display for IncompatibleDatabase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Database was created by a newer version of blitzi (schema version {}, this version \
+             supports up to {}), upgrade blitzi or restore your wallet from its mnemonic into a \
+             fresh data directory",
+            self.found, self.supported
+        )
+    }
+}
+
+impl std::error::Error for IncompatibleDatabase {}
+
+/// An idempotency key passed to
+/// [`Blitzi::pay_idempotent`](crate::Blitzi::pay_idempotent) or
+/// [`Blitzi::lightning_invoice_idempotent`](crate::Blitzi::lightning_invoice_idempotent)
+/// was already used for a payment or invoice of a different amount.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdempotencyKeyConflict {
+    /// The reused key
+    pub key: String,
+    /// Amount of the invoice or payment the key was first used for
+    pub original_amount: Option<Amount>,
+    /// Amount of the invoice or payment the key was reused for
+    pub attempted_amount: Option<Amount>,
+}
+
+impl fmt::Display for IdempotencyKeyConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let format_amount = |amount: Option<Amount>| match amount {
+            Some(amount) => format!("an amount of {} msat", amount.msats),
+            None => "no amount".to_string(),
+        };
+        write!(
+            f,
+            "Idempotency key {:?} was already used with {}, but was reused with {}",
+            self.key,
+            format_amount(self.original_amount),
+            format_amount(self.attempted_amount)
+        )
+    }
+}
+
+impl std::error::Error for IdempotencyKeyConflict {}
+
+/// A payment was rejected because it would exceed the configured
+/// [`SpendLimit`](crate::SpendLimit).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpendLimitExceeded {
+    /// The limit that would be exceeded, either the per-payment maximum or the
+    /// maximum of the rolling window
+    pub limit: Amount,
+    /// Amount of the rejected payment
+    pub attempted: Amount,
+    /// Amount that can still be spent in the current window, `None` if no
+    /// window is configured
+    pub window_remaining: Option<Amount>,
+}
+
+impl fmt::Display for SpendLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Payment of {} msat exceeds the spend limit of {} msat",
+            self.attempted.msats, self.limit.msats
+        )?;
+        if let Some(remaining) = self.window_remaining {
+            write!(
+                f,
+                ", {} msat remaining in the current window",
+                remaining.msats
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SpendLimitExceeded {}
+
+/// A payment was rejected by the policy configured via
+/// [`BlitziBuilder::payment_policy`](crate::BlitziBuilder::payment_policy).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyDenied {
+    /// Reason given by the policy
+    pub reason: String,
+}
+
+impl fmt::Display for PolicyDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Payment denied by policy: {}", self.reason)
+    }
+}
+
+impl std::error::Error for PolicyDenied {}
+
+/// Receiving an amount would push the balance above the cap configured via
+/// [`BlitziBuilder::max_balance`](crate::BlitziBuilder::max_balance).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BalanceCapExceeded {
+    /// The maximum balance
+    pub max: Amount,
+    /// The balance at the time of the check
+    pub balance: Amount,
+    /// Amount that was about to be received
+    pub amount: Amount,
+}
+
+impl fmt::Display for BalanceCapExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Receiving {} msat would push the balance of {} msat above the maximum of {} msat",
+            self.amount.msats, self.balance.msats, self.max.msats
+        )
+    }
+}
+
+impl std::error::Error for BalanceCapExceeded {}
+
+/// The amount to withdraw from an LNURL-withdraw link is outside the bounds
+/// the service allows, see
+/// [`Blitzi::claim_lnurl_withdraw`](crate::Blitzi::claim_lnurl_withdraw).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WithdrawAmountOutOfRange {
+    /// The requested amount
+    pub amount: Amount,
+    /// Minimum amount the service allows to withdraw
+    pub min: Amount,
+    /// Maximum amount the service allows to withdraw
+    pub max: Amount,
+}
+
+impl fmt::Display for WithdrawAmountOutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Withdrawal of {} msat is outside the allowed range of {} to {} msat",
+            self.amount.msats, self.min.msats, self.max.msats
+        )
+    }
+}
+
+impl std::error::Error for WithdrawAmountOutOfRange {}
+
+/// An invoice can't be canceled using
+/// [`Blitzi::cancel_invoice`](crate::Blitzi::cancel_invoice) because it was
+/// already paid, or the payment is already being received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlreadyPaid {
+    /// Payment hash of the invoice
+    pub payment_hash: PaymentHash,
+}
+
+impl fmt::Display for AlreadyPaid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invoice with payment hash {} was already paid",
+            self.payment_hash
+        )
+    }
+}
+
+impl std::error::Error for AlreadyPaid {}
+
+/// The wallet's seed is encrypted, but no passphrase was set via
+/// [`BlitziBuilder::encryption_passphrase`](crate::BlitziBuilder::encryption_passphrase).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PassphraseRequired;
+
+impl fmt::Display for PassphraseRequired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The wallet seed is encrypted, a passphrase is required")
+    }
+}
+
+impl std::error::Error for PassphraseRequired {}
+
+/// The passphrase doesn't decrypt the wallet's seed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WrongPassphrase;
+
+impl fmt::Display for WrongPassphrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Wrong passphrase for the wallet seed")
+    }
+}
+
+impl std::error::Error for WrongPassphrase {}
+
+/// A [`TransferToken`](crate::TransferToken) can't be redeemed because it
+/// expired, the sender reclaims the funds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferExpired {
+    /// Time at which the token expired
+    pub expires_at: SystemTime,
+}
+
+impl fmt::Display for TransferExpired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self
+            .expires_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        write!(f, "Transfer token expired at unix time {}", secs)
+    }
+}
+
+impl std::error::Error for TransferExpired {}
+
+/// The federation couldn't be reached within the
+/// [config timeout](crate::BlitziBuilder::config_timeout).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectTimeout {
+    /// The configured timeout
+    pub timeout: Duration,
+    /// API endpoints of the guardians from the invite code that couldn't be
+    /// reached in time
+    pub endpoints: Vec<String>,
+}
+
+impl fmt::Display for ConnectTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Couldn't reach the federation within {} s, unreachable guardian endpoints: {}",
+            self.timeout.as_secs_f64(),
+            self.endpoints.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for ConnectTimeout {}
+
+/// Joining the federation didn't finish within the
+/// [join timeout](crate::BlitziBuilder::join_timeout). Anything written to
+/// the database by the attempt was removed, so joining can simply be retried.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JoinTimedOut {
+    /// The configured timeout
+    pub timeout: Duration,
+}
+
+impl fmt::Display for JoinTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Joining the federation didn't finish within {} s, check the connection and try again",
+            self.timeout.as_secs_f64()
+        )
+    }
+}
+
+impl std::error::Error for JoinTimedOut {}
+
+/// The federation rejected ecash notes passed to
+/// [`Blitzi::receive_ecash`](crate::Blitzi::receive_ecash) because they were
+/// already spent, e.g. redeemed by someone else the notes were also given to.
+/// Nothing was added to the balance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EcashAlreadySpent {
+    /// Total amount of the rejected notes
+    pub amount: Amount,
+}
+
+impl fmt::Display for EcashAlreadySpent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Ecash notes worth {} msat were already spent",
+            self.amount.msats
+        )
+    }
+}
+
+impl std::error::Error for EcashAlreadySpent {}
+
+/// The federation operates on a different Bitcoin network than the one set via
+/// [`BlitziBuilder::network`](crate::BlitziBuilder::network), e.g. a test
+/// network federation in a production build.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkMismatch {
+    /// The network set on the builder
+    pub expected: Network,
+    /// The network the federation operates on
+    pub got: Network,
+}
+
+impl fmt::Display for NetworkMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Expected a federation on {} but the federation operates on {}",
+            self.expected, self.got
+        )
+    }
+}
+
+impl std::error::Error for NetworkMismatch {}
+
+/// An LNURL service responded with an error status, see
+/// [`Blitzi::claim_lnurl_withdraw`](crate::Blitzi::claim_lnurl_withdraw).
+/// Server errors are only returned once retrying them failed until the
+/// [LNURL deadline](crate::BlitziBuilder::lnurl_deadline).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LnurlServiceError {
+    /// HTTP status code of the response
+    pub status: u16,
+    /// The beginning of the response body
+    pub body: String,
+}
+
+impl fmt::Display for LnurlServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LNURL service returned HTTP {}", self.status)?;
+        if !self.body.is_empty() {
+            write!(f, ": {}", self.body)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for LnurlServiceError {}
+
+/// A payment made using
+/// [`Blitzi::pay_with_deadline`](crate::Blitzi::pay_with_deadline) didn't
+/// reach a final state before the deadline. The payment may still succeed or
+/// fail later, see [`Blitzi::payment_result`](crate::Blitzi::payment_result).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaymentTimedOut {
+    /// Payment hash of the invoice being paid
+    pub payment_hash: PaymentHash,
+    /// The deadline that passed
+    pub deadline: Duration,
+}
+
+impl fmt::Display for PaymentTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Payment {} didn't finish within {:?}, it may still succeed",
+            self.payment_hash, self.deadline
+        )
+    }
+}
+
+impl std::error::Error for PaymentTimedOut {}
+
+/// An external id passed to
+/// [`Blitzi::lightning_invoice_tagged`](crate::Blitzi::lightning_invoice_tagged)
+/// or [`PayOptions::external_id`](crate::PayOptions::external_id) is already
+/// attached to another invoice or payment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExternalIdInUse {
+    /// The external id
+    pub external_id: String,
+    /// Operation the id is attached to
+    pub operation_id: OperationId,
+}
+
+impl fmt::Display for ExternalIdInUse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "External id {:?} is already attached to operation {}",
+            self.external_id,
+            hex::encode(self.operation_id.0)
+        )
+    }
+}
+
+impl std::error::Error for ExternalIdInUse {}
+
+/// The fee the gateway charges for a payment exceeds
+/// [`PayOptions::max_fee`](crate::PayOptions::max_fee), the payment wasn't
+/// started.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeeTooHigh {
+    /// Fee the gateway would charge
+    pub fee: Amount,
+    /// The maximum fee the caller accepted
+    pub max_fee: Amount,
+}
+
+impl fmt::Display for FeeTooHigh {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Gateway fee of {} msat exceeds the maximum fee of {} msat",
+            self.fee.msats, self.max_fee.msats
+        )
+    }
+}
+
+impl std::error::Error for FeeTooHigh {}
+
+/// The federation only supports the `lnv2` module, which doesn't offer the
+/// requested feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsupportedByLnv2 {
+    /// The feature that was requested
+    pub feature: &'static str,
+}
+
+impl fmt::Display for UnsupportedByLnv2 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} isn't supported by the lnv2 module", self.feature)
+    }
+}
+
+impl std::error::Error for UnsupportedByLnv2 {}
+
+/// A payment was started but failed, its funds were refunded to the wallet.
+/// A [`BlitziEvent::PaymentFailed`](crate::BlitziEvent::PaymentFailed) is
+/// emitted for it as well.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaymentRefunded {
+    /// Why the payment failed
+    pub reason: String,
+}
+
+impl fmt::Display for PaymentRefunded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Payment failed: {}", self.reason)
+    }
+}
+
+impl std::error::Error for PaymentRefunded {}
@@ -0,0 +1,229 @@
+//! Unified stream of wallet events, see
+//! [`Blitzi::subscribe_events`](crate::Blitzi::subscribe_events).
+use fedimint_core::Amount;
+use fedimint_core::core::OperationId;
+use fedimint_core::util::BoxStream;
+use futures_lite::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tracing::warn;
+
+use crate::PaymentHash;
+use crate::serde_util::operation_id_hex;
+
+/// Number of events buffered for every subscriber, subscribers falling
+/// further behind miss events.
+pub(crate) const EVENT_BUFFER: usize = 1024;
+
+/// An event of the wallet, yielded by
+/// [`Blitzi::subscribe_events`](crate::Blitzi::subscribe_events).
+///
+/// Serialized as an object with a `type` field (e.g. `"invoice_paid"`), the
+/// operation ids as hex and the amounts as `amount_msats`, `fee_msats` and
+/// `balance_msats`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BlitziEvent {
+    /// An invoice created by this wallet was paid and the funds were claimed
+    InvoicePaid {
+        /// Id of the operation receiving the payment
+        #[serde(with = "operation_id_hex")]
+        operation_id: OperationId,
+        /// Payment hash of the paid invoice, `None` if it couldn't be
+        /// determined
+        payment_hash: Option<PaymentHash>,
+        /// Amount claimed, `None` if it couldn't be determined
+        #[serde(rename = "amount_msats")]
+        amount: Option<Amount>,
+    },
+    /// An outgoing payment succeeded. Emitted once per payment, like
+    /// [`BlitziEvent::PaymentFailed`]
+    PaymentSent {
+        /// Id of the payment operation
+        #[serde(with = "operation_id_hex")]
+        operation_id: OperationId,
+    },
+    /// An outgoing payment failed, its funds were refunded
+    PaymentFailed {
+        /// Id of the payment operation
+        #[serde(with = "operation_id_hex")]
+        operation_id: OperationId,
+        /// Why the payment failed
+        reason: String,
+    },
+    /// Ecash notes, e.g. of a [transfer](crate::Blitzi::redeem_transfer), were
+    /// redeemed into the wallet
+    EcashReceived {
+        /// Amount added to the balance
+        #[serde(rename = "amount_msats")]
+        amount: Amount,
+    },
+    /// Ecash notes were spent out of the wallet to be handed to someone else
+    EcashSpent {
+        /// Id of the spend operation
+        #[serde(with = "operation_id_hex")]
+        operation_id: OperationId,
+        /// Total amount of the spent notes
+        #[serde(rename = "amount_msats")]
+        amount: Amount,
+    },
+    /// The balance changed
+    BalanceChanged {
+        /// The new balance
+        #[serde(rename = "balance_msats")]
+        balance: Amount,
+    },
+}
+
+/// Sends events to all current subscribers, events without subscribers are
+/// dropped.
+pub(crate) fn emit(sender: &broadcast::Sender<BlitziEvent>, event: BlitziEvent) {
+    let _ = sender.send(event);
+}
+
+/// Merges the events of `receiver` with the balance updates of `balance`,
+/// skipping events missed because the subscriber fell behind.
+pub(crate) fn subscribe(
+    receiver: broadcast::Receiver<BlitziEvent>,
+    balance: BoxStream<'static, Amount>,
+) -> BoxStream<'static, BlitziEvent> {
+    let events = futures_lite::stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => return Some((event, receiver)),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!(missed, "Event subscriber fell behind, skipping events");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    let balance = balance.map(|balance| BlitziEvent::BalanceChanged { balance });
+    Box::pin(events.race(balance))
+}
+
+/// Waits for the next invoice claimed after subscribing via `receiver` and
+/// returns its operation id, payment hash and claimed amount. Claims whose
+/// payment hash or amount couldn't be determined are skipped. Returns `None`
+/// if the client was shut down.
+pub(crate) async fn next_invoice_paid(
+    receiver: broadcast::Receiver<BlitziEvent>,
+) -> Option<(OperationId, PaymentHash, Amount)> {
+    let mut events = subscribe(receiver, Box::pin(futures_lite::stream::empty()));
+    while let Some(event) = events.next().await {
+        match event {
+            BlitziEvent::InvoicePaid {
+                operation_id,
+                payment_hash: Some(payment_hash),
+                amount: Some(amount),
+            } => return Some((operation_id, payment_hash, amount)),
+            BlitziEvent::InvoicePaid { operation_id, .. } => {
+                warn!(
+                    %operation_id,
+                    "Skipping claimed invoice with unknown payment hash or amount"
+                );
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Preimage;
+
+    #[test]
+    fn test_event_serde() {
+        let event = BlitziEvent::EcashSpent {
+            operation_id: OperationId([1; 32]),
+            amount: Amount::from_msats(1337),
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "type": "ecash_spent",
+                "operation_id": "01".repeat(32),
+                "amount_msats": 1337,
+            })
+        );
+        assert_eq!(serde_json::from_value::<BlitziEvent>(json).unwrap(), event);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let (sender, receiver) = broadcast::channel(2);
+        let mut events = subscribe(receiver, Box::pin(futures_lite::stream::empty()));
+
+        emit(
+            &sender,
+            BlitziEvent::EcashReceived {
+                amount: Amount::from_msats(1),
+            },
+        );
+        assert_eq!(
+            events.next().await,
+            Some(BlitziEvent::EcashReceived {
+                amount: Amount::from_msats(1)
+            })
+        );
+
+        // Events missed by a lagging subscriber are skipped
+        for msats in 2..=4 {
+            emit(
+                &sender,
+                BlitziEvent::EcashReceived {
+                    amount: Amount::from_msats(msats),
+                },
+            );
+        }
+        assert_eq!(
+            events.next().await,
+            Some(BlitziEvent::EcashReceived {
+                amount: Amount::from_msats(3)
+            })
+        );
+
+        drop(sender);
+        assert_eq!(events.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_next_invoice_paid() {
+        let (sender, receiver) = broadcast::channel(8);
+        let payment_hash = Preimage([2; 32]).payment_hash();
+
+        emit(
+            &sender,
+            BlitziEvent::PaymentSent {
+                operation_id: OperationId([1; 32]),
+            },
+        );
+        emit(
+            &sender,
+            BlitziEvent::InvoicePaid {
+                operation_id: OperationId([3; 32]),
+                payment_hash: None,
+                amount: Some(Amount::from_msats(1)),
+            },
+        );
+        emit(
+            &sender,
+            BlitziEvent::InvoicePaid {
+                operation_id: OperationId([4; 32]),
+                payment_hash: Some(payment_hash),
+                amount: Some(Amount::from_msats(1000)),
+            },
+        );
+        assert_eq!(
+            next_invoice_paid(receiver).await,
+            Some((OperationId([4; 32]), payment_hash, Amount::from_msats(1000)))
+        );
+
+        let receiver = sender.subscribe();
+        drop(sender);
+        assert_eq!(next_invoice_paid(receiver).await, None);
+    }
+}
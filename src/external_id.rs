@@ -0,0 +1,131 @@
+//! External reference ids (e.g. order ids) attached to invoices and payments
+//! for reconciliation, see
+//! [`Blitzi::lightning_invoice_tagged`](crate::Blitzi::lightning_invoice_tagged)
+//! and [`Blitzi::find_by_external_id`](crate::Blitzi::find_by_external_id).
+use fedimint_core::core::OperationId;
+use fedimint_core::db::{Database, IDatabaseTransactionOpsCore};
+
+use crate::error::ExternalIdInUse;
+
+/// Maximum length of an external id in bytes.
+pub const MAX_EXTERNAL_ID_LEN: usize = 256;
+
+/// Prefix of the entries mapping external ids to operation ids, in the key
+/// range Fedimint reserves for external use (`0xb1..=0xcf`).
+const EXTERNAL_ID_PREFIX: &[u8] = b"\xb1blitzi/external-id/";
+
+/// Prefix of the entries mapping operation ids back to their external id.
+const OPERATION_PREFIX: &[u8] = b"\xb1blitzi/operation-external-id/";
+
+/// Checks that `external_id` can be attached to a new operation.
+///
+/// # Errors
+/// Returns an error if the id is empty or longer than
+/// [`MAX_EXTERNAL_ID_LEN`], and an [`ExternalIdInUse`] error if it's already
+/// attached to another operation.
+pub(crate) async fn check_unused(db: &Database, external_id: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(!external_id.is_empty(), "External id must not be empty");
+    anyhow::ensure!(
+        external_id.len() <= MAX_EXTERNAL_ID_LEN,
+        "External id is {} bytes long, the maximum is {} bytes",
+        external_id.len(),
+        MAX_EXTERNAL_ID_LEN
+    );
+    if let Some(operation_id) = find(db, external_id).await? {
+        return Err(ExternalIdInUse {
+            external_id: external_id.to_owned(),
+            operation_id,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Attaches `external_id` to the operation `operation_id`.
+pub(crate) async fn store(
+    db: &Database,
+    external_id: &str,
+    operation_id: OperationId,
+) -> anyhow::Result<()> {
+    let mut dbtx = db.begin_transaction().await;
+    dbtx.raw_insert_bytes(
+        &[EXTERNAL_ID_PREFIX, external_id.as_bytes()].concat(),
+        &serde_json::to_vec(&hex::encode(operation_id.0))?,
+    )
+    .await?;
+    dbtx.raw_insert_bytes(
+        &[OPERATION_PREFIX, &operation_id.0].concat(),
+        &serde_json::to_vec(external_id)?,
+    )
+    .await?;
+    dbtx.commit_tx_result().await?;
+    Ok(())
+}
+
+/// Returns the operation `external_id` is attached to, if any.
+pub(crate) async fn find(db: &Database, external_id: &str) -> anyhow::Result<Option<OperationId>> {
+    let mut dbtx = db.begin_transaction_nc().await;
+    let Some(bytes) = dbtx
+        .raw_get_bytes(&[EXTERNAL_ID_PREFIX, external_id.as_bytes()].concat())
+        .await?
+    else {
+        return Ok(None);
+    };
+
+    let operation_id = hex::decode(serde_json::from_slice::<String>(&bytes)?)?;
+    Ok(Some(OperationId(operation_id.try_into().map_err(
+        |_| anyhow::anyhow!("Recorded operation id must be 32 bytes"),
+    )?)))
+}
+
+/// Returns the external id attached to the operation `operation_id`, if any.
+pub(crate) async fn of_operation(
+    db: &Database,
+    operation_id: OperationId,
+) -> anyhow::Result<Option<String>> {
+    let mut dbtx = db.begin_transaction_nc().await;
+    dbtx.raw_get_bytes(&[OPERATION_PREFIX, &operation_id.0].concat())
+        .await?
+        .map(|bytes| serde_json::from_slice(&bytes).map_err(anyhow::Error::from))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use fedimint_core::db::IRawDatabaseExt;
+    use fedimint_core::db::mem_impl::MemDatabase;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_store_and_find() {
+        let db = MemDatabase::new().into_database();
+        let operation_id = OperationId([1; 32]);
+        check_unused(&db, "order-1").await.unwrap();
+
+        store(&db, "order-1", operation_id).await.unwrap();
+        assert_eq!(find(&db, "order-1").await.unwrap(), Some(operation_id));
+        assert_eq!(
+            of_operation(&db, operation_id).await.unwrap().as_deref(),
+            Some("order-1")
+        );
+        // Ids sharing a prefix don't collide
+        assert_eq!(find(&db, "order-10").await.unwrap(), None);
+        assert_eq!(of_operation(&db, OperationId([2; 32])).await.unwrap(), None);
+
+        let error = check_unused(&db, "order-1").await.unwrap_err();
+        assert_eq!(
+            error
+                .downcast_ref::<ExternalIdInUse>()
+                .unwrap()
+                .operation_id,
+            operation_id
+        );
+        assert!(check_unused(&db, "").await.is_err());
+        assert!(
+            check_unused(&db, &"x".repeat(MAX_EXTERNAL_ID_LEN + 1))
+                .await
+                .is_err()
+        );
+    }
+}
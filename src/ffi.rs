@@ -0,0 +1,413 @@
+//! [UniFFI](https://mozilla.github.io/uniffi-rs/) bindings for Kotlin and Swift,
+//! enabled by the `uniffi` feature. See `bindings/README.md` for generating
+//! the bindings.
+//!
+//! [`BlitziFfi`] wraps a [`Blitzi`] client in an object-safe facade that only
+//! uses types UniFFI can pass across the FFI boundary: amounts are
+//! millisatoshi as `u64`, invoices, preimages and mnemonics are strings.
+//! Errors are mapped to [`BlitziFfiError`], which keeps the typed errors of
+//! [`crate::error`] distinguishable in the foreign language.
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::{
+    AlreadyPaid, BalanceCapExceeded, DatadirLocked, DescriptionTooLong, FederationIdMismatch,
+    FederationMismatch, GatewayUnavailable, IncompatibleDatabase, InvalidDescription,
+    InvalidInvoiceError, InvoiceAmountError, JoinTimedOut, NoFederationConfigured,
+    NoLightningModule, PassphraseRequired, PolicyDenied, SpendLimitExceeded, WrongPassphrase,
+};
+use crate::{Blitzi, Bolt11Invoice, Mnemonic, msats};
+
+#[cfg(not(any(feature = "native", feature = "db-redb")))]
+compile_error!("The `uniffi` feature requires the `native` or `db-redb` feature");
+
+/// Settings of a [`BlitziFfi`] client.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct BlitziFfiConfig {
+    /// Data directory the wallet is stored in, usually within the app's
+    /// private storage
+    pub datadir: String,
+    /// Invite code of the federation to join, the default federation is used
+    /// if not set and the `default-federation` feature is enabled
+    #[uniffi(default = None)]
+    pub invite: Option<String>,
+    /// Passphrase the wallet's seed is encrypted with, see
+    /// [`BlitziBuilder::encryption_passphrase`](crate::BlitziBuilder::encryption_passphrase)
+    #[uniffi(default = None)]
+    pub passphrase: Option<String>,
+    /// Seconds joining the federation may take, see
+    /// [`BlitziBuilder::join_timeout`](crate::BlitziBuilder::join_timeout)
+    #[uniffi(default = None)]
+    pub join_timeout_secs: Option<u64>,
+}
+
+/// Error returned by [`BlitziFfi`]. Variants correspond to the typed errors
+/// of Blitzi, errors without a dedicated variant are returned as
+/// [`BlitziFfiError::Other`].
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Error)]
+pub enum BlitziFfiError {
+    /// See [`PassphraseRequired`]
+    PassphraseRequired,
+    /// See [`WrongPassphrase`]
+    WrongPassphrase,
+    /// See [`DatadirLocked`]
+    DatadirLocked { path: String },
+    /// See [`IncompatibleDatabase`]
+    IncompatibleDatabase { found: u64, supported: u64 },
+    /// See [`NoFederationConfigured`]
+    NoFederationConfigured,
+    /// See [`FederationIdMismatch`]
+    FederationIdMismatch { expected: String, got: String },
+    /// See [`FederationMismatch`]
+    FederationMismatch { stored: String, requested: String },
+    /// See [`NoLightningModule`]
+    NoLightningModule,
+    /// See [`JoinTimedOut`], joining can be retried
+    JoinTimedOut { timeout_secs: u64 },
+    /// The invoice amount is zero or too large, see [`InvoiceAmountError`]
+    InvalidAmount { reason: String },
+    /// See [`DescriptionTooLong`] and [`InvalidDescription`]
+    InvalidDescription { reason: String },
+    /// The invoice can't be parsed or paid, see [`InvalidInvoiceError`]
+    InvalidInvoice { reason: String },
+    /// See [`GatewayUnavailable`]
+    GatewayUnavailable,
+    /// See [`BalanceCapExceeded`]
+    BalanceCapExceeded {
+        max_msats: u64,
+        balance_msats: u64,
+        amount_msats: u64,
+    },
+    /// See [`SpendLimitExceeded`]
+    SpendLimitExceeded {
+        limit_msats: u64,
+        attempted_msats: u64,
+    },
+    /// See [`PolicyDenied`]
+    PolicyDenied { reason: String },
+    /// See [`AlreadyPaid`]
+    AlreadyPaid { payment_hash: String },
+    /// The operation didn't complete within the given timeout
+    TimedOut,
+    /// Any other error
+    Other { reason: String },
+}
+
+impl fmt::Display for BlitziFfiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlitziFfiError::PassphraseRequired => write!(f, "{}", PassphraseRequired),
+            BlitziFfiError::WrongPassphrase => write!(f, "{}", WrongPassphrase),
+            BlitziFfiError::DatadirLocked { path } => {
+                write!(
+                    f,
+                    "Data directory {} is locked, is another process using it?",
+                    path
+                )
+            }
+            BlitziFfiError::IncompatibleDatabase { found, supported } => write!(
+                f,
+                "Database was created by a newer version of blitzi (schema version {}, this version \
+                 supports {})",
+                found, supported
+            ),
+            BlitziFfiError::NoFederationConfigured => write!(f, "{}", NoFederationConfigured),
+            BlitziFfiError::FederationIdMismatch { expected, got } => write!(
+                f,
+                "Expected federation {} but got federation {}",
+                expected, got
+            ),
+            BlitziFfiError::FederationMismatch { stored, requested } => write!(
+                f,
+                "The wallet belongs to federation {} but the invite code is for federation {}",
+                stored, requested
+            ),
+            BlitziFfiError::NoLightningModule => write!(f, "{}", NoLightningModule),
+            BlitziFfiError::JoinTimedOut { timeout_secs } => write!(
+                f,
+                "{}",
+                JoinTimedOut {
+                    timeout: Duration::from_secs(*timeout_secs)
+                }
+            ),
+            BlitziFfiError::InvalidAmount { reason }
+            | BlitziFfiError::InvalidDescription { reason }
+            | BlitziFfiError::InvalidInvoice { reason }
+            | BlitziFfiError::Other { reason } => write!(f, "{}", reason),
+            BlitziFfiError::GatewayUnavailable => write!(f, "No LN gateway available"),
+            BlitziFfiError::BalanceCapExceeded {
+                max_msats,
+                balance_msats,
+                amount_msats,
+            } => write!(
+                f,
+                "Receiving {} msat would push the balance of {} msat above the maximum of {} msat",
+                amount_msats, balance_msats, max_msats
+            ),
+            BlitziFfiError::SpendLimitExceeded {
+                limit_msats,
+                attempted_msats,
+            } => write!(
+                f,
+                "Payment of {} msat exceeds the spend limit of {} msat",
+                attempted_msats, limit_msats
+            ),
+            BlitziFfiError::PolicyDenied { reason } => {
+                write!(f, "Payment denied by policy: {}", reason)
+            }
+            BlitziFfiError::AlreadyPaid { payment_hash } => {
+                write!(
+                    f,
+                    "Invoice with payment hash {} was already paid",
+                    payment_hash
+                )
+            }
+            BlitziFfiError::TimedOut => write!(f, "Timed out"),
+        }
+    }
+}
+
+impl std::error::Error for BlitziFfiError {}
+
+impl From<anyhow::Error> for BlitziFfiError {
+    fn from(error: anyhow::Error) -> Self {
+        if error.is::<PassphraseRequired>() {
+            BlitziFfiError::PassphraseRequired
+        } else if error.is::<WrongPassphrase>() {
+            BlitziFfiError::WrongPassphrase
+        } else if let Some(e) = error.downcast_ref::<DatadirLocked>() {
+            BlitziFfiError::DatadirLocked {
+                path: e.path.display().to_string(),
+            }
+        } else if let Some(e) = error.downcast_ref::<IncompatibleDatabase>() {
+            BlitziFfiError::IncompatibleDatabase {
+                found: e.found,
+                supported: e.supported,
+            }
+        } else if error.is::<NoFederationConfigured>() {
+            BlitziFfiError::NoFederationConfigured
+        } else if let Some(e) = error.downcast_ref::<FederationIdMismatch>() {
+            BlitziFfiError::FederationIdMismatch {
+                expected: e.expected.to_string(),
+                got: e.got.to_string(),
+            }
+        } else if let Some(e) = error.downcast_ref::<FederationMismatch>() {
+            BlitziFfiError::FederationMismatch {
+                stored: e.stored.to_string(),
+                requested: e.requested.to_string(),
+            }
+        } else if error.is::<NoLightningModule>() {
+            BlitziFfiError::NoLightningModule
+        } else if let Some(e) = error.downcast_ref::<JoinTimedOut>() {
+            BlitziFfiError::JoinTimedOut {
+                timeout_secs: e.timeout.as_secs(),
+            }
+        } else if let Some(e) = error.downcast_ref::<InvoiceAmountError>() {
+            BlitziFfiError::InvalidAmount {
+                reason: e.to_string(),
+            }
+        } else if let Some(e) = error.downcast_ref::<DescriptionTooLong>() {
+            BlitziFfiError::InvalidDescription {
+                reason: e.to_string(),
+            }
+        } else if let Some(e) = error.downcast_ref::<InvalidDescription>() {
+            BlitziFfiError::InvalidDescription {
+                reason: e.to_string(),
+            }
+        } else if let Some(e) = error.downcast_ref::<InvalidInvoiceError>() {
+            BlitziFfiError::InvalidInvoice {
+                reason: e.to_string(),
+            }
+        } else if error.is::<GatewayUnavailable>() {
+            BlitziFfiError::GatewayUnavailable
+        } else if let Some(e) = error.downcast_ref::<BalanceCapExceeded>() {
+            BlitziFfiError::BalanceCapExceeded {
+                max_msats: e.max.msats,
+                balance_msats: e.balance.msats,
+                amount_msats: e.amount.msats,
+            }
+        } else if let Some(e) = error.downcast_ref::<SpendLimitExceeded>() {
+            BlitziFfiError::SpendLimitExceeded {
+                limit_msats: e.limit.msats,
+                attempted_msats: e.attempted.msats,
+            }
+        } else if let Some(e) = error.downcast_ref::<PolicyDenied>() {
+            BlitziFfiError::PolicyDenied {
+                reason: e.reason.clone(),
+            }
+        } else if let Some(e) = error.downcast_ref::<AlreadyPaid>() {
+            BlitziFfiError::AlreadyPaid {
+                payment_hash: e.payment_hash.to_string(),
+            }
+        } else {
+            BlitziFfiError::Other {
+                reason: format!("{:#}", error),
+            }
+        }
+    }
+}
+
+/// Blitzi client exposed to Kotlin and Swift. Its async methods map to
+/// `suspend` functions in Kotlin and `async` functions in Swift, and run on a
+/// Tokio runtime managed by UniFFI.
+#[derive(uniffi::Object)]
+pub struct BlitziFfi {
+    blitzi: Blitzi,
+}
+
+#[uniffi::export(async_runtime = "tokio")]
+impl BlitziFfi {
+    /// Opens the wallet in the configured data directory, joining the
+    /// federation with a new wallet if the directory is empty.
+    #[uniffi::constructor]
+    pub async fn open(config: BlitziFfiConfig) -> Result<Arc<Self>, BlitziFfiError> {
+        let blitzi = builder(&config)?.build().await?;
+        Ok(Arc::new(BlitziFfi { blitzi }))
+    }
+
+    /// Restores the wallet backed up using [`Self::mnemonic`] into the
+    /// configured data directory, see
+    /// [`BlitziBuilder::restore_from_mnemonic`](crate::BlitziBuilder::restore_from_mnemonic).
+    #[uniffi::constructor]
+    pub async fn restore(
+        config: BlitziFfiConfig,
+        mnemonic: String,
+    ) -> Result<Arc<Self>, BlitziFfiError> {
+        let mnemonic = Mnemonic::from_str(&mnemonic).map_err(|e| BlitziFfiError::Other {
+            reason: format!("Invalid mnemonic: {}", e),
+        })?;
+        let blitzi = builder(&config)?
+            .restore_from_mnemonic(mnemonic)
+            .build()
+            .await?;
+        Ok(Arc::new(BlitziFfi { blitzi }))
+    }
+
+    /// Returns the wallet's mnemonic as space separated words, see
+    /// [`Blitzi::mnemonic`].
+    pub fn mnemonic(&self) -> Result<String, BlitziFfiError> {
+        Ok(self.blitzi.mnemonic()?.to_string())
+    }
+
+    /// Returns the balance in millisatoshi.
+    pub async fn balance_msats(&self) -> u64 {
+        self.blitzi.balance().await.msats
+    }
+
+    /// Generates a Lightning invoice for `amount_msats` and returns it BOLT11
+    /// encoded, see [`Blitzi::lightning_invoice`].
+    pub async fn lightning_invoice(
+        &self,
+        amount_msats: u64,
+        description: String,
+    ) -> Result<String, BlitziFfiError> {
+        let invoice = self
+            .blitzi
+            .lightning_invoice(msats(amount_msats), &description)
+            .await?;
+        Ok(invoice.to_string())
+    }
+
+    /// Returns how many seconds invoices generated using
+    /// [`Self::lightning_invoice`] are valid, see
+    /// [`Blitzi::default_invoice_expiry`].
+    pub async fn default_invoice_expiry_secs(&self) -> u64 {
+        self.blitzi.default_invoice_expiry().await.as_secs()
+    }
+
+    /// Waits up to `timeout_secs` for the invoice generated using
+    /// [`Self::lightning_invoice`] to be paid.
+    ///
+    /// # Errors
+    /// Returns [`BlitziFfiError::TimedOut`] if it isn't paid in time.
+    pub async fn await_incoming_payment(
+        &self,
+        invoice: String,
+        timeout_secs: u64,
+    ) -> Result<(), BlitziFfiError> {
+        let invoice = parse_invoice(&invoice)?;
+        fedimint_core::runtime::timeout(
+            Duration::from_secs(timeout_secs),
+            self.blitzi.await_incoming_payment(&invoice),
+        )
+        .await
+        .map_err(|_| BlitziFfiError::TimedOut)??;
+        Ok(())
+    }
+
+    /// Pays the BOLT11 `invoice` and returns the hex encoded preimage, see
+    /// [`Blitzi::pay`].
+    pub async fn pay(&self, invoice: String) -> Result<String, BlitziFfiError> {
+        let invoice = parse_invoice(&invoice)?;
+        Ok(self.blitzi.pay(&invoice).await?.to_string())
+    }
+}
+
+fn builder(config: &BlitziFfiConfig) -> Result<crate::BlitziBuilder, BlitziFfiError> {
+    let mut builder = Blitzi::builder().datadir(&config.datadir);
+    if let Some(invite) = &config.invite {
+        builder = builder.federation(invite)?;
+    }
+    if let Some(passphrase) = &config.passphrase {
+        builder = builder.encryption_passphrase(passphrase);
+    }
+    if let Some(secs) = config.join_timeout_secs {
+        builder = builder.join_timeout(Duration::from_secs(secs));
+    }
+    Ok(builder)
+}
+
+fn parse_invoice(invoice: &str) -> Result<Bolt11Invoice, BlitziFfiError> {
+    Bolt11Invoice::from_str(invoice.trim()).map_err(|e| BlitziFfiError::InvalidInvoice {
+        reason: format!("Invalid invoice: {}", e),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sats;
+
+    #[test]
+    fn test_error_mapping() {
+        assert_eq!(
+            BlitziFfiError::from(anyhow::Error::from(WrongPassphrase)),
+            BlitziFfiError::WrongPassphrase
+        );
+        assert_eq!(
+            BlitziFfiError::from(anyhow::Error::from(BalanceCapExceeded {
+                max: sats(10),
+                balance: sats(8),
+                amount: sats(5),
+            })),
+            BlitziFfiError::BalanceCapExceeded {
+                max_msats: 10_000,
+                balance_msats: 8_000,
+                amount_msats: 5_000,
+            }
+        );
+        // Context added on top doesn't hide the typed error
+        assert_eq!(
+            BlitziFfiError::from(
+                anyhow::Error::from(PassphraseRequired).context("Failed to open the wallet")
+            ),
+            BlitziFfiError::PassphraseRequired
+        );
+        assert_eq!(
+            BlitziFfiError::from(anyhow::anyhow!("Something failed")),
+            BlitziFfiError::Other {
+                reason: "Something failed".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_invoice() {
+        assert!(matches!(
+            parse_invoice("lnbc1invalid"),
+            Err(BlitziFfiError::InvalidInvoice { .. })
+        ));
+    }
+}
@@ -0,0 +1,261 @@
+//! Lightning gateways returned by
+//! [`Blitzi::list_gateways`](crate::Blitzi::list_gateways), the policy
+//! choosing between them and the gateway preferred by the user, see
+//! [`Blitzi::set_preferred_gateway`](crate::Blitzi::set_preferred_gateway).
+use std::collections::HashMap;
+use std::time::Duration;
+
+use fedimint_core::Amount;
+use fedimint_core::db::{Database, IDatabaseTransactionOpsCore};
+use fedimint_core::secp256k1::PublicKey;
+use serde::{Deserialize, Serialize};
+
+use crate::gateway_stats::GatewayRecord;
+use crate::serde_util::duration_secs;
+
+/// Key of the preferred gateway, in the key range Fedimint reserves for
+/// external use (`0xb1..=0xcf`).
+const PREFERRED_GATEWAY_KEY: &[u8] = b"\xb1blitzi/preferred-gateway";
+
+/// How the gateway for invoices and payments is chosen if the call doesn't
+/// select one explicitly, see
+/// [`BlitziBuilder::gateway_selection`](crate::BlitziBuilder::gateway_selection).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GatewaySelection {
+    /// Let the Fedimint client choose, preferring gateways vetted by the
+    /// federation
+    #[default]
+    Automatic,
+    /// The gateway charging the lowest fee for the amount, preferring vetted
+    /// gateways if fees are equal
+    Cheapest,
+    /// The gateway with the highest success rate of outgoing payments made by
+    /// this client, preferring cheaper gateways if rates are equal
+    MostReliable,
+    /// Always the given gateway, fails with a
+    /// [`GatewayUnavailable`](crate::GatewayUnavailable) error if it's not
+    /// available
+    Pinned(PublicKey),
+}
+
+/// A Lightning gateway registered with the federation, which routes payments
+/// between the federation and the Lightning network.
+///
+/// Serialized with keys as hex, the base fee as `base_fee_msats` and the TTL
+/// as `ttl_secs`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GatewayInfo {
+    /// Id of the gateway, used to select it e.g. in
+    /// [`InvoiceOptions::gateway`](crate::InvoiceOptions::gateway)
+    pub gateway_id: PublicKey,
+    /// Node id of the gateway's Lightning node
+    pub node_pub_key: PublicKey,
+    /// Alias of the gateway's Lightning node
+    pub lightning_alias: String,
+    /// URL of the gateway's API
+    pub api: String,
+    /// Base fee charged for outgoing payments
+    #[serde(rename = "base_fee_msats")]
+    pub base_fee: Amount,
+    /// Proportional fee charged for outgoing payments in parts per million
+    pub proportional_fee_ppm: u32,
+    /// Whether the gateway was vetted by the federation's guardians
+    pub vetted: bool,
+    /// Time until the gateway's registration expires unless it's renewed,
+    /// gateways renew their registration periodically while they are online
+    #[serde(rename = "ttl_secs", with = "duration_secs")]
+    pub ttl: Duration,
+}
+
+impl GatewayInfo {
+    /// Returns the fee the gateway charges for routing an outgoing payment of
+    /// `amount`, e.g. to sort gateways by cost.
+    pub fn fee(&self, amount: Amount) -> Amount {
+        let proportional =
+            u128::from(amount.msats) * u128::from(self.proportional_fee_ppm) / 1_000_000;
+        self.base_fee + Amount::from_msats(proportional as u64)
+    }
+}
+
+/// Chooses a gateway from `gateways` for an invoice or payment of `amount`
+/// according to `selection`, returns `None` to let the Fedimint client choose.
+pub(crate) fn select_gateway(
+    selection: GatewaySelection,
+    gateways: &[GatewayInfo],
+    amount: Amount,
+    records: &HashMap<PublicKey, GatewayRecord>,
+) -> Option<PublicKey> {
+    let gateway = match selection {
+        GatewaySelection::Automatic => return None,
+        GatewaySelection::Pinned(gateway_id) => return Some(gateway_id),
+        GatewaySelection::Cheapest => gateways
+            .iter()
+            .min_by_key(|gateway| (gateway.fee(amount), !gateway.vetted)),
+        GatewaySelection::MostReliable => gateways.iter().max_by(|a, b| {
+            let record = |gateway: &GatewayInfo| {
+                records
+                    .get(&gateway.gateway_id)
+                    .copied()
+                    .unwrap_or_default()
+            };
+            record(a)
+                .cmp_reliability(&record(b))
+                .then_with(|| b.fee(amount).cmp(&a.fee(amount)))
+        }),
+    };
+    gateway.map(|gateway| gateway.gateway_id)
+}
+
+/// Persists `gateway` as the preferred gateway, `None` to remove it.
+pub(crate) async fn store_preferred_gateway(
+    db: &Database,
+    gateway: Option<PublicKey>,
+) -> anyhow::Result<()> {
+    let mut dbtx = db.begin_transaction().await;
+    match gateway {
+        Some(gateway) => {
+            dbtx.raw_insert_bytes(PREFERRED_GATEWAY_KEY, &serde_json::to_vec(&gateway)?)
+                .await?;
+        }
+        None => {
+            dbtx.raw_remove_entry(PREFERRED_GATEWAY_KEY).await?;
+        }
+    }
+    dbtx.commit_tx_result().await?;
+    Ok(())
+}
+
+/// Returns the preferred gateway, if one was set.
+pub(crate) async fn preferred_gateway(db: &Database) -> anyhow::Result<Option<PublicKey>> {
+    let mut dbtx = db.begin_transaction_nc().await;
+    dbtx.raw_get_bytes(PREFERRED_GATEWAY_KEY)
+        .await?
+        .map(|bytes| serde_json::from_slice(&bytes))
+        .transpose()
+        .map_err(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+    use fedimint_core::db::IRawDatabaseExt;
+    use fedimint_core::db::mem_impl::MemDatabase;
+    use fedimint_core::secp256k1::{Secp256k1, SecretKey};
+
+    use super::*;
+
+    fn test_gateway() -> GatewayInfo {
+        let secp = Secp256k1::new();
+        let key = SecretKey::from_slice(&[1; 32]).unwrap().public_key(&secp);
+
+        GatewayInfo {
+            gateway_id: key,
+            node_pub_key: key,
+            lightning_alias: "gateway".to_string(),
+            api: "https://gateway.example.com/".to_string(),
+            base_fee: Amount::from_msats(1000),
+            proportional_fee_ppm: 5000,
+            vetted: true,
+            ttl: Duration::from_secs(600),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_preferred_gateway() {
+        let db = MemDatabase::new().into_database();
+        let gateway_id = test_gateway().gateway_id;
+
+        assert_eq!(preferred_gateway(&db).await.unwrap(), None);
+        store_preferred_gateway(&db, Some(gateway_id))
+            .await
+            .unwrap();
+        assert_eq!(preferred_gateway(&db).await.unwrap(), Some(gateway_id));
+        store_preferred_gateway(&db, None).await.unwrap();
+        assert_eq!(preferred_gateway(&db).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_fee() {
+        let gateway = test_gateway();
+        assert_eq!(gateway.fee(Amount::ZERO), Amount::from_msats(1000));
+        assert_eq!(
+            gateway.fee(Amount::from_msats(1_000_000)),
+            Amount::from_msats(6000)
+        );
+    }
+
+    #[test]
+    fn test_gateway_info_serde() {
+        let gateway = test_gateway();
+        let json = serde_json::to_value(&gateway).unwrap();
+        assert_eq!(json["gateway_id"], gateway.gateway_id.to_string());
+        assert_eq!(json["base_fee_msats"], 1000);
+        assert_eq!(json["proportional_fee_ppm"], 5000);
+        assert_eq!(json["ttl_secs"], 600);
+        assert_eq!(
+            serde_json::from_value::<GatewayInfo>(json).unwrap(),
+            gateway
+        );
+    }
+
+    #[test]
+    fn test_select_gateway() {
+        let secp = Secp256k1::new();
+        let cheap = GatewayInfo {
+            gateway_id: SecretKey::from_slice(&[2; 32]).unwrap().public_key(&secp),
+            base_fee: Amount::ZERO,
+            ..test_gateway()
+        };
+        let expensive = test_gateway();
+        let gateways = [expensive.clone(), cheap.clone()];
+        let amount = Amount::from_msats(1_000_000);
+        let mut records = HashMap::new();
+
+        assert_eq!(
+            select_gateway(GatewaySelection::Automatic, &gateways, amount, &records),
+            None
+        );
+        assert_eq!(
+            select_gateway(
+                GatewaySelection::Pinned(expensive.gateway_id),
+                &gateways,
+                amount,
+                &records
+            ),
+            Some(expensive.gateway_id)
+        );
+        assert_eq!(
+            select_gateway(GatewaySelection::Cheapest, &gateways, amount, &records),
+            Some(cheap.gateway_id)
+        );
+        assert_eq!(
+            select_gateway(GatewaySelection::Cheapest, &[], amount, &records),
+            None
+        );
+
+        // Without history the cheaper gateway wins
+        assert_eq!(
+            select_gateway(GatewaySelection::MostReliable, &gateways, amount, &records),
+            Some(cheap.gateway_id)
+        );
+        records.insert(
+            cheap.gateway_id,
+            GatewayRecord {
+                successes: 1,
+                failures: 3,
+                last_used: None,
+            },
+        );
+        records.insert(
+            expensive.gateway_id,
+            GatewayRecord {
+                successes: 5,
+                failures: 0,
+                last_used: None,
+            },
+        );
+        assert_eq!(
+            select_gateway(GatewaySelection::MostReliable, &gateways, amount, &records),
+            Some(expensive.gateway_id)
+        );
+    }
+}
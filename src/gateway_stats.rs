@@ -0,0 +1,203 @@
+//! Outcomes of outgoing payments per gateway, recorded in the client database
+//! for [`GatewaySelection::MostReliable`](crate::GatewaySelection::MostReliable)
+//! and returned by [`Blitzi::gateway_stats`](crate::Blitzi::gateway_stats).
+use std::cmp::{Ordering, Reverse};
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use fedimint_core::db::{Database, IDatabaseTransactionOpsCore};
+use fedimint_core::secp256k1::PublicKey;
+use futures_lite::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::serde_util::unix_secs_option;
+
+/// Prefix of the per gateway records, in the key range Fedimint reserves for
+/// external use (`0xb1..=0xcf`).
+const GATEWAY_STATS_PREFIX: &[u8] = b"\xb1blitzi/gateway_stats/";
+
+/// Number of successful and failed payments routed through a gateway.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct GatewayRecord {
+    pub(crate) successes: u64,
+    pub(crate) failures: u64,
+    /// Missing for records written before it was tracked
+    #[serde(default, with = "unix_secs_option")]
+    pub(crate) last_used: Option<SystemTime>,
+}
+
+/// Outcomes of the outgoing payments routed through a gateway, returned by
+/// [`Blitzi::gateway_stats`](crate::Blitzi::gateway_stats).
+///
+/// Serialized with the gateway id as hex and `last_used` as unix seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GatewayStats {
+    /// Id of the gateway, see [`GatewayInfo`](crate::GatewayInfo)
+    pub gateway_id: PublicKey,
+    /// Number of payments the gateway routed successfully
+    pub successes: u64,
+    /// Number of payments the gateway failed to route
+    pub failures: u64,
+    /// Time the last payment routed through the gateway finished, `None` if
+    /// it wasn't recorded by the Blitzi version that made the payment
+    #[serde(with = "unix_secs_option")]
+    pub last_used: Option<SystemTime>,
+}
+
+impl GatewayRecord {
+    /// Compares the estimated success rates of two gateways. Gateways without
+    /// history are estimated at 50% (Laplace smoothing), so a single failure
+    /// doesn't rule out a gateway forever and a single success doesn't make it
+    /// the best one.
+    pub(crate) fn cmp_reliability(&self, other: &GatewayRecord) -> Ordering {
+        let rate = |record: &GatewayRecord| {
+            (
+                u128::from(record.successes) + 1,
+                u128::from(record.successes) + u128::from(record.failures) + 2,
+            )
+        };
+        let (successes, total) = rate(self);
+        let (other_successes, other_total) = rate(other);
+        (successes * other_total).cmp(&(other_successes * total))
+    }
+}
+
+/// Records the outcome of a payment routed through `gateway_id`.
+pub(crate) async fn record(
+    db: &Database,
+    gateway_id: PublicKey,
+    success: bool,
+) -> anyhow::Result<()> {
+    let key = record_key(gateway_id);
+    let mut dbtx = db.begin_transaction().await;
+
+    let mut record = match dbtx.raw_get_bytes(&key).await? {
+        Some(bytes) => serde_json::from_slice(&bytes)?,
+        None => GatewayRecord::default(),
+    };
+    if success {
+        record.successes += 1;
+    } else {
+        record.failures += 1;
+    }
+    record.last_used = Some(fedimint_core::time::now());
+
+    dbtx.raw_insert_bytes(&key, &serde_json::to_vec(&record)?)
+        .await?;
+    dbtx.commit_tx_result().await?;
+    Ok(())
+}
+
+/// Returns the records of all gateways that were used for payments.
+pub(crate) async fn load(db: &Database) -> anyhow::Result<HashMap<PublicKey, GatewayRecord>> {
+    let mut dbtx = db.begin_transaction_nc().await;
+    let entries = dbtx
+        .raw_find_by_prefix(GATEWAY_STATS_PREFIX)
+        .await?
+        .collect::<Vec<_>>()
+        .await;
+
+    entries
+        .into_iter()
+        .map(|(key, value)| {
+            Ok((
+                PublicKey::from_slice(&key[GATEWAY_STATS_PREFIX.len()..])?,
+                serde_json::from_slice(&value)?,
+            ))
+        })
+        .collect()
+}
+
+/// Returns the statistics of all gateways that were used for payments, most
+/// recently used first.
+pub(crate) async fn stats(db: &Database) -> anyhow::Result<Vec<GatewayStats>> {
+    let mut stats = load(db)
+        .await?
+        .into_iter()
+        .map(|(gateway_id, record)| GatewayStats {
+            gateway_id,
+            successes: record.successes,
+            failures: record.failures,
+            last_used: record.last_used,
+        })
+        .collect::<Vec<_>>();
+    stats.sort_by_key(|stats| (Reverse(stats.last_used), stats.gateway_id));
+    Ok(stats)
+}
+
+fn record_key(gateway_id: PublicKey) -> Vec<u8> {
+    [GATEWAY_STATS_PREFIX, &gateway_id.serialize()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use fedimint_core::db::IRawDatabaseExt;
+    use fedimint_core::db::mem_impl::MemDatabase;
+    use fedimint_core::secp256k1::{Secp256k1, SecretKey};
+
+    use super::*;
+
+    fn counts(successes: u64, failures: u64) -> GatewayRecord {
+        GatewayRecord {
+            successes,
+            failures,
+            last_used: None,
+        }
+    }
+
+    #[test]
+    fn test_cmp_reliability() {
+        assert_eq!(
+            counts(9, 1).cmp_reliability(&counts(1, 0)),
+            Ordering::Greater
+        );
+        assert_eq!(
+            counts(0, 0).cmp_reliability(&counts(0, 1)),
+            Ordering::Greater
+        );
+        assert_eq!(counts(1, 1).cmp_reliability(&counts(0, 0)), Ordering::Equal);
+        assert_eq!(counts(2, 2).cmp_reliability(&counts(5, 1)), Ordering::Less);
+    }
+
+    #[tokio::test]
+    async fn test_record_and_load() {
+        let db = MemDatabase::new().into_database();
+        let secp = Secp256k1::new();
+        let gateway = SecretKey::from_slice(&[1; 32]).unwrap().public_key(&secp);
+        let other = SecretKey::from_slice(&[2; 32]).unwrap().public_key(&secp);
+
+        record(&db, gateway, true).await.unwrap();
+        record(&db, gateway, true).await.unwrap();
+        record(&db, gateway, false).await.unwrap();
+        record(&db, other, false).await.unwrap();
+
+        let records = load(&db).await.unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            (records[&gateway].successes, records[&gateway].failures),
+            (2, 1)
+        );
+        assert_eq!(
+            (records[&other].successes, records[&other].failures),
+            (0, 1)
+        );
+        assert!(records[&gateway].last_used.is_some());
+
+        let stats = stats(&db).await.unwrap();
+        assert_eq!(stats.len(), 2);
+        assert!(stats[0].last_used >= stats[1].last_used);
+        let gateway_stats = stats
+            .iter()
+            .find(|stats| stats.gateway_id == gateway)
+            .unwrap();
+        assert_eq!((gateway_stats.successes, gateway_stats.failures), (2, 1));
+    }
+
+    #[test]
+    fn test_record_without_last_used() {
+        // Records written before `last_used` was tracked
+        let record: GatewayRecord =
+            serde_json::from_str(r#"{"successes": 3, "failures": 1}"#).unwrap();
+        assert_eq!(record, counts(3, 1));
+    }
+}
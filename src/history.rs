@@ -0,0 +1,467 @@
+//! Types describing entries of the operation history returned by
+//! [`Blitzi::list_operations`](crate::Blitzi::list_operations).
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, ensure};
+use fedimint_client::Client;
+use fedimint_client::db::{ChronologicalOperationLogKey, OperationLogKey};
+use fedimint_client::oplog::OperationLogEntry;
+use fedimint_core::Amount;
+use fedimint_core::core::OperationId;
+use fedimint_core::db::{Database, IDatabaseTransactionOpsCore, IDatabaseTransactionOpsCoreTyped};
+use fedimint_ln_client::{
+    InternalPayState, LightningOperationMeta, LightningOperationMetaPay,
+    LightningOperationMetaVariant, LnPayState, LnReceiveState,
+};
+use fedimint_mint_client::MintOperationMeta;
+use lightning_invoice::Bolt11Invoice;
+use serde::{Deserialize, Serialize};
+
+use crate::PaymentHash;
+use crate::invoice::InvoiceDescription;
+use crate::serde_util::{impl_serde_via_string, operation_id_hex, unix_secs, unix_secs_option};
+
+/// Prefix of the times operations settled at, keyed by operation id. In the key
+/// range Fedimint reserves for external use (`0xb1..=0xcf`).
+const SETTLED_PREFIX: &[u8] = b"\xb1blitzi/settled/";
+
+/// Prefix of the outgoing payments whose outcome was handled, keyed by
+/// operation id, see [`record_finished`].
+const FINISHED_PREFIX: &[u8] = b"\xb1blitzi/finished/";
+
+/// Opaque cursor pointing at an entry of the operation history. Pass the
+/// cursor of the last entry of a page to
+/// [`Blitzi::list_operations`](crate::Blitzi::list_operations) to fetch the
+/// next (older) page.
+///
+/// The cursor can be converted to and from a string, which makes it easy to
+/// hand out to API clients. It is serialized as that string too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OperationCursor(pub(crate) ChronologicalOperationLogKey);
+
+impl fmt::Display for OperationCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let micros = self
+            .0
+            .creation_time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros();
+        write!(f, "{}_{}", micros, hex::encode(self.0.operation_id.0))
+    }
+}
+
+impl FromStr for OperationCursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (micros, operation_id) = s
+            .split_once('_')
+            .context("Cursor must have the format <timestamp>_<operation id>")?;
+
+        let micros = micros.parse::<u64>().context("Invalid cursor timestamp")?;
+        let operation_id = hex::decode(operation_id).context("Invalid cursor operation id")?;
+        ensure!(
+            operation_id.len() == 32,
+            "Cursor operation id must be 32 bytes"
+        );
+
+        Ok(OperationCursor(ChronologicalOperationLogKey {
+            creation_time: UNIX_EPOCH + Duration::from_micros(micros),
+            operation_id: OperationId(operation_id.try_into().expect("length checked above")),
+        }))
+    }
+}
+
+impl_serde_via_string!(OperationCursor);
+
+/// The kind of operation a [`HistoryEntry`] describes.
+///
+/// Serialized as `{"kind": "receive"}` etc.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum HistoryEntryKind {
+    /// Incoming Lightning payment (invoice created by us)
+    Receive,
+    /// Outgoing Lightning payment
+    Pay,
+    /// Ecash operation, e.g. spending or reissuing notes
+    Ecash,
+}
+
+/// Status of an operation as recorded in the operation log.
+///
+/// Serialized as `{"status": "pending"}` etc.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum HistoryEntryStatus {
+    /// The operation hasn't finished yet or its outcome hasn't been observed
+    /// yet.
+    Pending,
+    /// The operation finished successfully.
+    Succeeded,
+    /// The operation failed, was canceled or refunded.
+    Failed,
+}
+
+/// A single entry of the operation history.
+///
+/// Serialized as a flat object with the fields `cursor`, `operation_id` (hex),
+/// `timestamp` and `settled_at` (unix seconds), `kind`, `amount_msats`,
+/// `fee_msats` and `status`, plus `payment_hash`, `description` and
+/// `external_id` if set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Cursor pointing at this entry, used for pagination
+    pub cursor: OperationCursor,
+    /// Id of the underlying Fedimint operation
+    #[serde(with = "operation_id_hex")]
+    pub operation_id: OperationId,
+    /// Time the operation was started, e.g. when the invoice was created
+    #[serde(with = "unix_secs")]
+    pub timestamp: SystemTime,
+    /// Time an incoming payment was claimed or an outgoing payment succeeded,
+    /// as observed by this client. `None` for other operations and for
+    /// payments that settled before Blitzi started recording the time.
+    #[serde(default, with = "unix_secs_option")]
+    pub settled_at: Option<SystemTime>,
+    /// Kind of operation
+    #[serde(flatten)]
+    pub kind: HistoryEntryKind,
+    /// Amount of the operation, if known (e.g. `None` for amountless invoices)
+    #[serde(rename = "amount_msats")]
+    pub amount: Option<Amount>,
+    /// Fee charged by the gateway for outgoing payments, which is refunded if
+    /// the payment fails. `None` for other operations.
+    #[serde(rename = "fee_msats")]
+    pub fee: Option<Amount>,
+    /// Last known status of the operation
+    #[serde(flatten)]
+    pub status: HistoryEntryStatus,
+    /// Payment hash of the invoice of Lightning payments, `None` for ecash
+    /// operations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payment_hash: Option<PaymentHash>,
+    /// Description of the invoice of Lightning payments, `None` for other
+    /// operations and invoices that only commit to a description hash
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// External id attached to the invoice or payment, see
+    /// [`Blitzi::lightning_invoice_tagged`](crate::Blitzi::lightning_invoice_tagged)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_id: Option<String>,
+}
+
+impl HistoryEntry {
+    /// Converts an operation log entry into a history entry, returns `None`
+    /// for operations that aren't relevant to Blitzi users.
+    pub(crate) fn from_operation(
+        key: ChronologicalOperationLogKey,
+        operation: &OperationLogEntry,
+    ) -> Option<Self> {
+        let (kind, amount, fee, status, invoice) = match operation.operation_module_kind() {
+            "ln" => match operation.meta::<LightningOperationMeta>().variant {
+                LightningOperationMetaVariant::Receive { invoice, .. } => {
+                    let status = match operation.outcome::<LnReceiveState>() {
+                        Some(LnReceiveState::Claimed) => HistoryEntryStatus::Succeeded,
+                        Some(LnReceiveState::Canceled { .. }) => HistoryEntryStatus::Failed,
+                        _ => HistoryEntryStatus::Pending,
+                    };
+                    (
+                        HistoryEntryKind::Receive,
+                        invoice.amount_milli_satoshis().map(Amount::from_msats),
+                        None,
+                        status,
+                        Some(invoice),
+                    )
+                }
+                LightningOperationMetaVariant::Pay(LightningOperationMetaPay {
+                    invoice,
+                    fee,
+                    is_internal_payment,
+                    ..
+                }) => {
+                    let status = if is_internal_payment {
+                        match operation.outcome::<InternalPayState>() {
+                            Some(InternalPayState::Preimage(_)) => HistoryEntryStatus::Succeeded,
+                            Some(_) => HistoryEntryStatus::Failed,
+                            None => HistoryEntryStatus::Pending,
+                        }
+                    } else {
+                        match operation.outcome::<LnPayState>() {
+                            Some(LnPayState::Success { .. }) => HistoryEntryStatus::Succeeded,
+                            Some(
+                                LnPayState::Canceled
+                                | LnPayState::Refunded { .. }
+                                | LnPayState::UnexpectedError { .. },
+                            ) => HistoryEntryStatus::Failed,
+                            _ => HistoryEntryStatus::Pending,
+                        }
+                    };
+                    (
+                        HistoryEntryKind::Pay,
+                        invoice.amount_milli_satoshis().map(Amount::from_msats),
+                        Some(fee),
+                        status,
+                        Some(invoice),
+                    )
+                }
+                // Claims are only used by LN gateways
+                LightningOperationMetaVariant::Claim { .. } => return None,
+            },
+            crate::lnv2::KIND => {
+                let (kind, amount, fee, status) = crate::lnv2::history_fields(operation);
+                let invoice = crate::lnv2::operation_invoice(operation);
+                (kind, amount, fee, status, invoice)
+            }
+            "mint" => {
+                // Mint operations have different outcome types depending on the kind of
+                // operation, we only care about whether it finished
+                let status = if operation.outcome::<serde_json::Value>().is_some() {
+                    HistoryEntryStatus::Succeeded
+                } else {
+                    HistoryEntryStatus::Pending
+                };
+                (
+                    HistoryEntryKind::Ecash,
+                    Some(operation.meta::<MintOperationMeta>().amount),
+                    None,
+                    status,
+                    None,
+                )
+            }
+            _ => return None,
+        };
+
+        Some(HistoryEntry {
+            cursor: OperationCursor(key),
+            operation_id: key.operation_id,
+            timestamp: key.creation_time,
+            settled_at: None,
+            kind,
+            amount,
+            fee,
+            status,
+            payment_hash: invoice
+                .as_ref()
+                .map(|invoice| PaymentHash::from(invoice.payment_hash())),
+            description: invoice.as_ref().and_then(direct_description),
+            external_id: None,
+        })
+    }
+}
+
+fn direct_description(invoice: &Bolt11Invoice) -> Option<String> {
+    match InvoiceDescription::of(invoice) {
+        InvoiceDescription::Direct(description) => Some(description),
+        InvoiceDescription::Hash(_) => None,
+    }
+}
+
+/// Records that the payment `operation_id` settled now, unless an earlier time
+/// was already recorded.
+pub(crate) async fn record_settled(db: &Database, operation_id: OperationId) -> anyhow::Result<()> {
+    let key = [SETTLED_PREFIX, &operation_id.0].concat();
+    let mut dbtx = db.begin_transaction().await;
+    if dbtx.raw_get_bytes(&key).await?.is_some() {
+        return Ok(());
+    }
+
+    let secs = fedimint_core::time::duration_since_epoch().as_secs();
+    dbtx.raw_insert_bytes(&key, &serde_json::to_vec(&secs)?)
+        .await?;
+    dbtx.commit_tx_result().await?;
+    Ok(())
+}
+
+/// Returns the time the payment `operation_id` settled at, if it was recorded.
+pub(crate) async fn settled_at(
+    db: &Database,
+    operation_id: OperationId,
+) -> anyhow::Result<Option<SystemTime>> {
+    let mut dbtx = db.begin_transaction_nc().await;
+    let Some(bytes) = dbtx
+        .raw_get_bytes(&[SETTLED_PREFIX, &operation_id.0].concat())
+        .await?
+    else {
+        return Ok(None);
+    };
+    Ok(Some(
+        UNIX_EPOCH + Duration::from_secs(serde_json::from_slice(&bytes)?),
+    ))
+}
+
+/// Records that the outcome of the payment `operation_id` was handled and
+/// returns whether that happened for the first time, so the outcome of a
+/// payment is only reported once even if it's observed again, e.g. after a
+/// restart.
+pub(crate) async fn record_finished(
+    db: &Database,
+    operation_id: OperationId,
+) -> anyhow::Result<bool> {
+    let key = [FINISHED_PREFIX, &operation_id.0].concat();
+    let mut dbtx = db.begin_transaction().await;
+    if dbtx.raw_get_bytes(&key).await?.is_some() {
+        return Ok(false);
+    }
+
+    dbtx.raw_insert_bytes(&key, &[]).await?;
+    dbtx.commit_tx_result().await?;
+    Ok(true)
+}
+
+/// Removes finished operations started before `older_than` from the operation
+/// log and returns how many were removed, see
+/// [`Blitzi::prune_operations`](crate::Blitzi::prune_operations).
+pub(crate) async fn prune_operations(
+    client: &Client,
+    older_than: SystemTime,
+) -> anyhow::Result<u64> {
+    const PAGE_SIZE: usize = 100;
+
+    // Operations that still have active state machines are referenced by their
+    // modules, even if their outcome was already recorded
+    let active_operations = client.get_active_operations().await;
+
+    let mut pruned = 0;
+    let mut before = Some(ChronologicalOperationLogKey {
+        creation_time: older_than,
+        operation_id: OperationId([0; 32]),
+    });
+    loop {
+        let page = client
+            .operation_log()
+            .paginate_operations_rev(PAGE_SIZE, before)
+            .await;
+
+        let prunable = page
+            .iter()
+            .filter(|(key, operation)| {
+                !active_operations.contains(&key.operation_id)
+                    && HistoryEntry::from_operation(*key, operation)
+                        .is_some_and(|entry| entry.status != HistoryEntryStatus::Pending)
+            })
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+
+        if !prunable.is_empty() {
+            let mut dbtx = client.db().begin_transaction().await;
+            for key in &prunable {
+                dbtx.remove_entry(&OperationLogKey {
+                    operation_id: key.operation_id,
+                })
+                .await;
+                dbtx.remove_entry(key).await;
+            }
+            dbtx.commit_tx_result().await?;
+            pruned += prunable.len() as u64;
+        }
+
+        match page.last() {
+            Some((key, _)) if page.len() == PAGE_SIZE => before = Some(*key),
+            _ => break,
+        }
+    }
+
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod tests {
+    use fedimint_core::db::IRawDatabaseExt;
+    use fedimint_core::db::mem_impl::MemDatabase;
+
+    use super::*;
+
+    #[test]
+    fn test_cursor_roundtrip() {
+        let cursor = OperationCursor(ChronologicalOperationLogKey {
+            creation_time: UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456),
+            operation_id: OperationId([42; 32]),
+        });
+
+        let parsed: OperationCursor = cursor.to_string().parse().unwrap();
+        assert_eq!(parsed, cursor);
+    }
+
+    #[test]
+    fn test_history_entry_serde() {
+        let key = ChronologicalOperationLogKey {
+            creation_time: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            operation_id: OperationId([42; 32]),
+        };
+        let entry = HistoryEntry {
+            cursor: OperationCursor(key),
+            operation_id: key.operation_id,
+            timestamp: key.creation_time,
+            settled_at: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_060)),
+            kind: HistoryEntryKind::Receive,
+            amount: Some(Amount::from_msats(1000)),
+            fee: None,
+            status: HistoryEntryStatus::Succeeded,
+            payment_hash: None,
+            description: None,
+            external_id: None,
+        };
+
+        let json = serde_json::to_value(&entry).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "cursor": format!("1700000000000000_{}", "2a".repeat(32)),
+                "operation_id": "2a".repeat(32),
+                "timestamp": 1_700_000_000,
+                "settled_at": 1_700_000_060,
+                "kind": "receive",
+                "amount_msats": 1000,
+                "fee_msats": null,
+                "status": "succeeded",
+            })
+        );
+        assert_eq!(serde_json::from_value::<HistoryEntry>(json).unwrap(), entry);
+
+        // Entries serialized before settlement times were recorded lack them
+        let mut json = serde_json::to_value(&entry).unwrap();
+        json.as_object_mut().unwrap().remove("settled_at");
+        assert_eq!(
+            serde_json::from_value::<HistoryEntry>(json).unwrap(),
+            HistoryEntry {
+                settled_at: None,
+                ..entry
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record_settled() {
+        let db = MemDatabase::new().into_database();
+        let operation_id = OperationId([1; 32]);
+        assert_eq!(settled_at(&db, operation_id).await.unwrap(), None);
+
+        record_settled(&db, operation_id).await.unwrap();
+        let settled = settled_at(&db, operation_id).await.unwrap().unwrap();
+        assert!(settled <= fedimint_core::time::now());
+
+        // The first observation is kept
+        record_settled(&db, operation_id).await.unwrap();
+        assert_eq!(settled_at(&db, operation_id).await.unwrap(), Some(settled));
+    }
+
+    #[tokio::test]
+    async fn test_record_finished() {
+        let db = MemDatabase::new().into_database();
+        assert!(record_finished(&db, OperationId([1; 32])).await.unwrap());
+        assert!(!record_finished(&db, OperationId([1; 32])).await.unwrap());
+        assert!(record_finished(&db, OperationId([2; 32])).await.unwrap());
+    }
+
+    #[test]
+    fn test_cursor_invalid() {
+        assert!("".parse::<OperationCursor>().is_err());
+        assert!("123".parse::<OperationCursor>().is_err());
+        assert!("abc_00".parse::<OperationCursor>().is_err());
+        assert!("123_0011".parse::<OperationCursor>().is_err());
+    }
+}
@@ -0,0 +1,152 @@
+//! Persistent mapping of application-provided idempotency keys to the payments
+//! made for them by [`Blitzi::pay_idempotent`](crate::Blitzi::pay_idempotent)
+//! and the invoices created for them by
+//! [`Blitzi::lightning_invoice_idempotent`](crate::Blitzi::lightning_invoice_idempotent).
+use fedimint_core::Amount;
+use fedimint_core::db::{Database, IDatabaseTransactionOpsCore};
+use serde::{Deserialize, Serialize};
+
+use crate::{CreatedInvoice, InvoiceStatus, PaymentHash, Preimage};
+
+/// Prefix of the idempotency key entries, in the key range Fedimint reserves
+/// for external use (`0xb1..=0xcf`).
+const IDEMPOTENCY_KEY_PREFIX: &[u8] = b"\xb1blitzi/idempotency/";
+
+/// Prefix of the entries mapping idempotency keys to created invoices.
+const INVOICE_KEY_PREFIX: &[u8] = b"\xb1blitzi/idempotent-invoice/";
+
+/// Outcome of a payment made using
+/// [`Blitzi::pay_idempotent`](crate::Blitzi::pay_idempotent).
+///
+/// Serialized with the hashes as hex.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdempotentPayment {
+    /// Payment hash of the invoice that was actually paid, which differs from
+    /// the supplied invoice if the key was used before
+    pub payment_hash: PaymentHash,
+    /// Preimage of the paid invoice, proof of payment
+    pub preimage: Preimage,
+    /// Set if the key was already used for a different invoice and the outcome
+    /// of that payment was returned instead of paying the supplied invoice
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warning: Option<String>,
+}
+
+/// Invoice returned by
+/// [`Blitzi::lightning_invoice_idempotent`](crate::Blitzi::lightning_invoice_idempotent).
+///
+/// Serialized with the invoice as nested object and the status flattened into
+/// a `status` field.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdempotentInvoice {
+    /// The invoice created for the key, either now or by an earlier call
+    pub invoice: CreatedInvoice,
+    /// Status of the invoice. [`InvoiceStatus::Paid`] if the invoice created
+    /// by an earlier call was already paid, in which case the order must not
+    /// be charged again.
+    #[serde(flatten)]
+    pub status: InvoiceStatus,
+    /// Whether the invoice was created by an earlier call with the same key
+    pub reused: bool,
+}
+
+/// The payment an idempotency key was used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct IdempotencyRecord {
+    pub(crate) payment_hash: PaymentHash,
+    #[serde(rename = "amount_msats")]
+    pub(crate) amount: Option<Amount>,
+}
+
+/// Warning returned when a key is reused for a different invoice.
+pub(crate) fn reused_key_warning(original_payment_hash: PaymentHash) -> String {
+    format!(
+        "Idempotency key was already used for the invoice with payment hash {}, returning the \
+         outcome of that payment",
+        original_payment_hash
+    )
+}
+
+fn db_key(key: &str) -> Vec<u8> {
+    [IDEMPOTENCY_KEY_PREFIX, key.as_bytes()].concat()
+}
+
+/// Returns the payment `key` was used for, if any.
+pub(crate) async fn load_record(
+    db: &Database,
+    key: &str,
+) -> anyhow::Result<Option<IdempotencyRecord>> {
+    let mut dbtx = db.begin_transaction_nc().await;
+    dbtx.raw_get_bytes(&db_key(key))
+        .await?
+        .map(|bytes| serde_json::from_slice(&bytes).map_err(anyhow::Error::from))
+        .transpose()
+}
+
+/// Records that `key` is used for the payment described by `record`,
+/// replacing any previous record.
+pub(crate) async fn store_record(
+    db: &Database,
+    key: &str,
+    record: &IdempotencyRecord,
+) -> anyhow::Result<()> {
+    let mut dbtx = db.begin_transaction().await;
+    dbtx.raw_insert_bytes(&db_key(key), &serde_json::to_vec(record)?)
+        .await?;
+    dbtx.commit_tx_result().await?;
+    Ok(())
+}
+
+fn invoice_db_key(key: &str) -> Vec<u8> {
+    [INVOICE_KEY_PREFIX, key.as_bytes()].concat()
+}
+
+/// Returns the invoice last created for `key`, if any.
+pub(crate) async fn load_invoice(
+    db: &Database,
+    key: &str,
+) -> anyhow::Result<Option<CreatedInvoice>> {
+    let mut dbtx = db.begin_transaction_nc().await;
+    dbtx.raw_get_bytes(&invoice_db_key(key))
+        .await?
+        .map(|bytes| serde_json::from_slice(&bytes).map_err(anyhow::Error::from))
+        .transpose()
+}
+
+/// Records that `invoice` was created for `key`, replacing any previous
+/// invoice.
+pub(crate) async fn store_invoice(
+    db: &Database,
+    key: &str,
+    invoice: &CreatedInvoice,
+) -> anyhow::Result<()> {
+    let mut dbtx = db.begin_transaction().await;
+    dbtx.raw_insert_bytes(&invoice_db_key(key), &serde_json::to_vec(invoice)?)
+        .await?;
+    dbtx.commit_tx_result().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use fedimint_core::db::IRawDatabaseExt;
+    use fedimint_core::db::mem_impl::MemDatabase;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_store_and_load_record() {
+        let db = MemDatabase::new().into_database();
+        assert_eq!(load_record(&db, "order-1").await.unwrap(), None);
+
+        let record = IdempotencyRecord {
+            payment_hash: Preimage([1; 32]).payment_hash(),
+            amount: Some(Amount::from_msats(1000)),
+        };
+        store_record(&db, "order-1", &record).await.unwrap();
+        assert_eq!(load_record(&db, "order-1").await.unwrap(), Some(record));
+        // Keys sharing a prefix don't collide
+        assert_eq!(load_record(&db, "order-10").await.unwrap(), None);
+        assert_eq!(load_record(&db, "order-").await.unwrap(), None);
+    }
+}
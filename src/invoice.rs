@@ -0,0 +1,543 @@
+//! Decoded view of a Lightning invoice returned by
+//! [`Blitzi::decode_invoice`](crate::Blitzi::decode_invoice).
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fedimint_core::Amount;
+use fedimint_core::bitcoin::Network;
+use fedimint_core::bitcoin::hashes::sha256;
+use fedimint_core::core::OperationId;
+use fedimint_core::secp256k1::PublicKey;
+use fedimint_ln_client::LnReceiveState;
+use fedimint_ln_common::route_hints;
+use lightning_invoice::{Bolt11Invoice, Bolt11InvoiceDescriptionRef, Currency, RouteHint};
+use serde::{Deserialize, Serialize};
+
+use crate::PaymentHash;
+use crate::error::{DescriptionTooLong, InvalidDescription, InvalidInvoiceError, InvalidRouteHint};
+use crate::serde_util::{bolt11_string, operation_id_hex, unix_secs};
+
+/// Maximum length of a BOLT11 tagged field in bytes.
+const MAX_TAGGED_FIELD_LEN: usize = 639;
+
+/// Maximum length of an invoice description in bytes (UTF-8).
+pub const MAX_DESCRIPTION_LEN: usize = MAX_TAGGED_FIELD_LEN;
+
+/// Maximum number of hops of a route hint, every hop takes 51 bytes of the
+/// tagged field.
+const MAX_ROUTE_HINT_HOPS: usize = MAX_TAGGED_FIELD_LEN / 51;
+
+/// Options for creating an invoice using
+/// [`Blitzi::lightning_invoice_with_options`](crate::Blitzi::lightning_invoice_with_options).
+#[derive(Debug, Clone, Default)]
+pub struct InvoiceOptions {
+    /// Id of the gateway payers should route through, its route hints are
+    /// embedded in the invoice. By default the gateway is chosen according to
+    /// [`BlitziBuilder::gateway_selection`](crate::BlitziBuilder::gateway_selection).
+    /// Not supported by the `lnv2` module, neither are route hints.
+    pub gateway: Option<PublicKey>,
+    /// Maximum number of route hints advertised by the gateway to embed in
+    /// the invoice, e.g. `Some(0)` to not reveal the gateway's channels. By
+    /// default all of them are embedded. The hint pointing at the gateway
+    /// itself is always included, otherwise the invoice couldn't be paid.
+    pub max_route_hints: Option<usize>,
+    /// Route hints to embed instead of the ones advertised by the gateway, see
+    /// [`Blitzi::lightning_invoice_with_hints`](crate::Blitzi::lightning_invoice_with_hints)
+    pub route_hints: Option<Vec<RouteHint>>,
+    /// Time after which the invoice expires, defaults to
+    /// [`Blitzi::default_invoice_expiry`](crate::Blitzi::default_invoice_expiry)
+    pub expiry: Option<Duration>,
+    /// External reference id (e.g. an order id) to attach to the invoice, see
+    /// [`Blitzi::lightning_invoice_tagged`](crate::Blitzi::lightning_invoice_tagged)
+    pub external_id: Option<String>,
+}
+
+/// An invoice created using
+/// [`Blitzi::lightning_invoice_with_options`](crate::Blitzi::lightning_invoice_with_options).
+///
+/// Serialized with the invoice as string and the ids and payment hash as hex.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CreatedInvoice {
+    /// The invoice to hand to the payer
+    #[serde(with = "bolt11_string")]
+    pub invoice: Bolt11Invoice,
+    /// Id of the operation receiving the payment, see
+    /// [`Blitzi::await_incoming_payment_by_operation`](crate::Blitzi::await_incoming_payment_by_operation)
+    #[serde(with = "operation_id_hex")]
+    pub operation_id: OperationId,
+    /// Payment hash of the invoice
+    pub payment_hash: PaymentHash,
+    /// Id of the gateway payers will route through. For invoices created by
+    /// the `lnv2` module this is the id of the gateway's Lightning node.
+    pub gateway_id: PublicKey,
+}
+
+/// Description of an invoice, either the description itself or its hash.
+///
+/// Serialized as `{"type": "direct", "value": "..."}` or
+/// `{"type": "hash", "value": "<hex>"}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum InvoiceDescription {
+    /// The description text
+    Direct(String),
+    /// Hash of a description that was communicated out of band
+    Hash(sha256::Hash),
+}
+
+impl InvoiceDescription {
+    pub(crate) fn of(invoice: &Bolt11Invoice) -> Self {
+        match invoice.description() {
+            Bolt11InvoiceDescriptionRef::Direct(description) => {
+                InvoiceDescription::Direct(description.to_string())
+            }
+            Bolt11InvoiceDescriptionRef::Hash(hash) => InvoiceDescription::Hash(hash.0),
+        }
+    }
+}
+
+/// Status of an invoice issued by Blitzi, see
+/// [`Blitzi::invoice_status`](crate::Blitzi::invoice_status).
+///
+/// Serialized as `{"status": "pending"}` etc.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum InvoiceStatus {
+    /// The invoice hasn't been paid yet
+    Pending,
+    /// The invoice was paid and the funds were received
+    Paid,
+    /// The invoice expired, the payment was canceled or the invoice was
+    /// canceled using [`Blitzi::cancel_invoice`](crate::Blitzi::cancel_invoice)
+    Canceled,
+}
+
+/// State of the incoming payment for an invoice issued by Blitzi, yielded by
+/// [`Blitzi::subscribe_invoice_updates`](crate::Blitzi::subscribe_invoice_updates).
+/// The last state yielded is always either [`ReceiveState::Claimed`] or
+/// [`ReceiveState::Canceled`].
+///
+/// Serialized as `{"state": "funded"}` etc.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ReceiveState {
+    /// The invoice hasn't been paid yet
+    AwaitingPayment,
+    /// The payment was seen and the funds are being claimed
+    Funded,
+    /// The funds were claimed and added to the balance
+    Claimed,
+    /// The invoice expired or was canceled, or claiming the payment failed
+    Canceled {
+        /// Why the payment was canceled
+        reason: String,
+    },
+}
+
+impl ReceiveState {
+    /// Returns `true` if no further updates will follow this one.
+    pub fn is_final(&self) -> bool {
+        matches!(self, ReceiveState::Claimed | ReceiveState::Canceled { .. })
+    }
+
+    pub(crate) fn from_ln_receive_state(state: LnReceiveState) -> Self {
+        match state {
+            LnReceiveState::Created | LnReceiveState::WaitingForPayment { .. } => {
+                ReceiveState::AwaitingPayment
+            }
+            LnReceiveState::Funded | LnReceiveState::AwaitingFunds => ReceiveState::Funded,
+            LnReceiveState::Claimed => ReceiveState::Claimed,
+            LnReceiveState::Canceled { reason } => ReceiveState::Canceled {
+                reason: reason.to_string(),
+            },
+        }
+    }
+}
+
+/// An incoming payment that was received, returned by
+/// [`Blitzi::await_incoming_payment_details`](crate::Blitzi::await_incoming_payment_details).
+///
+/// Serialized with the amount as `amount_msats`, the payment hash as hex and
+/// `claimed_at` as unix seconds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReceivedPayment {
+    /// Amount that was claimed and added to the balance. With the `ln`
+    /// module this is the invoice amount, anything a payer sends on top is
+    /// kept by the gateway. With the `lnv2` module this is the amount of the
+    /// incoming contract, the invoice amount minus the gateway's fee.
+    #[serde(rename = "amount_msats")]
+    pub amount: Amount,
+    /// Payment hash of the invoice that was paid
+    pub payment_hash: PaymentHash,
+    /// Time at which the payment was claimed
+    #[serde(with = "unix_secs")]
+    pub claimed_at: SystemTime,
+}
+
+/// Details of a decoded Lightning invoice, e.g. to show to a user before they
+/// confirm a payment.
+///
+/// Serialized with the amount as `amount_msats`, hashes and keys as hex and
+/// `expires_at` as unix seconds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InvoiceDetails {
+    /// Amount requested by the invoice, `None` for amountless invoices
+    #[serde(rename = "amount_msats")]
+    pub amount: Option<Amount>,
+    /// Description or description hash of the invoice
+    pub description: InvoiceDescription,
+    /// Payment hash of the invoice
+    pub payment_hash: PaymentHash,
+    /// Time at which the invoice expires
+    #[serde(with = "unix_secs")]
+    pub expires_at: SystemTime,
+    /// Whether the invoice was already expired when it was decoded
+    pub is_expired: bool,
+    /// Node id of the payee
+    pub destination: PublicKey,
+    /// Whether the invoice is for a different network than the federation,
+    /// such invoices can't be paid
+    pub network_mismatch: bool,
+}
+
+impl InvoiceDetails {
+    pub(crate) fn new(invoice: &Bolt11Invoice, network: Network) -> Self {
+        InvoiceDetails {
+            amount: invoice.amount_milli_satoshis().map(Amount::from_msats),
+            description: InvoiceDescription::of(invoice),
+            payment_hash: invoice.payment_hash().into(),
+            expires_at: UNIX_EPOCH + invoice.duration_since_epoch() + invoice.expiry_time(),
+            is_expired: invoice.is_expired(),
+            destination: invoice.get_payee_pub_key(),
+            network_mismatch: invoice.currency() != Currency::from(network),
+        }
+    }
+}
+
+/// Checks that `description` can be embedded in an invoice and returns it. If
+/// `truncate` is set, descriptions exceeding [`MAX_DESCRIPTION_LEN`] are cut
+/// off at the last character boundary before it instead of being rejected.
+pub(crate) fn check_description(description: &str, truncate: bool) -> anyhow::Result<&str> {
+    let description = if description.len() <= MAX_DESCRIPTION_LEN {
+        description
+    } else if truncate {
+        let mut end = MAX_DESCRIPTION_LEN;
+        while !description.is_char_boundary(end) {
+            end -= 1;
+        }
+        &description[..end]
+    } else {
+        return Err(DescriptionTooLong {
+            max: MAX_DESCRIPTION_LEN,
+            got: description.len(),
+        }
+        .into());
+    };
+
+    if let Some((position, character)) = description
+        .char_indices()
+        .find(|(_, character)| character.is_control())
+    {
+        return Err(InvalidDescription {
+            character,
+            position,
+        }
+        .into());
+    }
+
+    Ok(description)
+}
+
+/// Checks that route hints can be embedded in an invoice and converts them to
+/// the representation used by Fedimint.
+pub(crate) fn convert_route_hints(
+    hints: &[RouteHint],
+) -> Result<Vec<route_hints::RouteHint>, InvalidRouteHint> {
+    hints
+        .iter()
+        .enumerate()
+        .map(|(index, hint)| {
+            if hint.0.is_empty() {
+                return Err(InvalidRouteHint::Empty { index });
+            }
+            if hint.0.len() > MAX_ROUTE_HINT_HOPS {
+                return Err(InvalidRouteHint::TooManyHops {
+                    index,
+                    hops: hint.0.len(),
+                    max: MAX_ROUTE_HINT_HOPS,
+                });
+            }
+
+            hint.0
+                .iter()
+                .map(|hop| match (hop.htlc_minimum_msat, hop.htlc_maximum_msat) {
+                    (Some(min), Some(max)) if min > max => {
+                        Err(InvalidRouteHint::InvalidHtlcRange { index })
+                    }
+                    _ => Ok(route_hints::RouteHintHop {
+                        src_node_id: hop.src_node_id,
+                        short_channel_id: hop.short_channel_id,
+                        base_msat: hop.fees.base_msat,
+                        proportional_millionths: hop.fees.proportional_millionths,
+                        cltv_expiry_delta: hop.cltv_expiry_delta,
+                        htlc_minimum_msat: hop.htlc_minimum_msat,
+                        htlc_maximum_msat: hop.htlc_maximum_msat,
+                    }),
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map(route_hints::RouteHint)
+        })
+        .collect()
+}
+
+/// Checks that an invoice can be paid using a federation on `network`.
+pub(crate) fn check_payable(
+    invoice: &Bolt11Invoice,
+    network: Network,
+) -> Result<(), InvalidInvoiceError> {
+    if invoice.currency() != Currency::from(network) {
+        return Err(InvalidInvoiceError::NetworkMismatch {
+            invoice: invoice.currency(),
+            federation: network,
+        });
+    }
+
+    if invoice.is_expired() {
+        return Err(InvalidInvoiceError::Expired);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use fedimint_core::BitcoinHash;
+    use fedimint_core::secp256k1::{Secp256k1, SecretKey};
+    use lightning_invoice::{
+        Description, InvoiceBuilder, PaymentSecret, RouteHintHop, RoutingFees,
+    };
+
+    use super::*;
+
+    fn test_invoice(currency: Currency, timestamp: SystemTime) -> Bolt11Invoice {
+        let secp = Secp256k1::new();
+        let key = SecretKey::from_slice(&[1; 32]).unwrap();
+
+        InvoiceBuilder::new(currency)
+            .description("test".into())
+            .payment_hash(sha256::Hash::hash(&[2; 32]))
+            .payment_secret(PaymentSecret([3; 32]))
+            .timestamp(timestamp)
+            .min_final_cltv_expiry_delta(144)
+            .amount_milli_satoshis(1000)
+            .build_signed(|hash| secp.sign_ecdsa_recoverable(hash, &key))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_invoice_details() {
+        let invoice = test_invoice(Currency::Bitcoin, SystemTime::now());
+        let details = InvoiceDetails::new(&invoice, Network::Bitcoin);
+
+        assert_eq!(details.amount, Some(Amount::from_msats(1000)));
+        assert_eq!(
+            details.description,
+            InvoiceDescription::Direct("test".to_string())
+        );
+        assert_eq!(
+            details.payment_hash,
+            PaymentHash(sha256::Hash::hash(&[2; 32]))
+        );
+        assert!(!details.is_expired);
+        assert!(!details.network_mismatch);
+
+        let details = InvoiceDetails::new(&invoice, Network::Signet);
+        assert!(details.network_mismatch);
+    }
+
+    #[test]
+    fn test_invoice_details_serde() {
+        let timestamp = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let invoice = test_invoice(Currency::Bitcoin, timestamp);
+        let details = InvoiceDetails::new(&invoice, Network::Bitcoin);
+
+        let json = serde_json::to_value(&details).unwrap();
+        assert_eq!(json["amount_msats"], 1000);
+        assert_eq!(
+            json["description"],
+            serde_json::json!({ "type": "direct", "value": "test" })
+        );
+        assert_eq!(
+            json["payment_hash"],
+            sha256::Hash::hash(&[2; 32]).to_string()
+        );
+        assert_eq!(json["expires_at"], 1_700_000_000 + 3600);
+        assert_eq!(json["destination"], details.destination.to_string());
+        assert_eq!(json["is_expired"], true);
+        assert_eq!(json["network_mismatch"], false);
+        assert_eq!(
+            serde_json::from_value::<InvoiceDetails>(json).unwrap(),
+            details
+        );
+
+        let description = InvoiceDescription::Hash(sha256::Hash::hash(&[4; 32]));
+        let json = serde_json::to_value(&description).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "type": "hash",
+                "value": sha256::Hash::hash(&[4; 32]).to_string(),
+            })
+        );
+        assert_eq!(
+            serde_json::from_value::<InvoiceDescription>(json).unwrap(),
+            description
+        );
+    }
+
+    #[test]
+    fn test_received_payment_serde() {
+        let payment = ReceivedPayment {
+            amount: Amount::from_msats(999),
+            payment_hash: PaymentHash(sha256::Hash::hash(&[2; 32])),
+            claimed_at: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        };
+
+        let json = serde_json::to_value(&payment).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "amount_msats": 999,
+                "payment_hash": sha256::Hash::hash(&[2; 32]).to_string(),
+                "claimed_at": 1_700_000_000,
+            })
+        );
+        assert_eq!(
+            serde_json::from_value::<ReceivedPayment>(json).unwrap(),
+            payment
+        );
+    }
+
+    #[test]
+    fn test_invoice_status_serde() {
+        for (status, name) in [
+            (InvoiceStatus::Pending, "pending"),
+            (InvoiceStatus::Paid, "paid"),
+            (InvoiceStatus::Canceled, "canceled"),
+        ] {
+            let json = serde_json::to_value(status).unwrap();
+            assert_eq!(json, serde_json::json!({ "status": name }));
+            assert_eq!(
+                serde_json::from_value::<InvoiceStatus>(json).unwrap(),
+                status
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_payable() {
+        let invoice = test_invoice(Currency::Bitcoin, SystemTime::now());
+        assert_eq!(check_payable(&invoice, Network::Bitcoin), Ok(()));
+        assert_eq!(
+            check_payable(&invoice, Network::Signet),
+            Err(InvalidInvoiceError::NetworkMismatch {
+                invoice: Currency::Bitcoin,
+                federation: Network::Signet,
+            })
+        );
+
+        let expired = test_invoice(
+            Currency::Bitcoin,
+            UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+        );
+        assert_eq!(
+            check_payable(&expired, Network::Bitcoin),
+            Err(InvalidInvoiceError::Expired)
+        );
+    }
+
+    fn test_hop(htlc_minimum_msat: Option<u64>) -> RouteHintHop {
+        let secp = Secp256k1::new();
+        RouteHintHop {
+            src_node_id: SecretKey::from_slice(&[1; 32]).unwrap().public_key(&secp),
+            short_channel_id: 42,
+            fees: RoutingFees {
+                base_msat: 1000,
+                proportional_millionths: 100,
+            },
+            cltv_expiry_delta: 144,
+            htlc_minimum_msat,
+            htlc_maximum_msat: Some(1_000_000),
+        }
+    }
+
+    #[test]
+    fn test_convert_route_hints() {
+        let converted = convert_route_hints(&[RouteHint(vec![test_hop(Some(1))])]).unwrap();
+        assert_eq!(converted.len(), 1);
+        assert_eq!(converted[0].0[0].short_channel_id, 42);
+        assert_eq!(converted[0].0[0].base_msat, 1000);
+        assert_eq!(converted[0].0[0].proportional_millionths, 100);
+
+        assert_eq!(
+            convert_route_hints(&[RouteHint(vec![test_hop(None)]), RouteHint(vec![])]),
+            Err(InvalidRouteHint::Empty { index: 1 })
+        );
+        assert_eq!(
+            convert_route_hints(&[RouteHint(vec![test_hop(None); 13])]),
+            Err(InvalidRouteHint::TooManyHops {
+                index: 0,
+                hops: 13,
+                max: MAX_ROUTE_HINT_HOPS,
+            })
+        );
+        assert_eq!(
+            convert_route_hints(&[RouteHint(vec![test_hop(Some(2_000_000))])]),
+            Err(InvalidRouteHint::InvalidHtlcRange { index: 0 })
+        );
+    }
+
+    #[test]
+    fn test_check_description() {
+        assert_eq!(check_description("coffee ☕", false).unwrap(), "coffee ☕");
+
+        let max = "a".repeat(MAX_DESCRIPTION_LEN);
+        assert_eq!(check_description(&max, false).unwrap(), max);
+
+        // The 4 byte emoji straddles the limit
+        let long = format!("{}🚀", "a".repeat(MAX_DESCRIPTION_LEN - 2));
+        assert_eq!(
+            check_description(&long, false)
+                .unwrap_err()
+                .downcast_ref::<DescriptionTooLong>(),
+            Some(&DescriptionTooLong {
+                max: MAX_DESCRIPTION_LEN,
+                got: MAX_DESCRIPTION_LEN + 2,
+            })
+        );
+        assert_eq!(
+            check_description(&long, true).unwrap(),
+            "a".repeat(MAX_DESCRIPTION_LEN - 2)
+        );
+
+        let emojis = "🚀".repeat(200);
+        let truncated = check_description(&emojis, true).unwrap();
+        assert_eq!(truncated, "🚀".repeat(MAX_DESCRIPTION_LEN / 4));
+        assert!(Description::new(truncated.to_owned()).is_ok());
+
+        assert_eq!(
+            check_description("line\nbreak", false)
+                .unwrap_err()
+                .downcast_ref::<InvalidDescription>(),
+            Some(&InvalidDescription {
+                character: '\n',
+                position: 4,
+            })
+        );
+        // Control characters cut off by truncation don't matter
+        let trailing = format!("{}\u{7}", "a".repeat(MAX_DESCRIPTION_LEN));
+        assert!(check_description(&trailing, true).is_ok());
+        assert!(check_description(&trailing, false).is_err());
+    }
+}
@@ -16,7 +16,7 @@
 //!
 //! ```no_run
 //! # use anyhow::Result;
-//! use blitzi::{Amount, Blitzi};
+//! use blitzi::{Blitzi, msats};
 //!
 //! # #[tokio::main]
 //! # async fn main() -> Result<()> {
@@ -25,7 +25,7 @@
 //!
 //! // Generate a new Lightning invoice for 1000 millisatoshi and await its payment
 //! let invoice = blitzi
-//!     .lightning_invoice(Amount::from_msats(1000), "Test payment")
+//!     .lightning_invoice(msats(1000), "Test payment")
 //!     .await?;
 //! println!("Invoice: {}", invoice);
 //!
@@ -38,6 +38,24 @@
 //! # }
 //! ```
 //!
+//! # Amounts
+//! Amounts are [`Amount`]s, which count millisatoshi (msat): 1 sat = 1000
+//! msat. Construct them using [`sats`] or [`msats`] rather than
+//! [`Amount::from_msats`] to make the unit visible at the call site, e.g.
+//! `sats(21)` and `msats(21_000)` are the same amount. Methods taking an
+//! amount accept `impl Into<Amount>`, the few taking plain numbers say so in
+//! their name, e.g. [`Blitzi::lightning_invoice_sats`]. Use [`format_sats`]
+//! or [`format_btc`] to display amounts.
+//!
+//! # Dependencies
+//! All types from Fedimint and `lightning-invoice` that appear in Blitzi's API,
+//! such as [`Bolt11Invoice`], [`Amount`], [`InviteCode`] and [`OperationId`],
+//! are reexported by Blitzi. Use these reexports instead of adding direct
+//! dependencies on the underlying crates: if your copy of a crate has a
+//! different version than Blitzi's, its types are incompatible with Blitzi's
+//! and compilation fails with confusing errors like "expected
+//! `Bolt11Invoice`, found `Bolt11Invoice`".
+//!
 //! # Fedimint
 //! Blitzi uses Fedimint, an open source federated ecash mint implementation on
 //! Bitcoin, to connect you to the Lighning network. Federated in this context
@@ -61,38 +79,182 @@
 //!
 //! Lightning bolts are called "Blitz" in German and adding an "i" at the end
 //! makes it sound cute and wholesome for me :D
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use anyhow::{Context, anyhow, ensure};
-use fedimint_bip39::{Bip39RootSecretStrategy, Mnemonic};
+use anyhow::{Context, anyhow, bail, ensure};
+use fedimint_bip39::Bip39RootSecretStrategy;
 use fedimint_client::meta::MetaService;
 use fedimint_client::module::meta::LegacyMetaSource;
+use fedimint_client::oplog::OperationLogEntry;
 use fedimint_client::secret::RootSecretStrategy;
-use fedimint_client::{Client, ClientHandle, ClientModuleInstance, RootSecret};
+use fedimint_client::{
+    Client, ClientHandle, ClientHandleArc, ClientModuleInstance, ClientPreview, RootSecret,
+};
+use fedimint_core::bitcoin::Network;
 use fedimint_core::bitcoin::hashes::sha256;
-use fedimint_core::core::OperationId;
-use fedimint_core::db::{Database, IRawDatabaseExt};
-use fedimint_core::invite_code::InviteCode;
+use fedimint_core::secp256k1::PublicKey;
+use fedimint_core::task::TaskGroup;
 use fedimint_core::util::BoxStream;
-use fedimint_core::{BitcoinHash, anyhow, hex};
+use fedimint_core::{BitcoinHash, anyhow};
 use fedimint_ln_client::{
-    LightningClientInit, LightningClientModule, LightningOperationMeta, LightningOperationMetaPay,
-    LightningOperationMetaVariant, LnReceiveState, PayType,
+    InternalPayState, LightningClientInit, LightningClientModule, LightningOperationMeta,
+    LightningOperationMetaPay, LightningOperationMetaVariant, LnPayState, LnReceiveState, PayType,
 };
+use fedimint_lnv2_client::{ReceiveOperationState, SendOperationState, SendPaymentError};
 use fedimint_meta_client::MetaModuleMetaSourceWithFallback;
-use fedimint_mint_client::MintClientInit;
+use fedimint_mint_client::{
+    MintClientInit, MintClientModule, OOBNotes, ReissueExternalNotesState,
+    SelectNotesWithAtleastAmount, SelectNotesWithExactAmount, SpendOOBState,
+};
 use futures_lite::stream::StreamExt;
-use lightning_invoice::{Bolt11Invoice, Bolt11InvoiceDescription, Description};
-use tracing::info;
+use lightning_invoice::{Bolt11InvoiceDescription, Description, RouteHint};
+use tracing::{debug, info, warn};
 
+mod account;
+mod amount;
+mod backend;
+mod balance;
+mod balance_cap;
+mod cancel;
+mod capabilities;
+#[cfg(feature = "capi")]
+mod capi;
+mod config_check;
+mod database;
+mod ecash;
+mod error;
+mod events;
+mod external_id;
+#[cfg(feature = "uniffi")]
+mod ffi;
+mod gateway;
+mod gateway_stats;
+mod history;
+mod idempotency;
+mod invoice;
+#[cfg(feature = "lnurl")]
+mod lnurl;
+mod lnv2;
+#[cfg(feature = "test-util")]
+mod mock;
+mod payment;
+mod policy;
+mod preview;
+mod reclaim;
+mod recovery;
+mod schema;
+mod seed;
+mod serde_util;
+mod spend_limit;
+mod stats;
+mod status;
+#[cfg(feature = "devimint-tests")]
+pub mod testing;
+mod types;
+
+#[cfg(feature = "uniffi")]
+uniffi::setup_scaffolding!();
+
+/// Federation joined if none is set via [`BlitziBuilder::federation`].
+#[cfg(feature = "default-federation")]
 const ECASH_CLUB_INVITE: &str = "fed11qgqzggnhwden5te0v9cxjtn9vd3jue3wvfkxjmnyva6kzunyd9skutnwv46z7qqpyzhv5mxgpl79xz7j649sj6qldmde5s2uxchy4uh7840qgymsqmazzp6sn43";
 
+/// How often operations are pruned if [`BlitziBuilder::auto_prune`] is set.
+const AUTO_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How often pending incoming payments are reclaimed, see
+/// [`Blitzi::reclaim_pending`].
+const RECLAIM_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Default maximum amount an invoice can be created for, 1 BTC.
+pub const DEFAULT_MAX_INVOICE_AMOUNT: Amount = sats(100_000_000);
+
+/// Default of [`BlitziBuilder::join_timeout`].
+pub const DEFAULT_JOIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Expiry of invoices that don't set one, unless the federation announces
+/// another default, see [`Blitzi::default_invoice_expiry`].
+pub const DEFAULT_INVOICE_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Mnemonic of the client's root secret, reexported from fedimint-bip39.
+pub use fedimint_bip39::Mnemonic;
 /// Utility type for amounts in millisatoshi reexported from fedimint-core.
 pub use fedimint_core::Amount;
+/// Bitcoin types such as [`Network`](bitcoin::Network) and
+/// [`sha256`](bitcoin::hashes::sha256) hashes, reexported from fedimint-core.
+pub use fedimint_core::bitcoin;
+/// Id of a federation, reexported from fedimint-core.
+pub use fedimint_core::config::FederationId;
+/// Id of an operation in the operation log, reexported from fedimint-core.
+pub use fedimint_core::core::OperationId;
+/// Database a client can be built on, see [`BlitziBuilder::database`],
+/// reexported from fedimint-core.
+pub use fedimint_core::db::Database;
+/// Federation invite code, reexported from fedimint-core.
+pub use fedimint_core::invite_code::InviteCode;
+/// Keys identifying gateways and Lightning nodes, reexported from
+/// fedimint-core.
+pub use fedimint_core::secp256k1;
 /// Utility module for parsing lightning invoices reexported from
 /// lightning-invoice.
 pub use lightning_invoice;
+/// Lightning invoice type reexported from lightning-invoice.
+pub use lightning_invoice::Bolt11Invoice;
+
+pub use crate::account::BlitziAccount;
+pub use crate::amount::{
+    checked_sats, format_btc, format_btc_on, format_sats, format_sats_on, msats, sats,
+};
+pub use crate::backend::LightningBackend;
+pub use crate::balance_cap::BalanceCap;
+pub use crate::capabilities::{ConsensusVersion, FederationCapabilities};
+#[cfg(feature = "capi")]
+pub use crate::capi::{BlitziHandle, BlitziStatus};
+pub use crate::database::{DatabaseBackend, migrate_database};
+pub use crate::ecash::{SpentEcash, TransferToken};
+pub use crate::error::{
+    AlreadyPaid, BalanceCapExceeded, BalanceTimeout, ConnectTimeout, DatabaseBackendMismatch,
+    DatadirLocked, DescriptionTooLong, EcashAlreadySpent, ExternalIdInUse, FederationIdMismatch,
+    FederationMismatch, FeeTooHigh, GatewayUnavailable, IdempotencyKeyConflict,
+    IncomingPaymentTimedOut, IncompatibleDatabase, InvalidDescription, InvalidInvoiceError,
+    InvalidRouteHint, InvoiceAmountError, JoinTimedOut, LeaveFederationError, LnurlServiceError,
+    NetworkMismatch, NoFederationConfigured, NoLightningModule, PassphraseRequired,
+    PaymentRefunded, PaymentTimedOut, PolicyDenied, SpendLimitExceeded, TransferExpired,
+    UnsupportedByLnv2, WithdrawAmountOutOfRange, WrongPassphrase,
+};
+pub use crate::events::BlitziEvent;
+pub use crate::external_id::MAX_EXTERNAL_ID_LEN;
+#[cfg(feature = "uniffi")]
+pub use crate::ffi::{BlitziFfi, BlitziFfiConfig, BlitziFfiError};
+pub use crate::gateway::{GatewayInfo, GatewaySelection};
+pub use crate::gateway_stats::GatewayStats;
+pub use crate::history::{HistoryEntry, HistoryEntryKind, HistoryEntryStatus, OperationCursor};
+use crate::idempotency::IdempotencyRecord;
+pub use crate::idempotency::{IdempotentInvoice, IdempotentPayment};
+pub use crate::invoice::{
+    CreatedInvoice, InvoiceDescription, InvoiceDetails, InvoiceOptions, InvoiceStatus,
+    MAX_DESCRIPTION_LEN, ReceiveState, ReceivedPayment,
+};
+pub use crate::lnv2::LightningVersion;
+#[cfg(feature = "test-util")]
+pub use crate::mock::{MockCall, MockIncomingPayment, MockLightning, MockPayment};
+use crate::payment::KeyedLocks;
+pub use crate::payment::{
+    DEFAULT_BATCH_CONCURRENCY, PayOptions, PayProgress, PaymentQuote, PaymentReceipt, PaymentResult,
+};
+use crate::policy::PaymentPolicy;
+pub use crate::policy::{PaymentIntent, PolicyDecision};
+pub use crate::preview::{FederationPreview, Guardian};
+pub use crate::recovery::{ModuleRecoveryProgress, RecoveryProgress};
+use crate::spend_limit::SpendLimiter;
+pub use crate::spend_limit::{SpendLimit, SpendWindow};
+pub use crate::stats::{Bucket, FeeBucket, PeriodStats, WalletStats};
+pub use crate::status::WalletStatus;
+pub use crate::types::{PaymentHash, Preimage};
 
 /// Builder for the Blitzi client that allows configuring the fedimint client's
 /// settings.
@@ -112,139 +274,933 @@ pub use lightning_invoice;
 /// # }
 /// ```
 pub struct BlitziBuilder {
-    datadir: PathBuf,
-    federation: InviteCode,
+    datadir: Option<PathBuf>,
+    database: Option<Database>,
+    database_backend: DatabaseBackend,
+    federation: Option<InviteCode>,
+    /// Whether the federation was set via [`Self::federation`] or
+    /// [`Self::federation_invite`] rather than being the default one
+    federation_set: bool,
+    max_invoice_amount: Amount,
+    max_balance: Option<BalanceCap>,
+    truncate_description: bool,
+    gateway_selection: GatewaySelection,
+    auto_prune: Option<Duration>,
+    spend_limit: Option<SpendLimit>,
+    payment_policy: Option<PaymentPolicy>,
+    preferred_lightning_version: LightningVersion,
+    expected_federation_id: Option<FederationId>,
+    encryption_passphrase: Option<String>,
+    restore_mnemonic: Option<Mnemonic>,
+    recover_in_background: bool,
+    config_timeout: Option<Duration>,
+    join_timeout: Duration,
+    gateway_timeout: Option<Duration>,
+    network: Option<Network>,
+    config_check_interval: Option<Duration>,
+    #[cfg(feature = "lnurl")]
+    lnurl_deadline: Duration,
 }
 
 impl Default for BlitziBuilder {
     fn default() -> Self {
-        let xdg = xdg::BaseDirectories::new();
-
         Self {
-            datadir: xdg
-                .data_home
-                .expect("Could not determine XDG data home")
-                .join("fedimint/default"),
-            federation: InviteCode::from_str(ECASH_CLUB_INVITE).expect("can be parsed"),
+            datadir: default_datadir(),
+            database: None,
+            database_backend: DatabaseBackend::default(),
+            federation: default_federation(),
+            federation_set: false,
+            max_invoice_amount: DEFAULT_MAX_INVOICE_AMOUNT,
+            max_balance: None,
+            truncate_description: false,
+            gateway_selection: GatewaySelection::Automatic,
+            auto_prune: None,
+            spend_limit: None,
+            payment_policy: None,
+            preferred_lightning_version: LightningVersion::V2,
+            expected_federation_id: None,
+            encryption_passphrase: None,
+            restore_mnemonic: None,
+            recover_in_background: false,
+            config_timeout: None,
+            join_timeout: DEFAULT_JOIN_TIMEOUT,
+            gateway_timeout: None,
+            network: None,
+            config_check_interval: None,
+            #[cfg(feature = "lnurl")]
+            lnurl_deadline: lnurl::DEFAULT_DEADLINE,
         }
     }
 }
 
+#[cfg(feature = "default-federation")]
+fn default_federation() -> Option<InviteCode> {
+    Some(InviteCode::from_str(ECASH_CLUB_INVITE).expect("can be parsed"))
+}
+
+#[cfg(not(feature = "default-federation"))]
+fn default_federation() -> Option<InviteCode> {
+    None
+}
+
+#[cfg(any(feature = "native", feature = "db-redb"))]
+fn default_datadir() -> Option<PathBuf> {
+    datadir_in(xdg::BaseDirectories::new().data_home)
+}
+
+/// Returns the default data directory inside the XDG data home, `None` if
+/// it couldn't be determined (e.g. neither `XDG_DATA_HOME` nor `HOME` is
+/// set), in which case [`BlitziBuilder::datadir`] has to be used.
+#[cfg(any(feature = "native", feature = "db-redb"))]
+fn datadir_in(data_home: Option<PathBuf>) -> Option<PathBuf> {
+    Some(data_home?.join("fedimint/default"))
+}
+
+#[cfg(not(any(feature = "native", feature = "db-redb")))]
+fn default_datadir() -> Option<PathBuf> {
+    None
+}
+
 impl BlitziBuilder {
     /// Sets the directory where Fedimint data will be stored. Defaults to
-    /// `$XDG_DATA_HOME/fedimint/default`
+    /// `$XDG_DATA_HOME/fedimint/default`, building fails if neither this
+    /// was called nor the XDG data home could be determined.
+    #[cfg(any(feature = "native", feature = "db-redb"))]
     pub fn datadir(mut self, path: impl Into<PathBuf>) -> Self {
-        self.datadir = path.into();
+        self.datadir = Some(path.into());
+        self
+    }
+
+    /// Sets the database used to store the wallet in the
+    /// [data directory](Self::datadir). Defaults to
+    /// [`DatabaseBackend::RocksDb`] if the `native` feature is enabled, and to
+    /// [`DatabaseBackend::Redb`] (which requires the `db-redb` feature)
+    /// otherwise.
+    ///
+    /// Data directories containing a database of a different backend are
+    /// rejected with a [`DatabaseBackendMismatch`] error, use
+    /// [`migrate_database`] to switch the backend of an existing wallet.
+    #[cfg(any(feature = "native", feature = "db-redb"))]
+    pub fn database_backend(mut self, backend: DatabaseBackend) -> Self {
+        self.database_backend = backend;
+        self
+    }
+
+    /// Uses the given database instead of opening a database in the
+    /// [data directory](Self::datadir). This is required on targets without
+    /// the `native` or `db-redb` feature (e.g. wasm), where you can pass an
+    /// in-memory database (`fedimint_core::db::mem_impl::MemDatabase`) or
+    /// any other persistent database implementation available for your
+    /// platform.
+    pub fn database(mut self, database: Database) -> Self {
+        self.database = Some(database);
         self
     }
 
     /// Sets the federation to connect to via an already parsed invite code. If
     /// you have a string invite code, use [`Self::federation`] instead.
     pub fn federation_invite(mut self, invite: InviteCode) -> Self {
-        self.federation = invite;
+        self.federation = Some(invite);
+        self.federation_set = true;
         self
     }
 
     /// Sets the federation to connect to via an invite code string. If you
     /// already have a parsed invite code, use [`Self::federation_invite`]
     /// instead.
+    ///
+    /// Defaults to the E-Cash Club federation if the `default-federation`
+    /// feature is enabled (the default). Without it a federation has to be
+    /// set to join one, otherwise [`Self::build`] and [`Self::preview`] fail
+    /// with a [`NoFederationConfigured`] error.
+    ///
+    /// If the data directory already holds a wallet, the federation is only
+    /// used to check that the wallet belongs to it, [`Self::build`] fails with
+    /// a [`FederationMismatch`] error otherwise.
     pub fn federation(mut self, invite: &str) -> anyhow::Result<Self> {
         let invite = InviteCode::from_str(invite)?;
-        self.federation = invite;
+        self.federation = Some(invite);
+        self.federation_set = true;
         Ok(self)
     }
 
+    /// Sets the Bitcoin network the federation has to operate on, e.g.
+    /// [`Network::Signet`] when developing against a Mutinynet federation.
+    /// [`Self::build`] and [`Self::preview`] fail with a [`NetworkMismatch`]
+    /// error if the federation (or the wallet in the data directory) is on a
+    /// different network, so a production build can't accidentally use test
+    /// funds or vice versa. By default any network is accepted.
+    ///
+    /// The default federation operates on mainnet, so for any other network it
+    /// is unset and a federation has to be set using [`Self::federation`]. No
+    /// test network federation is bundled since none is maintained for long
+    /// enough to hard-code its invite code.
+    pub fn network(mut self, network: Network) -> Self {
+        if network != Network::Bitcoin && !self.federation_set {
+            self.federation = None;
+        }
+        self.network = Some(network);
+        self
+    }
+
+    /// Makes [`Self::build`] and [`Self::preview`] verify that the
+    /// [federation invite](Self::federation) leads to the federation with the
+    /// given id, e.g. to detect invite codes that were swapped in transit. The
+    /// id is checked against the config fetched from the federation before it
+    /// is joined, and against the config of an already joined client when it
+    /// is opened. A mismatch results in a [`FederationIdMismatch`] error.
+    ///
+    /// To only check the id contained in an invite code without contacting the
+    /// federation use [`verify_invite`].
+    pub fn expect_federation_id(mut self, federation_id: FederationId) -> Self {
+        self.expected_federation_id = Some(federation_id);
+        self
+    }
+
+    /// Sets the maximum amount [`Blitzi::lightning_invoice`] will create
+    /// invoices for. This is a sanity check to catch unit mistakes (e.g.
+    /// passing sats where millisatoshi are expected). Defaults to
+    /// [`DEFAULT_MAX_INVOICE_AMOUNT`] (1 BTC).
+    pub fn max_invoice_amount(mut self, max: impl Into<Amount>) -> Self {
+        self.max_invoice_amount = max.into();
+        self
+    }
+
+    /// Caps the balance held in the federation, either at a fixed amount or at
+    /// the maximum the federation announces in its meta data. Invoices that
+    /// would push the balance above the cap if paid are rejected with a
+    /// [`BalanceCapExceeded`] error. Disabled by default.
+    ///
+    /// The cap is checked against the balance at the time the invoice is
+    /// created, so paying several unpaid invoices can still exceed it.
+    pub fn max_balance(mut self, cap: BalanceCap) -> Self {
+        self.max_balance = Some(cap);
+        self
+    }
+
+    /// Makes [`Blitzi::lightning_invoice`] shorten descriptions that don't fit
+    /// into an invoice (639 bytes) instead of rejecting them with a
+    /// [`DescriptionTooLong`] error. Descriptions are cut off at a character
+    /// boundary, so they stay valid UTF-8. Disabled by default.
+    pub fn truncate_description(mut self, truncate: bool) -> Self {
+        self.truncate_description = truncate;
+        self
+    }
+
+    /// Sets how the gateway is chosen for invoices and payments that don't
+    /// select one explicitly, e.g. to always use the cheapest gateway. Defaults
+    /// to [`GatewaySelection::Automatic`].
+    ///
+    /// The outcome of every outgoing payment is recorded per gateway in the
+    /// client database, which [`GatewaySelection::MostReliable`] ranks
+    /// gateways by.
+    ///
+    /// Only applies to the `ln` module, the `lnv2` module chooses gateways on
+    /// its own, see [`Self::preferred_lightning_version`].
+    pub fn gateway_selection(mut self, selection: GatewaySelection) -> Self {
+        self.gateway_selection = selection;
+        self
+    }
+
+    /// Periodically removes finished operations older than `retention` from
+    /// the operation log in the background, see [`Blitzi::prune_operations`].
+    /// Disabled by default.
+    pub fn auto_prune(mut self, retention: Duration) -> Self {
+        self.auto_prune = Some(retention);
+        self
+    }
+
+    /// Periodically checks in the background whether the federation's config
+    /// changed, see [`Blitzi::check_federation_config`]. Disabled by
+    /// default.
+    pub fn config_check_interval(mut self, interval: Duration) -> Self {
+        self.config_check_interval = Some(interval);
+        self
+    }
+
+    /// Enforces a [`SpendLimit`] on outgoing payments, as a safety rail when
+    /// payments are made automatically. Payments exceeding it are rejected with
+    /// a [`SpendLimitExceeded`] error before they are started. Disabled by
+    /// default.
+    ///
+    /// Payments count towards the rolling window with the amount of their
+    /// invoice (excluding fees) once they are started, and are credited back
+    /// if they fail. Started payments are recorded in the client database so
+    /// restarting the client doesn't reset the window.
+    pub fn spend_limit(mut self, limit: SpendLimit) -> Self {
+        self.spend_limit = Some(limit);
+        self
+    }
+
+    /// Consults `policy` before every outgoing payment, e.g. to only allow
+    /// paying a set of known nodes. Payments it denies are rejected with a
+    /// [`PolicyDenied`] error before any operation is created.
+    ///
+    /// The policy is consulted for retries too (paying the same invoice again
+    /// or reusing an idempotency key), so tightening it also stops retries of
+    /// payments that were allowed before.
+    pub fn payment_policy(
+        mut self,
+        policy: impl Fn(&PaymentIntent) -> PolicyDecision + Send + Sync + 'static,
+    ) -> Self {
+        self.payment_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Sets which Lightning module to use if the federation supports both the
+    /// original `ln` module and the newer `lnv2` module. Defaults to
+    /// [`LightningVersion::V2`]. If the federation only supports one of them
+    /// that one is used regardless of this setting.
+    ///
+    /// Invoices and payments created by either module can be looked up by
+    /// payment hash no matter which module is currently used, so changing this
+    /// setting for an existing data directory is safe.
+    pub fn preferred_lightning_version(mut self, version: LightningVersion) -> Self {
+        self.preferred_lightning_version = version;
+        self
+    }
+
+    /// Encrypts the wallet's seed at rest with `passphrase`. A new wallet
+    /// stores its seed encrypted (XChaCha20-Poly1305 with a key derived from
+    /// the passphrase using Argon2id), and an existing wallet with an encrypted
+    /// seed can only be opened with the same passphrase. Not set by default,
+    /// which stores the seed unencrypted.
+    ///
+    /// Setting a passphrase doesn't encrypt the seed of an existing wallet,
+    /// use [`Blitzi::encrypt_existing_secret`] to do so.
+    ///
+    /// **This only protects the seed.** The ecash notes and the operation
+    /// history are still stored unencrypted, so anyone with access to the
+    /// data directory can spend the balance held by the wallet, but can't
+    /// recover it from the mnemonic or access funds received later.
+    pub fn encryption_passphrase(mut self, passphrase: &str) -> Self {
+        self.encryption_passphrase = Some(passphrase.to_owned());
+        self
+    }
+
+    /// Restores the wallet backed up using [`Blitzi::mnemonic`] instead of
+    /// creating a new one when joining the federation. The ecash notes of the
+    /// wallet are recovered from the federation, which may take a while for
+    /// wallets with a long history, and [`Self::build`] only returns once
+    /// recovery is complete, unless [`Self::recover_in_background`] is set.
+    ///
+    /// Has no effect on an already initialized data directory, other than
+    /// [`Self::build`] failing if it contains a different wallet.
+    pub fn restore_from_mnemonic(mut self, mnemonic: Mnemonic) -> Self {
+        self.restore_mnemonic = Some(mnemonic);
+        self
+    }
+
+    /// Makes [`Self::build`] return right away when
+    /// [restoring a wallet](Self::restore_from_mnemonic) instead of waiting
+    /// for its recovery to complete, e.g. to show the recovery progress using
+    /// [`Blitzi::subscribe_recovery`]. Disabled by default.
+    ///
+    /// The balance and history are incomplete until the recovery is done, use
+    /// [`Blitzi::await_recovery`] to wait for it before using the wallet.
+    pub fn recover_in_background(mut self, background: bool) -> Self {
+        self.recover_in_background = background;
+        self
+    }
+
+    /// Limits how long fetching the federation's config may take, e.g. to
+    /// fail fast on flaky mobile networks. [`Self::build`] and
+    /// [`Self::preview`] fail with a [`ConnectTimeout`] error naming the
+    /// guardians from the invite code if they don't serve it in time, and
+    /// [`Blitzi::check_federation_config`] fails as well. By default they
+    /// wait until the Fedimint client gives up, which can take minutes if the
+    /// guardians are unreachable.
+    ///
+    /// Only fetching the config is limited, other requests use the timeouts
+    /// of the Fedimint client. Opening an already joined wallet doesn't
+    /// contact the federation and is unaffected.
+    pub fn config_timeout(mut self, timeout: Duration) -> Self {
+        self.config_timeout = Some(timeout);
+        self
+    }
+
+    /// Limits how long joining a federation may take, from fetching its
+    /// config to storing the new wallet, so a "Join Federation" screen can
+    /// fail fast and offer a retry instead of hanging while the guardians are
+    /// unreachable. [`Self::build`], [`Self::preview`] and
+    /// [`FederationPreview::join`] fail with a [`JoinTimedOut`] error once it
+    /// is exceeded. Defaults to [`DEFAULT_JOIN_TIMEOUT`] (60 seconds).
+    ///
+    /// If joining fails or times out, the keys it added to the database are
+    /// removed again, so the next attempt starts from scratch. Keys that were
+    /// in a [provided database](Self::database) before are never touched.
+    /// Waiting for the recovery of a
+    /// [restored wallet](Self::restore_from_mnemonic) isn't limited, and
+    /// neither is opening an already joined wallet.
+    pub fn join_timeout(mut self, timeout: Duration) -> Self {
+        self.join_timeout = timeout;
+        self
+    }
+
+    /// Limits how long fetching the details of a gateway from the federation
+    /// may take when creating an invoice, paying or quoting a payment using
+    /// the `ln` module. Calls whose lookup times out fail with an error. Not
+    /// limited by default.
+    ///
+    /// Only the gateway lookup is limited, other requests, e.g. submitting
+    /// transactions or awaiting their outcome, use the timeouts of the
+    /// Fedimint client.
+    pub fn gateway_timeout(mut self, timeout: Duration) -> Self {
+        self.gateway_timeout = Some(timeout);
+        self
+    }
+
+    /// Limits how long requests to LNURL services, e.g. by
+    /// [`Blitzi::claim_lnurl_withdraw`], are retried. Timeouts, connection
+    /// errors and server errors are retried with exponential backoff until
+    /// the deadline has passed since the first attempt, other errors fail
+    /// right away. Defaults to 30 seconds. Requires the `lnurl` feature.
+    #[cfg(feature = "lnurl")]
+    pub fn lnurl_deadline(mut self, deadline: Duration) -> Self {
+        self.lnurl_deadline = deadline;
+        self
+    }
+
     /// Builds the Blitzi client.
     ///
     /// This function will open the existing Fedimint client or join the
     /// federation depending on whether the client has already been initialized.
+    /// To show the federation to the user before joining it use
+    /// [`Self::preview`] instead.
     ///
     /// # Errors
-    /// Returns an error if the database cannot be opened or if joining the
-    /// federation fails.
+    /// Returns a [`DatadirLocked`] error if the data directory is in use by
+    /// another process, an [`IncompatibleDatabase`] error if it was written
+    /// by a newer version of Blitzi, a [`NoLightningModule`] error if the
+    /// federation supports neither Lightning module, a
+    /// [`FederationIdMismatch`] error if it isn't the
+    /// [expected one](Self::expect_federation_id), a [`FederationMismatch`]
+    /// error if the data directory holds a wallet of another federation than
+    /// the [one set](Self::federation), a [`NetworkMismatch`]
+    /// error if it isn't on the [expected network](Self::network), a
+    /// [`PassphraseRequired`] or
+    /// [`WrongPassphrase`] error if the wallet's seed is encrypted and no or a
+    /// different [passphrase](Self::encryption_passphrase) was set, an error
+    /// if the wallet isn't the one [being
+    /// restored](Self::restore_from_mnemonic), a [`NoFederationConfigured`]
+    /// error if a federation would have to be joined but [none is
+    /// set](Self::federation), a [`ConnectTimeout`] error if it can't be
+    /// reached within the [config timeout](Self::config_timeout), a
+    /// [`JoinTimedOut`] error if joining it takes longer than the [join
+    /// timeout](Self::join_timeout), and an error if the database
+    /// cannot be opened for any other reason or if
+    /// joining the federation fails. Without the `native` feature an error
+    /// is returned if no database was provided via [`Self::database`].
     pub async fn build(self) -> anyhow::Result<Blitzi> {
-        let mut client_builder = fedimint_client::Client::builder().await?;
-        client_builder.with_module(MintClientInit);
-        client_builder.with_module(LightningClientInit::default());
-        let mut client_builder = client_builder.with_iroh_enable_next(false);
-        client_builder.with_meta_service(MetaService::new(MetaModuleMetaSourceWithFallback::<
-            LegacyMetaSource,
-        >::default()));
-
-        info!("Opening database: {:?}", self.datadir);
-        let db = fedimint_rocksdb::RocksDb::open(self.datadir)
-            .await?
-            .into_database();
+        let (db, datadir, location) = self.open_database().await?;
 
-        // TODO: use config being present to decide if to open or join
-        let client = if let Some(root_secret) = try_load_root_secret(&db).await? {
-            client_builder.open(db, root_secret).await?
-        } else {
-            let root_secret = generate_root_secret(&db).await?;
-            client_builder
-                .preview(&self.federation)
+        // A stored seed means the federation was joined before
+        let passphrase = self.encryption_passphrase.as_deref();
+        let (client, mnemonic) = if let Some(mnemonic) = seed::load(&db, passphrase).await? {
+            let client = client_builder()
                 .await?
-                .join(db, root_secret)
+                .open(db, root_secret(&mnemonic))
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to open the Fedimint client in {}. It may have been created by an \
+                         incompatible version of blitzi (this is blitzi {}), try upgrading blitzi \
+                         or restore your wallet from its mnemonic into a fresh data directory",
+                        location,
+                        env!("CARGO_PKG_VERSION")
+                    )
+                })?;
+            if self
+                .restore_mnemonic
+                .as_ref()
+                .is_some_and(|restore| *restore != mnemonic)
+            {
+                client.shutdown().await;
+                bail!(
+                    "A different wallet was already initialized in {}, restore the mnemonic into \
+                     a fresh data directory",
+                    location
+                );
+            }
+            if let Err(e) = self.check_stored_federation(client.federation_id()) {
+                client.shutdown().await;
+                return Err(e.into());
+            }
+            (client, mnemonic)
+        } else {
+            let invite = self.federation.as_ref().ok_or(NoFederationConfigured)?;
+            self.check_federation_id(invite.federation_id())?;
+            let join = async {
+                let preview = self.fetch_preview(invite).await?;
+                // Don't join federations that can't or shouldn't be used
+                self.check_federation_id(preview.config().global.calculate_federation_id())?;
+                self.check_network(FederationCapabilities::from_config(preview.config())?.network)?;
+                self.join_or_restore(preview, db.clone()).await
+            };
+            let joined = self.cleanup_failed_join(&db, join).await?;
+            self.await_recovery(joined).await?
+        };
+
+        self.finish(client, datadir, mnemonic).await
+    }
+
+    /// Fetches the federation's config without joining it, e.g. to show the
+    /// user which federation they are about to join. Call
+    /// [`FederationPreview::join`] to join it using the fetched config, or
+    /// drop the preview to cancel.
+    ///
+    /// Previewing contacts the federation and therefore needs network access,
+    /// but doesn't touch the data directory (or the provided database), so
+    /// cancelling leaves no state behind.
+    ///
+    /// # Errors
+    /// Returns an error if the federation can't be reached or its config is
+    /// invalid, a [`NoLightningModule`] error if it supports neither
+    /// Lightning module, a [`FederationIdMismatch`] error if it isn't the
+    /// [expected one](Self::expect_federation_id), a [`NetworkMismatch`]
+    /// error if it isn't on the [expected network](Self::network), a
+    /// [`ConnectTimeout`] error if it can't be reached within the
+    /// [config timeout](Self::config_timeout), a [`JoinTimedOut`] error if
+    /// fetching the config takes longer than the [join
+    /// timeout](Self::join_timeout) and a [`NoFederationConfigured`] error if
+    /// [none is set](Self::federation).
+    pub async fn preview(self) -> anyhow::Result<FederationPreview> {
+        let invite = self.federation.as_ref().ok_or(NoFederationConfigured)?;
+        self.check_federation_id(invite.federation_id())?;
+        let preview = self.with_join_timeout(self.fetch_preview(invite)).await?;
+        self.check_federation_id(preview.config().global.calculate_federation_id())?;
+        self.check_network(FederationCapabilities::from_config(preview.config())?.network)?;
+        FederationPreview::new(self, preview)
+    }
+
+    /// Fetches the config of the federation `invite` is for, giving up after
+    /// the [config timeout](Self::config_timeout).
+    async fn fetch_preview(&self, invite: &InviteCode) -> anyhow::Result<ClientPreview> {
+        let preview = async { client_builder().await?.preview(invite).await };
+        let Some(timeout) = self.config_timeout else {
+            return preview.await;
+        };
+
+        fedimint_core::runtime::timeout(timeout, preview)
+            .await
+            .map_err(|_| {
+                anyhow::Error::from(ConnectTimeout {
+                    timeout,
+                    endpoints: invite.peers().values().map(ToString::to_string).collect(),
+                })
+            })?
+    }
+
+    /// Joins the federation previewed using [`Self::preview`] without fetching
+    /// its config again.
+    pub(crate) async fn join_preview(self, preview: ClientPreview) -> anyhow::Result<Blitzi> {
+        let (db, datadir, location) = self.open_database().await?;
+        ensure!(
+            Client::load_decodable_client_secret_opt::<Vec<u8>>(&db)
                 .await?
+                .is_none(),
+            "A wallet was already initialized in {}, use BlitziBuilder::build to open it",
+            location
+        );
+
+        let join = self.join_or_restore(preview, db.clone());
+        let joined = self.cleanup_failed_join(&db, join).await?;
+        let (client, mnemonic) = self.await_recovery(joined).await?;
+        self.finish(client, datadir, mnemonic).await
+    }
+
+    /// Joins the previewed federation with a new wallet, or starts restoring
+    /// the one set via [`Self::restore_from_mnemonic`].
+    async fn join_or_restore(
+        &self,
+        preview: ClientPreview,
+        db: Database,
+    ) -> anyhow::Result<(ClientHandle, Mnemonic)> {
+        let passphrase = self.encryption_passphrase.as_deref();
+        let Some(mnemonic) = self.restore_mnemonic.clone() else {
+            let mnemonic = seed::generate(&db, passphrase).await?;
+            return Ok((preview.join(db, root_secret(&mnemonic)).await?, mnemonic));
+        };
+
+        seed::store(&db, &mnemonic, passphrase).await?;
+        let backup = preview
+            .download_backup_from_federation(root_secret(&mnemonic))
+            .await?;
+        let client = preview.recover(db, root_secret(&mnemonic), backup).await?;
+        info!("Recovering the wallet from its mnemonic");
+        Ok((client, mnemonic))
+    }
+
+    /// Waits for the recovery of a [restored
+    /// wallet](Self::restore_from_mnemonic) unless it should [continue in the
+    /// background](Self::recover_in_background).
+    async fn await_recovery(
+        &self,
+        (client, mnemonic): (ClientHandle, Mnemonic),
+    ) -> anyhow::Result<(ClientHandle, Mnemonic)> {
+        if self.restore_mnemonic.is_some() && !self.recover_in_background {
+            client.wait_for_all_recoveries().await?;
+            info!("Recovered the wallet");
+        }
+        Ok((client, mnemonic))
+    }
+
+    /// Runs `join` within the [join timeout](Self::join_timeout). If it fails
+    /// or times out, the keys it added to `db` are removed, so a half
+    /// initialized wallet doesn't prevent the next attempt. Anything that was
+    /// in `db` before, e.g. data of the application that
+    /// [provided it](Self::database), is kept.
+    async fn cleanup_failed_join<T>(
+        &self,
+        db: &Database,
+        join: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        let existing = database::keys(db).await?;
+        let result = self.with_join_timeout(join).await;
+        if result.is_err() {
+            if let Err(e) = database::remove_keys_except(db, &existing).await {
+                warn!(error = %e, "Failed to clean up after joining the federation failed");
+            }
+        }
+        result
+    }
+
+    /// Runs `future`, failing with a [`JoinTimedOut`] error if it doesn't
+    /// finish within the [join timeout](Self::join_timeout).
+    async fn with_join_timeout<T>(
+        &self,
+        future: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        fedimint_core::runtime::timeout(self.join_timeout, future)
+            .await
+            .map_err(|_| {
+                anyhow::Error::from(JoinTimedOut {
+                    timeout: self.join_timeout,
+                })
+            })?
+    }
+
+    /// Checks `network` against the one set via [`Self::network`], if any.
+    fn check_network(&self, network: Network) -> Result<(), NetworkMismatch> {
+        match self.network {
+            Some(expected) if expected != network => Err(NetworkMismatch {
+                expected,
+                got: network,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks `federation_id` against the one set via
+    /// [`Self::expect_federation_id`], if any.
+    fn check_federation_id(&self, federation_id: FederationId) -> Result<(), FederationIdMismatch> {
+        match self.expected_federation_id {
+            Some(expected) if expected != federation_id => Err(FederationIdMismatch {
+                expected,
+                got: federation_id,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that an existing wallet of the federation `stored` belongs to the
+    /// federation that was [set](Self::federation). The default federation
+    /// isn't checked unless it was set explicitly, so wallets of other
+    /// federations can be opened without passing their invite code again.
+    fn check_stored_federation(&self, stored: FederationId) -> Result<(), FederationMismatch> {
+        match &self.federation {
+            Some(invite) if self.federation_set => {
+                let requested = invite.federation_id();
+                if requested == stored {
+                    Ok(())
+                } else {
+                    Err(FederationMismatch { stored, requested })
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Opens the configured database and checks that it can be used. Returns
+    /// the database, the data directory it was opened from and a description
+    /// of its location for error messages.
+    async fn open_database(&self) -> anyhow::Result<(Database, Option<PathBuf>, String)> {
+        let (db, datadir) = match &self.database {
+            Some(db) => (db.clone(), None),
+            None => (
+                open_datadir(self.datadir.clone(), self.database_backend).await?,
+                self.datadir.clone(),
+            ),
+        };
+
+        let location = match &datadir {
+            Some(datadir) => format!("data directory {}", datadir.display()),
+            None => "the provided database".to_string(),
+        };
+        schema::check_schema_version(&db)
+            .await
+            .with_context(|| format!("Can't use {}", location))?;
+
+        Ok((db, datadir, location))
+    }
+
+    /// Wraps an opened or joined Fedimint client and starts the background
+    /// tasks.
+    async fn finish(
+        self,
+        client: ClientHandle,
+        datadir: Option<PathBuf>,
+        mnemonic: Mnemonic,
+    ) -> anyhow::Result<Blitzi> {
+        if let Err(e) = self.check_federation_id(client.federation_id()) {
+            client.shutdown().await;
+            return Err(e.into());
+        }
+
+        let capabilities = FederationCapabilities::from_config(&client.config().await)?;
+        if let Err(e) = self.check_network(capabilities.network) {
+            client.shutdown().await;
+            return Err(e.into());
+        }
+        let lightning_version =
+            lnv2::choose_version(&capabilities, self.preferred_lightning_version)?;
+
+        let client = Arc::new(client);
+        let task_group = TaskGroup::new();
+        let recovery = recovery::track(&client, &task_group).await;
+        let blitzi = Blitzi {
+            client,
+            datadir,
+            task_group,
+            recovery,
+            events: tokio::sync::broadcast::channel(events::EVENT_BUFFER).0,
+            payment_locks: KeyedLocks::default(),
+            watched_payments: Arc::default(),
+            watched_invoices: Arc::default(),
+            idempotency_locks: KeyedLocks::default(),
+            max_invoice_amount: self.max_invoice_amount,
+            max_balance: self.max_balance,
+            truncate_description: self.truncate_description,
+            gateway_selection: self.gateway_selection,
+            gateway_timeout: self.gateway_timeout,
+            config_timeout: self.config_timeout,
+            #[cfg(feature = "lnurl")]
+            lnurl_deadline: self.lnurl_deadline,
+            spend_limit: self.spend_limit.map(SpendLimiter::new),
+            payment_policy: self.payment_policy,
+            lightning_version,
+            network: capabilities.network,
+            mnemonic: Some(mnemonic),
+            accounts: tokio::sync::Mutex::default(),
+            account_locks: KeyedLocks::default(),
         };
+        blitzi.start_background_tasks().await?;
+        if let Some(retention) = self.auto_prune {
+            blitzi.spawn_auto_prune(retention);
+        }
+        if let Some(interval) = self.config_check_interval {
+            blitzi.spawn_config_check(interval);
+        }
+
+        Ok(blitzi)
+    }
+}
+
+/// Checks that `invite` is for the federation with the `expected` id without
+/// contacting the federation. This only checks the id contained in the invite
+/// code, use [`BlitziBuilder::expect_federation_id`] to also verify it against
+/// the config the federation serves.
+///
+/// # Errors
+/// Returns a [`FederationIdMismatch`] error if the ids don't match.
+pub fn verify_invite(invite: &InviteCode, expected: FederationId) -> anyhow::Result<()> {
+    let got = invite.federation_id();
+    if got != expected {
+        return Err(FederationIdMismatch { expected, got }.into());
+    }
+    Ok(())
+}
+
+/// Fetches the config of the federation the `invite` code is for without
+/// joining it, e.g. to show a confirmation screen before building a client.
+/// Nothing is written to disk, this is a shortcut for previewing with a
+/// default [`BlitziBuilder`], so [`FederationPreview::join`] joins using the
+/// default data directory. Use [`BlitziBuilder::preview`] to customize the
+/// client joining the federation.
+///
+/// # Errors
+/// Returns an error if the invite code is invalid, and otherwise the same
+/// errors as [`BlitziBuilder::preview`].
+pub async fn preview_federation(invite: &str) -> anyhow::Result<FederationPreview> {
+    Blitzi::builder().federation(invite)?.preview().await
+}
+
+/// Returns a Fedimint client builder with the modules Blitzi uses.
+async fn client_builder() -> anyhow::Result<fedimint_client::ClientBuilder> {
+    let mut client_builder = fedimint_client::Client::builder().await?;
+    client_builder.with_module(MintClientInit);
+    client_builder.with_module(LightningClientInit::default());
+    client_builder.with_module(fedimint_lnv2_client::LightningClientInit::default());
+    let mut client_builder = client_builder.with_iroh_enable_next(false);
+    client_builder.with_meta_service(MetaService::new(MetaModuleMetaSourceWithFallback::<
+        LegacyMetaSource,
+    >::default()));
+    Ok(client_builder)
+}
+
+#[cfg(any(feature = "native", feature = "db-redb"))]
+async fn open_datadir(
+    datadir: Option<PathBuf>,
+    backend: DatabaseBackend,
+) -> anyhow::Result<Database> {
+    let datadir = datadir.context(
+        "No data directory configured and the XDG data home could not be determined, use \
+         BlitziBuilder::datadir to set one",
+    )?;
 
-        Ok(Blitzi { client })
+    info!("Opening {} database: {:?}", backend, datadir);
+    match database::open(&datadir, backend).await {
+        Ok(db) => Ok(db),
+        Err(e) if is_lock_error(&e) => Err(DatadirLocked { path: datadir }.into()),
+        Err(e) => Err(e),
     }
 }
 
-async fn try_load_root_secret(db: &Database) -> anyhow::Result<Option<RootSecret>> {
-    let Some(entropy) = Client::load_decodable_client_secret_opt::<Vec<u8>>(db).await? else {
-        return Ok(None);
-    };
+#[cfg(not(any(feature = "native", feature = "db-redb")))]
+async fn open_datadir(
+    _datadir: Option<PathBuf>,
+    _backend: DatabaseBackend,
+) -> anyhow::Result<Database> {
+    Err(anyhow!(
+        "No database configured, use BlitziBuilder::database to provide one (opening a data \
+         directory requires the `native` or `db-redb` feature)"
+    ))
+}
 
-    let mnemonic = Mnemonic::from_entropy(&entropy)?;
+/// RocksDB and redb hold an exclusive lock on their database, opening it a
+/// second time fails with an IO error mentioning the lock file or an error
+/// saying the database is already open, respectively.
+#[cfg_attr(not(any(feature = "native", feature = "db-redb")), allow(dead_code))]
+fn is_lock_error(error: &anyhow::Error) -> bool {
+    let message = format!("{:#}", error);
+    message.contains("lock file")
+        || message.contains("lock hold")
+        || message.contains("/LOCK")
+        || message.contains("already open")
+}
 
-    Ok(Some(RootSecret::StandardDoubleDerive(
-        Bip39RootSecretStrategy::<12>::to_root_secret(&mnemonic),
-    )))
+/// The guardians reject spent notes with "The note is already spent", the
+/// mint module refuses to reissue notes it already reissued before submitting
+/// anything with "We already reissued these notes".
+fn is_already_spent(reason: &str) -> bool {
+    let reason = reason.to_lowercase();
+    reason.contains("already spent") || reason.contains("already reissued")
 }
 
-async fn generate_root_secret(db: &Database) -> anyhow::Result<RootSecret> {
-    let mnemonic = Mnemonic::generate(12)?;
-    let entropy = mnemonic.to_entropy();
+pub(crate) fn validate_invoice_amount(
+    amount: Amount,
+    max: Amount,
+) -> Result<(), InvoiceAmountError> {
+    if amount == Amount::ZERO {
+        return Err(InvoiceAmountError::Zero);
+    }
+
+    if amount > max {
+        return Err(InvoiceAmountError::TooLarge { amount, max });
+    }
 
-    Client::store_encodable_client_secret(db, &entropy).await?;
+    Ok(())
+}
 
-    Ok(RootSecret::StandardDoubleDerive(Bip39RootSecretStrategy::<
-        12,
-    >::to_root_secret(
-        &mnemonic
-    )))
+/// Returns the root secret of the Fedimint client of the wallet with
+/// `mnemonic`.
+fn root_secret(mnemonic: &Mnemonic) -> RootSecret {
+    RootSecret::StandardDoubleDerive(Bip39RootSecretStrategy::<12>::to_root_secret(mnemonic))
 }
 
 /// The Blitzi client that allows paying and receiving payments on Lightning.
 ///
 /// ```no_run
 /// # use anyhow::Result;
-/// # use fedimint_core::hex;
-/// use blitzi::{Amount, Blitzi};
+/// use blitzi::{Blitzi, msats};
 ///
 /// # #[tokio::main]
 /// # async fn main() -> Result<()> {
 /// let blitzi = Blitzi::new().await?;
 ///
 /// let invoice = blitzi
-///     .lightning_invoice(Amount::from_msats(1000), "Test payment")
+///     .lightning_invoice(msats(1000), "Test payment")
 ///     .await?;
 /// println!("Invoice: {}", invoice);
 ///
 /// let preimage = blitzi.pay(&invoice).await?;
-/// println!("Preimage: {}", hex::encode(preimage));
+/// println!("Preimage: {}", preimage);
 ///
 /// # Ok(())
 /// # }
 /// ```
+///
+/// # Thread safety
+/// `Blitzi` is `Send` and `Sync` and all its methods take `&self` (except
+/// for shutting it down), so a single instance can be shared between tasks
+/// and threads, e.g. wrapped in an [`Arc`], and used concurrently. The
+/// futures returned by its methods are `Send` on native targets, so they can
+/// be spawned onto a multi-threaded runtime.
+///
+/// Concurrent calls are safe: paying the same invoice (or reusing an
+/// idempotency key) from several tasks at once results in a single payment
+/// that all callers follow, and concurrent payments can't exceed the
+/// [spend limit](BlitziBuilder::spend_limit) together.
 pub struct Blitzi {
-    client: ClientHandle,
+    client: ClientHandleArc,
+    /// Data directory the database was opened from, `None` if the database was
+    /// provided via [`BlitziBuilder::database`]
+    datadir: Option<PathBuf>,
+    /// Background tasks that need to be stopped before the client can be shut
+    /// down
+    task_group: TaskGroup,
+    /// Progress of recovering a restored wallet, see
+    /// [`Self::recovery_progress`]
+    recovery: tokio::sync::watch::Receiver<RecoveryProgress>,
+    /// Events of the wallet, see [`Self::subscribe_events`]
+    events: tokio::sync::broadcast::Sender<BlitziEvent>,
+    payment_locks: KeyedLocks<PaymentHash>,
+    /// Outgoing payments currently followed by a background task, see
+    /// [`Self::watch_outgoing_payment`]
+    watched_payments: Arc<std::sync::Mutex<HashSet<OperationId>>>,
+    /// Incoming payments currently followed by a background task, see
+    /// [`Self::watch_incoming_payment`]
+    watched_invoices: Arc<std::sync::Mutex<HashSet<OperationId>>>,
+    idempotency_locks: KeyedLocks<String>,
+    max_invoice_amount: Amount,
+    max_balance: Option<BalanceCap>,
+    truncate_description: bool,
+    gateway_selection: GatewaySelection,
+    gateway_timeout: Option<Duration>,
+    config_timeout: Option<Duration>,
+    #[cfg(feature = "lnurl")]
+    lnurl_deadline: Duration,
+    spend_limit: Option<SpendLimiter>,
+    payment_policy: Option<PaymentPolicy>,
+    lightning_version: LightningVersion,
+    network: Network,
+    /// Mnemonic of the wallet, which the secrets of its accounts are derived
+    /// from. `None` for accounts other than the default one.
+    mnemonic: Option<Mnemonic>,
+    /// Accounts other than the default one opened so far, see
+    /// [`Blitzi::account`]
+    accounts: tokio::sync::Mutex<BTreeMap<u32, Arc<Blitzi>>>,
+    /// Held while opening an account, so each is opened once without
+    /// blocking access to the others meanwhile
+    account_locks: KeyedLocks<u32>,
 }
 
 impl Blitzi {
     /// Creates a new Blitzi client with default settings.
+    ///
+    /// Without the `native` feature (e.g. on wasm) there is no default
+    /// database, use [`Blitzi::builder`] and [`BlitziBuilder::database`]
+    /// instead.
     pub async fn new() -> anyhow::Result<Self> {
         Self::builder().build().await
     }
@@ -255,201 +1211,3208 @@ impl Blitzi {
         BlitziBuilder::default()
     }
 
-    fn ln_module(&self) -> ClientModuleInstance<'_, LightningClientModule> {
+    fn ln_module(&self) -> anyhow::Result<ClientModuleInstance<'_, LightningClientModule>> {
         self.client
             .get_first_module::<LightningClientModule>()
-            .expect("LN module not found")
+            .context("The federation doesn't support the ln module")
     }
 
-    /// Returns the current balance held by Blitzi.
-    ///
-    /// If you want to be notified when the balance changes, use
-    /// [`Self::subscribe_balance_changes`] instead.
-    pub async fn balance(&self) -> Amount {
+    fn lnv2_module(
+        &self,
+    ) -> anyhow::Result<ClientModuleInstance<'_, fedimint_lnv2_client::LightningClientModule>> {
         self.client
-            .get_balance()
-            .await
-            .expect("Primary module not available, should not happen")
+            .get_first_module::<fedimint_lnv2_client::LightningClientModule>()
+            .context("The federation doesn't support the lnv2 module")
     }
 
-    /// Returns a stream that yields the current balance every time it changes.
-    /// Returns the balance in millisatoshi right away.
-    pub async fn subscribe_balance_changes(&self) -> BoxStream<'static, Amount> {
-        self.client.subscribe_balance_changes().await
+    fn mint_module(&self) -> ClientModuleInstance<'_, MintClientModule> {
+        self.client
+            .get_first_module::<MintClientModule>()
+            .expect("Mint module not found")
     }
 
-    /// Generates a new Lightning invoice for the given `amount` (up to milli
-    /// satoshi precision) containing the given `description`.
+    /// Returns the data directory the wallet is stored in, `None` if it was
+    /// opened from a [database](BlitziBuilder::database) instead.
+    pub fn datadir(&self) -> Option<&Path> {
+        self.datadir.as_deref()
+    }
+
+    /// Returns the id of the joined federation.
+    pub fn federation_id(&self) -> FederationId {
+        self.client.federation_id()
+    }
+
+    /// Returns the Bitcoin network the federation operates on. Use
+    /// [`format_sats_on`] to display amounts tagged with it.
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    /// Returns an invite code for the joined federation, e.g. to let users
+    /// share it. The invite code is reconstructed from the federation's config
+    /// and points at its first guardian, so it may differ from the one the
+    /// federation was joined with, but leads to the same federation.
     ///
     /// # Errors
-    /// Returns an error if no LN gateway is available or if the invoice cannot
-    /// be generated for any other reason.
-    pub async fn lightning_invoice(
-        &self,
-        amount: Amount,
-        description: &str,
-    ) -> anyhow::Result<Bolt11Invoice> {
-        let ln_client = self.ln_module();
+    /// Returns an error if the federation's config doesn't list any guardian.
+    pub async fn federation_invite_code(&self) -> anyhow::Result<InviteCode> {
+        let config = self.client.config().await;
+        let peer = config
+            .global
+            .api_endpoints
+            .keys()
+            .next()
+            .copied()
+            .context("The federation config doesn't list any guardian")?;
+        self.client
+            .invite_code(peer)
+            .await
+            .context("Failed to construct an invite code for the federation")
+    }
 
-        let ln_gateway = ln_client
-            .get_gateway(None, false)
-            .await?
-            .ok_or_else(|| anyhow!("No LN gateway available"))?;
-        let (_, invoice, _) = ln_client
-            .create_bolt11_invoice(
-                amount,
-                Bolt11InvoiceDescription::Direct(Description::new(description.into())?),
-                None,
-                (),
-                Some(ln_gateway),
-            )
-            .await?;
+    /// Fetches the latest config from the federation's guardians and returns
+    /// whether it differs from the config the client uses, e.g. because the
+    /// guardians upgraded the consensus version or added a module.
+    ///
+    /// The Fedimint client keeps the config it joined the federation with and
+    /// can't switch to a changed one while running, so a change is only
+    /// detected and logged. The parts that do change over time are kept up to
+    /// date by the client itself: guardian API endpoints are synced from the
+    /// guardians' announcements and the [meta data](Self::meta) is refreshed
+    /// periodically. Use [`BlitziBuilder::config_check_interval`] to check
+    /// for changes in the background.
+    ///
+    /// # Errors
+    /// Returns an error if none of the guardians can be reached or serve the
+    /// config within the [config timeout](BlitziBuilder::config_timeout), and
+    /// a [`FederationIdMismatch`] error if a guardian serves the config of
+    /// another federation.
+    pub async fn check_federation_config(&self) -> anyhow::Result<bool> {
+        let fetch = config_check::fetch_latest_config(&self.client);
+        let latest = match self.config_timeout {
+            Some(timeout) => fedimint_core::runtime::timeout(timeout, fetch)
+                .await
+                .map_err(|_| {
+                    anyhow!(
+                        "Fetching the federation config timed out after {:?}",
+                        timeout
+                    )
+                })??,
+            None => fetch.await?,
+        };
+        Ok(config_check::has_changed(
+            &self.client.config().await,
+            &latest,
+        ))
+    }
 
-        Ok(invoice)
+    /// Returns the modules and settings of the federation, e.g. to check that
+    /// it operates on the expected [network](FederationCapabilities::network)
+    /// before using it.
+    ///
+    /// # Errors
+    /// Returns an error if the federation config can't be read.
+    pub async fn capabilities(&self) -> anyhow::Result<FederationCapabilities> {
+        capabilities::capabilities(&self.client).await
     }
 
-    /// Waits for an invoice generated using [`Self::lightning_invoice`] to be
-    /// paid.
+    /// Returns the meta data the federation publishes, e.g. its
+    /// `welcome_message` or a recommended `max_balance_msats`. Values are
+    /// returned as published, fields that aren't strings are JSON encoded.
     ///
-    /// Returns an error in case it times out. There is no need to call this
-    /// function unless you need to know if an invoice was paid. The funds will
-    /// be received either way.
-    pub async fn await_incoming_payment(&self, invoice: &Bolt11Invoice) -> anyhow::Result<()> {
-        self.await_incoming_payment_by_hash(invoice.payment_hash())
+    /// The meta data is fetched by the Fedimint client's meta service from the
+    /// federation's meta module (or the URL in its config as a fallback) and
+    /// cached in the client database. The first call after the client was
+    /// started may wait for the initial fetch, afterwards the cached data is
+    /// returned immediately while the meta service refreshes it periodically
+    /// in the background, so changes made by the federation show up with a
+    /// delay.
+    ///
+    /// # Errors
+    /// Currently never returns an error, the `Result` allows for changes to
+    /// the meta service without breaking the API.
+    pub async fn meta(&self) -> anyhow::Result<BTreeMap<String, String>> {
+        Ok(self
+            .client
+            .meta_service()
+            .entries(self.client.db())
             .await
+            .unwrap_or_default())
     }
 
-    /// Waits for an invoice generated using [`Self::lightning_invoice`] to be
-    /// paid. See [`Self::await_incoming_payment`] for more details.
-    pub async fn await_incoming_payment_by_hash(
+    /// Returns the value of a single field of the federation's meta data,
+    /// `None` if the federation doesn't publish it. See [`Self::meta`] for
+    /// caching and refresh behavior.
+    ///
+    /// # Errors
+    /// See [`Self::meta`].
+    pub async fn meta_value(&self, key: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.meta().await?.remove(key))
+    }
+
+    /// Returns the expiry of invoices created without an explicit
+    /// [`InvoiceOptions::expiry`], e.g. to tell users how long an invoice is
+    /// valid. It's the `default_invoice_expiry_secs` the federation announces
+    /// in its [meta data](Self::meta), or [`DEFAULT_INVOICE_EXPIRY`] (one day)
+    /// if it doesn't announce a valid one. Gateways don't announce an expiry,
+    /// so it's the same for all of them.
+    ///
+    /// Like the meta data, the value may change while the client runs.
+    pub async fn default_invoice_expiry(&self) -> Duration {
+        capabilities::announced_invoice_expiry(&self.client)
+            .await
+            .unwrap_or(DEFAULT_INVOICE_EXPIRY)
+    }
+
+    /// Returns the Lightning module used for new invoices and payments, see
+    /// [`BlitziBuilder::preferred_lightning_version`].
+    pub fn lightning_version(&self) -> LightningVersion {
+        self.lightning_version
+    }
+
+    /// Returns the Lightning gateways registered with the federation's `ln`
+    /// module, including the fees they charge for outgoing payments. Empty if
+    /// the federation only supports the `lnv2` module, which chooses gateways
+    /// on its own.
+    pub async fn list_gateways(&self) -> Vec<GatewayInfo> {
+        let Ok(ln_module) = self.ln_module() else {
+            return vec![];
+        };
+        ln_module
+            .list_gateways()
+            .await
+            .into_iter()
+            .map(|announcement| GatewayInfo {
+                gateway_id: announcement.info.gateway_id,
+                node_pub_key: announcement.info.node_pub_key,
+                lightning_alias: announcement.info.lightning_alias,
+                api: announcement.info.api.to_string(),
+                base_fee: Amount::from_msats(announcement.info.fees.base_msat.into()),
+                proportional_fee_ppm: announcement.info.fees.proportional_millionths,
+                vetted: announcement.vetted,
+                ttl: announcement.ttl,
+            })
+            .collect()
+    }
+
+    /// Returns how many outgoing payments each gateway routed successfully or
+    /// failed to route, most recently used gateway first. Gateways that were
+    /// never used for a payment aren't included.
+    ///
+    /// The outcomes are recorded in the client database after every payment
+    /// routed through a gateway, so they persist across restarts. Internal
+    /// payments and payments made using the `lnv2` module aren't recorded.
+    ///
+    /// # Errors
+    /// Returns an error if the records can't be read from the database.
+    pub async fn gateway_stats(&self) -> anyhow::Result<Vec<GatewayStats>> {
+        gateway_stats::stats(self.client.db()).await
+    }
+
+    /// Makes invoices and payments that don't select a gateway explicitly use
+    /// `gateway` (see [`Self::list_gateways`]), e.g. after finding one that
+    /// works well. The preference is stored in the client database, so it
+    /// persists across restarts.
+    ///
+    /// If the gateway isn't registered with the federation anymore (e.g.
+    /// because it went offline), the gateway is chosen according to
+    /// [`BlitziBuilder::gateway_selection`] instead and a warning is logged.
+    /// Only applies to the `ln` module.
+    ///
+    /// # Errors
+    /// Returns an error if the preference can't be stored.
+    pub async fn set_preferred_gateway(&self, gateway: PublicKey) -> anyhow::Result<()> {
+        gateway::store_preferred_gateway(self.client.db(), Some(gateway)).await
+    }
+
+    /// Returns the gateway set using [`Self::set_preferred_gateway`], if any.
+    ///
+    /// # Errors
+    /// Returns an error if the preference can't be read from the database.
+    pub async fn preferred_gateway(&self) -> anyhow::Result<Option<PublicKey>> {
+        gateway::preferred_gateway(self.client.db()).await
+    }
+
+    /// Removes the gateway set using [`Self::set_preferred_gateway`], so
+    /// gateways are chosen according to [`BlitziBuilder::gateway_selection`]
+    /// again.
+    ///
+    /// # Errors
+    /// Returns an error if the preference can't be removed.
+    pub async fn clear_preferred_gateway(&self) -> anyhow::Result<()> {
+        gateway::store_preferred_gateway(self.client.db(), None).await
+    }
+
+    /// Returns the current balance held by Blitzi.
+    ///
+    /// If you want to be notified when the balance changes, use
+    /// [`Self::subscribe_balance`] instead.
+    pub async fn balance(&self) -> Amount {
+        self.client
+            .get_balance()
+            .await
+            .expect("Primary module not available, should not happen")
+    }
+
+    /// Returns a stream that yields the current balance every time it changes.
+    /// Returns the balance in millisatoshi right away.
+    ///
+    /// Every note operation results in an update, use
+    /// [`Self::subscribe_balance`] to get one update per change instead.
+    pub async fn subscribe_balance_changes(&self) -> BoxStream<'static, Amount> {
+        self.client.subscribe_balance_changes().await
+    }
+
+    /// Returns a stream that yields the current balance right away and the new
+    /// balance whenever it changes afterwards, e.g. to update a UI without
+    /// polling [`Self::balance`]. Changes are debounced, so a burst of note
+    /// operations (e.g. issuing the notes of a claimed invoice or reissuing
+    /// ecash) results in a single update, and updates that don't change the
+    /// balance are dropped.
+    ///
+    /// The balance is tracked in the local database, so the stream is
+    /// unaffected by the connection to the federation dropping and
+    /// reconnecting. It ends when the client is shut down.
+    pub async fn subscribe_balance(&self) -> BoxStream<'static, Amount> {
+        balance::debounced(self.client.subscribe_balance_changes().await)
+    }
+
+    /// Returns a stream of the events of the wallet, e.g. to update a UI:
+    /// claimed invoices, finished outgoing payments, ecash spent and redeemed
+    /// and balance changes (debounced like [`Self::subscribe_balance`], the
+    /// current balance is yielded right away). Only events happening after
+    /// subscribing are yielded, use [`Self::list_operations`] to catch up on
+    /// earlier ones.
+    ///
+    /// Events are buffered per subscriber, so a slow subscriber doesn't block
+    /// the wallet or other subscribers. A subscriber falling more than 1024
+    /// events behind misses the oldest ones, those gaps are logged but not
+    /// reported in the stream. Balance changes are tracked separately and
+    /// always catch up with the latest balance. Keep consuming the stream (e.g.
+    /// by moving slow work to a separate task) and use
+    /// [`Self::list_operations`] to resynchronize if gaps matter.
+    ///
+    /// The stream ends when the client is shut down.
+    pub async fn subscribe_events(&self) -> BoxStream<'static, BlitziEvent> {
+        events::subscribe(self.events.subscribe(), self.subscribe_balance().await)
+    }
+
+    /// Waits until the spendable balance is at least `target`, e.g. for a
+    /// user to top up their wallet, and returns the balance at that point.
+    ///
+    /// # Errors
+    /// Returns a [`BalanceTimeout`] error if the balance didn't reach the
+    /// target within `timeout`.
+    pub async fn await_balance_at_least(
+        &self,
+        target: impl Into<Amount>,
+        timeout: Duration,
+    ) -> anyhow::Result<Amount> {
+        let target = target.into();
+        let mut updates = self.subscribe_balance_changes().await;
+
+        let mut balance = Amount::ZERO;
+        let result = fedimint_core::runtime::timeout(timeout, async {
+            while let Some(new_balance) = updates.next().await {
+                balance = new_balance;
+                if balance >= target {
+                    return Ok(balance);
+                }
+            }
+            Err(anyhow::anyhow!("Balance update stream ended unexpectedly"))
+        })
+        .await;
+
+        match result {
+            Ok(result) => result,
+            Err(_) => Err(BalanceTimeout { target, balance }.into()),
+        }
+    }
+
+    /// Returns the number of ecash notes held per denomination, ordered by
+    /// denomination. Can be used to decide when to call [`Self::consolidate`].
+    pub async fn note_summary(&self) -> Vec<(Amount, usize)> {
+        let mint = self.mint_module();
+        let mut dbtx = mint.db.begin_transaction().await;
+        mint.get_note_counts_by_denomination(&mut dbtx)
+            .await
+            .iter()
+            .collect()
+    }
+
+    /// Returns statistics about the wallet such as the number of ecash notes
+    /// held and the number of pending operations.
+    pub async fn wallet_stats(&self) -> WalletStats {
+        const PAGE_SIZE: usize = 100;
+
+        let notes_by_denomination = self.note_summary().await;
+
+        let mut pending_operations = 0;
+        let mut before = None;
+        loop {
+            let page = self.list_operations(PAGE_SIZE, before).await;
+            pending_operations += page
+                .iter()
+                .filter(|entry| entry.status == HistoryEntryStatus::Pending)
+                .count();
+
+            match page.last() {
+                Some(last) if page.len() == PAGE_SIZE => before = Some(last.cursor),
+                _ => break,
+            }
+        }
+
+        WalletStats {
+            balance: self.balance().await,
+            note_count: notes_by_denomination.iter().map(|(_, count)| count).sum(),
+            notes_by_denomination,
+            pending_operations,
+        }
+    }
+
+    /// Returns the balance, the number of pending invoices and how many of the
+    /// federation's guardians and gateways are reachable. Unlike
+    /// [`Self::wallet_stats`] it doesn't read the operation log, so it can be
+    /// polled, but it asks every guardian for the federation's config, which
+    /// takes up to five seconds if some of them don't answer.
+    pub async fn status(&self) -> WalletStatus {
+        let pending_invoices = self.watched_invoices.lock().expect("lock poisoned").len();
+        let guardians = self.client.config().await.global.api_endpoints.len();
+        let (reachable_guardians, gateways) = futures_util::future::join(
+            status::reachable_guardians(&self.client),
+            self.list_gateways(),
+        )
+        .await;
+
+        WalletStatus {
+            balance: self.balance().await,
+            pending_invoices,
+            guardians,
+            reachable_guardians,
+            gateways: gateways.len(),
+        }
+    }
+
+    /// Removes operations that finished (e.g. paid or expired invoices and
+    /// completed payments) and were started before `older_than` from the
+    /// operation log to keep the database from growing without bound. Returns
+    /// the number of removed operations.
+    ///
+    /// Pending operations and operations still referenced by the client's
+    /// modules are never removed, so balances are not affected. Removed
+    /// operations no longer show up in [`Self::list_operations`] and paying a
+    /// removed invoice again isn't detected as a retry anymore.
+    pub async fn prune_operations(&self, older_than: SystemTime) -> anyhow::Result<u64> {
+        let pruned = history::prune_operations(&self.client, older_than).await?;
+        info!(pruned, "Pruned operation log");
+        Ok(pruned)
+    }
+
+    /// Prunes operations older than `retention` every
+    /// [`AUTO_PRUNE_INTERVAL`] until the client is shut down.
+    fn spawn_auto_prune(&self, retention: Duration) {
+        let client = self.client.clone();
+        self.task_group
+            .spawn_cancellable("blitzi-auto-prune", async move {
+                loop {
+                    let older_than = fedimint_core::time::now()
+                        .checked_sub(retention)
+                        .unwrap_or(UNIX_EPOCH);
+                    match history::prune_operations(&client, older_than).await {
+                        Ok(pruned) => info!(pruned, "Pruned operation log"),
+                        Err(e) => warn!(error = %e, "Failed to prune operation log"),
+                    }
+                    fedimint_core::runtime::sleep(AUTO_PRUNE_INTERVAL).await;
+                }
+            });
+    }
+
+    /// Claims incoming payments that were paid while the client was offline,
+    /// or whose claim was interrupted by a crash, and returns how many were
+    /// claimed.
+    ///
+    /// This runs automatically when the client is started and every 10
+    /// minutes afterwards, so calling it manually is only necessary to claim
+    /// such payments right away. Invoices that weren't paid yet are skipped.
+    pub async fn reclaim_pending(&self) -> anyhow::Result<usize> {
+        reclaim::reclaim_pending(&self.client).await
+    }
+
+    /// Runs [`Self::reclaim_pending`] every [`RECLAIM_INTERVAL`] until the
+    /// client is shut down.
+    fn spawn_reclaim_pending(&self) {
+        let client = self.client.clone();
+        self.task_group
+            .spawn_cancellable("blitzi-reclaim-pending", async move {
+                loop {
+                    match reclaim::reclaim_pending(&client).await {
+                        Ok(0) => {}
+                        Ok(reclaimed) => info!(reclaimed, "Reclaimed pending incoming payments"),
+                        Err(e) => warn!(error = %e, "Failed to reclaim pending incoming payments"),
+                    }
+                    fedimint_core::runtime::sleep(RECLAIM_INTERVAL).await;
+                }
+            });
+    }
+
+    fn spawn_config_check(&self, interval: Duration) {
+        let client = self.client.clone();
+        self.task_group
+            .spawn_cancellable("blitzi-config-check", async move {
+                loop {
+                    fedimint_core::runtime::sleep(interval).await;
+                    let latest = match config_check::fetch_latest_config(&client).await {
+                        Ok(latest) => latest,
+                        Err(e) => {
+                            warn!(error = %e, "Failed to check the federation config");
+                            continue;
+                        }
+                    };
+                    if config_check::has_changed(&client.config().await, &latest) {
+                        warn!(
+                            current_version = ?client.config().await.global.consensus_version,
+                            latest_version = ?latest.global.consensus_version,
+                            "The federation config changed, the client keeps using the config it joined with"
+                        );
+                    }
+                }
+            });
+    }
+
+    /// Reissues all ecash notes held by the client into an optimal
+    /// denomination set and returns the amount that was processed.
+    ///
+    /// Over time a wallet accumulates many small-denomination notes, which
+    /// bloats the database and makes spending slower. Consolidating them from
+    /// time to time keeps the number of notes small. Note that federations
+    /// charging fees per note will deduct these fees from the balance.
+    ///
+    /// # Errors
+    /// Returns an error if the notes can't be spent or reissuing them fails.
+    pub async fn consolidate(&self) -> anyhow::Result<Amount> {
+        let mint = self.mint_module();
+        let amount = self.balance().await;
+        if amount == Amount::ZERO {
+            return Ok(Amount::ZERO);
+        }
+
+        // The notes are reissued right away, so the spend never needs to be canceled
+        let (_, notes) = mint
+            .spend_notes_with_selector(
+                &SelectNotesWithExactAmount,
+                amount,
+                Duration::from_secs(24 * 60 * 60),
+                false,
+                (),
+            )
+            .await
+            .context("Failed to select notes for consolidation")?;
+
+        self.reissue_notes_blocking(notes).await?;
+        info!("Consolidated {} of ecash notes", amount);
+        Ok(amount)
+    }
+
+    /// Spends notes worth exactly `amount`, first [making
+    /// change](Self::make_change) if the notes held can't represent it.
+    ///
+    /// # Errors
+    /// Returns an error if the wallet doesn't hold enough notes or they still
+    /// can't represent `amount` afterwards, e.g. because the federation
+    /// charges fees for reissuing.
+    async fn spend_exact_notes(
+        &self,
+        amount: Amount,
+        try_cancel_after: Duration,
+        include_invite: bool,
+    ) -> anyhow::Result<(OperationId, OOBNotes)> {
+        let mint = self.mint_module();
+        let spend = || {
+            mint.spend_notes_with_selector(
+                &SelectNotesWithExactAmount,
+                amount,
+                try_cancel_after,
+                include_invite,
+                (),
+            )
+        };
+        if let Ok(spent) = spend().await {
+            return Ok(spent);
+        }
+        self.make_change(amount).await?;
+        spend().await
+    }
+
+    /// Reissues notes worth at least `amount` into the wallet, which splits
+    /// them into the denominations needed to spend `amount` exactly.
+    async fn make_change(&self, amount: Amount) -> anyhow::Result<()> {
+        // The notes are reissued right away, so the spend never needs to be canceled
+        let (_, notes) = self
+            .mint_module()
+            .spend_notes_with_selector(
+                &SelectNotesWithAtleastAmount,
+                amount,
+                Duration::from_secs(24 * 60 * 60),
+                false,
+                (),
+            )
+            .await
+            .context("Failed to select notes to spend")?;
+        debug!(
+            "Reissuing {} of ecash notes to make change",
+            notes.total_amount()
+        );
+        self.reissue_notes_blocking(notes).await
+    }
+
+    /// Reissues `notes` into the wallet and waits until the federation
+    /// confirmed the reissue and the new notes were added to the balance, not
+    /// just until the transaction was submitted.
+    ///
+    /// # Errors
+    /// Returns an [`EcashAlreadySpent`] error if the federation rejected the
+    /// notes as spent, or this client already redeemed them.
+    async fn reissue_notes_blocking(&self, notes: OOBNotes) -> anyhow::Result<()> {
+        let mint = self.mint_module();
+        let amount = notes.total_amount();
+        let operation_id = match mint.reissue_external_notes(notes, ()).await {
+            Ok(operation_id) => operation_id,
+            Err(e) if is_already_spent(&format!("{:#}", e)) => {
+                return Err(anyhow::Error::new(EcashAlreadySpent { amount }).context(e));
+            }
+            Err(e) => return Err(e),
+        };
+        let mut update_stream = mint
+            .subscribe_reissue_external_notes(operation_id)
+            .await
+            .context("Unexpected error subscribing to operation")?
+            .into_stream();
+        while let Some(update) = update_stream.next().await {
+            match update {
+                ReissueExternalNotesState::Done => return Ok(()),
+                ReissueExternalNotesState::Failed(reason) if is_already_spent(&reason) => {
+                    return Err(anyhow::Error::new(EcashAlreadySpent { amount })
+                        .context(format!("Reissuing notes failed: {}", reason)));
+                }
+                ReissueExternalNotesState::Failed(reason) => {
+                    return Err(anyhow!("Reissuing notes failed: {}", reason));
+                }
+                _ => {}
+            }
+        }
+
+        unreachable!("Stream ended unexpectedly");
+    }
+
+    /// Redeems ecash notes received from someone else, e.g. created by
+    /// [`Self::spend_ecash_with_timeout`] or any other Fedimint client, and
+    /// returns the amount added to the balance.
+    ///
+    /// Returns only once the federation confirmed the notes were reissued to
+    /// this wallet, so `Ok` means the funds are irreversibly in the balance.
+    /// Notes from untrusted parties are safe to accept this way, the sender
+    /// can't spend them anymore.
+    ///
+    /// # Errors
+    /// Returns an [`EcashAlreadySpent`] error if the notes were already spent,
+    /// e.g. by the sender reclaiming them or redeeming them elsewhere, and an
+    /// error if they are invalid or for a different federation.
+    pub async fn receive_ecash(&self, notes: &str) -> anyhow::Result<Amount> {
+        let notes =
+            OOBNotes::from_str(notes.trim()).map_err(|e| anyhow!("Invalid ecash notes: {}", e))?;
+        ensure!(
+            notes.federation_id_prefix() == self.client.federation_id().to_prefix(),
+            "Ecash notes are for a different federation"
+        );
+        let amount = notes.total_amount();
+
+        self.reissue_notes_blocking(notes).await?;
+        events::emit(&self.events, BlitziEvent::EcashReceived { amount });
+        info!(%amount, "Received ecash");
+        Ok(amount)
+    }
+
+    /// Spends `amount` as ecash notes that can be handed to someone else, who
+    /// redeems them with any Fedimint client.
+    ///
+    /// If the notes weren't redeemed after `try_cancel_after` the client
+    /// automatically reclaims them in the background, so they aren't lost if
+    /// the recipient never redeems them. Use [`Self::reclaim_ecash`] to
+    /// reclaim them earlier.
+    ///
+    /// The notes sum up to exactly `amount` if possible. If the notes held
+    /// can't represent it, enough of them are first reissued into smaller
+    /// denominations to make change. Only if that still doesn't allow an
+    /// exact selection (e.g. because the federation charges fees for
+    /// reissuing) the notes exceed `amount` by less than the value of the
+    /// smallest note that covers the difference. [`SpentEcash::amount`] always
+    /// reports the amount actually spent.
+    ///
+    /// # Errors
+    /// Returns an error if the wallet doesn't hold enough notes.
+    pub async fn spend_ecash_with_timeout(
+        &self,
+        amount: impl Into<Amount>,
+        try_cancel_after: Duration,
+    ) -> anyhow::Result<SpentEcash> {
+        let amount = amount.into();
+        let (operation_id, notes) =
+            match self.spend_exact_notes(amount, try_cancel_after, true).await {
+                Ok(spent) => spent,
+                Err(_) => self
+                    .mint_module()
+                    .spend_notes_with_selector(
+                        &SelectNotesWithAtleastAmount,
+                        amount,
+                        try_cancel_after,
+                        true,
+                        (),
+                    )
+                    .await
+                    .context("Failed to select notes to spend")?,
+            };
+        events::emit(
+            &self.events,
+            BlitziEvent::EcashSpent {
+                operation_id,
+                amount: notes.total_amount(),
+            },
+        );
+
+        Ok(SpentEcash {
+            operation_id,
+            amount: notes.total_amount(),
+            notes: notes.to_string(),
+        })
+    }
+
+    /// Reclaims ecash notes spent using [`Self::spend_ecash_with_timeout`]
+    /// that the recipient didn't redeem, returning them to the balance.
+    ///
+    /// Reclaiming is only possible while the notes remain unspent. Once the
+    /// recipient redeemed them they are gone and an error is returned. It's
+    /// a race, so don't reclaim notes you still expect to be redeemed.
+    ///
+    /// # Errors
+    /// Returns an error if the notes were already redeemed by the recipient
+    /// or the operation isn't an ecash spend of this client.
+    pub async fn reclaim_ecash(&self, operation_id: OperationId) -> anyhow::Result<()> {
+        let mint = self.mint_module();
+        let mut update_stream = mint
+            .subscribe_spend_notes(operation_id)
+            .await
+            .context("No ecash spend found for the operation id")?
+            .into_stream();
+        mint.try_cancel_spend_notes(operation_id).await;
+
+        while let Some(update) = update_stream.next().await {
+            match update {
+                SpendOOBState::UserCanceledSuccess | SpendOOBState::Refunded => return Ok(()),
+                SpendOOBState::UserCanceledFailure | SpendOOBState::Success => {
+                    return Err(anyhow!("Notes were already redeemed by the recipient"));
+                }
+                _ => {}
+            }
+        }
+
+        unreachable!("Stream ended unexpectedly");
+    }
+
+    /// Transfers `amount` to another Blitzi client of the same federation,
+    /// e.g. a service run by yourself, without creating a Lightning invoice.
+    /// Returns a token to pass to the recipient, who redeems it using
+    /// [`Self::redeem_transfer`].
+    ///
+    /// The token expires after `expiry`. Funds not redeemed by then are
+    /// returned to the balance automatically, [`Self::reclaim_transfer`]
+    /// waits for that.
+    ///
+    /// # Errors
+    /// Returns an error if the wallet doesn't hold enough notes.
+    pub async fn transfer_ecash(
+        &self,
+        amount: impl Into<Amount>,
+        expiry: Duration,
+    ) -> anyhow::Result<TransferToken> {
+        let expires_at = fedimint_core::time::now() + expiry;
+        let spent = self.spend_ecash_with_timeout(amount, expiry).await?;
+        Ok(TransferToken {
+            operation_id: spent.operation_id,
+            amount: spent.amount,
+            expires_at,
+            notes: spent.notes,
+        })
+    }
+
+    /// Redeems a token created using [`Self::transfer_ecash`] and returns the
+    /// amount added to the balance. A token can only be redeemed once.
+    ///
+    /// # Errors
+    /// Returns a [`TransferExpired`] error if the token expired, and an error
+    /// if it is for a different federation, was already redeemed or the
+    /// sender reclaimed the funds.
+    pub async fn redeem_transfer(&self, token: &TransferToken) -> anyhow::Result<Amount> {
+        if token.is_expired() {
+            return Err(TransferExpired {
+                expires_at: token.expires_at,
+            }
+            .into());
+        }
+        let notes =
+            OOBNotes::from_str(&token.notes).map_err(|e| anyhow!("Invalid ecash notes: {}", e))?;
+        ensure!(
+            notes.federation_id_prefix() == self.client.federation_id().to_prefix(),
+            "Transfer token is for a different federation"
+        );
+        ensure!(
+            notes.total_amount() == token.amount,
+            "Transfer token amount doesn't match its notes"
+        );
+
+        self.reissue_notes_blocking(notes)
+            .await
+            .context("Failed to redeem the transfer, was it already redeemed?")?;
+        events::emit(
+            &self.events,
+            BlitziEvent::EcashReceived {
+                amount: token.amount,
+            },
+        );
+        info!(amount = %token.amount, "Redeemed ecash transfer");
+        Ok(token.amount)
+    }
+
+    /// Returns the funds of a transfer that wasn't redeemed before its token
+    /// expired to the balance.
+    ///
+    /// # Errors
+    /// Returns an error if the token hasn't expired yet, the recipient already
+    /// redeemed it or it wasn't created by this client.
+    pub async fn reclaim_transfer(&self, token: &TransferToken) -> anyhow::Result<()> {
+        ensure!(
+            token.is_expired(),
+            "Transfer token hasn't expired yet, the recipient may still redeem it"
+        );
+        self.reclaim_ecash(token.operation_id).await
+    }
+
+    /// Returns the account with `index` of the wallet, e.g. one per user of a
+    /// multi-user app. Accounts hold their own funds and keep their own
+    /// operation history, but share the wallet's mnemonic, data directory and
+    /// settings. Account 0 is the wallet itself, i.e. the funds managed by
+    /// `self`.
+    ///
+    /// Every other account is a separate Fedimint client with its own
+    /// connection to the federation. It derives its secret from the wallet's
+    /// mnemonic, so restoring the mnemonic restores all accounts. Accounts are
+    /// opened (and joined to the federation on first use) when first
+    /// requested and stay open until the wallet is shut down. Their spend
+    /// limits are enforced per account, automatic pruning only applies to
+    /// account 0.
+    ///
+    /// Use [`Self::transfer_between_accounts`] to move funds between accounts
+    /// without a Lightning payment.
+    ///
+    /// # Errors
+    /// Returns an error if the account's client can't be opened or joined, or
+    /// if `self` is an account other than account 0 itself.
+    pub async fn account(&self, index: u32) -> anyhow::Result<BlitziAccount<'_>> {
+        if index == 0 {
+            return Ok(BlitziAccount::default_account(self));
+        }
+
+        let _lock = self.account_locks.lock(index).await;
+        if let Some(account) = self.accounts.lock().await.get(&index) {
+            return Ok(BlitziAccount::sub_account(index, account.clone()));
+        }
+
+        let account = Arc::new(self.open_account(index).await?);
+        self.accounts.lock().await.insert(index, account.clone());
+        Ok(BlitziAccount::sub_account(index, account))
+    }
+
+    /// Opens the client of the account with `index`, joining the federation
+    /// using the wallet's config if the account is opened for the first time.
+    async fn open_account(&self, index: u32) -> anyhow::Result<Blitzi> {
+        let mnemonic = self
+            .mnemonic
+            .as_ref()
+            .context("Accounts can only be opened from the default account")?;
+        let root_secret = account::root_secret(mnemonic, index);
+        let db = account::account_db(self.client.db(), index);
+        let client = if Client::is_initialized(&db).await {
+            client_builder().await?.open(db, root_secret).await?
+        } else {
+            client_builder()
+                .await?
+                .preview_with_existing_config(self.client.config().await, None)
+                .await?
+                .join(db, root_secret)
+                .await?
+        };
+        account::register(self.client.db(), index).await?;
+        info!(index, "Opened account");
+
+        let client = Arc::new(client);
+        let task_group = TaskGroup::new();
+        let recovery = recovery::track(&client, &task_group).await;
+        let blitzi = Blitzi {
+            client,
+            datadir: None,
+            task_group,
+            recovery,
+            events: tokio::sync::broadcast::channel(events::EVENT_BUFFER).0,
+            payment_locks: KeyedLocks::default(),
+            watched_payments: Arc::default(),
+            watched_invoices: Arc::default(),
+            idempotency_locks: KeyedLocks::default(),
+            max_invoice_amount: self.max_invoice_amount,
+            max_balance: self.max_balance,
+            truncate_description: self.truncate_description,
+            gateway_selection: self.gateway_selection,
+            gateway_timeout: self.gateway_timeout,
+            config_timeout: self.config_timeout,
+            #[cfg(feature = "lnurl")]
+            lnurl_deadline: self.lnurl_deadline,
+            spend_limit: self
+                .spend_limit
+                .as_ref()
+                .map(|limiter| SpendLimiter::new(limiter.limit())),
+            payment_policy: self.payment_policy.clone(),
+            lightning_version: self.lightning_version,
+            network: self.network,
+            mnemonic: None,
+            accounts: tokio::sync::Mutex::default(),
+            account_locks: KeyedLocks::default(),
+        };
+        blitzi.start_background_tasks().await?;
+        Ok(blitzi)
+    }
+
+    /// Returns the balances of all accounts of the wallet that hold funds,
+    /// keyed by account index, see [`Self::account`].
+    ///
+    /// This opens every account that was ever used, which requires connecting
+    /// each of them to the federation. The accounts are opened concurrently.
+    ///
+    /// # Errors
+    /// Returns an error if an account can't be opened.
+    pub async fn account_balances(&self) -> anyhow::Result<BTreeMap<u32, Amount>> {
+        let indices = std::iter::once(0).chain(account::registered(self.client.db()).await?);
+        let balances = indices.map(|index| async move {
+            let balance = self.account(index).await?.balance().await;
+            anyhow::Ok((index, balance))
+        });
+        Ok(futures_util::future::try_join_all(balances)
+            .await?
+            .into_iter()
+            .filter(|(_, balance)| *balance != Amount::ZERO)
+            .collect())
+    }
+
+    /// Moves exactly `amount` from the account `from` to the account `to`
+    /// (see [`Self::account`]) by spending ecash notes in one account and
+    /// reissuing them in the other, without a Lightning payment. Returns the
+    /// amount that was moved. If the notes held by the sending account can't
+    /// represent `amount`, enough of them are first reissued into smaller
+    /// denominations to make change.
+    ///
+    /// If reissuing fails the notes are reclaimed by the sending account.
+    ///
+    /// # Errors
+    /// Returns an error if both accounts are the same, an account can't be
+    /// opened, the sending account doesn't hold enough notes to move exactly
+    /// `amount` or reissuing them fails.
+    pub async fn transfer_between_accounts(
+        &self,
+        from: u32,
+        to: u32,
+        amount: impl Into<Amount>,
+    ) -> anyhow::Result<Amount> {
+        ensure!(from != to, "Can't transfer funds to the same account");
+        let sender = self.account(from).await?;
+        let recipient = self.account(to).await?;
+
+        let (operation_id, notes) = sender
+            .spend_exact_notes(amount.into(), Duration::from_secs(24 * 60 * 60), false)
+            .await
+            .context("Failed to select notes to transfer")?;
+        let amount = notes.total_amount();
+
+        if let Err(e) = recipient.reissue_notes_blocking(notes).await {
+            sender
+                .mint_module()
+                .try_cancel_spend_notes(operation_id)
+                .await;
+            return Err(e);
+        }
+        events::emit(
+            &sender.events,
+            BlitziEvent::EcashSpent {
+                operation_id,
+                amount,
+            },
+        );
+        events::emit(&recipient.events, BlitziEvent::EcashReceived { amount });
+        info!(
+            from,
+            to,
+            amount_msats = amount.msats,
+            "Transferred funds between accounts"
+        );
+        Ok(amount)
+    }
+
+    /// Returns the mnemonic of the wallet, which can be written down as a
+    /// backup and [restored](BlitziBuilder::restore_from_mnemonic) into a new
+    /// data directory. Anyone who knows the mnemonic can spend the wallet's
+    /// funds, so it should never leave the user's device unencrypted.
+    ///
+    /// # Errors
+    /// Returns an error if called on an [account](Self::account) other than
+    /// the default one, which share the wallet's mnemonic.
+    pub fn mnemonic(&self) -> anyhow::Result<&Mnemonic> {
+        self.mnemonic
+            .as_ref()
+            .context("The mnemonic is only available from the default account")
+    }
+
+    /// Returns the progress of recovering the wallet's funds after it was
+    /// [restored from its mnemonic](BlitziBuilder::restore_from_mnemonic) per
+    /// module, e.g. to show "recovering ecash: 40%". Only useful together with
+    /// [`BlitziBuilder::recover_in_background`], otherwise the recovery is
+    /// done by the time the client is built. Empty if the wallet isn't
+    /// recovering.
+    pub async fn recovery_progress(&self) -> RecoveryProgress {
+        self.recovery.borrow().clone()
+    }
+
+    /// Returns a stream yielding the current [recovery
+    /// progress](Self::recovery_progress) and every update to it. The stream
+    /// ends once the recovery is done.
+    pub fn subscribe_recovery(&self) -> BoxStream<'static, RecoveryProgress> {
+        recovery::subscribe(self.recovery.clone())
+    }
+
+    /// Waits for the recovery of a wallet
+    /// [restored in the background](BlitziBuilder::recover_in_background) to
+    /// complete. Returns immediately if the wallet isn't recovering.
+    ///
+    /// # Errors
+    /// Returns an error if the recovery of a module failed.
+    pub async fn await_recovery(&self) -> anyhow::Result<()> {
+        self.client.wait_for_all_recoveries().await?;
+        Ok(())
+    }
+
+    /// Encrypts the wallet's seed, which was stored unencrypted, with
+    /// `passphrase`, e.g. to migrate a wallet created before
+    /// [`BlitziBuilder::encryption_passphrase`] was used. The wallet has to be
+    /// opened with the same passphrase from then on.
+    ///
+    /// See [`BlitziBuilder::encryption_passphrase`] for what the encryption
+    /// protects, previous copies of the data directory (e.g. backups) still
+    /// contain the unencrypted seed.
+    ///
+    /// # Errors
+    /// Returns an error if the seed is already encrypted, use
+    /// [`Self::change_passphrase`] instead, or if called on an
+    /// [account](Self::account) other than the default one.
+    pub async fn encrypt_existing_secret(&self, passphrase: &str) -> anyhow::Result<()> {
+        seed::encrypt_existing(self.client.db(), passphrase).await?;
+        info!("Encrypted the wallet seed");
+        Ok(())
+    }
+
+    /// Changes the passphrase the wallet's seed is encrypted with from `old`
+    /// to `new`. The wallet has to be opened with `new` from then on.
+    ///
+    /// # Errors
+    /// Returns a [`WrongPassphrase`] error if `old` doesn't decrypt the seed,
+    /// and an error if the seed isn't encrypted, use
+    /// [`Self::encrypt_existing_secret`] instead.
+    pub async fn change_passphrase(&self, old: &str, new: &str) -> anyhow::Result<()> {
+        seed::change_passphrase(self.client.db(), old, new).await?;
+        info!("Changed the passphrase of the wallet seed");
+        Ok(())
+    }
+
+    /// Shuts down the client: stops its background tasks and closes the
+    /// database after flushing it, e.g. before the process exits. Payments
+    /// that are still in flight are resumed the next time the client is
+    /// started. Open [accounts](Self::account) are shut down too.
+    ///
+    /// # Errors
+    /// Returns an error if the client is still shared (e.g. other clones of an
+    /// `Arc<Blitzi>` exist) or a background task can't be stopped.
+    pub async fn shutdown(self) -> anyhow::Result<()> {
+        self.shutdown_client().await?;
+        Ok(())
+    }
+
+    /// Shuts down the client and returns the data directory it was opened
+    /// from, see [`Self::shutdown`].
+    async fn shutdown_client(self) -> anyhow::Result<Option<PathBuf>> {
+        let Blitzi {
+            client,
+            datadir,
+            task_group,
+            accounts,
+            ..
+        } = self;
+
+        for (_, account) in accounts.into_inner() {
+            let account = Arc::try_unwrap(account)
+                .map_err(|_| anyhow!("Account is still in use, can't shut it down"))?;
+            Box::pin(account.shutdown_client()).await?;
+        }
+        task_group.shutdown_join_all(None).await?;
+        let client = Arc::try_unwrap(client)
+            .map_err(|_| anyhow!("Client is still in use, can't shut it down"))?;
+        client.shutdown().await;
+
+        Ok(datadir)
+    }
+
+    /// Leaves the federation: shuts down the client and deletes its data
+    /// directory, e.g. to let users switch to a different federation. If the
+    /// database was provided via [`BlitziBuilder::database`] it is closed but
+    /// not deleted.
+    ///
+    /// Unless `force` is set, leaving is refused with a
+    /// [`LeaveFederationError`] if the wallet still holds funds in any of its
+    /// [accounts](Self::account) or has pending operations (e.g. unpaid
+    /// invoices that could still be paid), since these would be lost.
+    ///
+    /// # Errors
+    /// Returns an error if leaving is refused, if the client is still shared
+    /// or if the data directory can't be deleted.
+    pub async fn leave_federation(self, force: bool) -> anyhow::Result<()> {
+        if !force {
+            let balance = self
+                .account_balances()
+                .await?
+                .into_values()
+                .fold(Amount::ZERO, |total, balance| total + balance);
+            if balance != Amount::ZERO {
+                return Err(LeaveFederationError::NonZeroBalance { balance }.into());
+            }
+
+            let pending_operations = self.wallet_stats().await.pending_operations;
+            if pending_operations != 0 {
+                return Err(LeaveFederationError::PendingOperations {
+                    count: pending_operations,
+                }
+                .into());
+            }
+        }
+
+        if let Some(datadir) = self.shutdown_client().await? {
+            info!("Deleting data directory: {:?}", datadir);
+            std::fs::remove_dir_all(&datadir)
+                .with_context(|| format!("Failed to delete data directory {:?}", datadir))?;
+        }
+
+        Ok(())
+    }
+
+    /// Generates a new Lightning invoice for the given `amount` (up to milli
+    /// satoshi precision) containing the given `description`. Use [`sats`] or
+    /// [`msats`] to construct the amount.
+    ///
+    /// The amount has to be greater than zero and must not exceed the maximum
+    /// configured via [`BlitziBuilder::max_invoice_amount`] (1 BTC by
+    /// default).
+    ///
+    /// The description must not contain control characters and must be at
+    /// most 639 bytes long, unless [`BlitziBuilder::truncate_description`] is
+    /// enabled.
+    ///
+    /// To choose the gateway or control the embedded route hints use
+    /// [`Self::lightning_invoice_with_options`] or
+    /// [`Self::lightning_invoice_with_hints`]. The former also returns the id
+    /// of the operation receiving the payment, which can be persisted to
+    /// follow the payment using [`Self::await_incoming_payment_by_operation`].
+    ///
+    /// # Errors
+    /// Returns an [`InvoiceAmountError`] if the amount is out of bounds, a
+    /// [`BalanceCapExceeded`] error if receiving it would exceed the
+    /// [balance cap](BlitziBuilder::max_balance), a [`DescriptionTooLong`] or
+    /// [`InvalidDescription`] error if the description can't be embedded in an
+    /// invoice, a [`GatewayUnavailable`] error if no LN gateway is available
+    /// and an error if the invoice cannot be generated for any other
+    /// reason.
+    pub async fn lightning_invoice(
+        &self,
+        amount: impl Into<Amount>,
+        description: &str,
+    ) -> anyhow::Result<Bolt11Invoice> {
+        Ok(self
+            .lightning_invoice_with_options(amount, description, InvoiceOptions::default())
+            .await?
+            .invoice)
+    }
+
+    /// Generates a new Lightning invoice for `sats` satoshi (1 sat = 1000
+    /// msat) like [`Self::lightning_invoice`], for callers that don't have an
+    /// [`Amount`] at hand. `lightning_invoice_sats(21, ..)` is the same as
+    /// `lightning_invoice(sats(21), ..)`.
+    ///
+    /// # Errors
+    /// Returns an [`InvoiceAmountError::TooLarge`] error if the amount can't
+    /// be represented in millisatoshi, and otherwise the same errors as
+    /// [`Self::lightning_invoice`].
+    pub async fn lightning_invoice_sats(
+        &self,
+        sats: u64,
+        description: &str,
+    ) -> anyhow::Result<Bolt11Invoice> {
+        let amount = checked_sats(sats).ok_or(InvoiceAmountError::TooLarge {
+            amount: Amount::from_msats(u64::MAX),
+            max: self.max_invoice_amount,
+        })?;
+        self.lightning_invoice(amount, description).await
+    }
+
+    /// Generates a new Lightning invoice like [`Self::lightning_invoice`], but
+    /// embeds the given route hints instead of the ones advertised by the
+    /// gateway. This helps payers find a route if the gateway's channels are
+    /// unannounced and it doesn't advertise suitable hints itself.
+    ///
+    /// Each hint describes a path ending at the gateway's Lightning node, the
+    /// final hop from the gateway to the federation is added automatically.
+    ///
+    /// # Errors
+    /// Returns an [`InvalidRouteHint`] error if a hint is empty, too long to
+    /// fit into an invoice or contains a hop with an invalid HTLC range, and
+    /// otherwise the same errors as [`Self::lightning_invoice`].
+    pub async fn lightning_invoice_with_hints(
+        &self,
+        amount: impl Into<Amount>,
+        description: &str,
+        hints: Vec<RouteHint>,
+    ) -> anyhow::Result<Bolt11Invoice> {
+        Ok(self
+            .lightning_invoice_with_options(
+                amount,
+                description,
+                InvoiceOptions {
+                    route_hints: Some(hints),
+                    ..Default::default()
+                },
+            )
+            .await?
+            .invoice)
+    }
+
+    /// Generates a new Lightning invoice like [`Self::lightning_invoice`], but
+    /// allows choosing the gateway whose route hints are embedded in the
+    /// invoice, limiting the number of route hints and setting the expiry.
+    /// Returns the invoice together with the id of the gateway payers will
+    /// route through.
+    ///
+    /// # Errors
+    /// Returns an [`InvoiceAmountError`] if the amount is out of bounds, a
+    /// [`BalanceCapExceeded`] error if receiving it would exceed the
+    /// [balance cap](BlitziBuilder::max_balance), a [`DescriptionTooLong`] or
+    /// [`InvalidDescription`] error if the description can't be embedded in an
+    /// invoice, an [`InvalidRouteHint`]
+    /// error if custom route hints are malformed, a
+    /// [`GatewayUnavailable`] error if the selected gateway (or any gateway if
+    /// none was selected) isn't available, an [`ExternalIdInUse`] error if the
+    /// [external id](InvoiceOptions::external_id) is already attached to
+    /// another invoice or payment and an error if the invoice cannot be
+    /// generated for any other reason.
+    pub async fn lightning_invoice_with_options(
+        &self,
+        amount: impl Into<Amount>,
+        description: &str,
+        options: InvoiceOptions,
+    ) -> anyhow::Result<CreatedInvoice> {
+        let Some(external_id) = options.external_id.clone() else {
+            return self
+                .create_invoice(amount.into(), description, options, serde_json::Value::Null)
+                .await;
+        };
+
+        // Held until the id is stored, so concurrent calls can't both use it
+        let _lock = self
+            .idempotency_locks
+            .lock(format!("external-id/{}", external_id))
+            .await;
+        external_id::check_unused(self.client.db(), &external_id).await?;
+        let extra_meta = serde_json::json!({ "external_id": external_id });
+        let invoice = self
+            .create_invoice(amount.into(), description, options, extra_meta)
+            .await?;
+        external_id::store(self.client.db(), &external_id, invoice.operation_id).await?;
+        Ok(invoice)
+    }
+
+    /// Generates a new Lightning invoice like [`Self::lightning_invoice`] and
+    /// attaches `external_id`, e.g. the id of the order the invoice is for,
+    /// to it. The id can be used to find the invoice's operation again with
+    /// [`Self::find_by_external_id`] when reconciling payments with an
+    /// external system. It's also stored in the operation's meta data.
+    ///
+    /// Every id can only be attached once. To safely retry creating an
+    /// invoice for an order use [`Self::lightning_invoice_idempotent`].
+    ///
+    /// # Errors
+    /// Returns an error if the id is empty or longer than
+    /// [`MAX_EXTERNAL_ID_LEN`], an [`ExternalIdInUse`] error if it's already
+    /// attached to another invoice or payment, and otherwise the same errors
+    /// as [`Self::lightning_invoice`].
+    pub async fn lightning_invoice_tagged(
+        &self,
+        amount: impl Into<Amount>,
+        description: &str,
+        external_id: &str,
+    ) -> anyhow::Result<CreatedInvoice> {
+        self.lightning_invoice_with_options(
+            amount,
+            description,
+            InvoiceOptions {
+                external_id: Some(external_id.to_owned()),
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    /// Returns the operation of the invoice or payment `external_id` was
+    /// attached to, see [`Self::lightning_invoice_tagged`] and
+    /// [`PayOptions::external_id`]. For payments this is the operation id
+    /// used by [`Self::payment_result`] and the operation history.
+    ///
+    /// # Errors
+    /// Returns an error if the client database can't be read.
+    pub async fn find_by_external_id(
+        &self,
+        external_id: &str,
+    ) -> anyhow::Result<Option<OperationId>> {
+        external_id::find(self.client.db(), external_id).await
+    }
+
+    /// Returns the external id attached to the operation `operation_id`, if
+    /// any. See [`Self::find_by_external_id`] for the reverse lookup.
+    ///
+    /// # Errors
+    /// Returns an error if the client database can't be read.
+    pub async fn external_id(&self, operation_id: OperationId) -> anyhow::Result<Option<String>> {
+        external_id::of_operation(self.client.db(), operation_id).await
+    }
+
+    /// Returns the external id attached to the invoice with `payment_hash`, if
+    /// any.
+    ///
+    /// # Errors
+    /// Returns an error if the invoice wasn't issued by this client.
+    pub async fn invoice_external_id(
+        &self,
+        payment_hash: impl Into<PaymentHash>,
+    ) -> anyhow::Result<Option<String>> {
+        let (operation_id, _, _) = self.get_receive_operation(payment_hash.into()).await?;
+        self.external_id(operation_id).await
+    }
+
+    /// Creates an invoice, see [`Self::lightning_invoice_with_options`].
+    /// `extra_meta` is stored in the meta data of the receive operation.
+    async fn create_invoice(
+        &self,
+        amount: Amount,
+        description: &str,
+        options: InvoiceOptions,
+        extra_meta: serde_json::Value,
+    ) -> anyhow::Result<CreatedInvoice> {
+        validate_invoice_amount(amount, self.max_invoice_amount)?;
+        if let Some(cap) = self.max_balance {
+            cap.check(&self.client, amount).await?;
+        }
+        let description =
+            crate::invoice::check_description(description, self.truncate_description)?;
+        let expiry = match options.expiry {
+            Some(expiry) => expiry,
+            None => self.default_invoice_expiry().await,
+        };
+        let route_hints = options
+            .route_hints
+            .as_deref()
+            .map(crate::invoice::convert_route_hints)
+            .transpose()?;
+
+        if self.lightning_version == LightningVersion::V2 {
+            if options.gateway.is_some() {
+                return Err(UnsupportedByLnv2 {
+                    feature: "Choosing the gateway",
+                }
+                .into());
+            }
+            if route_hints.is_some() || options.max_route_hints.is_some() {
+                return Err(UnsupportedByLnv2 {
+                    feature: "Choosing route hints",
+                }
+                .into());
+            }
+            return self
+                .lnv2_invoice(amount, description, expiry, extra_meta)
+                .await;
+        }
+
+        let gateway = match options.gateway {
+            Some(gateway) => Some(gateway),
+            None => self.select_gateway(amount).await?,
+        };
+
+        let ln_client = self.ln_module()?;
+
+        let mut ln_gateway = self
+            .gateway_request(ln_client.get_gateway(gateway, false))
+            .await?
+            .ok_or(GatewayUnavailable {
+                gateway_id: gateway,
+            })?;
+        if let Some(route_hints) = route_hints {
+            ln_gateway.route_hints = route_hints;
+        }
+        if let Some(max_route_hints) = options.max_route_hints {
+            ln_gateway.route_hints.truncate(max_route_hints);
+        }
+        let gateway_id = ln_gateway.gateway_id;
+
+        let (operation_id, invoice, _) = ln_client
+            .create_bolt11_invoice(
+                amount,
+                Bolt11InvoiceDescription::Direct(Description::new(description.into())?),
+                Some(expiry.as_secs()),
+                extra_meta,
+                Some(ln_gateway),
+            )
+            .await?;
+        self.watch_incoming_payment(operation_id, LightningVersion::V1);
+
+        Ok(CreatedInvoice {
+            payment_hash: invoice.payment_hash().into(),
+            invoice,
+            operation_id,
+            gateway_id,
+        })
+    }
+
+    /// Generates a new Lightning invoice like
+    /// [`Self::lightning_invoice_with_options`], but keyed on an
+    /// application-provided idempotency `key`, e.g. the id of the order the
+    /// invoice is for. This guards against creating two live invoices for the
+    /// same order, e.g. because a form was submitted twice.
+    ///
+    /// The invoice is recorded for the key in the client database, so it
+    /// survives restarts. Repeated calls with the same key return the recorded
+    /// invoice with [`IdempotentInvoice::reused`] set as long as it's still
+    /// payable. If it was already paid it's returned with
+    /// [`InvoiceStatus::Paid`], so the order isn't charged twice. If it expired
+    /// or was canceled a new invoice is created and recorded for the key.
+    ///
+    /// # Errors
+    /// Returns an [`IdempotencyKeyConflict`] error if the key was already used
+    /// for an invoice of a different amount, and otherwise the same errors as
+    /// [`Self::lightning_invoice_with_options`].
+    pub async fn lightning_invoice_idempotent(
+        &self,
+        key: &str,
+        amount: impl Into<Amount>,
+        description: &str,
+        options: InvoiceOptions,
+    ) -> anyhow::Result<IdempotentInvoice> {
+        let amount = amount.into();
+
+        // Held until the invoice is recorded, concurrent calls with the same key
+        // then return the same invoice
+        let _lock = self
+            .idempotency_locks
+            .lock(format!("invoice/{}", key))
+            .await;
+        if let Some(original) = idempotency::load_invoice(self.client.db(), key).await? {
+            let original_amount = original
+                .invoice
+                .amount_milli_satoshis()
+                .map(Amount::from_msats);
+            if original_amount != Some(amount) {
+                return Err(IdempotencyKeyConflict {
+                    key: key.to_owned(),
+                    original_amount,
+                    attempted_amount: Some(amount),
+                }
+                .into());
+            }
+
+            match self.invoice_status(original.payment_hash).await? {
+                InvoiceStatus::Paid => {
+                    return Ok(IdempotentInvoice {
+                        invoice: original,
+                        status: InvoiceStatus::Paid,
+                        reused: true,
+                    });
+                }
+                InvoiceStatus::Pending if !original.invoice.is_expired() => {
+                    return Ok(IdempotentInvoice {
+                        invoice: original,
+                        status: InvoiceStatus::Pending,
+                        reused: true,
+                    });
+                }
+                _ => debug!(
+                    key,
+                    payment_hash = %original.payment_hash,
+                    "Invoice for idempotency key expired or was canceled, creating a new one"
+                ),
+            }
+        }
+
+        let invoice = self
+            .lightning_invoice_with_options(amount, description, options)
+            .await?;
+        idempotency::store_invoice(self.client.db(), key, &invoice).await?;
+        Ok(IdempotentInvoice {
+            invoice,
+            status: InvoiceStatus::Pending,
+            reused: false,
+        })
+    }
+
+    /// Creates an invoice using the `lnv2` module, which chooses the gateway on
+    /// its own. The gateway creates the invoice, so its Lightning node is the
+    /// payee reported as the invoice's gateway id.
+    async fn lnv2_invoice(
+        &self,
+        amount: Amount,
+        description: &str,
+        expiry: Duration,
+        extra_meta: serde_json::Value,
+    ) -> anyhow::Result<CreatedInvoice> {
+        let (invoice, operation_id) = self
+            .lnv2_module()?
+            .receive(
+                amount,
+                u32::try_from(expiry.as_secs()).unwrap_or(u32::MAX),
+                Bolt11InvoiceDescription::Direct(Description::new(description.into())?),
+                None,
+                extra_meta,
+            )
+            .await
+            .map_err(|e| match e {
+                fedimint_lnv2_client::ReceiveError::FailedToSelectGateway(_) => {
+                    anyhow::Error::from(GatewayUnavailable { gateway_id: None })
+                }
+                e => e.into(),
+            })?;
+        lnv2::store_receive_operation(
+            self.client.db(),
+            invoice.payment_hash().to_byte_array(),
+            operation_id,
+        )
+        .await?;
+        self.watch_incoming_payment(operation_id, LightningVersion::V2);
+
+        Ok(CreatedInvoice {
+            gateway_id: invoice.recover_payee_pub_key(),
+            payment_hash: invoice.payment_hash().into(),
+            invoice,
+            operation_id,
+        })
+    }
+
+    /// Follows an incoming payment in the background until it's either claimed
+    /// or canceled, which records its outcome in the operation log even if
+    /// nobody awaits the payment. This keeps [`Self::invoice_status`] and
+    /// [`Self::list_operations`] up to date, including the time the payment
+    /// was claimed at. While followed, the payment counts as pending in
+    /// [`Self::status`].
+    fn watch_incoming_payment(&self, operation_id: OperationId, version: LightningVersion) {
+        if !self
+            .watched_invoices
+            .lock()
+            .expect("lock poisoned")
+            .insert(operation_id)
+        {
+            return;
+        }
+        let client = self.client.clone();
+        let events = self.events.clone();
+        let watched_invoices = self.watched_invoices.clone();
+        let claimed = move |client: ClientHandleArc| {
+            let events = events.clone();
+            async move {
+                if let Err(e) = history::record_settled(client.db(), operation_id).await {
+                    warn!(error = %e, "Failed to record when the payment settled");
+                }
+                let operation = client.operation_log().get_operation(operation_id).await;
+                let (invoice, amount) = match (operation, version) {
+                    (Some(operation), LightningVersion::V1) => {
+                        match operation.meta::<LightningOperationMeta>().variant {
+                            LightningOperationMetaVariant::Receive { invoice, .. } => {
+                                let amount =
+                                    invoice.amount_milli_satoshis().map(Amount::from_msats);
+                                (Some(invoice), amount)
+                            }
+                            _ => (None, None),
+                        }
+                    }
+                    (Some(operation), LightningVersion::V2) => (
+                        lnv2::received_invoice(&operation),
+                        lnv2::received_amount(&operation),
+                    ),
+                    (None, _) => (None, None),
+                };
+                events::emit(
+                    &events,
+                    BlitziEvent::InvoicePaid {
+                        operation_id,
+                        payment_hash: invoice.map(|invoice| invoice.payment_hash().into()),
+                        amount,
+                    },
+                );
+            }
+        };
+        let watch = async move {
+            match version {
+                LightningVersion::V1 => {
+                    let Ok(ln_module) = client.get_first_module::<LightningClientModule>() else {
+                        return;
+                    };
+                    let Ok(updates) = ln_module.subscribe_ln_receive(operation_id).await else {
+                        return;
+                    };
+                    let mut update_stream = updates.into_stream();
+                    while let Some(update) = update_stream.next().await {
+                        if matches!(update, LnReceiveState::Claimed) {
+                            claimed(client.clone()).await;
+                        }
+                    }
+                }
+                LightningVersion::V2 => {
+                    let Ok(lnv2_module) =
+                        client.get_first_module::<fedimint_lnv2_client::LightningClientModule>()
+                    else {
+                        return;
+                    };
+                    let Ok(updates) = lnv2_module
+                        .subscribe_receive_operation_state_updates(operation_id)
+                        .await
+                    else {
+                        return;
+                    };
+                    let mut update_stream = updates.into_stream();
+                    while let Some(update) = update_stream.next().await {
+                        if matches!(update, ReceiveOperationState::Claimed) {
+                            claimed(client.clone()).await;
+                        }
+                    }
+                }
+            }
+        };
+        self.task_group
+            .spawn_cancellable("blitzi-watch-incoming-payment", async move {
+                watch.await;
+                watched_invoices
+                    .lock()
+                    .expect("lock poisoned")
+                    .remove(&operation_id);
+            });
+    }
+
+    /// Starts following the payments that were still pending when the client
+    /// was last shut down and claiming incoming payments in the background.
+    async fn start_background_tasks(&self) -> anyhow::Result<()> {
+        self.watch_pending_incoming_payments().await;
+        self.watch_pending_outgoing_payments().await;
+        self.spawn_reclaim_pending();
+        Ok(())
+    }
+
+    /// Resumes watching outgoing payments that were still pending when the
+    /// client was last shut down, see [`Self::watch_outgoing_payment`].
+    async fn watch_pending_outgoing_payments(&self) {
+        for payment_hash in reclaim::pending_outgoing_payments(&self.client).await {
+            let operation_id = Self::get_payment_operation_id(&payment_hash.0);
+            if let Err(e) = self.watch_outgoing_payment(operation_id, None).await {
+                warn!(error = %e, %payment_hash, "Failed to resume watching payment");
+            }
+        }
+    }
+
+    /// Resumes watching incoming payments that were still pending when the
+    /// client was last shut down, see [`Self::watch_incoming_payment`].
+    async fn watch_pending_incoming_payments(&self) {
+        for (operation_id, version) in reclaim::pending_incoming_payments(&self.client).await {
+            self.watch_incoming_payment(operation_id, version);
+        }
+    }
+
+    /// Decodes a Lightning invoice and returns its details, e.g. to show them
+    /// to a user before paying it.
+    ///
+    /// Besides the data contained in the invoice, the result indicates whether
+    /// the invoice has already expired and whether it is for a different
+    /// network than the federation (in which case it can't be paid).
+    ///
+    /// # Errors
+    /// Returns an error if the invoice can't be parsed.
+    pub fn decode_invoice(&self, invoice: &str) -> anyhow::Result<InvoiceDetails> {
+        let invoice = Bolt11Invoice::from_str(invoice.trim())
+            .map_err(|e| anyhow!("Invalid invoice: {}", e))?;
+        Ok(InvoiceDetails::new(&invoice, self.network()))
+    }
+
+    /// Waits for an invoice generated using [`Self::lightning_invoice`] to be
+    /// paid.
+    ///
+    /// Returns an error in case it times out. There is no need to call this
+    /// function unless you need to know if an invoice was paid. The funds will
+    /// be received either way.
+    pub async fn await_incoming_payment(&self, invoice: &Bolt11Invoice) -> anyhow::Result<()> {
+        self.await_incoming_payment_by_hash(invoice.payment_hash())
+            .await
+    }
+
+    /// Waits for an invoice generated using [`Self::lightning_invoice`] to be
+    /// paid. See [`Self::await_incoming_payment`] for more details.
+    pub async fn await_incoming_payment_by_hash(
+        &self,
+        payment_hash: impl Into<PaymentHash>,
+    ) -> anyhow::Result<()> {
+        self.await_incoming_payment_details_by_hash(payment_hash)
+            .await?;
+        Ok(())
+    }
+
+    /// Waits for the incoming payment of the operation `operation_id`, as
+    /// returned in [`CreatedInvoice::operation_id`], to be paid. See
+    /// [`Self::await_incoming_payment`] for more details.
+    ///
+    /// # Errors
+    /// Returns an error if the operation doesn't exist or isn't an incoming
+    /// Lightning payment, and otherwise the same errors as
+    /// [`Self::await_incoming_payment`].
+    pub async fn await_incoming_payment_by_operation(
+        &self,
+        operation_id: OperationId,
+    ) -> anyhow::Result<()> {
+        self.await_incoming_payment_details_by_operation(operation_id)
+            .await?;
+        Ok(())
+    }
+
+    /// Waits for the incoming payment of the operation `operation_id` to be
+    /// paid. See [`Self::await_incoming_payment_details`] for more details.
+    pub async fn await_incoming_payment_details_by_operation(
+        &self,
+        operation_id: OperationId,
+    ) -> anyhow::Result<ReceivedPayment> {
+        let (version, operation, payment_hash) =
+            self.get_receive_operation_by_id(operation_id).await?;
+        self.await_receive_operation(operation_id, version, operation, payment_hash)
+            .await
+    }
+
+    /// Waits for an invoice generated using [`Self::lightning_invoice`] to be
+    /// paid like [`Self::await_incoming_payment`], and returns the amount that
+    /// was actually claimed and when, e.g. for reconciliation. Returns
+    /// immediately if the invoice was already paid.
+    pub async fn await_incoming_payment_details(
+        &self,
+        invoice: &Bolt11Invoice,
+    ) -> anyhow::Result<ReceivedPayment> {
+        self.await_incoming_payment_details_by_hash(invoice.payment_hash())
+            .await
+    }
+
+    /// Waits for an invoice generated using [`Self::lightning_invoice`] to be
+    /// paid. See [`Self::await_incoming_payment_details`] for more details.
+    pub async fn await_incoming_payment_details_by_hash(
+        &self,
+        payment_hash: impl Into<PaymentHash>,
+    ) -> anyhow::Result<ReceivedPayment> {
+        let payment_hash = payment_hash.into();
+        let (operation_id, version, operation) = self.get_receive_operation(payment_hash).await?;
+        self.await_receive_operation(operation_id, version, operation, payment_hash)
+            .await
+    }
+
+    /// Waits for the next incoming payment to any invoice created using
+    /// [`Self::lightning_invoice`], e.g. for a tip jar displaying a static
+    /// invoice or a script waiting for money to arrive, and returns it. Only
+    /// payments claimed after calling this are considered.
+    ///
+    /// # Errors
+    /// Returns an [`IncomingPaymentTimedOut`] error if no payment was claimed
+    /// within `timeout`, and an error if the client is shut down while
+    /// waiting.
+    pub async fn await_any_incoming_payment(
+        &self,
+        timeout: Duration,
+    ) -> anyhow::Result<ReceivedPayment> {
+        // Subscribe before awaiting so no claim is missed
+        let receiver = self.events.subscribe();
+        let (operation_id, payment_hash, amount) =
+            fedimint_core::runtime::timeout(timeout, events::next_invoice_paid(receiver))
+                .await
+                .map_err(|_| IncomingPaymentTimedOut { timeout })?
+                .context("Client was shut down while waiting for a payment")?;
+        self.received_payment(operation_id, payment_hash, amount)
+            .await
+    }
+
+    /// Waits for the incoming payment `operation_id` to be claimed.
+    async fn await_receive_operation(
+        &self,
+        operation_id: OperationId,
+        version: LightningVersion,
+        operation: OperationLogEntry,
+        payment_hash: PaymentHash,
+    ) -> anyhow::Result<ReceivedPayment> {
+        if cancel::is_canceled(self.client.db(), operation_id).await?
+            && self.invoice_status(payment_hash).await? != InvoiceStatus::Paid
+        {
+            return Err(anyhow!(
+                "Payment was canceled: {}",
+                cancel::CANCELED_BY_USER
+            ));
+        }
+
+        if version == LightningVersion::V2 {
+            let mut update_stream = self
+                .lnv2_module()?
+                .subscribe_receive_operation_state_updates(operation_id)
+                .await
+                .context("Unexpected error subscribing to operation")?
+                .into_stream();
+            while let Some(update) = update_stream.next().await {
+                match update {
+                    ReceiveOperationState::Claimed => {
+                        let amount = lnv2::received_amount(&operation)
+                            .context("Operation is not an incoming payment")?;
+                        return self
+                            .received_payment(operation_id, payment_hash, amount)
+                            .await;
+                    }
+                    ReceiveOperationState::Expired => {
+                        return Err(anyhow!("Payment was canceled: invoice expired"));
+                    }
+                    ReceiveOperationState::Failure => {
+                        return Err(anyhow!("Payment was canceled: claiming the payment failed"));
+                    }
+                    _ => {}
+                }
+            }
+            unreachable!("Stream ended unexpectedly");
+        }
+
+        let ln_module = self.ln_module()?;
+        let mut update_stream = ln_module
+            .subscribe_ln_receive(operation_id)
+            .await
+            .context("Unexpected error subscribing to operation")?
+            .into_stream();
+        while let Some(update) = update_stream.next().await {
+            match update {
+                LnReceiveState::Canceled { reason } => {
+                    return Err(anyhow!("Payment was canceled: {}", reason));
+                }
+                LnReceiveState::Claimed => {
+                    let LightningOperationMetaVariant::Receive { invoice, .. } =
+                        operation.meta::<LightningOperationMeta>().variant
+                    else {
+                        unreachable!("Checked by get_receive_operation");
+                    };
+                    // The gateway funds the incoming contract with the amount
+                    // of the offer, which is the invoice amount, and keeps
+                    // anything paid on top. Invoices are never issued without
+                    // an amount, see `InvoiceAmountError::Zero`.
+                    let amount = invoice
+                        .amount_milli_satoshis()
+                        .map(Amount::from_msats)
+                        .context("Invoice has no amount")?;
+                    return self
+                        .received_payment(operation_id, payment_hash, amount)
+                        .await;
+                }
+                _ => {}
+            }
+        }
+
+        unreachable!("Stream ended unexpectedly");
+    }
+
+    /// Returns the details of the claimed incoming payment `operation_id`,
+    /// recording when it settled unless the background task already did.
+    async fn received_payment(
+        &self,
+        operation_id: OperationId,
+        payment_hash: PaymentHash,
+        amount: Amount,
+    ) -> anyhow::Result<ReceivedPayment> {
+        history::record_settled(self.client.db(), operation_id).await?;
+        let claimed_at = history::settled_at(self.client.db(), operation_id)
+            .await?
+            .context("Settlement time was just recorded")?;
+        Ok(ReceivedPayment {
+            amount,
+            payment_hash,
+            claimed_at,
+        })
+    }
+
+    /// Claims `amount` from an LNURL-withdraw link (LUD-03), e.g. a faucet or
+    /// a withdrawal offered by an exchange: fetches the link's withdraw
+    /// parameters, creates an invoice using [`Self::lightning_invoice`] with
+    /// the service's default description, submits it to the service and waits
+    /// for the service to pay it.
+    ///
+    /// Accepts bech32 encoded LNURLs (optionally prefixed with `lightning:`),
+    /// `lnurlw://` links and the `https://` URLs they encode. Requires the
+    /// `lnurl` feature.
+    ///
+    /// # Errors
+    /// Returns a [`WithdrawAmountOutOfRange`] error if the service doesn't
+    /// allow withdrawing `amount`, a [`LnurlServiceError`] with the HTTP
+    /// status if it responds with an error (server errors are retried until
+    /// the [LNURL deadline](BlitziBuilder::lnurl_deadline)), the same errors as
+    /// [`Self::lightning_invoice`] if the invoice can't be created, and an
+    /// error if the link is invalid, the service refuses the withdrawal or
+    /// the invoice expires before it is paid.
+    #[cfg(feature = "lnurl")]
+    pub async fn claim_lnurl_withdraw(
         &self,
-        payment_hash: &sha256::Hash,
+        lnurl: &str,
+        amount: impl Into<Amount>,
     ) -> anyhow::Result<()> {
-        let operation_id = OperationId(*payment_hash.as_ref());
+        let amount = amount.into();
+        let http = reqwest::Client::new();
+        let request = lnurl::WithdrawRequest::fetch(&http, lnurl, self.lnurl_deadline).await?;
+        request.check_amount(amount)?;
+
+        let invoice = self
+            .lightning_invoice(amount, &request.default_description)
+            .await?;
+        request.submit(&http, &invoice, self.lnurl_deadline).await?;
+        self.await_incoming_payment(&invoice).await
+    }
+
+    /// Returns the status of an invoice generated using
+    /// [`Self::lightning_invoice`] without waiting for it to be paid.
+    ///
+    /// # Errors
+    /// Returns an error if the invoice wasn't issued by this client.
+    pub async fn invoice_status(
+        &self,
+        payment_hash: impl Into<PaymentHash>,
+    ) -> anyhow::Result<InvoiceStatus> {
+        let (operation_id, version, operation) =
+            self.get_receive_operation(payment_hash.into()).await?;
+        let status = match version {
+            LightningVersion::V1 => match operation.outcome::<LnReceiveState>() {
+                Some(LnReceiveState::Claimed) => InvoiceStatus::Paid,
+                Some(LnReceiveState::Canceled { .. }) => InvoiceStatus::Canceled,
+                _ => InvoiceStatus::Pending,
+            },
+            LightningVersion::V2 => InvoiceStatus::from_receive_state(operation.outcome()),
+        };
+
+        if status == InvoiceStatus::Pending
+            && cancel::is_canceled(self.client.db(), operation_id).await?
+        {
+            return Ok(InvoiceStatus::Canceled);
+        }
+        Ok(status)
+    }
+
+    /// Returns a stream of the states the incoming payment for an invoice
+    /// generated using [`Self::lightning_invoice`] goes through, starting with
+    /// its current state and ending with a [final](ReceiveState::is_final)
+    /// one, e.g. to show a customer that their payment was seen before the
+    /// funds are claimed. Each state is yielded once.
+    ///
+    /// # Errors
+    /// Returns an error if the invoice wasn't issued by this client.
+    pub async fn subscribe_invoice_updates(
+        &self,
+        payment_hash: impl Into<PaymentHash>,
+    ) -> anyhow::Result<BoxStream<'static, ReceiveState>> {
+        let payment_hash = payment_hash.into();
+        let (operation_id, version, _) = self.get_receive_operation(payment_hash).await?;
+        if cancel::is_canceled(self.client.db(), operation_id).await?
+            && self.invoice_status(payment_hash).await? != InvoiceStatus::Paid
+        {
+            return Ok(Box::pin(futures_lite::stream::once(
+                ReceiveState::Canceled {
+                    reason: cancel::CANCELED_BY_USER.to_string(),
+                },
+            )));
+        }
+
+        let updates: BoxStream<'static, ReceiveState> = match version {
+            LightningVersion::V1 => Box::pin(
+                self.ln_module()?
+                    .subscribe_ln_receive(operation_id)
+                    .await
+                    .context("Unexpected error subscribing to operation")?
+                    .into_stream()
+                    .map(ReceiveState::from_ln_receive_state),
+            ),
+            LightningVersion::V2 => Box::pin(
+                self.lnv2_module()?
+                    .subscribe_receive_operation_state_updates(operation_id)
+                    .await
+                    .context("Unexpected error subscribing to operation")?
+                    .into_stream()
+                    .map(ReceiveState::from_lnv2_receive_state),
+            ),
+        };
+
+        // Several states of the `ln` module map to the same state
+        let mut last = None;
+        Ok(Box::pin(updates.filter(move |state| {
+            let is_new = last.as_ref() != Some(state);
+            last = Some(state.clone());
+            is_new
+        })))
+    }
+
+    /// Returns whether `invoice` was issued by this client, e.g. to verify an
+    /// invoice a customer claims to have received from you. Besides looking
+    /// up the receive operation for the invoice's payment hash, this checks
+    /// that the operation was created for exactly this invoice. Doesn't wait
+    /// for the invoice to be paid.
+    ///
+    /// # Errors
+    /// Returns an error if the operation log can't be read.
+    pub async fn is_invoice_ours(&self, invoice: &Bolt11Invoice) -> anyhow::Result<bool> {
+        Ok(self
+            .stored_receive_invoice(invoice.payment_hash().into())
+            .await?
+            .is_some_and(|stored| stored == *invoice))
+    }
+
+    /// Returns whether an invoice with `payment_hash` was issued by this
+    /// client. See [`Self::is_invoice_ours`] for more details.
+    pub async fn is_invoice_ours_by_hash(
+        &self,
+        payment_hash: impl Into<PaymentHash>,
+    ) -> anyhow::Result<bool> {
+        let payment_hash = payment_hash.into();
+        Ok(self
+            .stored_receive_invoice(payment_hash)
+            .await?
+            .is_some_and(|stored| PaymentHash::from(stored.payment_hash()) == payment_hash))
+    }
+
+    /// Returns the invoice with `payment_hash` if it was generated by this
+    /// client, e.g. to report its amount and expiry together with its
+    /// [status](Self::invoice_status).
+    ///
+    /// # Errors
+    /// Returns an error if the operation log can't be read.
+    pub async fn issued_invoice(
+        &self,
+        payment_hash: impl Into<PaymentHash>,
+    ) -> anyhow::Result<Option<Bolt11Invoice>> {
+        let payment_hash = payment_hash.into();
+        Ok(self
+            .stored_receive_invoice(payment_hash)
+            .await?
+            .filter(|stored| PaymentHash::from(stored.payment_hash()) == payment_hash))
+    }
+
+    /// Returns the invoice stored in the meta of the receive operation for
+    /// `payment_hash`, `None` if there is no such operation.
+    async fn stored_receive_invoice(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> anyhow::Result<Option<Bolt11Invoice>> {
+        let operation_log = self.client.operation_log();
+        if let Some(operation_id) =
+            lnv2::receive_operation(self.client.db(), payment_hash.to_byte_array()).await?
+        {
+            return Ok(operation_log
+                .get_operation(operation_id)
+                .await
+                .and_then(|operation| lnv2::received_invoice(&operation)));
+        }
+
+        let Some(operation) = operation_log
+            .get_operation(OperationId(payment_hash.to_byte_array()))
+            .await
+        else {
+            return Ok(None);
+        };
+        if operation.operation_module_kind() != "ln" {
+            return Ok(None);
+        }
+        match operation.meta::<LightningOperationMeta>().variant {
+            LightningOperationMetaVariant::Receive { invoice, .. } => Ok(Some(invoice)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Cancels an unpaid invoice generated using [`Self::lightning_invoice`],
+    /// e.g. when a checkout is abandoned. Afterwards [`Self::invoice_status`]
+    /// reports the invoice as [`InvoiceStatus::Canceled`],
+    /// [`Self::await_incoming_payment`] fails with the reason "canceled by
+    /// user" and [`Self::list_operations`] lists it as failed.
+    ///
+    /// The invoice itself can't be revoked on the Lightning network and the
+    /// federation keeps accepting payments to it until it expires. Should it
+    /// still be paid, the funds are received as usual and the invoice is
+    /// reported as [`InvoiceStatus::Paid`] instead, so no payment goes
+    /// unnoticed. Canceling an invoice that is already canceled or expired
+    /// does nothing.
+    ///
+    /// # Errors
+    /// Returns an [`AlreadyPaid`] error if the invoice was already paid or the
+    /// payment is being received, and an error if the invoice wasn't issued by
+    /// this client.
+    pub async fn cancel_invoice(&self, payment_hash: impl Into<PaymentHash>) -> anyhow::Result<()> {
+        let payment_hash = payment_hash.into();
+        let (operation_id, version, _) = self.get_receive_operation(payment_hash).await?;
+
+        let paid = match version {
+            LightningVersion::V1 => {
+                let updates = self
+                    .ln_module()?
+                    .subscribe_ln_receive(operation_id)
+                    .await
+                    .context("Unexpected error subscribing to operation")?
+                    .into_stream();
+                matches!(
+                    cancel::current_state(updates).await,
+                    Some(
+                        LnReceiveState::Funded
+                            | LnReceiveState::AwaitingFunds
+                            | LnReceiveState::Claimed
+                    )
+                )
+            }
+            LightningVersion::V2 => {
+                let updates = self
+                    .lnv2_module()?
+                    .subscribe_receive_operation_state_updates(operation_id)
+                    .await
+                    .context("Unexpected error subscribing to operation")?
+                    .into_stream();
+                matches!(
+                    cancel::current_state(updates).await,
+                    Some(ReceiveOperationState::Claiming | ReceiveOperationState::Claimed)
+                )
+            }
+        };
+        if paid {
+            return Err(AlreadyPaid { payment_hash }.into());
+        }
+
+        cancel::record(self.client.db(), operation_id).await
+    }
+
+    /// Returns the operation receiving payments to the invoice with
+    /// `payment_hash` and the Lightning module that created it.
+    async fn get_receive_operation(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> anyhow::Result<(OperationId, LightningVersion, OperationLogEntry)> {
+        if let Some(operation_id) =
+            lnv2::receive_operation(self.client.db(), payment_hash.to_byte_array()).await?
+        {
+            let operation = self
+                .client
+                .operation_log()
+                .get_operation(operation_id)
+                .await
+                .context("Operation recorded for the payment hash not found")?;
+            return Ok((operation_id, LightningVersion::V2, operation));
+        }
+
+        let operation_id = OperationId(payment_hash.to_byte_array());
+
+        let operation = self
+            .client
+            .operation_log()
+            .get_operation(operation_id)
+            .await
+            .context(
+                "No operation found for payment hash, was the invoice issued by us?".to_string(),
+            )?;
+        ensure!(
+            operation.operation_module_kind() == "ln",
+            "Operation associated with payment hash is not an LN operation"
+        );
+
+        let operation_meta = operation.meta::<LightningOperationMeta>();
+        ensure!(
+            matches!(
+                operation_meta.variant,
+                LightningOperationMetaVariant::Receive { .. }
+            ),
+            "Operation associated with the payment hash is not an incoming payment"
+        );
+
+        Ok((operation_id, LightningVersion::V1, operation))
+    }
+
+    /// Looks up the incoming payment with the given `operation_id` and
+    /// returns the module version it was received with, its operation log
+    /// entry and its payment hash.
+    async fn get_receive_operation_by_id(
+        &self,
+        operation_id: OperationId,
+    ) -> anyhow::Result<(LightningVersion, OperationLogEntry, PaymentHash)> {
+        let operation = self
+            .client
+            .operation_log()
+            .get_operation(operation_id)
+            .await
+            .context("No operation found for operation id")?;
+
+        match operation.operation_module_kind() {
+            "ln" => {
+                let LightningOperationMetaVariant::Receive { invoice, .. } =
+                    operation.meta::<LightningOperationMeta>().variant
+                else {
+                    bail!("Operation is not an incoming payment");
+                };
+                let payment_hash = invoice.payment_hash().into();
+                Ok((LightningVersion::V1, operation, payment_hash))
+            }
+            lnv2::KIND => {
+                let payment_hash = lnv2::received_payment_hash(&operation)
+                    .context("Operation is not an incoming payment")?;
+                Ok((LightningVersion::V2, operation, payment_hash))
+            }
+            _ => bail!("Operation is not a Lightning operation"),
+        }
+    }
+
+    /// Pays an invoice and returns the preimage of the payment.
+    ///
+    /// If an payment was already made to the same invoice, the result of the
+    /// previous payment will be returned again. This allows building safe retry
+    /// logic that just tries to pay an invoice again if it's unclear if a
+    /// previous call to this function succeeded or not (e.g. in the case of a
+    /// crash).
+    ///
+    /// Retries are not supported for now since they will likely fail too if the
+    /// original attempt failed and would add additional complexity. To follow
+    /// payments that were interrupted by a crash without paying again use
+    /// [`Self::resume_pending_payments`].
+    ///
+    /// To show the progress of the payment to users use
+    /// [`Self::pay_with_updates`] instead.
+    ///
+    /// # Errors
+    /// Returns an [`InvalidInvoiceError`] if a new payment is attempted for an
+    /// invoice that has expired or is for a different network than the
+    /// federation, a [`SpendLimitExceeded`] error if it would exceed the
+    /// [spend limit](BlitziBuilder::spend_limit), a [`PolicyDenied`] error if
+    /// the [payment policy](BlitziBuilder::payment_policy) rejects it, a
+    /// [`PaymentRefunded`] error if the payment was started but failed, and an
+    /// error if it fails for any other reason.
+    pub async fn pay(&self, invoice: &Bolt11Invoice) -> anyhow::Result<Preimage> {
+        Self::pay_outcome(self.pay_with_updates(invoice).await?).await
+    }
+
+    /// Pays an invoice like [`Self::pay`], but keyed on an application-provided
+    /// idempotency `key` instead of the invoice. This is useful when every
+    /// attempt produces a new invoice for the same purchase (e.g. LNURL), where
+    /// retrying after a crash would otherwise pay twice.
+    ///
+    /// The key is recorded in the client database before the payment is
+    /// started, so it survives restarts. Repeated calls with the same key
+    /// return the outcome of the original payment, even if `invoice` differs,
+    /// in which case [`IdempotentPayment::warning`] is set. If the original
+    /// payment failed the failure is returned again, use a new key to retry.
+    ///
+    /// # Errors
+    /// Returns an [`IdempotencyKeyConflict`] error if the key was already used
+    /// for an invoice of a different amount, an [`ExternalIdInUse`] error if
+    /// the [external id](PayOptions::external_id) is already attached to
+    /// another invoice or payment, a [`FeeTooHigh`] error if the gateway
+    /// charges more than [`PayOptions::max_fee`], and otherwise the same
+    /// errors as [`Self::pay`].
+    pub async fn pay_idempotent(
+        &self,
+        key: &str,
+        invoice: &Bolt11Invoice,
+        options: PayOptions,
+    ) -> anyhow::Result<IdempotentPayment> {
+        policy::check(self.payment_policy.as_ref(), invoice)?;
+
+        let amount = invoice.amount_milli_satoshis().map(Amount::from_msats);
+        let record = IdempotencyRecord {
+            payment_hash: invoice.payment_hash().into(),
+            amount,
+        };
+
+        // Held until the payment is started, concurrent calls with the same key
+        // then find the record and follow the same payment
+        let lock = self.idempotency_locks.lock(key.to_owned()).await;
+        let original = idempotency::load_record(self.client.db(), key).await?;
+        let external_id_lock = match &options.external_id {
+            Some(external_id) => {
+                let lock = self
+                    .idempotency_locks
+                    .lock(format!("external-id/{}", external_id))
+                    .await;
+                // Retries find the id attached to the original payment
+                let followed =
+                    original.map_or(record.payment_hash, |original| original.payment_hash);
+                if external_id::find(self.client.db(), external_id).await?
+                    != Some(Self::get_payment_operation_id(&followed.0))
+                {
+                    external_id::check_unused(self.client.db(), external_id).await?;
+                }
+                Some(lock)
+            }
+            None => None,
+        };
+        let (payment_hash, updates, warning) = match original {
+            Some(original) if original.amount != amount => {
+                return Err(IdempotencyKeyConflict {
+                    key: key.to_owned(),
+                    original_amount: original.amount,
+                    attempted_amount: amount,
+                }
+                .into());
+            }
+            Some(original) if original.payment_hash != record.payment_hash => {
+                let operation_id = Self::get_payment_operation_id(&original.payment_hash.0);
+                match self.subscribe_payment(operation_id).await? {
+                    Some(updates) => {
+                        warn!(
+                            key,
+                            original_payment_hash = %original.payment_hash,
+                            payment_hash = %record.payment_hash,
+                            "Idempotency key was already used for a different invoice"
+                        );
+                        (
+                            original.payment_hash,
+                            updates,
+                            Some(idempotency::reused_key_warning(original.payment_hash)),
+                        )
+                    }
+                    // The original payment was never started (e.g. because of a crash
+                    // right after recording the key), so the new invoice can be paid
+                    None => {
+                        idempotency::store_record(self.client.db(), key, &record).await?;
+                        let updates = self
+                            .start_payment(invoice, options.gateway, options.max_fee)
+                            .await?;
+                        (record.payment_hash, updates, None)
+                    }
+                }
+            }
+            Some(_) => {
+                let updates = self
+                    .start_payment(invoice, options.gateway, options.max_fee)
+                    .await?;
+                (record.payment_hash, updates, None)
+            }
+            None => {
+                idempotency::store_record(self.client.db(), key, &record).await?;
+                let updates = self
+                    .start_payment(invoice, options.gateway, options.max_fee)
+                    .await?;
+                (record.payment_hash, updates, None)
+            }
+        };
+        if let Some(external_id) = &options.external_id {
+            let operation_id = Self::get_payment_operation_id(&payment_hash.0);
+            external_id::store(self.client.db(), external_id, operation_id).await?;
+        }
+        drop(external_id_lock);
+        drop(lock);
+
+        Ok(IdempotentPayment {
+            payment_hash,
+            preimage: Self::pay_outcome(updates).await?,
+            warning,
+        })
+    }
+
+    /// Pays several invoices concurrently, e.g. to settle payouts to many
+    /// recipients, and returns the outcome of every payment in the order of
+    /// `invoices`. Failing payments don't affect the others. Invoices with the
+    /// same payment hash are only paid once and reported once.
+    ///
+    /// At most [`PayOptions::concurrency`] payments (4 by default) are made at
+    /// once to not overwhelm the gateway. Each payment behaves like
+    /// [`Self::pay`]: it's checked against the
+    /// [payment policy](BlitziBuilder::payment_policy) and the
+    /// [spend limit](BlitziBuilder::spend_limit), and invoices that were
+    /// already paid return the result of the previous payment.
+    ///
+    /// Dropping the returned future stops starting new payments, payments that
+    /// were already started continue in the background. Their outcome can be
+    /// looked up using [`Self::payment_result`] or awaited using
+    /// [`Self::resume_pending_payments`], and calling this function again with
+    /// the same invoices follows them instead of paying twice.
+    pub async fn pay_batch(
+        &self,
+        invoices: Vec<Bolt11Invoice>,
+        options: PayOptions,
+    ) -> Vec<(PaymentHash, anyhow::Result<PaymentReceipt>)> {
+        let mut seen = HashSet::new();
+        let invoices = invoices
+            .into_iter()
+            .filter(|invoice| seen.insert(*invoice.payment_hash()))
+            .collect::<Vec<_>>();
+        let concurrency = options
+            .concurrency
+            .unwrap_or(DEFAULT_BATCH_CONCURRENCY)
+            .max(1);
+
+        let (gateway, max_fee) = (options.gateway, options.max_fee);
+        let payments = futures_lite::stream::iter(invoices).map(|invoice| async move {
+            let payment_hash = PaymentHash::from(invoice.payment_hash());
+            (
+                payment_hash,
+                self.pay_receipt(&invoice, gateway, max_fee).await,
+            )
+        });
+        // The payments borrow `self`, so they are polled here instead of spawned
+        futures_util::StreamExt::buffered(payments, concurrency)
+            .collect()
+            .await
+    }
+
+    /// Pays an invoice like [`Self::pay`], but gives up waiting for the outcome
+    /// once `deadline` has passed, e.g. to not keep a synchronous checkout
+    /// waiting for the gateway's full retry window.
+    ///
+    /// Giving up doesn't cancel the payment: once started it may still
+    /// succeed (or fail and be refunded) after the deadline. Reconcile such
+    /// payments later using [`Self::payment_result`], or by calling
+    /// [`Self::pay`] for the same invoice, which follows the payment instead
+    /// of paying again.
+    ///
+    /// # Errors
+    /// Returns a [`PaymentTimedOut`] error if the payment didn't reach a final
+    /// state before the deadline, and otherwise the same errors as
+    /// [`Self::pay`].
+    pub async fn pay_with_deadline(
+        &self,
+        invoice: &Bolt11Invoice,
+        deadline: Duration,
+    ) -> anyhow::Result<Preimage> {
+        payment::with_deadline(invoice.payment_hash().into(), deadline, self.pay(invoice)).await
+    }
+
+    /// Pays an invoice like [`Self::pay`], but honors the
+    /// [gateway](PayOptions::gateway) and [fee cap](PayOptions::max_fee) of
+    /// `options` and returns a receipt with the amount and fee paid.
+    ///
+    /// # Errors
+    /// Returns a [`FeeTooHigh`] error if the gateway charges more than
+    /// [`PayOptions::max_fee`], and otherwise the same errors as [`Self::pay`].
+    pub async fn pay_detailed(
+        &self,
+        invoice: &Bolt11Invoice,
+        options: PayOptions,
+    ) -> anyhow::Result<PaymentReceipt> {
+        self.pay_receipt(invoice, options.gateway, options.max_fee)
+            .await
+    }
+
+    /// Returns what paying `invoice` would cost without paying it, e.g. to
+    /// ask the user to confirm the fee. The gateway is chosen the same way as
+    /// by [`Self::pay`], but it may change its fees until the payment is made,
+    /// use [`PayOptions::max_fee`] to bound them.
+    ///
+    /// # Errors
+    /// Returns an [`InvalidInvoiceError`] if the invoice has expired or is for
+    /// a different network than the federation, a [`GatewayUnavailable`] error
+    /// if no gateway is available, an [`UnsupportedByLnv2`] error for
+    /// federations that only support the `lnv2` module, and an error for
+    /// amountless invoices.
+    pub async fn quote_payment(&self, invoice: &Bolt11Invoice) -> anyhow::Result<PaymentQuote> {
+        crate::invoice::check_payable(invoice, self.network())?;
+        if self.lightning_version != LightningVersion::V1 {
+            return Err(UnsupportedByLnv2 {
+                feature: "Quoting payments",
+            }
+            .into());
+        }
+
+        let amount = invoice.amount_milli_satoshis().unwrap_or_default();
+        let gateway = self.select_gateway(Amount::from_msats(amount)).await?;
+        let ln_gateway = self
+            .gateway_request(self.ln_module()?.get_gateway(gateway, false))
+            .await?
+            .ok_or(GatewayUnavailable {
+                gateway_id: gateway,
+            })?;
+        PaymentQuote::ln(
+            invoice,
+            ln_gateway.node_pub_key,
+            ln_gateway.federation_index,
+            ln_gateway.fees,
+        )
+    }
+
+    /// Returns the receipt of the successful payment of the invoice with
+    /// `payment_hash`, `None` if this client didn't pay it or the payment
+    /// hasn't succeeded (yet).
+    ///
+    /// # Errors
+    /// Returns an error if the payment hash belongs to an operation that isn't
+    /// an outgoing payment.
+    pub async fn payment_receipt(
+        &self,
+        payment_hash: impl Into<PaymentHash>,
+    ) -> anyhow::Result<Option<PaymentReceipt>> {
+        let payment_hash = payment_hash.into();
+        let Some(PaymentResult::Succeeded { preimage, fee }) =
+            self.payment_result(payment_hash).await?
+        else {
+            return Ok(None);
+        };
+
+        let operation_id = Self::get_payment_operation_id(&payment_hash.0);
+        let (amount, internal) = match lnv2::send_operation(self.client.db(), operation_id).await? {
+            Some(lnv2_operation_id) => {
+                let operation = self
+                    .client
+                    .operation_log()
+                    .get_operation(lnv2_operation_id)
+                    .await
+                    .context("Operation recorded for the payment hash not found")?;
+                let invoice = lnv2::operation_invoice(&operation)
+                    .context("Operation is not an outgoing payment")?;
+                (invoice.amount_milli_satoshis(), false)
+            }
+            None => {
+                let operation = self
+                    .client
+                    .operation_log()
+                    .get_operation(operation_id)
+                    .await
+                    .context("Operation associated with the payment hash not found")?;
+                let LightningOperationMetaVariant::Pay(LightningOperationMetaPay {
+                    invoice,
+                    is_internal_payment,
+                    ..
+                }) = operation.meta::<LightningOperationMeta>().variant
+                else {
+                    bail!("Operation associated with the payment hash is not an outgoing payment");
+                };
+                (invoice.amount_milli_satoshis(), is_internal_payment)
+            }
+        };
+
+        Ok(Some(PaymentReceipt {
+            payment_hash,
+            preimage,
+            amount: Amount::from_msats(amount.unwrap_or_default()),
+            fee,
+            internal,
+        }))
+    }
+
+    /// Pays `invoice` like [`Self::pay`] and returns its receipt.
+    async fn pay_receipt(
+        &self,
+        invoice: &Bolt11Invoice,
+        gateway: Option<PublicKey>,
+        max_fee: Option<Amount>,
+    ) -> anyhow::Result<PaymentReceipt> {
+        policy::check(self.payment_policy.as_ref(), invoice)?;
+        Self::pay_outcome(self.start_payment(invoice, gateway, max_fee).await?).await?;
+        self.payment_receipt(invoice.payment_hash())
+            .await?
+            .context("Payment succeeded but its result wasn't recorded")
+    }
+
+    /// Waits for the final state of a payment and returns its preimage.
+    async fn pay_outcome(mut updates: BoxStream<'static, PayProgress>) -> anyhow::Result<Preimage> {
+        while let Some(progress) = updates.next().await {
+            match progress {
+                PayProgress::Succeeded { preimage } => return Ok(preimage),
+                PayProgress::Failed { reason } => return Err(PaymentRefunded { reason }.into()),
+                _ => {}
+            }
+        }
+
+        Err(anyhow!("No outcome found for payment, should never happen"))
+    }
+
+    /// Pays an invoice like [`Self::pay`], but returns a stream of the
+    /// payment's progress, e.g. to show "routing payment…" instead of a
+    /// spinner. The last item of the stream is either
+    /// [`PayProgress::Succeeded`] containing the preimage or
+    /// [`PayProgress::Failed`].
+    ///
+    /// Just like [`Self::pay`], calling this function for an invoice that was
+    /// already paid follows the previous payment instead of paying again.
+    ///
+    /// # Errors
+    /// Returns an [`InvalidInvoiceError`] if a new payment is attempted for an
+    /// invoice that has expired or is for a different network than the
+    /// federation, and an error if the payment can't be started for any other
+    /// reason. Failures after the payment was started are reported as
+    /// [`PayProgress::Failed`].
+    pub async fn pay_with_updates(
+        &self,
+        invoice: &Bolt11Invoice,
+    ) -> anyhow::Result<BoxStream<'static, PayProgress>> {
+        policy::check(self.payment_policy.as_ref(), invoice)?;
+        self.start_payment(invoice, None, None).await
+    }
+
+    /// Returns the result of paying the invoice with `payment_hash`, `None` if
+    /// this client never started paying it. Unlike calling [`Self::pay`] again
+    /// this never starts a payment, e.g. to find out whether a payment that was
+    /// in flight when the application crashed went through.
+    ///
+    /// The result reflects the last outcome recorded by the Fedimint client. As
+    /// long as it's [`PaymentResult::Pending`] the payment is followed in the
+    /// background, so its outcome is recorded as soon as it's known.
+    ///
+    /// # Errors
+    /// Returns an error if the payment hash belongs to an operation that isn't
+    /// an outgoing payment.
+    pub async fn payment_result(
+        &self,
+        payment_hash: impl Into<PaymentHash>,
+    ) -> anyhow::Result<Option<PaymentResult>> {
+        let operation_id = Self::get_payment_operation_id(&payment_hash.into().0);
+        if let Some(lnv2_operation_id) =
+            lnv2::send_operation(self.client.db(), operation_id).await?
+        {
+            return self
+                .lnv2_payment_result(operation_id, lnv2_operation_id)
+                .await
+                .map(Some);
+        }
+
+        let Some(operation) = self
+            .client
+            .operation_log()
+            .get_operation(operation_id)
+            .await
+        else {
+            return Ok(None);
+        };
+
+        let LightningOperationMetaVariant::Pay(LightningOperationMetaPay {
+            fee,
+            is_internal_payment,
+            ..
+        }) = operation.meta::<LightningOperationMeta>().variant
+        else {
+            return Err(anyhow!(
+                "Operation associated with the payment hash is not an outgoing payment"
+            ));
+        };
+
+        let progress = if is_internal_payment {
+            operation
+                .outcome::<InternalPayState>()
+                .map(PayProgress::from_internal_pay_state)
+        } else {
+            operation
+                .outcome::<LnPayState>()
+                .map(PayProgress::from_ln_pay_state)
+        };
+
+        match progress {
+            Some(progress) => Ok(Some(PaymentResult::from_progress(progress, fee))),
+            None => {
+                self.watch_outgoing_payment(operation_id, None).await?;
+                Ok(Some(PaymentResult::Pending))
+            }
+        }
+    }
 
+    /// Returns the result of a payment made using the `lnv2` module, see
+    /// [`Self::payment_result`].
+    async fn lnv2_payment_result(
+        &self,
+        payment_id: OperationId,
+        operation_id: OperationId,
+    ) -> anyhow::Result<PaymentResult> {
         let operation = self
             .client
             .operation_log()
             .get_operation(operation_id)
             .await
-            .context(
-                "No operation found for payment hash, was the invoice issued by us?".to_string(),
-            )?;
-        ensure!(
-            operation.operation_module_kind() == "ln",
-            "Operation associated with payment hash is not an LN operation"
-        );
+            .context("Operation recorded for the payment hash not found")?;
+        let fedimint_lnv2_client::LightningOperationMeta::Send(meta) = operation.meta() else {
+            return Err(anyhow!(
+                "Operation associated with the payment hash is not an outgoing payment"
+            ));
+        };
 
-        let operation_meta = operation.meta::<LightningOperationMeta>();
-        ensure!(
-            matches!(
-                operation_meta.variant,
-                LightningOperationMetaVariant::Receive { .. }
-            ),
-            "Operation associated with the payment hash is not an incoming payment"
-        );
+        match operation.outcome::<SendOperationState>() {
+            Some(state) => Ok(PaymentResult::from_progress(
+                PayProgress::from_send_state(state),
+                lnv2::send_fee(&meta),
+            )),
+            None => {
+                self.watch_outgoing_payment(payment_id, None).await?;
+                Ok(PaymentResult::Pending)
+            }
+        }
+    }
 
-        let ln_module = self.ln_module();
-        let mut update_stream = ln_module
-            .subscribe_ln_receive(operation_id)
-            .await
-            .context("Unexpected error subscribing to operation")?
-            .into_stream();
-        while let Some(update) = update_stream.next().await {
-            match update {
-                LnReceiveState::Canceled { reason } => {
-                    return Err(anyhow!("Payment was canceled: {}", reason));
-                }
-                LnReceiveState::Claimed => {
-                    return Ok(());
+    /// Follows all outgoing payments that haven't finished yet, e.g. because
+    /// the application crashed while they were in flight, until they succeed
+    /// or fail, and returns their payment hashes and results.
+    ///
+    /// This resumes the existing payments instead of paying their invoices
+    /// again, so it never pays an invoice twice. Call it after starting the
+    /// client to learn the outcome of payments nobody is waiting for anymore.
+    /// Payments whose outcome can't be determined are reported as
+    /// [`PaymentResult::Pending`].
+    pub async fn resume_pending_payments(&self) -> Vec<(PaymentHash, PaymentResult)> {
+        let mut results = vec![];
+        for payment_hash in reclaim::pending_outgoing_payments(&self.client).await {
+            let result = match self.await_payment_result(payment_hash).await {
+                Ok(result) => result,
+                Err(e) => {
+                    warn!(error = %e, %payment_hash, "Failed to resume payment");
+                    PaymentResult::Pending
                 }
-                _ => {}
+            };
+            results.push((payment_hash, result));
+        }
+        results
+    }
+
+    /// Waits for the payment of the invoice with `payment_hash` to finish and
+    /// returns its result.
+    async fn await_payment_result(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> anyhow::Result<PaymentResult> {
+        let operation_id = Self::get_payment_operation_id(&payment_hash.0);
+        let mut updates = self
+            .subscribe_payment(operation_id)
+            .await?
+            .context("No payment found for the payment hash")?;
+        while let Some(progress) = updates.next().await {
+            if progress.is_final() {
+                break;
             }
         }
 
-        unreachable!("Stream ended unexpectedly");
+        Ok(self
+            .payment_result(payment_hash)
+            .await?
+            .unwrap_or(PaymentResult::Pending))
     }
 
-    /// Pays an invoice and returns the preimage of the payment.
-    ///
-    /// If an payment was already made to the same invoice, the result of the
-    /// previous payment will be returned again. This allows building safe retry
-    /// logic that just tries to pay an invoice again if it's unclear if a
-    /// previous call to this function succeeded or not (e.g. in the case of a
-    /// crash).
-    ///
-    /// Retries are not supported for now since they will likely fail too if the
-    /// original attempt failed and would add additional complexity.
-    pub async fn pay(&self, invoice: &Bolt11Invoice) -> anyhow::Result<[u8; 32]> {
-        let ln_client = self.ln_module();
+    /// Starts paying an invoice through `gateway` if its fee doesn't exceed
+    /// `max_fee`, or follows the existing payment if the invoice was already
+    /// paid.
+    async fn start_payment(
+        &self,
+        invoice: &Bolt11Invoice,
+        gateway: Option<PublicKey>,
+        max_fee: Option<Amount>,
+    ) -> anyhow::Result<BoxStream<'static, PayProgress>> {
         let operation_id = Self::get_payment_operation_id(invoice.payment_hash());
-        let pay_type = if let Some(operation) = self
+
+        // Held until the payment's operation exists, concurrent calls for the same
+        // invoice then find it and follow it instead of paying again
+        let _lock = self.payment_locks.lock(invoice.payment_hash().into()).await;
+        if let Some(updates) = self.subscribe_payment(operation_id).await? {
+            return Ok(updates);
+        }
+
+        crate::invoice::check_payable(invoice, self.network())?;
+
+        if let Some(spend_limit) = &self.spend_limit {
+            let amount = invoice
+                .amount_milli_satoshis()
+                .map(Amount::from_msats)
+                .context("Amountless invoices can't be checked against the spend limit")?;
+            spend_limit
+                .reserve(self.client.db(), operation_id, amount)
+                .await?;
+        }
+
+        let payment = match self.lightning_version {
+            LightningVersion::V1 => self.start_ln_payment(invoice, gateway, max_fee).await,
+            LightningVersion::V2 => {
+                self.start_lnv2_payment(invoice, gateway, max_fee, operation_id)
+                    .await
+            }
+        };
+        let gateway_id = match payment {
+            Ok(gateway_id) => gateway_id,
+            Err(e) => {
+                if self.spend_limit.is_some() {
+                    spend_limit::credit(self.client.db(), operation_id).await?;
+                }
+                return Err(e);
+            }
+        };
+
+        // Handles the outcome even if the caller stops following the payment
+        self.watch_outgoing_payment(operation_id, gateway_id)
+            .await?;
+
+        self.subscribe_payment(operation_id)
+            .await?
+            .context("Started payment not found")
+    }
+
+    /// Pays an invoice using the `ln` module and returns the id of the gateway
+    /// it's routed through, `None` for internal payments which don't involve a
+    /// gateway.
+    async fn start_ln_payment(
+        &self,
+        invoice: &Bolt11Invoice,
+        gateway: Option<PublicKey>,
+        max_fee: Option<Amount>,
+    ) -> anyhow::Result<Option<PublicKey>> {
+        let ln_client = self.ln_module()?;
+        let gateway = match gateway {
+            Some(gateway) => Some(gateway),
+            None => {
+                let amount = invoice.amount_milli_satoshis().unwrap_or_default();
+                self.select_gateway(Amount::from_msats(amount)).await?
+            }
+        };
+        let ln_gateway = self
+            .gateway_request(ln_client.get_gateway(gateway, false))
+            .await?
+            .ok_or(GatewayUnavailable {
+                gateway_id: gateway,
+            })?;
+        let gateway_id = ln_gateway.gateway_id;
+        if let Some(max_fee) = max_fee {
+            let quote = PaymentQuote::ln(
+                invoice,
+                ln_gateway.node_pub_key,
+                ln_gateway.federation_index,
+                ln_gateway.fees,
+            )?;
+            if quote.fee > max_fee {
+                return Err(FeeTooHigh {
+                    fee: quote.fee,
+                    max_fee,
+                }
+                .into());
+            }
+        }
+
+        let payment = ln_client
+            .pay_bolt11_invoice(Some(ln_gateway), invoice.clone(), ())
+            .await?;
+        Ok(match payment.payment_type {
+            PayType::Internal(_) => None,
+            PayType::Lightning(_) => Some(gateway_id),
+        })
+    }
+
+    /// Pays an invoice using the `lnv2` module, which chooses the gateway on
+    /// its own, and records the operation under `payment_id` (the operation id
+    /// the `ln` module would have used) so it can be found by payment hash.
+    /// Gateways used by the `lnv2` module aren't identified by a public key, so
+    /// no gateway id is returned.
+    async fn start_lnv2_payment(
+        &self,
+        invoice: &Bolt11Invoice,
+        gateway: Option<PublicKey>,
+        max_fee: Option<Amount>,
+        payment_id: OperationId,
+    ) -> anyhow::Result<Option<PublicKey>> {
+        if gateway.is_some() {
+            return Err(UnsupportedByLnv2 {
+                feature: "Choosing the gateway",
+            }
+            .into());
+        }
+        if max_fee.is_some() {
+            return Err(UnsupportedByLnv2 {
+                feature: "Limiting the fee",
+            }
+            .into());
+        }
+
+        let operation_id = match self
+            .lnv2_module()?
+            .send(invoice.clone(), None, serde_json::Value::Null)
+            .await
+        {
+            Ok(operation_id) => operation_id,
+            // The payment was started before but not recorded, e.g. due to a crash
+            Err(
+                SendPaymentError::PendingPreviousPayment(operation_id)
+                | SendPaymentError::SuccessfulPreviousPayment(operation_id),
+            ) => operation_id,
+            Err(SendPaymentError::FailedToSelectGateway(_)) => {
+                return Err(GatewayUnavailable { gateway_id: None }.into());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        lnv2::store_send_operation(self.client.db(), payment_id, operation_id).await?;
+
+        Ok(None)
+    }
+
+    /// Returns the progress of the outgoing payment with the given operation
+    /// id, `None` if no such payment was started. Payments made using the
+    /// `lnv2` module are found by the operation id the `ln` module would have
+    /// used for them.
+    async fn subscribe_payment(
+        &self,
+        operation_id: OperationId,
+    ) -> anyhow::Result<Option<BoxStream<'static, PayProgress>>> {
+        if let Some(lnv2_operation_id) =
+            lnv2::send_operation(self.client.db(), operation_id).await?
+        {
+            let updates = self
+                .lnv2_module()?
+                .subscribe_send_operation_state_updates(lnv2_operation_id)
+                .await?
+                .into_stream()
+                .map(PayProgress::from_send_state);
+            return Ok(Some(Box::pin(updates)));
+        }
+
+        let Some(operation) = self
             .client
             .operation_log()
             .get_operation(operation_id)
             .await
-        {
-            match operation.meta::<LightningOperationMeta>().variant {
-                LightningOperationMetaVariant::Pay(LightningOperationMetaPay {
-                    is_internal_payment,
-                    ..
-                }) => {
-                    if is_internal_payment {
-                        PayType::Internal(operation_id)
-                    } else {
-                        PayType::Lightning(operation_id)
-                    }
-                }
-                _ => {
-                    return Err(anyhow!(
-                        "Operation associated with the payment hash is not an incoming payment"
-                    ));
+        else {
+            return Ok(None);
+        };
+
+        let pay_type = match operation.meta::<LightningOperationMeta>().variant {
+            LightningOperationMetaVariant::Pay(LightningOperationMetaPay {
+                is_internal_payment,
+                ..
+            }) => {
+                if is_internal_payment {
+                    PayType::Internal(operation_id)
+                } else {
+                    PayType::Lightning(operation_id)
                 }
             }
-        } else {
-            let ln_gateway = ln_client
-                .get_gateway(None, false)
-                .await?
-                .ok_or_else(|| anyhow!("No LN gateway available"))?;
-
-            let payment = ln_client
-                .pay_bolt11_invoice(Some(ln_gateway), invoice.clone(), ())
-                .await?;
-            payment.payment_type
+            _ => {
+                return Err(anyhow!(
+                    "Operation associated with the payment hash is not an outgoing payment"
+                ));
+            }
         };
 
-        let preimage = match pay_type {
-            PayType::Internal(operation_id) => {
-                match ln_client
+        Ok(Some(self.subscribe_pay_type(&pay_type).await?))
+    }
+
+    async fn subscribe_pay_type(
+        &self,
+        pay_type: &PayType,
+    ) -> anyhow::Result<BoxStream<'static, PayProgress>> {
+        let ln_client = self.ln_module()?;
+        Ok(match *pay_type {
+            PayType::Internal(operation_id) => Box::pin(
+                ln_client
                     .subscribe_internal_pay(operation_id)
                     .await?
-                    .await_outcome()
-                    .await
-                    .context("No outcome found for payment, should never happen")?
-                {
-                    fedimint_ln_client::InternalPayState::Preimage(preimage) => preimage.0,
-                    state => return Err(anyhow!("Payment failed: {:?}", state)),
-                }
-            }
-            PayType::Lightning(operation_id) => {
-                match ln_client
+                    .into_stream()
+                    .map(PayProgress::from_internal_pay_state),
+            ),
+            PayType::Lightning(operation_id) => Box::pin(
+                ln_client
                     .subscribe_ln_pay(operation_id)
                     .await?
-                    .await_outcome()
-                    .await
-                    .context("No outcome found for payment, should never happen")?
-                {
-                    fedimint_ln_client::LnPayState::Success { preimage } => hex::decode(preimage)
-                        .context("Invalid preimage")?
-                        .try_into()
-                        .ok()
-                        .context("Invalid preimage length")?,
-                    state => return Err(anyhow!("Payment failed: {:?}", state)),
+                    .into_stream()
+                    .map(PayProgress::from_ln_pay_state),
+            ),
+        })
+    }
+
+    /// Follows the outgoing payment `payment_id` in the background until it's
+    /// final, unless it's already followed. This is the only place handling a
+    /// payment's outcome, the streams returned to callers have no side
+    /// effects. Once the payment finishes, the time it succeeded at is
+    /// recorded (see [`HistoryEntry::settled_at`]) or a failed payment is
+    /// credited back to the spend limit. The first time its outcome is seen
+    /// it's also recorded for `gateway_id` and a [`BlitziEvent::PaymentSent`]
+    /// or [`BlitziEvent::PaymentFailed`] is emitted.
+    async fn watch_outgoing_payment(
+        &self,
+        payment_id: OperationId,
+        gateway_id: Option<PublicKey>,
+    ) -> anyhow::Result<()> {
+        // Events and settlement times of lnv2 payments use the operation id of
+        // the lnv2 module
+        let operation_id = lnv2::send_operation(self.client.db(), payment_id)
+            .await?
+            .unwrap_or(payment_id);
+        if !self
+            .watched_payments
+            .lock()
+            .expect("lock poisoned")
+            .insert(payment_id)
+        {
+            return Ok(());
+        }
+        let mut updates = match self.subscribe_payment(payment_id).await {
+            Ok(Some(updates)) => updates,
+            result => {
+                self.watched_payments
+                    .lock()
+                    .expect("lock poisoned")
+                    .remove(&payment_id);
+                return result.map(|_| ());
+            }
+        };
+
+        let db = self.client.db().clone();
+        let events = self.events.clone();
+        let watched_payments = self.watched_payments.clone();
+        let credit_failed = self.spend_limit.is_some();
+        self.task_group
+            .spawn_cancellable("blitzi-watch-outgoing-payment", async move {
+                // Polled until the stream ends, which records the outcome in the
+                // operation log
+                while let Some(progress) = updates.next().await {
+                    let (success, event) = match progress {
+                        PayProgress::Succeeded { .. } => {
+                            if let Err(e) = history::record_settled(&db, operation_id).await {
+                                warn!(error = %e, "Failed to record when the payment settled");
+                            }
+                            (true, BlitziEvent::PaymentSent { operation_id })
+                        }
+                        PayProgress::Failed { reason } => {
+                            // Failed payments no longer count towards the spend limit
+                            if credit_failed {
+                                if let Err(e) = spend_limit::credit(&db, payment_id).await {
+                                    warn!(
+                                        error = %e,
+                                        "Failed to credit failed payment to the spend limit"
+                                    );
+                                }
+                            }
+                            (
+                                false,
+                                BlitziEvent::PaymentFailed {
+                                    operation_id,
+                                    reason,
+                                },
+                            )
+                        }
+                        _ => continue,
+                    };
+
+                    match history::record_finished(&db, operation_id).await {
+                        Ok(true) => {}
+                        // Already reported, e.g. before a restart
+                        Ok(false) => continue,
+                        Err(e) => {
+                            warn!(error = %e, "Failed to record that the payment finished");
+                            continue;
+                        }
+                    }
+                    if let Some(gateway_id) = gateway_id {
+                        if let Err(e) = gateway_stats::record(&db, gateway_id, success).await {
+                            warn!(
+                                error = %e,
+                                %gateway_id,
+                                "Failed to record payment outcome for gateway"
+                            );
+                        }
+                    }
+                    events::emit(&events, event);
                 }
+                watched_payments
+                    .lock()
+                    .expect("lock poisoned")
+                    .remove(&payment_id);
+            });
+        Ok(())
+    }
+
+    /// Awaits a gateway lookup, giving up after the
+    /// [gateway timeout](BlitziBuilder::gateway_timeout).
+    async fn gateway_request<T>(
+        &self,
+        request: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        let Some(timeout) = self.gateway_timeout else {
+            return request.await;
+        };
+        fedimint_core::runtime::timeout(timeout, request)
+            .await
+            .map_err(|_| anyhow!("Fetching the gateway timed out after {:?}", timeout))?
+    }
+
+    /// Chooses the gateway for an invoice or payment of `amount` according to
+    /// the configured [`GatewaySelection`], `None` to let the Fedimint client
+    /// choose.
+    async fn select_gateway(&self, amount: Amount) -> anyhow::Result<Option<PublicKey>> {
+        let preferred = gateway::preferred_gateway(self.client.db()).await?;
+        let gateways = match self.gateway_selection {
+            _ if preferred.is_some() => self.list_gateways().await,
+            GatewaySelection::Cheapest | GatewaySelection::MostReliable => {
+                self.list_gateways().await
+            }
+            GatewaySelection::Automatic | GatewaySelection::Pinned(_) => vec![],
+        };
+        if let Some(preferred) = preferred {
+            if gateways
+                .iter()
+                .any(|gateway| gateway.gateway_id == preferred)
+            {
+                return Ok(Some(preferred));
+            }
+            warn!(
+                gateway_id = %preferred,
+                "Preferred gateway is unavailable, falling back to the configured gateway selection"
+            );
+        }
+
+        let records = if self.gateway_selection == GatewaySelection::MostReliable {
+            gateway_stats::load(self.client.db()).await?
+        } else {
+            Default::default()
+        };
+
+        Ok(gateway::select_gateway(
+            self.gateway_selection,
+            &gateways,
+            amount,
+            &records,
+        ))
+    }
+
+    /// Returns up to `limit` entries of the operation history (incoming and
+    /// outgoing Lightning payments as well as ecash operations), newest first.
+    ///
+    /// To page through older entries pass the [`HistoryEntry::cursor`] of the
+    /// last entry returned as `before`. An empty result means there are no
+    /// more entries.
+    ///
+    /// The status of an entry reflects the last outcome recorded by the
+    /// Fedimint client, operations whose outcome hasn't been observed yet are
+    /// reported as [`HistoryEntryStatus::Pending`]. Unpaid invoices canceled
+    /// using [`Self::cancel_invoice`] are reported as
+    /// [`HistoryEntryStatus::Failed`].
+    ///
+    /// [`HistoryEntry::settled_at`] is set for payments this client saw
+    /// settle, i.e. invoices claimed while it was running or awaiting them and
+    /// outgoing payments whose success it observed.
+    pub async fn list_operations(
+        &self,
+        limit: usize,
+        before: Option<OperationCursor>,
+    ) -> Vec<HistoryEntry> {
+        self.operations_page(limit, before).await.0
+    }
+
+    /// Returns up to `limit` Lightning payments of the given `kind` (invoices
+    /// for [`HistoryEntryKind::Receive`], outgoing payments for
+    /// [`HistoryEntryKind::Pay`]), newest first, optionally only those with
+    /// the given `status`.
+    ///
+    /// Pages through older entries like [`Self::list_operations`], which
+    /// describes how the fields of the entries are filled in.
+    pub async fn list_transactions(
+        &self,
+        kind: HistoryEntryKind,
+        status: Option<HistoryEntryStatus>,
+        limit: usize,
+        mut before: Option<OperationCursor>,
+    ) -> Vec<HistoryEntry> {
+        const PAGE_SIZE: usize = 100;
+
+        let mut transactions = Vec::new();
+        while transactions.len() < limit {
+            let (page, next) = self.operations_page(PAGE_SIZE, before).await;
+            transactions.extend(page.into_iter().filter(|entry| {
+                entry.kind == kind && status.is_none_or(|status| entry.status == status)
+            }));
+            match next {
+                Some(next) => before = Some(next),
+                None => break,
             }
+        }
+        transactions.truncate(limit);
+        transactions
+    }
+
+    /// Returns the history entries among the `limit` operations before
+    /// `before` and the cursor to continue from, `None` if there are no older
+    /// operations. Operations that aren't relevant to Blitzi users are skipped,
+    /// so fewer than `limit` entries may be returned even if there are more.
+    async fn operations_page(
+        &self,
+        limit: usize,
+        before: Option<OperationCursor>,
+    ) -> (Vec<HistoryEntry>, Option<OperationCursor>) {
+        let operations = self
+            .client
+            .operation_log()
+            .paginate_operations_rev(limit, before.map(|cursor| cursor.0))
+            .await;
+        let next = match operations.last() {
+            Some((key, _)) if operations.len() == limit => Some(OperationCursor(*key)),
+            _ => None,
         };
+        let mut entries = operations
+            .into_iter()
+            .filter_map(|(key, operation)| HistoryEntry::from_operation(key, &operation))
+            .collect::<Vec<_>>();
+
+        // Invoices canceled using `cancel_invoice` are listed as failed
+        for entry in &mut entries {
+            entry.settled_at = history::settled_at(self.client.db(), entry.operation_id)
+                .await
+                .unwrap_or(None);
+            entry.external_id = external_id::of_operation(self.client.db(), entry.operation_id)
+                .await
+                .unwrap_or(None);
+            if entry.kind == HistoryEntryKind::Receive
+                && entry.status == HistoryEntryStatus::Pending
+                && cancel::is_canceled(self.client.db(), entry.operation_id)
+                    .await
+                    .unwrap_or(false)
+            {
+                entry.status = HistoryEntryStatus::Failed;
+            }
+        }
+        (entries, next)
+    }
 
-        Ok(preimage)
+    /// Returns statistics about the Lightning payments started between `since`
+    /// (inclusive) and `until` (exclusive), such as the total amounts received
+    /// and sent and the fees paid. See [`PeriodStats`] for how payments are
+    /// counted.
+    ///
+    /// # Errors
+    /// Returns an error if `since` is after `until`.
+    pub async fn stats(&self, since: SystemTime, until: SystemTime) -> anyhow::Result<PeriodStats> {
+        ensure!(since <= until, "Start of the time range is after its end");
+        Ok(stats::period_stats(&self.client, since, until).await)
+    }
+
+    /// Returns the gateway fees paid for the outgoing Lightning payments
+    /// started since `since`, summed up per day or month (UTC), e.g. to
+    /// compare the costs of different federations. Only successful payments
+    /// are counted, internal payments to other users of the federation count
+    /// as payments without fees. Periods without payments are omitted.
+    ///
+    /// # Errors
+    /// Returns an error if `since` is in the future.
+    pub async fn fee_report(
+        &self,
+        bucket: Bucket,
+        since: SystemTime,
+    ) -> anyhow::Result<Vec<FeeBucket>> {
+        let now = fedimint_core::time::now();
+        ensure!(since <= now, "Start of the fee report is in the future");
+        Ok(stats::fee_report(&self.client, bucket, since, now).await)
     }
 
     fn get_payment_operation_id(payment_hash: &sha256::Hash) -> OperationId {
@@ -469,3 +4432,290 @@ impl Blitzi {
         get_payment_operation_id(payment_hash, 1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[cfg(not(target_family = "wasm"))]
+    fn assert_send<T: Send>(_: &T) {}
+
+    #[cfg(any(feature = "native", feature = "db-redb"))]
+    #[test]
+    fn test_datadir_without_xdg() {
+        assert_eq!(
+            datadir_in(Some(PathBuf::from("/home/user/.local/share"))),
+            Some(PathBuf::from("/home/user/.local/share/fedimint/default"))
+        );
+
+        // Neither XDG_DATA_HOME nor HOME set
+        assert_eq!(datadir_in(None), None);
+        let builder = BlitziBuilder {
+            datadir: datadir_in(None),
+            ..BlitziBuilder::default()
+        };
+        assert_eq!(builder.datadir, None);
+        let builder = builder.datadir("/tmp/blitzi");
+        assert_eq!(builder.datadir, Some(PathBuf::from("/tmp/blitzi")));
+    }
+
+    #[test]
+    fn test_network() {
+        let builder = BlitziBuilder::default().network(Network::Bitcoin);
+        assert_eq!(builder.federation, default_federation());
+        assert!(builder.check_network(Network::Bitcoin).is_ok());
+        assert_eq!(
+            builder.check_network(Network::Signet).unwrap_err(),
+            NetworkMismatch {
+                expected: Network::Bitcoin,
+                got: Network::Signet,
+            }
+        );
+
+        // The default federation is on mainnet
+        let builder = BlitziBuilder::default().network(Network::Signet);
+        assert_eq!(builder.federation, None);
+
+        let invite = InviteCode::new(
+            fedimint_core::util::SafeUrl::parse("ws://127.0.0.1:8174/").unwrap(),
+            fedimint_core::PeerId::from(0),
+            FederationId::dummy(),
+            None,
+        );
+        let builder = BlitziBuilder::default()
+            .federation_invite(invite.clone())
+            .network(Network::Signet);
+        assert_eq!(builder.federation, Some(invite));
+
+        // An explicitly set default federation is kept, building then fails
+        if let Some(default) = default_federation() {
+            let builder = BlitziBuilder::default()
+                .federation_invite(default.clone())
+                .network(Network::Signet);
+            assert_eq!(builder.federation, Some(default));
+        }
+    }
+
+    #[test]
+    fn test_check_stored_federation() {
+        let stored = FederationId(BitcoinHash::hash(b"stored federation"));
+        // Wallets of any federation can be opened without setting one
+        assert!(
+            BlitziBuilder::default()
+                .check_stored_federation(stored)
+                .is_ok()
+        );
+
+        let invite = InviteCode::new(
+            fedimint_core::util::SafeUrl::parse("ws://127.0.0.1:8174/").unwrap(),
+            fedimint_core::PeerId::from(0),
+            FederationId::dummy(),
+            None,
+        );
+        let builder = BlitziBuilder::default().federation_invite(invite);
+        assert!(
+            builder
+                .check_stored_federation(FederationId::dummy())
+                .is_ok()
+        );
+        assert_eq!(
+            builder.check_stored_federation(stored),
+            Err(FederationMismatch {
+                stored,
+                requested: FederationId::dummy(),
+            })
+        );
+
+        // Setting the default federation explicitly checks it too
+        if let Some(default) = default_federation() {
+            let requested = default.federation_id();
+            let builder = BlitziBuilder::default().federation_invite(default);
+            assert_eq!(
+                builder.check_stored_federation(stored),
+                Err(FederationMismatch { stored, requested })
+            );
+        }
+    }
+
+    #[cfg(any(feature = "native", feature = "db-redb"))]
+    #[tokio::test]
+    async fn test_open_missing_datadir() {
+        let error = open_datadir(None, DatabaseBackend::default())
+            .await
+            .err()
+            .expect("opening without a data directory fails");
+        assert!(error.to_string().contains("BlitziBuilder::datadir"));
+    }
+
+    /// Binds a local listener that accepts connections but never answers the
+    /// websocket handshake, so connecting to it hangs until the timeout.
+    /// Returns it, to be kept alive, along with its endpoint.
+    #[cfg(not(target_family = "wasm"))]
+    fn silent_listener() -> (std::net::TcpListener, String) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("ws://{}/", listener.local_addr().unwrap());
+        (listener, endpoint)
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[tokio::test]
+    async fn test_config_timeout() {
+        use fedimint_core::PeerId;
+        use fedimint_core::db::mem_impl::MemDatabase;
+        use fedimint_core::util::SafeUrl;
+
+        let (_listener, endpoint) = silent_listener();
+        let invite = InviteCode::new(
+            SafeUrl::parse(&endpoint).unwrap(),
+            PeerId::from(0),
+            FederationId::dummy(),
+            None,
+        );
+        let start = std::time::Instant::now();
+        let error = Blitzi::builder()
+            .database(MemDatabase::new().into())
+            .federation_invite(invite)
+            .config_timeout(Duration::from_secs(2))
+            .build()
+            .await
+            .err()
+            .expect("the federation is unreachable");
+        assert!(start.elapsed() < Duration::from_secs(30));
+
+        let timeout = error
+            .downcast_ref::<ConnectTimeout>()
+            .expect("fails with a connect timeout");
+        assert_eq!(timeout.timeout, Duration::from_secs(2));
+        assert_eq!(timeout.endpoints, vec![endpoint]);
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[tokio::test]
+    async fn test_join_timeout() {
+        use fedimint_core::PeerId;
+        use fedimint_core::db::mem_impl::MemDatabase;
+        use fedimint_core::util::SafeUrl;
+
+        let (_listener, endpoint) = silent_listener();
+        let invite = InviteCode::new(
+            SafeUrl::parse(&endpoint).unwrap(),
+            PeerId::from(0),
+            FederationId::dummy(),
+            None,
+        );
+        let db: Database = MemDatabase::new().into();
+        let start = std::time::Instant::now();
+        let error = Blitzi::builder()
+            .database(db.clone())
+            .federation_invite(invite)
+            .join_timeout(Duration::from_secs(2))
+            .build()
+            .await
+            .err()
+            .expect("the federation is unreachable");
+        assert!(start.elapsed() < Duration::from_secs(30));
+
+        assert_eq!(
+            error.downcast_ref::<JoinTimedOut>(),
+            Some(&JoinTimedOut {
+                timeout: Duration::from_secs(2)
+            })
+        );
+        // Nothing is left behind that would prevent retrying
+        assert!(
+            Client::load_decodable_client_secret_opt::<Vec<u8>>(&db)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_blitzi_is_send_sync() {
+        assert_send_sync::<Blitzi>();
+        assert_send_sync::<BlitziAccount<'static>>();
+
+        // Only type checked, the closure is never called
+        #[cfg(not(target_family = "wasm"))]
+        let _ = |blitzi: &Blitzi, invoice: &Bolt11Invoice| {
+            assert_send(&blitzi.lightning_invoice(msats(1_000), "test"));
+            assert_send(&blitzi.pay(invoice));
+            assert_send(&blitzi.balance());
+            assert_send(&blitzi.account(1));
+        };
+    }
+
+    #[test]
+    fn test_is_lock_error() {
+        assert!(is_lock_error(&anyhow!(
+            "IO error: While lock file: /data/LOCK: Resource temporarily unavailable"
+        )));
+        assert!(is_lock_error(&anyhow!(
+            "IO error: lock hold by current process, acquire time 1700000000 acquiring thread \
+             123: /data/LOCK: No locks available"
+        )));
+        assert!(is_lock_error(&anyhow!(
+            "Database already open. Cannot acquire lock."
+        )));
+        assert!(!is_lock_error(&anyhow!(
+            "IO error: No such file or directory"
+        )));
+    }
+
+    #[test]
+    fn test_is_already_spent() {
+        assert!(is_already_spent(
+            "Transaction was rejected: The note is already spent"
+        ));
+        assert!(is_already_spent("We already reissued these notes"));
+        assert!(!is_already_spent("Federation not reachable"));
+    }
+
+    #[test]
+    fn test_validate_invoice_amount() {
+        assert_eq!(
+            validate_invoice_amount(Amount::ZERO, DEFAULT_MAX_INVOICE_AMOUNT),
+            Err(InvoiceAmountError::Zero)
+        );
+        assert_eq!(
+            validate_invoice_amount(Amount::from_msats(u64::MAX), DEFAULT_MAX_INVOICE_AMOUNT),
+            Err(InvoiceAmountError::TooLarge {
+                amount: Amount::from_msats(u64::MAX),
+                max: DEFAULT_MAX_INVOICE_AMOUNT,
+            })
+        );
+        assert_eq!(
+            validate_invoice_amount(Amount::from_msats(1), DEFAULT_MAX_INVOICE_AMOUNT),
+            Ok(())
+        );
+        assert_eq!(
+            validate_invoice_amount(DEFAULT_MAX_INVOICE_AMOUNT, DEFAULT_MAX_INVOICE_AMOUNT),
+            Ok(())
+        );
+    }
+
+    #[cfg(feature = "default-federation")]
+    #[test]
+    fn test_verify_invite() {
+        let invite = InviteCode::from_str(ECASH_CLUB_INVITE).unwrap();
+        let other = FederationId(BitcoinHash::hash(b"other federation"));
+
+        assert!(verify_invite(&invite, invite.federation_id()).is_ok());
+        let error = verify_invite(&invite, other).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<FederationIdMismatch>(),
+            Some(&FederationIdMismatch {
+                expected: other,
+                got: invite.federation_id(),
+            })
+        );
+
+        let builder = Blitzi::builder();
+        assert!(builder.check_federation_id(other).is_ok());
+        let builder = builder.expect_federation_id(invite.federation_id());
+        assert!(builder.check_federation_id(invite.federation_id()).is_ok());
+        assert!(builder.check_federation_id(other).is_err());
+    }
+}
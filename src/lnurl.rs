@@ -0,0 +1,384 @@
+//! Claiming funds from LNURL-withdraw links (LUD-03), see
+//! [`Blitzi::claim_lnurl_withdraw`](crate::Blitzi::claim_lnurl_withdraw).
+use std::time::Duration;
+
+use anyhow::{Context, anyhow, ensure};
+use fedimint_core::Amount;
+use lightning_invoice::Bolt11Invoice;
+use rand::Rng;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::error::{LnurlServiceError, WithdrawAmountOutOfRange};
+
+/// Human readable part of bech32 encoded LNURLs.
+const LNURL_HRP: &str = "lnurl";
+
+/// Default of
+/// [`BlitziBuilder::lnurl_deadline`](crate::BlitziBuilder::lnurl_deadline).
+pub(crate) const DEFAULT_DEADLINE: Duration = Duration::from_secs(30);
+
+/// Delay before the first retry, doubled after every further attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+
+/// Upper bound of the delay between retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(8);
+
+/// Number of characters of error responses included in [`LnurlServiceError`].
+const BODY_EXCERPT_LEN: usize = 200;
+
+/// Withdraw parameters returned by the service an LNURL points at.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct WithdrawRequest {
+    tag: String,
+    callback: String,
+    k1: String,
+    #[serde(default)]
+    pub(crate) default_description: String,
+    #[serde(rename = "minWithdrawable")]
+    min: u64,
+    #[serde(rename = "maxWithdrawable")]
+    max: u64,
+}
+
+/// Response of LNURL services to callbacks, or instead of the requested data
+/// if the request failed.
+#[derive(Debug, Deserialize)]
+struct StatusResponse {
+    status: String,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+/// Decodes an LNURL into the URL it points at. Accepts bech32 encoded LNURLs
+/// (optionally prefixed with `lightning:`), `lnurlw://` links (LUD-17) and
+/// plain `https://` URLs.
+pub(crate) fn decode(lnurl: &str) -> anyhow::Result<String> {
+    let lnurl = lnurl.trim();
+    let lnurl = lnurl
+        .strip_prefix("lightning:")
+        .or_else(|| lnurl.strip_prefix("LIGHTNING:"))
+        .unwrap_or(lnurl);
+
+    if let Some(rest) = lnurl.strip_prefix("lnurlw://") {
+        // LUD-17: onion services are reached via plain HTTP
+        let scheme = if rest
+            .split('/')
+            .next()
+            .is_some_and(|host| host.ends_with(".onion"))
+        {
+            "http"
+        } else {
+            "https"
+        };
+        return Ok(format!("{}://{}", scheme, rest));
+    }
+    if lnurl.starts_with("https://") || lnurl.starts_with("http://") {
+        return Ok(lnurl.to_string());
+    }
+
+    let (hrp, data) = bech32::decode(lnurl).map_err(|e| anyhow!("Invalid LNURL: {}", e))?;
+    ensure!(
+        hrp.as_str().eq_ignore_ascii_case(LNURL_HRP),
+        "Invalid LNURL: unexpected prefix {}",
+        hrp
+    );
+    String::from_utf8(data).context("Invalid LNURL: not a URL")
+}
+
+impl WithdrawRequest {
+    /// Fetches the withdraw parameters from the service `lnurl` points at.
+    pub(crate) async fn fetch(
+        http: &reqwest::Client,
+        lnurl: &str,
+        deadline: Duration,
+    ) -> anyhow::Result<Self> {
+        let url = decode(lnurl)?;
+        let body = send_with_retry(|| http.get(&url), deadline).await?;
+
+        check_status(&body)?;
+        let request = serde_json::from_str::<WithdrawRequest>(&body)
+            .context("LNURL service returned an invalid response")?;
+        ensure!(
+            request.tag == "withdrawRequest",
+            "LNURL is not a withdraw link but {}",
+            request.tag
+        );
+        Ok(request)
+    }
+
+    /// Checks that the service allows withdrawing `amount`.
+    ///
+    /// # Errors
+    /// Returns a [`WithdrawAmountOutOfRange`] error if it doesn't.
+    pub(crate) fn check_amount(&self, amount: Amount) -> Result<(), WithdrawAmountOutOfRange> {
+        if amount.msats < self.min || amount.msats > self.max {
+            return Err(WithdrawAmountOutOfRange {
+                amount,
+                min: Amount::from_msats(self.min),
+                max: Amount::from_msats(self.max),
+            });
+        }
+        Ok(())
+    }
+
+    /// Asks the service to pay `invoice`. The payment is made asynchronously,
+    /// a successful response only means the service accepted the invoice.
+    pub(crate) async fn submit(
+        &self,
+        http: &reqwest::Client,
+        invoice: &Bolt11Invoice,
+        deadline: Duration,
+    ) -> anyhow::Result<()> {
+        let invoice = invoice.to_string();
+        let body = send_with_retry(
+            || {
+                http.get(&self.callback)
+                    .query(&[("k1", self.k1.as_str()), ("pr", &invoice)])
+            },
+            deadline,
+        )
+        .await?;
+
+        check_status(&body)?;
+        let response = serde_json::from_str::<StatusResponse>(&body)
+            .context("LNURL service returned an invalid response")?;
+        ensure!(
+            response.status.eq_ignore_ascii_case("OK"),
+            "LNURL service returned unexpected status {}",
+            response.status
+        );
+        Ok(())
+    }
+}
+
+/// Sends the request built by `request` and returns the response body.
+/// Transient failures, i.e. timeouts, connection errors and server errors,
+/// are retried with exponential backoff and jitter until `deadline` has
+/// passed since the first attempt. Client errors aren't retried.
+///
+/// # Errors
+/// Returns a [`LnurlServiceError`] if the service responds with an error
+/// status, and an error if it can't be reached before the deadline.
+async fn send_with_retry(
+    request: impl Fn() -> reqwest::RequestBuilder,
+    deadline: Duration,
+) -> anyhow::Result<String> {
+    let start = fedimint_core::time::now();
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let elapsed = fedimint_core::time::now()
+            .duration_since(start)
+            .unwrap_or_default();
+        let remaining = deadline.saturating_sub(elapsed);
+        let error = match fedimint_core::runtime::timeout(remaining, request().send()).await {
+            Ok(Ok(response)) if response.status().is_success() => {
+                return Ok(response.text().await?);
+            }
+            Ok(Ok(response)) => {
+                let status = response.status();
+                let body = response.text().await.unwrap_or_default();
+                let error = LnurlServiceError {
+                    status: status.as_u16(),
+                    body: excerpt(&body),
+                };
+                if !status.is_server_error() {
+                    return Err(error.into());
+                }
+                anyhow::Error::from(error)
+            }
+            Ok(Err(e)) if e.is_builder() || e.is_redirect() => {
+                return Err(anyhow::Error::from(e).context("Failed to reach the LNURL service"));
+            }
+            Ok(Err(e)) => anyhow::Error::from(e).context("Failed to reach the LNURL service"),
+            Err(_) => {
+                return Err(anyhow!(
+                    "LNURL service didn't respond within {:?}",
+                    deadline
+                ));
+            }
+        };
+
+        let delay = jitter(backoff);
+        if elapsed + delay >= deadline {
+            return Err(error);
+        }
+        debug!(?delay, "Retrying LNURL request: {:#}", error);
+        fedimint_core::runtime::sleep(delay).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Randomizes `backoff` to between half and all of it, so clients failing at
+/// the same time don't retry in lockstep.
+fn jitter(backoff: Duration) -> Duration {
+    rand::thread_rng().gen_range(backoff / 2..=backoff)
+}
+
+/// Returns the beginning of `body` to include in error messages.
+fn excerpt(body: &str) -> String {
+    let body = body.trim();
+    match body.char_indices().nth(BODY_EXCERPT_LEN) {
+        Some((end, _)) => format!("{}…", &body[..end]),
+        None => body.to_string(),
+    }
+}
+
+/// Returns an error if `body` is an LNURL error response.
+fn check_status(body: &str) -> anyhow::Result<()> {
+    if let Ok(response) = serde_json::from_str::<StatusResponse>(body)
+        && response.status.eq_ignore_ascii_case("ERROR")
+    {
+        return Err(anyhow!(
+            "LNURL service refused the request: {}",
+            response.reason.unwrap_or_default()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        // Example from LUD-01
+        let lnurl = "LNURL1DP68GURN8GHJ7UM9WFMXJCM99E3K7MF0V9CXJ0M385EKVCENXC6R2C35XVUKXEFCV5MKVV34X5EKZD3EV56NYD3HXQURZEPEXEJXXEPNXSCRVWFNV9NXZCN9XQ6XYEFHVGCXXCMYXYMNSERXFQ5FNS";
+        assert_eq!(
+            decode(lnurl).unwrap(),
+            "https://service.com/api?q=3fc3645b439ce8e7f2553a69e5267081d96dcd340693afabe04be7b0ccd178df"
+        );
+        assert_eq!(
+            decode(&format!("lightning:{}", lnurl.to_lowercase())).unwrap(),
+            decode(lnurl).unwrap()
+        );
+
+        assert_eq!(
+            decode("lnurlw://service.com/withdraw?q=1").unwrap(),
+            "https://service.com/withdraw?q=1"
+        );
+        assert_eq!(
+            decode("lnurlw://example.onion/withdraw").unwrap(),
+            "http://example.onion/withdraw"
+        );
+        assert!(decode("not an lnurl").is_err());
+    }
+
+    #[test]
+    fn test_withdraw_request() {
+        let request = serde_json::from_value::<WithdrawRequest>(serde_json::json!({
+            "tag": "withdrawRequest",
+            "callback": "https://service.com/withdraw",
+            "k1": "secret",
+            "defaultDescription": "Faucet",
+            "minWithdrawable": 1_000,
+            "maxWithdrawable": 10_000,
+        }))
+        .unwrap();
+        assert_eq!(request.default_description, "Faucet");
+
+        assert!(request.check_amount(Amount::from_msats(1_000)).is_ok());
+        assert!(request.check_amount(Amount::from_msats(10_000)).is_ok());
+        assert_eq!(
+            request.check_amount(Amount::from_msats(999)),
+            Err(WithdrawAmountOutOfRange {
+                amount: Amount::from_msats(999),
+                min: Amount::from_msats(1_000),
+                max: Amount::from_msats(10_000),
+            })
+        );
+        assert!(request.check_amount(Amount::from_msats(10_001)).is_err());
+    }
+
+    #[test]
+    fn test_check_status() {
+        assert!(check_status(r#"{"status": "OK"}"#).is_ok());
+        assert!(check_status(r#"{"tag": "withdrawRequest"}"#).is_ok());
+        let error = check_status(r#"{"status": "ERROR", "reason": "Link expired"}"#).unwrap_err();
+        assert!(error.to_string().contains("Link expired"));
+    }
+
+    #[test]
+    fn test_backoff() {
+        for _ in 0..100 {
+            let delay = jitter(INITIAL_BACKOFF);
+            assert!(delay >= INITIAL_BACKOFF / 2 && delay <= INITIAL_BACKOFF);
+        }
+
+        assert_eq!(excerpt("  Bad Gateway\n"), "Bad Gateway");
+        let long = "ä".repeat(BODY_EXCERPT_LEN + 1);
+        assert_eq!(excerpt(&long), format!("{}…", "ä".repeat(BODY_EXCERPT_LEN)));
+    }
+
+    /// Serves `responses` (status and body) to consecutive requests and
+    /// returns the URL of the server and the number of requests served.
+    async fn serve(
+        responses: Vec<(u16, &'static str)>,
+    ) -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let served = Arc::new(AtomicUsize::new(0));
+        let counter = served.clone();
+        tokio::spawn(async move {
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await.unwrap();
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        (url, served)
+    }
+
+    #[tokio::test]
+    async fn test_send_with_retry() {
+        use std::sync::atomic::Ordering;
+
+        let http = reqwest::Client::new();
+        let deadline = Duration::from_secs(10);
+
+        // Server errors are retried
+        let (url, served) = serve(vec![(503, "unavailable"), (500, ""), (200, "ok")]).await;
+        let body = send_with_retry(|| http.get(&url), deadline).await.unwrap();
+        assert_eq!(body, "ok");
+        assert_eq!(served.load(Ordering::SeqCst), 3);
+
+        // Client errors aren't
+        let (url, served) = serve(vec![(404, "no such link"), (200, "ok")]).await;
+        let error = send_with_retry(|| http.get(&url), deadline)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<LnurlServiceError>(),
+            Some(&LnurlServiceError {
+                status: 404,
+                body: "no such link".to_string(),
+            })
+        );
+        assert_eq!(served.load(Ordering::SeqCst), 1);
+
+        // The last server error is returned once the deadline has passed
+        let (url, _) = serve(vec![(502, "bad gateway"); 20]).await;
+        let error = send_with_retry(|| http.get(&url), Duration::from_secs(1))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<LnurlServiceError>().map(|e| e.status),
+            Some(502)
+        );
+    }
+}
@@ -0,0 +1,387 @@
+//! Support for federations running the `lnv2` Lightning module instead of (or
+//! in addition to) the original `ln` module, see
+//! [`BlitziBuilder::preferred_lightning_version`](crate::BlitziBuilder::preferred_lightning_version).
+//!
+//! Unlike the `ln` module, `lnv2` doesn't derive operation ids from payment
+//! hashes, so the operations created for invoices and payments are recorded
+//! in the client database to look them up by payment hash later.
+use fedimint_core::Amount;
+use fedimint_core::core::OperationId;
+use fedimint_core::db::{Database, IDatabaseTransactionOpsCore};
+use fedimint_lnv2_client::{LightningOperationMeta, ReceiveOperationState, SendOperationState};
+use fedimint_lnv2_common::LightningInvoice;
+use lightning_invoice::Bolt11Invoice;
+use serde::{Deserialize, Serialize};
+
+use crate::error::NoLightningModule;
+use crate::{
+    FederationCapabilities, HistoryEntryKind, HistoryEntryStatus, InvoiceStatus, PayProgress,
+    PaymentHash, Preimage, ReceiveState,
+};
+
+/// Kind of the `lnv2` module as it appears in the operation log.
+pub(crate) const KIND: &str = "lnv2";
+
+/// Prefix of the operations created for outgoing payments, keyed by the
+/// operation id the `ln` module would have used for the payment. In the key
+/// range Fedimint reserves for external use (`0xb1..=0xcf`).
+const SEND_PREFIX: &[u8] = b"\xb1blitzi/lnv2/send/";
+
+/// Prefix of the operations created for invoices, keyed by payment hash.
+const RECEIVE_PREFIX: &[u8] = b"\xb1blitzi/lnv2/receive/";
+
+/// Lightning module used for invoices and payments.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LightningVersion {
+    /// The original `ln` module
+    V1,
+    /// The `lnv2` module, used by default if the federation supports it
+    #[default]
+    V2,
+}
+
+/// Chooses the Lightning module to use with a federation, `preferred` if the
+/// federation supports it and the other one otherwise.
+///
+/// # Errors
+/// Returns a [`NoLightningModule`] error if the federation supports neither.
+pub(crate) fn choose_version(
+    capabilities: &FederationCapabilities,
+    preferred: LightningVersion,
+) -> Result<LightningVersion, NoLightningModule> {
+    let has_v1 = capabilities.supports("ln");
+    let has_v2 = capabilities.supports(KIND);
+
+    match (preferred, has_v1, has_v2) {
+        (_, false, false) => Err(NoLightningModule),
+        (LightningVersion::V1, true, _) | (LightningVersion::V2, true, false) => {
+            Ok(LightningVersion::V1)
+        }
+        _ => Ok(LightningVersion::V2),
+    }
+}
+
+/// Records `operation_id` as the operation paying the invoice the `ln` module
+/// would have paid using `payment_id`.
+pub(crate) async fn store_send_operation(
+    db: &Database,
+    payment_id: OperationId,
+    operation_id: OperationId,
+) -> anyhow::Result<()> {
+    store_operation(db, &[SEND_PREFIX, &payment_id.0].concat(), operation_id).await
+}
+
+/// Returns the operation paying the invoice the `ln` module would have paid
+/// using `payment_id`, if any.
+pub(crate) async fn send_operation(
+    db: &Database,
+    payment_id: OperationId,
+) -> anyhow::Result<Option<OperationId>> {
+    load_operation(db, &[SEND_PREFIX, &payment_id.0].concat()).await
+}
+
+/// Records `operation_id` as the operation receiving payments to the invoice
+/// with `payment_hash`.
+pub(crate) async fn store_receive_operation(
+    db: &Database,
+    payment_hash: [u8; 32],
+    operation_id: OperationId,
+) -> anyhow::Result<()> {
+    store_operation(db, &[RECEIVE_PREFIX, &payment_hash].concat(), operation_id).await
+}
+
+/// Returns the operation receiving payments to the invoice with
+/// `payment_hash`, if it was created by the `lnv2` module.
+pub(crate) async fn receive_operation(
+    db: &Database,
+    payment_hash: [u8; 32],
+) -> anyhow::Result<Option<OperationId>> {
+    load_operation(db, &[RECEIVE_PREFIX, &payment_hash].concat()).await
+}
+
+async fn store_operation(
+    db: &Database,
+    key: &[u8],
+    operation_id: OperationId,
+) -> anyhow::Result<()> {
+    let mut dbtx = db.begin_transaction().await;
+    dbtx.raw_insert_bytes(key, &serde_json::to_vec(&hex::encode(operation_id.0))?)
+        .await?;
+    dbtx.commit_tx_result().await?;
+    Ok(())
+}
+
+async fn load_operation(db: &Database, key: &[u8]) -> anyhow::Result<Option<OperationId>> {
+    let mut dbtx = db.begin_transaction_nc().await;
+    let Some(bytes) = dbtx.raw_get_bytes(key).await? else {
+        return Ok(None);
+    };
+
+    let operation_id = hex::decode(serde_json::from_slice::<String>(&bytes)?)?;
+    Ok(Some(OperationId(operation_id.try_into().map_err(
+        |_| anyhow::anyhow!("Recorded operation id must be 32 bytes"),
+    )?)))
+}
+
+impl PayProgress {
+    pub(crate) fn from_send_state(state: SendOperationState) -> Self {
+        match state {
+            SendOperationState::Funding => PayProgress::Funding,
+            SendOperationState::Funded => PayProgress::AwaitingGateway,
+            SendOperationState::Success(preimage) => PayProgress::Succeeded {
+                preimage: Preimage(preimage),
+            },
+            SendOperationState::Refunding => PayProgress::Refunding {
+                reason: "Gateway failed to pay the invoice".to_string(),
+            },
+            state => PayProgress::Failed {
+                reason: format!("{:?}", state),
+            },
+        }
+    }
+}
+
+impl InvoiceStatus {
+    pub(crate) fn from_receive_state(state: Option<ReceiveOperationState>) -> Self {
+        match state {
+            Some(ReceiveOperationState::Claimed) => InvoiceStatus::Paid,
+            Some(ReceiveOperationState::Expired | ReceiveOperationState::Failure) => {
+                InvoiceStatus::Canceled
+            }
+            _ => InvoiceStatus::Pending,
+        }
+    }
+}
+
+impl ReceiveState {
+    pub(crate) fn from_lnv2_receive_state(state: ReceiveOperationState) -> Self {
+        match state {
+            ReceiveOperationState::Pending => ReceiveState::AwaitingPayment,
+            ReceiveOperationState::Claiming => ReceiveState::Funded,
+            ReceiveOperationState::Claimed => ReceiveState::Claimed,
+            ReceiveOperationState::Expired => ReceiveState::Canceled {
+                reason: "invoice expired".to_string(),
+            },
+            ReceiveOperationState::Failure => ReceiveState::Canceled {
+                reason: "claiming the payment failed".to_string(),
+            },
+        }
+    }
+}
+
+/// Returns the kind, amount, fee and status of an `lnv2` operation for the
+/// operation history.
+pub(crate) fn history_fields(
+    operation: &fedimint_client::oplog::OperationLogEntry,
+) -> (
+    HistoryEntryKind,
+    Option<Amount>,
+    Option<Amount>,
+    HistoryEntryStatus,
+) {
+    match operation.meta::<LightningOperationMeta>() {
+        LightningOperationMeta::Receive(meta) => {
+            let status = match InvoiceStatus::from_receive_state(operation.outcome()) {
+                InvoiceStatus::Paid => HistoryEntryStatus::Succeeded,
+                InvoiceStatus::Canceled => HistoryEntryStatus::Failed,
+                InvoiceStatus::Pending => HistoryEntryStatus::Pending,
+            };
+            (
+                HistoryEntryKind::Receive,
+                invoice_amount(&meta.invoice),
+                None,
+                status,
+            )
+        }
+        LightningOperationMeta::Send(meta) => {
+            let status = match operation.outcome::<SendOperationState>() {
+                Some(SendOperationState::Success(_)) => HistoryEntryStatus::Succeeded,
+                Some(SendOperationState::Refunded | SendOperationState::Failure) => {
+                    HistoryEntryStatus::Failed
+                }
+                _ => HistoryEntryStatus::Pending,
+            };
+            let amount = invoice_amount(&meta.invoice);
+            (HistoryEntryKind::Pay, amount, Some(send_fee(&meta)), status)
+        }
+    }
+}
+
+/// Returns the amount claimed by the receive `operation`, the invoice amount
+/// minus the gateway's fee.
+pub(crate) fn received_amount(
+    operation: &fedimint_client::oplog::OperationLogEntry,
+) -> Option<Amount> {
+    match operation.meta::<LightningOperationMeta>() {
+        LightningOperationMeta::Receive(meta) => Some(meta.contract.commitment.amount),
+        LightningOperationMeta::Send(_) => None,
+    }
+}
+
+/// Returns the invoice of the receive `operation`.
+pub(crate) fn received_invoice(
+    operation: &fedimint_client::oplog::OperationLogEntry,
+) -> Option<Bolt11Invoice> {
+    match operation.meta::<LightningOperationMeta>() {
+        LightningOperationMeta::Receive(meta) => match meta.invoice {
+            LightningInvoice::Bolt11(invoice) => Some(invoice),
+        },
+        LightningOperationMeta::Send(_) => None,
+    }
+}
+
+/// Returns the invoice paid or issued by the `operation`.
+pub(crate) fn operation_invoice(
+    operation: &fedimint_client::oplog::OperationLogEntry,
+) -> Option<Bolt11Invoice> {
+    match operation.meta::<LightningOperationMeta>() {
+        LightningOperationMeta::Receive(meta) => match meta.invoice {
+            LightningInvoice::Bolt11(invoice) => Some(invoice),
+        },
+        LightningOperationMeta::Send(meta) => match meta.invoice {
+            LightningInvoice::Bolt11(invoice) => Some(invoice),
+        },
+    }
+}
+
+/// Returns the payment hash of the invoice of the receive `operation`.
+pub(crate) fn received_payment_hash(
+    operation: &fedimint_client::oplog::OperationLogEntry,
+) -> Option<PaymentHash> {
+    received_invoice(operation).map(|invoice| invoice.payment_hash().into())
+}
+
+/// Returns the fee the gateway charges for an outgoing payment.
+pub(crate) fn send_fee(meta: &fedimint_lnv2_client::SendOperationMeta) -> Amount {
+    let amount = invoice_amount(&meta.invoice).unwrap_or(Amount::ZERO);
+    Amount::from_msats(meta.contract.amount.msats.saturating_sub(amount.msats))
+}
+
+fn invoice_amount(invoice: &LightningInvoice) -> Option<Amount> {
+    match invoice {
+        LightningInvoice::Bolt11(invoice) => {
+            invoice.amount_milli_satoshis().map(Amount::from_msats)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fedimint_core::db::IRawDatabaseExt;
+    use fedimint_core::db::mem_impl::MemDatabase;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_operation_mapping() {
+        let db = MemDatabase::new().into_database();
+        let payment_id = OperationId([1; 32]);
+        let operation_id = OperationId([2; 32]);
+
+        assert_eq!(send_operation(&db, payment_id).await.unwrap(), None);
+        store_send_operation(&db, payment_id, operation_id)
+            .await
+            .unwrap();
+        assert_eq!(
+            send_operation(&db, payment_id).await.unwrap(),
+            Some(operation_id)
+        );
+
+        // Sends and receives don't share their keys
+        assert_eq!(receive_operation(&db, payment_id.0).await.unwrap(), None);
+        store_receive_operation(&db, [3; 32], operation_id)
+            .await
+            .unwrap();
+        assert_eq!(
+            receive_operation(&db, [3; 32]).await.unwrap(),
+            Some(operation_id)
+        );
+    }
+
+    #[test]
+    fn test_choose_version() {
+        let capabilities = |modules: &[&str]| FederationCapabilities {
+            modules: modules.iter().map(|kind| kind.to_string()).collect(),
+            consensus_version: crate::ConsensusVersion { major: 2, minor: 0 },
+            module_versions: Default::default(),
+            max_balance: None,
+            network: fedimint_core::bitcoin::Network::Regtest,
+        };
+
+        let both = capabilities(&["ln", "lnv2", "mint"]);
+        assert_eq!(
+            choose_version(&both, LightningVersion::V2),
+            Ok(LightningVersion::V2)
+        );
+        assert_eq!(
+            choose_version(&both, LightningVersion::V1),
+            Ok(LightningVersion::V1)
+        );
+
+        // The preferred module is ignored if the federation doesn't support it
+        let v1_only = capabilities(&["ln", "mint"]);
+        assert_eq!(
+            choose_version(&v1_only, LightningVersion::V2),
+            Ok(LightningVersion::V1)
+        );
+        let v2_only = capabilities(&["lnv2", "mint"]);
+        assert_eq!(
+            choose_version(&v2_only, LightningVersion::V1),
+            Ok(LightningVersion::V2)
+        );
+
+        assert_eq!(
+            choose_version(&capabilities(&["mint"]), LightningVersion::V2),
+            Err(NoLightningModule)
+        );
+    }
+
+    #[test]
+    fn test_from_send_state() {
+        assert_eq!(
+            PayProgress::from_send_state(SendOperationState::Success([0xab; 32])),
+            PayProgress::Succeeded {
+                preimage: Preimage([0xab; 32])
+            }
+        );
+        assert_eq!(
+            PayProgress::from_send_state(SendOperationState::Funded),
+            PayProgress::AwaitingGateway
+        );
+        assert!(PayProgress::from_send_state(SendOperationState::Refunded).is_final());
+        assert!(PayProgress::from_send_state(SendOperationState::Failure).is_final());
+        assert!(!PayProgress::from_send_state(SendOperationState::Refunding).is_final());
+    }
+
+    #[test]
+    fn test_from_receive_state() {
+        assert_eq!(
+            InvoiceStatus::from_receive_state(Some(ReceiveOperationState::Claimed)),
+            InvoiceStatus::Paid
+        );
+        assert_eq!(
+            InvoiceStatus::from_receive_state(Some(ReceiveOperationState::Expired)),
+            InvoiceStatus::Canceled
+        );
+        assert_eq!(
+            InvoiceStatus::from_receive_state(Some(ReceiveOperationState::Claiming)),
+            InvoiceStatus::Pending
+        );
+        assert_eq!(
+            InvoiceStatus::from_receive_state(None),
+            InvoiceStatus::Pending
+        );
+    }
+
+    #[test]
+    fn test_receive_state_from_lnv2() {
+        assert_eq!(
+            ReceiveState::from_lnv2_receive_state(ReceiveOperationState::Claiming),
+            ReceiveState::Funded
+        );
+        assert!(ReceiveState::from_lnv2_receive_state(ReceiveOperationState::Claimed).is_final());
+        assert!(ReceiveState::from_lnv2_receive_state(ReceiveOperationState::Expired).is_final());
+        assert!(!ReceiveState::from_lnv2_receive_state(ReceiveOperationState::Pending).is_final());
+    }
+}
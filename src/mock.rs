@@ -0,0 +1,1089 @@
+//! In-memory [`LightningBackend`] for tests, enabled by the `test-util`
+//! feature.
+//!
+//! ```
+//! # use std::time::Duration;
+//! use blitzi::{LightningBackend, MockIncomingPayment, MockLightning, sats};
+//!
+//! # #[tokio::main]
+//! # async fn main() -> anyhow::Result<()> {
+//! let mock = MockLightning::new();
+//! mock.set_default_incoming(MockIncomingPayment::PaidAfter(Duration::from_millis(10)));
+//!
+//! let invoice = mock.lightning_invoice(sats(100), "Checkout").await?;
+//! mock.await_incoming_payment(invoice.payment_hash().into())
+//!     .await?;
+//! assert_eq!(mock.balance().await, sats(100));
+//! # Ok(())
+//! # }
+//! ```
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, bail};
+use fedimint_core::Amount;
+use fedimint_core::bitcoin::Network;
+use fedimint_core::bitcoin::hashes::sha256;
+use fedimint_core::config::FederationId;
+use fedimint_core::core::OperationId;
+use fedimint_core::secp256k1::{Secp256k1, SecretKey};
+use fedimint_core::util::BoxStream;
+use lightning_invoice::{Bolt11Invoice, Currency, InvoiceBuilder, PaymentSecret};
+use tokio::sync::broadcast;
+
+use crate::idempotency::reused_key_warning;
+use crate::{
+    BlitziEvent, ConsensusVersion, DEFAULT_MAX_INVOICE_AMOUNT, ExternalIdInUse,
+    FederationCapabilities, FeeTooHigh, GatewayInfo, HistoryEntry, HistoryEntryKind,
+    HistoryEntryStatus, IdempotencyKeyConflict, IdempotentPayment, InvoiceStatus, LightningBackend,
+    OperationCursor, PayOptions, PaymentHash, PaymentQuote, PaymentReceipt, PaymentRefunded,
+    Preimage, ReceiveState, ReceivedPayment, UnsupportedByLnv2, WalletStats, WalletStatus, events,
+    validate_invoice_amount,
+};
+
+/// Scripted outcome of an incoming payment to an invoice created by
+/// [`MockLightning`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockIncomingPayment {
+    /// The invoice is paid the given time after it was created
+    PaidAfter(Duration),
+    /// The invoice is canceled, e.g. because it expired
+    Canceled,
+    /// The invoice is never paid
+    Never,
+}
+
+/// Scripted outcome of an outgoing payment made using
+/// [`MockLightning::pay`](LightningBackend::pay).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MockPayment {
+    /// The payment succeeds, deducting the invoice amount and `fee` from the
+    /// balance
+    Success {
+        /// Fee charged for the payment
+        fee: Amount,
+    },
+    /// The payment fails with the given error message
+    Failure(String),
+}
+
+/// A call made to [`MockLightning`], recorded so tests can assert on them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MockCall {
+    /// [`LightningBackend::lightning_invoice`] was called
+    LightningInvoice {
+        /// Requested amount
+        amount: Amount,
+        /// Requested description
+        description: String,
+    },
+    /// [`LightningBackend::lightning_invoice_tagged`] was called
+    LightningInvoiceTagged {
+        /// Requested amount
+        amount: Amount,
+        /// Requested description
+        description: String,
+        /// Requested external id
+        external_id: String,
+    },
+    /// [`LightningBackend::invoice_external_id`] was called
+    InvoiceExternalId(PaymentHash),
+    /// [`LightningBackend::pay`] was called
+    Pay(Bolt11Invoice),
+    /// [`LightningBackend::pay_idempotent`] was called
+    PayIdempotent {
+        /// Idempotency key
+        key: String,
+        /// Invoice to pay
+        invoice: Bolt11Invoice,
+    },
+    /// [`LightningBackend::pay_detailed`] was called
+    PayDetailed {
+        /// Invoice to pay
+        invoice: Bolt11Invoice,
+        /// Requested fee cap
+        max_fee: Option<Amount>,
+    },
+    /// [`LightningBackend::quote_payment`] was called
+    QuotePayment(Bolt11Invoice),
+    /// [`LightningBackend::payment_receipt`] was called
+    PaymentReceipt(PaymentHash),
+    /// [`LightningBackend::await_incoming_payment`] was called
+    AwaitIncomingPayment(PaymentHash),
+    /// [`LightningBackend::await_incoming_payment_details`] was called
+    AwaitIncomingPaymentDetails(PaymentHash),
+    /// [`LightningBackend::invoice_status`] was called
+    InvoiceStatus(PaymentHash),
+    /// [`LightningBackend::subscribe_invoice_updates`] was called
+    SubscribeInvoiceUpdates(PaymentHash),
+    /// [`LightningBackend::issued_invoice`] was called
+    IssuedInvoice(PaymentHash),
+    /// [`LightningBackend::is_invoice_ours`] was called
+    IsInvoiceOurs(PaymentHash),
+    /// [`LightningBackend::balance`] was called
+    Balance,
+    /// [`LightningBackend::list_operations`] was called
+    ListOperations,
+    /// [`LightningBackend::list_transactions`] was called
+    ListTransactions,
+    /// [`LightningBackend::wallet_stats`] was called
+    WalletStats,
+    /// [`LightningBackend::status`] was called
+    Status,
+    /// [`LightningBackend::list_gateways`] was called
+    ListGateways,
+    /// [`LightningBackend::capabilities`] was called
+    Capabilities,
+    /// [`LightningBackend::meta`] was called
+    Meta,
+}
+
+struct MockInvoice {
+    invoice: Bolt11Invoice,
+    amount: Amount,
+    preimage: Preimage,
+    created_at: Instant,
+    incoming: MockIncomingPayment,
+    claimed_at: Option<SystemTime>,
+    external_id: Option<String>,
+}
+
+/// Payment made for an idempotency key, the outcome is `Err` with the reason
+/// if the payment failed.
+#[derive(Clone)]
+struct MockIdempotentPayment {
+    payment_hash: PaymentHash,
+    amount: Option<Amount>,
+    outcome: Result<Preimage, String>,
+}
+
+struct MockState {
+    calls: Vec<MockCall>,
+    balance: Amount,
+    invoices: HashMap<PaymentHash, MockInvoice>,
+    default_incoming: MockIncomingPayment,
+    payments: HashMap<PaymentHash, MockPayment>,
+    default_payment: MockPayment,
+    gateways: Vec<GatewayInfo>,
+    /// Number of guardians and how many of them are reachable
+    guardians: (usize, usize),
+    idempotent_payments: HashMap<String, MockIdempotentPayment>,
+    /// Newest first
+    history: Vec<HistoryEntry>,
+    receipts: HashMap<PaymentHash, PaymentReceipt>,
+    /// `None` to fail reading them
+    capabilities: Option<FederationCapabilities>,
+    meta: BTreeMap<String, String>,
+}
+
+/// In-memory implementation of [`LightningBackend`] that needs neither network
+/// nor disk access.
+///
+/// Like [`Blitzi`](crate::Blitzi), the mock rejects invoice amounts that are
+/// zero or exceed [`DEFAULT_MAX_INVOICE_AMOUNT`].
+///
+/// Invoices created by the mock are paid according to
+/// [`MockLightning::set_default_incoming`] or
+/// [`MockLightning::script_incoming`], outgoing payments succeed or fail
+/// according to [`MockLightning::set_default_payment`] or
+/// [`MockLightning::script_payment`]. All calls are recorded and can be
+/// inspected using [`MockLightning::calls`].
+///
+/// The operation history is not simulated,
+/// [`LightningBackend::list_operations`]
+/// and [`LightningBackend::list_transactions`] return the entries set using
+/// [`MockLightning::set_history`] (none by default). Of the
+/// [events](LightningBackend::subscribe_events) only
+/// [`BlitziEvent::InvoicePaid`] is emitted, once a paid invoice is first
+/// polled, and [`BlitziEvent::PaymentSent`] or [`BlitziEvent::PaymentFailed`]
+/// once per payment. Others can be emitted using
+/// [`MockLightning::emit_event`].
+pub struct MockLightning {
+    state: Mutex<MockState>,
+    events: broadcast::Sender<BlitziEvent>,
+}
+
+impl Default for MockLightning {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockLightning {
+    /// Creates a new mock with zero balance whose invoices are never paid and
+    /// whose payments succeed without fees.
+    pub fn new() -> Self {
+        MockLightning {
+            state: Mutex::new(MockState {
+                calls: vec![],
+                balance: Amount::ZERO,
+                invoices: HashMap::new(),
+                default_incoming: MockIncomingPayment::Never,
+                payments: HashMap::new(),
+                default_payment: MockPayment::Success { fee: Amount::ZERO },
+                gateways: vec![],
+                guardians: (4, 4),
+                idempotent_payments: HashMap::new(),
+                history: vec![],
+                receipts: HashMap::new(),
+                capabilities: Some(FederationCapabilities {
+                    modules: vec!["ln".to_string(), "mint".to_string()],
+                    consensus_version: ConsensusVersion { major: 2, minor: 0 },
+                    module_versions: BTreeMap::new(),
+                    max_balance: None,
+                    network: Network::Regtest,
+                }),
+                meta: BTreeMap::new(),
+            }),
+            events: broadcast::channel(events::EVENT_BUFFER).0,
+        }
+    }
+
+    /// Sets the balance of the mock.
+    pub fn set_balance(&self, balance: impl Into<Amount>) {
+        self.state().balance = balance.into();
+    }
+
+    /// Sets the outcome of incoming payments for invoices created from now on.
+    pub fn set_default_incoming(&self, incoming: MockIncomingPayment) {
+        self.state().default_incoming = incoming;
+    }
+
+    /// Sets the outcome of the incoming payment for an already created
+    /// invoice.
+    ///
+    /// # Panics
+    /// Panics if the invoice wasn't created by this mock.
+    pub fn script_incoming(
+        &self,
+        payment_hash: impl Into<PaymentHash>,
+        incoming: MockIncomingPayment,
+    ) {
+        self.state()
+            .invoices
+            .get_mut(&payment_hash.into())
+            .expect("Invoice not created by this mock")
+            .incoming = incoming;
+    }
+
+    /// Sets the outcome of payments to invoices that weren't scripted using
+    /// [`Self::script_payment`].
+    pub fn set_default_payment(&self, payment: MockPayment) {
+        self.state().default_payment = payment;
+    }
+
+    /// Sets the outcome of paying the invoice with the given payment hash.
+    pub fn script_payment(&self, payment_hash: impl Into<PaymentHash>, payment: MockPayment) {
+        self.state().payments.insert(payment_hash.into(), payment);
+    }
+
+    /// Sets the gateways returned by
+    /// [`list_gateways`](LightningBackend::list_gateways), empty by default.
+    pub fn set_gateways(&self, gateways: Vec<GatewayInfo>) {
+        self.state().gateways = gateways;
+    }
+
+    /// Sets the number of guardians and how many of them are reachable
+    /// according to [`status`](LightningBackend::status), all four of four by
+    /// default.
+    pub fn set_guardians(&self, guardians: usize, reachable: usize) {
+        self.state().guardians = (guardians, reachable);
+    }
+
+    /// Sets the capabilities returned by
+    /// [`capabilities`](LightningBackend::capabilities), `None` to make it
+    /// fail. By default a regtest federation running the `ln` and `mint`
+    /// modules is simulated, a federation that only runs `lnv2` rejects fee
+    /// limits and quotes.
+    pub fn set_capabilities(&self, capabilities: Option<FederationCapabilities>) {
+        self.state().capabilities = capabilities;
+    }
+
+    /// Sets the meta data returned by [`meta`](LightningBackend::meta), empty
+    /// by default.
+    pub fn set_meta(&self, meta: BTreeMap<String, String>) {
+        self.state().meta = meta;
+    }
+
+    /// Sets the operation history, `entries` have to be ordered newest first.
+    pub fn set_history(&self, entries: Vec<HistoryEntry>) {
+        self.state().history = entries;
+    }
+
+    /// Returns the history entries after the one `before` points at.
+    fn history_before(&self, before: Option<OperationCursor>) -> Vec<HistoryEntry> {
+        let history = &self.state().history;
+        let start = before
+            .and_then(|before| history.iter().position(|entry| entry.cursor == before))
+            .map_or(0, |position| position + 1);
+        history[start..].to_vec()
+    }
+
+    /// Sends `event` to all [subscribers](LightningBackend::subscribe_events).
+    pub fn emit_event(&self, event: BlitziEvent) {
+        events::emit(&self.events, event);
+    }
+
+    /// Returns all calls made to the mock so far.
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.state().calls.clone()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().expect("Mock state lock poisoned")
+    }
+
+    fn record(&self, call: MockCall) {
+        self.state().calls.push(call);
+    }
+
+    /// Whether the simulated federation only runs the `lnv2` module.
+    fn lnv2_only(&self) -> bool {
+        self.state()
+            .capabilities
+            .as_ref()
+            .is_some_and(|capabilities| {
+                capabilities.modules.iter().any(|module| module == "lnv2")
+                    && !capabilities.modules.iter().any(|module| module == "ln")
+            })
+    }
+
+    /// Quotes the scripted fee of paying an invoice, failing payments are
+    /// quoted without a fee.
+    fn quote(&self, invoice: &Bolt11Invoice) -> anyhow::Result<PaymentQuote> {
+        let payment_hash = PaymentHash::from(invoice.payment_hash());
+        let state = self.state();
+        let Some(amount) = invoice.amount_milli_satoshis().map(Amount::from_msats) else {
+            bail!("Amountless invoices are not supported");
+        };
+        let fee = match state
+            .payments
+            .get(&payment_hash)
+            .unwrap_or(&state.default_payment)
+        {
+            MockPayment::Success { fee } => *fee,
+            MockPayment::Failure(_) => Amount::ZERO,
+        };
+        Ok(PaymentQuote {
+            payment_hash,
+            amount,
+            fee,
+            internal: state.invoices.contains_key(&payment_hash),
+        })
+    }
+
+    /// Pays an invoice according to the scripted outcome unless its fee
+    /// exceeds `max_fee`. Returns an error if the payment can't be started and
+    /// the reason if it fails.
+    fn pay_invoice(
+        &self,
+        invoice: &Bolt11Invoice,
+        max_fee: Option<Amount>,
+    ) -> anyhow::Result<Result<Preimage, String>> {
+        if max_fee.is_some() && self.lnv2_only() {
+            return Err(UnsupportedByLnv2 {
+                feature: "Limiting the fee",
+            }
+            .into());
+        }
+
+        let quote = self.quote(invoice)?;
+        if let Some(max_fee) = max_fee {
+            if quote.fee > max_fee {
+                return Err(FeeTooHigh {
+                    fee: quote.fee,
+                    max_fee,
+                }
+                .into());
+            }
+        }
+
+        let mut state = self.state();
+        let payment = state
+            .payments
+            .get(&quote.payment_hash)
+            .unwrap_or(&state.default_payment)
+            .clone();
+        let operation_id = OperationId(quote.payment_hash.to_byte_array());
+
+        match payment {
+            MockPayment::Success { fee } => {
+                if state.balance < quote.amount + fee {
+                    bail!("Insufficient balance");
+                }
+                state.balance -= quote.amount + fee;
+
+                // Invoices created by the mock itself are settled internally
+                let preimage = state
+                    .invoices
+                    .get(&quote.payment_hash)
+                    .map(|invoice| invoice.preimage)
+                    .unwrap_or_else(|| Preimage(rand::random()));
+                state.receipts.insert(
+                    quote.payment_hash,
+                    PaymentReceipt {
+                        payment_hash: quote.payment_hash,
+                        preimage,
+                        amount: quote.amount,
+                        fee,
+                        internal: quote.internal,
+                    },
+                );
+                events::emit(&self.events, BlitziEvent::PaymentSent { operation_id });
+                Ok(Ok(preimage))
+            }
+            MockPayment::Failure(reason) => {
+                events::emit(
+                    &self.events,
+                    BlitziEvent::PaymentFailed {
+                        operation_id,
+                        reason: reason.clone(),
+                    },
+                );
+                Ok(Err(reason))
+            }
+        }
+    }
+
+    /// Returns the status of an invoice, crediting the balance once it's paid.
+    fn poll_invoice(&self, payment_hash: &PaymentHash) -> anyhow::Result<InvoiceStatus> {
+        let mut state = self.state();
+        let state = &mut *state;
+        let invoice = state
+            .invoices
+            .get_mut(payment_hash)
+            .ok_or_else(|| anyhow!("No operation found for payment hash"))?;
+
+        match invoice.incoming {
+            MockIncomingPayment::PaidAfter(delay) if invoice.created_at.elapsed() >= delay => {
+                if invoice.claimed_at.is_none() {
+                    invoice.claimed_at = Some(SystemTime::now());
+                    state.balance += invoice.amount;
+                    events::emit(
+                        &self.events,
+                        BlitziEvent::InvoicePaid {
+                            operation_id: OperationId(payment_hash.to_byte_array()),
+                            payment_hash: Some(*payment_hash),
+                            amount: Some(invoice.amount),
+                        },
+                    );
+                }
+                Ok(InvoiceStatus::Paid)
+            }
+            MockIncomingPayment::Canceled => Ok(InvoiceStatus::Canceled),
+            _ => Ok(InvoiceStatus::Pending),
+        }
+    }
+
+    /// Waits until the invoice is paid and returns the payment it was claimed
+    /// with.
+    async fn await_claim(&self, payment_hash: PaymentHash) -> anyhow::Result<ReceivedPayment> {
+        loop {
+            match self.poll_invoice(&payment_hash)? {
+                InvoiceStatus::Paid => {
+                    let state = self.state();
+                    let invoice = &state.invoices[&payment_hash];
+                    return Ok(ReceivedPayment {
+                        amount: invoice.amount,
+                        payment_hash,
+                        claimed_at: invoice.claimed_at.expect("Paid invoices are claimed"),
+                    });
+                }
+                InvoiceStatus::Canceled => bail!("Payment was canceled: invoice expired"),
+                InvoiceStatus::Pending => {}
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    /// Creates an invoice whose incoming payment follows the default outcome.
+    fn issue_invoice(
+        &self,
+        amount: Amount,
+        description: &str,
+        external_id: Option<String>,
+    ) -> anyhow::Result<Bolt11Invoice> {
+        let preimage = Preimage(rand::random());
+        validate_invoice_amount(amount, DEFAULT_MAX_INVOICE_AMOUNT)
+            .map_err(anyhow::Error::from)
+            .and_then(|()| crate::invoice::check_description(description, false))
+            .and_then(|description| create_invoice(amount, description, &preimage))
+            .map(|invoice| {
+                let mut state = self.state();
+                let incoming = state.default_incoming;
+                state.invoices.insert(
+                    preimage.payment_hash(),
+                    MockInvoice {
+                        invoice: invoice.clone(),
+                        amount,
+                        preimage,
+                        created_at: Instant::now(),
+                        incoming,
+                        claimed_at: None,
+                        external_id,
+                    },
+                );
+                invoice
+            })
+    }
+}
+
+impl LightningBackend for MockLightning {
+    fn lightning_invoice(
+        &self,
+        amount: Amount,
+        description: &str,
+    ) -> impl Future<Output = anyhow::Result<Bolt11Invoice>> + Send {
+        self.record(MockCall::LightningInvoice {
+            amount,
+            description: description.to_owned(),
+        });
+        let result = self.issue_invoice(amount, description, None);
+        async move { result }
+    }
+
+    fn lightning_invoice_tagged(
+        &self,
+        amount: Amount,
+        description: &str,
+        external_id: &str,
+    ) -> impl Future<Output = anyhow::Result<Bolt11Invoice>> + Send {
+        self.record(MockCall::LightningInvoiceTagged {
+            amount,
+            description: description.to_owned(),
+            external_id: external_id.to_owned(),
+        });
+
+        let existing = self
+            .state()
+            .invoices
+            .iter()
+            .find(|(_, invoice)| invoice.external_id.as_deref() == Some(external_id))
+            .map(|(payment_hash, _)| *payment_hash);
+        let result = match existing {
+            Some(payment_hash) => Err(ExternalIdInUse {
+                external_id: external_id.to_owned(),
+                operation_id: OperationId(payment_hash.to_byte_array()),
+            }
+            .into()),
+            None => self.issue_invoice(amount, description, Some(external_id.to_owned())),
+        };
+        async move { result }
+    }
+
+    fn invoice_external_id(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<Option<String>>> + Send {
+        self.record(MockCall::InvoiceExternalId(payment_hash));
+        let result = self
+            .state()
+            .invoices
+            .get(&payment_hash)
+            .map(|invoice| invoice.external_id.clone())
+            .ok_or_else(|| anyhow!("No operation found for payment hash"));
+        async move { result }
+    }
+
+    fn pay(
+        &self,
+        invoice: &Bolt11Invoice,
+    ) -> impl Future<Output = anyhow::Result<Preimage>> + Send {
+        self.record(MockCall::Pay(invoice.clone()));
+
+        let result = self.pay_invoice(invoice, None).and_then(|outcome| {
+            outcome.map_err(|reason| anyhow::Error::from(PaymentRefunded { reason }))
+        });
+
+        async move { result }
+    }
+
+    fn pay_idempotent(
+        &self,
+        key: &str,
+        invoice: &Bolt11Invoice,
+        options: PayOptions,
+    ) -> impl Future<Output = anyhow::Result<IdempotentPayment>> + Send {
+        self.record(MockCall::PayIdempotent {
+            key: key.to_owned(),
+            invoice: invoice.clone(),
+        });
+
+        let result = (|| {
+            let payment_hash = PaymentHash::from(invoice.payment_hash());
+            let amount = invoice.amount_milli_satoshis().map(Amount::from_msats);
+            let original = self.state().idempotent_payments.get(key).cloned();
+
+            let payment = match original {
+                Some(original) if original.amount != amount => {
+                    return Err(IdempotencyKeyConflict {
+                        key: key.to_owned(),
+                        original_amount: original.amount,
+                        attempted_amount: amount,
+                    }
+                    .into());
+                }
+                Some(original) => original,
+                None => {
+                    let payment = MockIdempotentPayment {
+                        payment_hash,
+                        amount,
+                        outcome: self.pay_invoice(invoice, options.max_fee)?,
+                    };
+                    self.state()
+                        .idempotent_payments
+                        .insert(key.to_owned(), payment.clone());
+                    payment
+                }
+            };
+
+            Ok(IdempotentPayment {
+                payment_hash: payment.payment_hash,
+                preimage: payment
+                    .outcome
+                    .map_err(|reason| anyhow::Error::from(PaymentRefunded { reason }))?,
+                warning: (payment.payment_hash != payment_hash)
+                    .then(|| reused_key_warning(payment.payment_hash)),
+            })
+        })();
+
+        async move { result }
+    }
+
+    fn pay_detailed(
+        &self,
+        invoice: &Bolt11Invoice,
+        options: PayOptions,
+    ) -> impl Future<Output = anyhow::Result<PaymentReceipt>> + Send {
+        self.record(MockCall::PayDetailed {
+            invoice: invoice.clone(),
+            max_fee: options.max_fee,
+        });
+
+        let payment_hash = PaymentHash::from(invoice.payment_hash());
+        let result = self
+            .pay_invoice(invoice, options.max_fee)
+            .and_then(|outcome| {
+                outcome.map_err(|reason| anyhow::Error::from(PaymentRefunded { reason }))
+            })
+            .map(|_| self.state().receipts[&payment_hash].clone());
+
+        async move { result }
+    }
+
+    fn quote_payment(
+        &self,
+        invoice: &Bolt11Invoice,
+    ) -> impl Future<Output = anyhow::Result<PaymentQuote>> + Send {
+        self.record(MockCall::QuotePayment(invoice.clone()));
+        let result = if self.lnv2_only() {
+            Err(UnsupportedByLnv2 {
+                feature: "Quoting payments",
+            }
+            .into())
+        } else {
+            self.quote(invoice)
+        };
+        async move { result }
+    }
+
+    fn payment_receipt(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<Option<PaymentReceipt>>> + Send {
+        self.record(MockCall::PaymentReceipt(payment_hash));
+        let receipt = self.state().receipts.get(&payment_hash).cloned();
+        async move { Ok(receipt) }
+    }
+
+    fn await_incoming_payment(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        self.record(MockCall::AwaitIncomingPayment(payment_hash));
+        async move {
+            self.await_claim(payment_hash).await?;
+            Ok(())
+        }
+    }
+
+    fn await_incoming_payment_details(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<ReceivedPayment>> + Send {
+        self.record(MockCall::AwaitIncomingPaymentDetails(payment_hash));
+        self.await_claim(payment_hash)
+    }
+
+    fn invoice_status(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<InvoiceStatus>> + Send {
+        self.record(MockCall::InvoiceStatus(payment_hash));
+        let result = self.poll_invoice(&payment_hash);
+        async move { result }
+    }
+
+    fn subscribe_invoice_updates(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<BoxStream<'_, ReceiveState>>> + Send {
+        self.record(MockCall::SubscribeInvoiceUpdates(payment_hash));
+
+        // The mock claims payments instantly, so there is no funded state
+        let result = self.poll_invoice(&payment_hash).map(|_| {
+            let updates = futures_lite::stream::unfold(Some(None), move |last| async move {
+                let last = last?;
+                loop {
+                    let status = self.poll_invoice(&payment_hash).ok()?;
+                    if Some(status) != last {
+                        let state = match status {
+                            InvoiceStatus::Pending => ReceiveState::AwaitingPayment,
+                            InvoiceStatus::Paid => ReceiveState::Claimed,
+                            InvoiceStatus::Canceled => ReceiveState::Canceled {
+                                reason: "invoice expired".to_string(),
+                            },
+                        };
+                        let next = (!state.is_final()).then_some(Some(status));
+                        return Some((state, next));
+                    }
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            });
+            Box::pin(updates) as BoxStream<'_, ReceiveState>
+        });
+
+        async move { result }
+    }
+
+    fn issued_invoice(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<Option<Bolt11Invoice>>> + Send {
+        self.record(MockCall::IssuedInvoice(payment_hash));
+        let invoice = self
+            .state()
+            .invoices
+            .get(&payment_hash)
+            .map(|invoice| invoice.invoice.clone());
+        async move { Ok(invoice) }
+    }
+
+    fn is_invoice_ours(
+        &self,
+        payment_hash: PaymentHash,
+    ) -> impl Future<Output = anyhow::Result<bool>> + Send {
+        self.record(MockCall::IsInvoiceOurs(payment_hash));
+        let ours = self.state().invoices.contains_key(&payment_hash);
+        async move { Ok(ours) }
+    }
+
+    fn balance(&self) -> impl Future<Output = Amount> + Send {
+        self.record(MockCall::Balance);
+        let balance = self.state().balance;
+        async move { balance }
+    }
+
+    fn subscribe_events(&self) -> impl Future<Output = BoxStream<'static, BlitziEvent>> + Send {
+        let events = events::subscribe(
+            self.events.subscribe(),
+            Box::pin(futures_lite::stream::empty()),
+        );
+        async move { events }
+    }
+
+    fn list_operations(
+        &self,
+        limit: usize,
+        before: Option<OperationCursor>,
+    ) -> impl Future<Output = Vec<HistoryEntry>> + Send {
+        self.record(MockCall::ListOperations);
+        let mut entries = self.history_before(before);
+        entries.truncate(limit);
+        async move { entries }
+    }
+
+    fn list_transactions(
+        &self,
+        kind: HistoryEntryKind,
+        status: Option<HistoryEntryStatus>,
+        limit: usize,
+        before: Option<OperationCursor>,
+    ) -> impl Future<Output = Vec<HistoryEntry>> + Send {
+        self.record(MockCall::ListTransactions);
+        let entries = self
+            .history_before(before)
+            .into_iter()
+            .filter(|entry| {
+                entry.kind == kind && status.is_none_or(|status| entry.status == status)
+            })
+            .take(limit)
+            .collect();
+        async move { entries }
+    }
+
+    fn wallet_stats(&self) -> impl Future<Output = WalletStats> + Send {
+        self.record(MockCall::WalletStats);
+        let balance = self.state().balance;
+        async move {
+            WalletStats {
+                balance,
+                note_count: 0,
+                notes_by_denomination: vec![],
+                pending_operations: 0,
+            }
+        }
+    }
+
+    fn status(&self) -> impl Future<Output = WalletStatus> + Send {
+        self.record(MockCall::Status);
+        let state = self.state();
+        let status = WalletStatus {
+            balance: state.balance,
+            pending_invoices: state
+                .invoices
+                .values()
+                .filter(|invoice| {
+                    invoice.claimed_at.is_none()
+                        && !matches!(invoice.incoming, MockIncomingPayment::Canceled)
+                })
+                .count(),
+            guardians: state.guardians.0,
+            reachable_guardians: state.guardians.1,
+            gateways: state.gateways.len(),
+        };
+        async move { status }
+    }
+
+    fn list_gateways(&self) -> impl Future<Output = Vec<GatewayInfo>> + Send {
+        self.record(MockCall::ListGateways);
+        let gateways = self.state().gateways.clone();
+        async move { gateways }
+    }
+
+    fn federation_id(&self) -> FederationId {
+        FederationId::dummy()
+    }
+
+    fn capabilities(&self) -> impl Future<Output = anyhow::Result<FederationCapabilities>> + Send {
+        self.record(MockCall::Capabilities);
+        let capabilities = self.state().capabilities.clone();
+        async move { capabilities.ok_or_else(|| anyhow!("Federation config unavailable")) }
+    }
+
+    fn meta(&self) -> impl Future<Output = anyhow::Result<BTreeMap<String, String>>> + Send {
+        self.record(MockCall::Meta);
+        let meta = self.state().meta.clone();
+        async move { Ok(meta) }
+    }
+}
+
+fn create_invoice(
+    amount: Amount,
+    description: &str,
+    preimage: &Preimage,
+) -> anyhow::Result<Bolt11Invoice> {
+    let secp = Secp256k1::new();
+    let node_key = SecretKey::from_slice(&[42; 32]).expect("valid key");
+    let payment_hash: sha256::Hash = preimage.payment_hash().into();
+
+    InvoiceBuilder::new(Currency::Regtest)
+        .description(description.to_owned())
+        .payment_hash(payment_hash)
+        .payment_secret(PaymentSecret(rand::random()))
+        .current_timestamp()
+        .min_final_cltv_expiry_delta(18)
+        .amount_milli_satoshis(amount.msats)
+        .build_signed(|hash| secp.sign_ecdsa_recoverable(hash, &node_key))
+        .map_err(|e| anyhow!("Failed to create invoice: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sats;
+
+    #[tokio::test]
+    async fn test_incoming_payment() {
+        let mock = MockLightning::new();
+        mock.set_default_incoming(MockIncomingPayment::PaidAfter(Duration::from_millis(20)));
+
+        let mut events = mock.subscribe_events().await;
+        let invoice = mock.lightning_invoice(sats(10), "test").await.unwrap();
+        let payment_hash = PaymentHash::from(invoice.payment_hash());
+        assert_eq!(
+            mock.invoice_status(payment_hash).await.unwrap(),
+            InvoiceStatus::Pending
+        );
+
+        mock.await_incoming_payment(payment_hash).await.unwrap();
+        assert_eq!(
+            mock.invoice_status(payment_hash).await.unwrap(),
+            InvoiceStatus::Paid
+        );
+        assert_eq!(mock.balance().await, sats(10));
+        assert!(matches!(
+            futures_lite::StreamExt::next(&mut events).await,
+            Some(BlitziEvent::InvoicePaid {
+                payment_hash: Some(hash),
+                amount: Some(amount),
+                ..
+            }) if hash == payment_hash && amount == sats(10)
+        ));
+
+        let invoice = mock.lightning_invoice(sats(10), "test").await.unwrap();
+        mock.script_incoming(invoice.payment_hash(), MockIncomingPayment::Canceled);
+        assert!(
+            mock.await_incoming_payment(invoice.payment_hash().into())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_invoice_updates() {
+        let mock = MockLightning::new();
+        mock.set_default_incoming(MockIncomingPayment::PaidAfter(Duration::from_millis(20)));
+
+        let invoice = mock.lightning_invoice(sats(10), "test").await.unwrap();
+        let updates = mock
+            .subscribe_invoice_updates(invoice.payment_hash().into())
+            .await
+            .unwrap();
+        assert_eq!(
+            futures_lite::StreamExt::collect::<Vec<_>>(updates).await,
+            vec![ReceiveState::AwaitingPayment, ReceiveState::Claimed]
+        );
+        assert_eq!(mock.balance().await, sats(10));
+
+        let unknown = Preimage([0; 32]).payment_hash();
+        assert!(mock.subscribe_invoice_updates(unknown).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lightning_invoice_tagged() {
+        let mock = MockLightning::new();
+        let invoice = mock
+            .lightning_invoice_tagged(sats(10), "test", "order-1")
+            .await
+            .unwrap();
+        assert_eq!(
+            mock.invoice_external_id(invoice.payment_hash().into())
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("order-1")
+        );
+
+        let error = mock
+            .lightning_invoice_tagged(sats(10), "test", "order-1")
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<ExternalIdInUse>().is_some());
+
+        let untagged = mock.lightning_invoice(sats(10), "test").await.unwrap();
+        assert_eq!(
+            mock.invoice_external_id(untagged.payment_hash().into())
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pay() {
+        let mock = MockLightning::new();
+        mock.set_balance(sats(100));
+        mock.set_default_payment(MockPayment::Success { fee: sats(1) });
+
+        let invoice = mock.lightning_invoice(sats(10), "test").await.unwrap();
+        let preimage = mock.pay(&invoice).await.unwrap();
+        assert_eq!(&preimage.payment_hash().0, invoice.payment_hash());
+        assert_eq!(mock.balance().await, sats(89));
+
+        let invoice = mock.lightning_invoice(sats(10), "test").await.unwrap();
+        mock.script_payment(
+            invoice.payment_hash(),
+            MockPayment::Failure("no route".into()),
+        );
+        assert!(mock.pay(&invoice).await.is_err());
+        assert_eq!(mock.balance().await, sats(89));
+
+        assert!(matches!(
+            mock.calls().first(),
+            Some(MockCall::LightningInvoice { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_pay_detailed() {
+        let mock = MockLightning::new();
+        mock.set_balance(sats(100));
+        mock.set_default_payment(MockPayment::Success { fee: sats(2) });
+
+        let invoice = mock.lightning_invoice(sats(10), "test").await.unwrap();
+        let quote = mock.quote_payment(&invoice).await.unwrap();
+        assert_eq!((quote.amount, quote.fee), (sats(10), sats(2)));
+
+        let options = PayOptions {
+            max_fee: Some(sats(1)),
+            ..PayOptions::default()
+        };
+        let error = mock.pay_detailed(&invoice, options).await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<FeeTooHigh>(),
+            Some(&FeeTooHigh {
+                fee: sats(2),
+                max_fee: sats(1),
+            })
+        );
+        assert_eq!(mock.balance().await, sats(100));
+        let payment_hash = PaymentHash::from(invoice.payment_hash());
+        assert_eq!(mock.payment_receipt(payment_hash).await.unwrap(), None);
+
+        let receipt = mock
+            .pay_detailed(&invoice, PayOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(receipt.fee, sats(2));
+        assert!(receipt.internal);
+        assert_eq!(
+            mock.payment_receipt(payment_hash).await.unwrap(),
+            Some(receipt)
+        );
+        assert_eq!(mock.balance().await, sats(88));
+    }
+
+    #[tokio::test]
+    async fn test_pay_idempotent() {
+        let mock = MockLightning::new();
+        mock.set_balance(sats(100));
+
+        let invoice = mock.lightning_invoice(sats(10), "test").await.unwrap();
+        let payment = mock
+            .pay_idempotent("order-1", &invoice, PayOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(payment.warning, None);
+
+        // A new invoice for the same key returns the original outcome
+        let other = mock.lightning_invoice(sats(10), "test").await.unwrap();
+        let repeated = mock
+            .pay_idempotent("order-1", &other, PayOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(repeated.preimage, payment.preimage);
+        assert_eq!(repeated.payment_hash, invoice.payment_hash().into());
+        assert!(repeated.warning.is_some());
+        assert_eq!(mock.balance().await, sats(90));
+
+        let larger = mock.lightning_invoice(sats(20), "test").await.unwrap();
+        let error = mock
+            .pay_idempotent("order-1", &larger, PayOptions::default())
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<IdempotencyKeyConflict>().is_some());
+    }
+}
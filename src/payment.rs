@@ -0,0 +1,532 @@
+//! Progress of an outgoing payment reported by
+//! [`Blitzi::pay_with_updates`](crate::Blitzi::pay_with_updates), its result
+//! returned by [`Blitzi::payment_result`](crate::Blitzi::payment_result) and
+//! locking to keep concurrent calls from paying the same invoice twice.
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+use fedimint_core::Amount;
+use fedimint_core::secp256k1::PublicKey;
+use fedimint_ln_client::{InternalPayState, LnPayState};
+use lightning_invoice::{Bolt11Invoice, RoutingFees};
+use serde::{Deserialize, Serialize};
+use tokio::sync::OwnedMutexGuard;
+
+use crate::error::PaymentTimedOut;
+use crate::{PaymentHash, Preimage};
+
+/// Default number of payments [`Blitzi::pay_batch`](crate::Blitzi::pay_batch)
+/// makes at once.
+pub const DEFAULT_BATCH_CONCURRENCY: usize = 4;
+
+/// Options for [`Blitzi::pay_detailed`](crate::Blitzi::pay_detailed),
+/// [`Blitzi::pay_idempotent`](crate::Blitzi::pay_idempotent) and
+/// [`Blitzi::pay_batch`](crate::Blitzi::pay_batch).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PayOptions {
+    /// Gateway to route the payment through, see
+    /// [`Blitzi::list_gateways`](crate::Blitzi::list_gateways). By default the
+    /// gateway is chosen according to
+    /// [`BlitziBuilder::gateway_selection`](crate::BlitziBuilder::gateway_selection).
+    /// Not supported by the `lnv2` module.
+    pub gateway: Option<PublicKey>,
+    /// Maximum number of payments
+    /// [`Blitzi::pay_batch`](crate::Blitzi::pay_batch) makes at once,
+    /// defaults to [`DEFAULT_BATCH_CONCURRENCY`]. Ignored by other methods.
+    pub concurrency: Option<usize>,
+    /// External reference id (e.g. an order id) to attach to the payment made
+    /// by [`Blitzi::pay_idempotent`](crate::Blitzi::pay_idempotent), see
+    /// [`Blitzi::find_by_external_id`](crate::Blitzi::find_by_external_id).
+    /// Ignored by other methods.
+    pub external_id: Option<String>,
+    /// Maximum fee to pay the gateway. If the gateway charges more the
+    /// payment isn't started and a [`FeeTooHigh`](crate::FeeTooHigh) error is
+    /// returned, see [`Blitzi::quote_payment`](crate::Blitzi::quote_payment).
+    /// Not supported by the `lnv2` module.
+    pub max_fee: Option<Amount>,
+}
+
+/// Fee quote for paying an invoice, returned by
+/// [`Blitzi::quote_payment`](crate::Blitzi::quote_payment).
+///
+/// Serialized with the payment hash as hex and the amounts as `amount_msats`
+/// and `fee_msats`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PaymentQuote {
+    /// Payment hash of the invoice
+    pub payment_hash: PaymentHash,
+    /// Amount of the invoice
+    #[serde(rename = "amount_msats")]
+    pub amount: Amount,
+    /// Fee the gateway charges, zero for internal payments
+    #[serde(rename = "fee_msats")]
+    pub fee: Amount,
+    /// Whether the invoice was issued by a user of the same federation, in
+    /// which case it's settled without involving the Lightning network
+    pub internal: bool,
+}
+
+/// A successful payment made using
+/// [`Blitzi::pay_batch`](crate::Blitzi::pay_batch).
+///
+/// Serialized with the payment hash and preimage as hex and the amounts as
+/// `amount_msats` and `fee_msats`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PaymentReceipt {
+    /// Payment hash of the invoice that was paid
+    pub payment_hash: PaymentHash,
+    /// Preimage of the invoice, proof of payment
+    pub preimage: Preimage,
+    /// Amount of the invoice
+    #[serde(rename = "amount_msats")]
+    pub amount: Amount,
+    /// Fee paid to the gateway
+    #[serde(rename = "fee_msats")]
+    pub fee: Amount,
+    /// Whether the payment was settled within the federation
+    pub internal: bool,
+}
+
+impl PaymentQuote {
+    /// Quotes paying `invoice` through the `ln` module's gateway with the
+    /// Lightning node `node_pub_key`. Invoices issued by users of the same
+    /// federation route through that node and a channel id equal to the
+    /// gateway's `federation_index`, they are paid internally without a fee.
+    pub(crate) fn ln(
+        invoice: &Bolt11Invoice,
+        node_pub_key: PublicKey,
+        federation_index: u64,
+        fees: RoutingFees,
+    ) -> anyhow::Result<Self> {
+        let amount = invoice
+            .amount_milli_satoshis()
+            .map(Amount::from_msats)
+            .context("Amountless invoices aren't supported")?;
+        let internal = invoice.route_hints().iter().any(|hint| {
+            hint.0.last().is_some_and(|hop| {
+                hop.src_node_id == node_pub_key && hop.short_channel_id == federation_index
+            })
+        });
+        // Same calculation as the `ln` module uses when paying
+        let fee = if internal {
+            Amount::ZERO
+        } else {
+            let proportional = match fees.proportional_millionths {
+                0 => 0,
+                ppm => amount.msats / (1_000_000 / u64::from(ppm)),
+            };
+            Amount::from_msats(u64::from(fees.base_msat) + proportional)
+        };
+        Ok(PaymentQuote {
+            payment_hash: invoice.payment_hash().into(),
+            amount,
+            fee,
+            internal,
+        })
+    }
+}
+
+/// State of an outgoing payment. The last state yielded for a payment is
+/// always either [`PayProgress::Succeeded`] or [`PayProgress::Failed`].
+///
+/// Serialized as `{"state": "succeeded", "preimage": "<hex>"}` etc.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum PayProgress {
+    /// The funds for the payment are being locked in a contract
+    Funding,
+    /// The contract was funded and the gateway is routing the payment
+    AwaitingGateway,
+    /// The gateway failed to route the payment, the funds are being refunded
+    Refunding {
+        /// Why the gateway failed to pay
+        reason: String,
+    },
+    /// The payment succeeded, change from the payment is being claimed
+    AwaitingChange,
+    /// The payment succeeded
+    Succeeded {
+        /// Preimage of the invoice, proof of payment
+        preimage: Preimage,
+    },
+    /// The payment failed, funds that were locked for it were refunded
+    Failed {
+        /// Why the payment failed
+        reason: String,
+    },
+}
+
+impl PayProgress {
+    /// Returns `true` if no further updates will follow this one.
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            PayProgress::Succeeded { .. } | PayProgress::Failed { .. }
+        )
+    }
+
+    pub(crate) fn from_ln_pay_state(state: LnPayState) -> Self {
+        match state {
+            LnPayState::Created => PayProgress::Funding,
+            LnPayState::Funded { .. } => PayProgress::AwaitingGateway,
+            LnPayState::WaitingForRefund { error_reason } => PayProgress::Refunding {
+                reason: error_reason,
+            },
+            LnPayState::AwaitingChange => PayProgress::AwaitingChange,
+            LnPayState::Success { preimage } => match parse_preimage(&preimage) {
+                Some(preimage) => PayProgress::Succeeded { preimage },
+                None => PayProgress::Failed {
+                    reason: format!("Gateway returned invalid preimage: {}", preimage),
+                },
+            },
+            state => PayProgress::Failed {
+                reason: format!("{:?}", state),
+            },
+        }
+    }
+
+    pub(crate) fn from_internal_pay_state(state: InternalPayState) -> Self {
+        match state {
+            InternalPayState::Funding => PayProgress::Funding,
+            InternalPayState::Preimage(preimage) => PayProgress::Succeeded {
+                preimage: Preimage(preimage.0),
+            },
+            state => PayProgress::Failed {
+                reason: format!("{:?}", state),
+            },
+        }
+    }
+}
+
+/// Result of an outgoing payment returned by
+/// [`Blitzi::payment_result`](crate::Blitzi::payment_result).
+///
+/// Serialized as `{"status": "succeeded", "preimage": "<hex>", "fee_msats":
+/// 1000}` etc.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum PaymentResult {
+    /// The payment hasn't finished yet or its outcome hasn't been observed yet
+    Pending,
+    /// The payment succeeded
+    Succeeded {
+        /// Preimage of the invoice, proof of payment
+        preimage: Preimage,
+        /// Fee paid to the gateway
+        #[serde(rename = "fee_msats")]
+        fee: Amount,
+    },
+    /// The payment failed, funds that were locked for it were refunded
+    Failed {
+        /// Why the payment failed
+        reason: String,
+    },
+}
+
+impl PaymentResult {
+    /// Converts the final state of a payment charging `fee` into its result.
+    pub(crate) fn from_progress(progress: PayProgress, fee: Amount) -> Self {
+        match progress {
+            PayProgress::Succeeded { preimage } => PaymentResult::Succeeded { preimage, fee },
+            PayProgress::Failed { reason } => PaymentResult::Failed { reason },
+            _ => PaymentResult::Pending,
+        }
+    }
+}
+
+/// Per key locks that serialize starting payments, e.g. by payment hash so a
+/// concurrent second call to [`Blitzi::pay`](crate::Blitzi::pay) for the same
+/// invoice waits for the first one to create its operation and then follows it
+/// instead of starting a conflicting payment.
+#[derive(Debug)]
+pub(crate) struct KeyedLocks<K> {
+    locks: Mutex<HashMap<K, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl<K> Default for KeyedLocks<K> {
+    fn default() -> Self {
+        KeyedLocks {
+            locks: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> KeyedLocks<K> {
+    /// Waits until no other task holds the lock for `key` and acquires it. The
+    /// lock is released when the returned guard is dropped.
+    pub(crate) async fn lock(&self, key: K) -> KeyedLockGuard<'_, K> {
+        let lock = self
+            .locks
+            .lock()
+            .expect("lock poisoned")
+            .entry(key.clone())
+            .or_default()
+            .clone();
+
+        KeyedLockGuard {
+            locks: self,
+            key,
+            guard: Some(lock.lock_owned().await),
+        }
+    }
+}
+
+pub(crate) struct KeyedLockGuard<'a, K: Hash + Eq> {
+    locks: &'a KeyedLocks<K>,
+    key: K,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl<K: Hash + Eq> Drop for KeyedLockGuard<'_, K> {
+    fn drop(&mut self) {
+        let mut locks = self.locks.locks.lock().expect("lock poisoned");
+        self.guard.take();
+
+        // Remove the lock once nobody is holding or waiting for it anymore
+        if locks
+            .get(&self.key)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.key);
+        }
+    }
+}
+
+/// Waits for `payment` of the invoice with `payment_hash` to finish, giving up
+/// with a [`PaymentTimedOut`] error once `deadline` has passed.
+pub(crate) async fn with_deadline<T>(
+    payment_hash: PaymentHash,
+    deadline: Duration,
+    payment: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    fedimint_core::runtime::timeout(deadline, payment)
+        .await
+        .map_err(|_| PaymentTimedOut {
+            payment_hash,
+            deadline,
+        })?
+}
+
+fn parse_preimage(preimage: &str) -> Option<Preimage> {
+    Some(Preimage(hex::decode(preimage).ok()?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use fedimint_core::BitcoinHash;
+    use fedimint_core::bitcoin::hashes::sha256;
+    use fedimint_core::secp256k1::{Secp256k1, SecretKey};
+    use lightning_invoice::{Currency, InvoiceBuilder, PaymentSecret, RouteHint, RouteHintHop};
+
+    use super::*;
+
+    #[test]
+    fn test_ln_quote() {
+        let secp = Secp256k1::new();
+        let gateway_node = SecretKey::from_slice(&[1; 32]).unwrap().public_key(&secp);
+        let fees = RoutingFees {
+            base_msat: 1000,
+            proportional_millionths: 100,
+        };
+        let invoice = |short_channel_id: u64| {
+            InvoiceBuilder::new(Currency::Regtest)
+                .description("test".into())
+                .payment_hash(sha256::Hash::hash(&[2; 32]))
+                .payment_secret(PaymentSecret([3; 32]))
+                .current_timestamp()
+                .min_final_cltv_expiry_delta(144)
+                .amount_milli_satoshis(1_000_000)
+                .private_route(RouteHint(vec![RouteHintHop {
+                    src_node_id: gateway_node,
+                    short_channel_id,
+                    fees,
+                    cltv_expiry_delta: 144,
+                    htlc_minimum_msat: None,
+                    htlc_maximum_msat: None,
+                }]))
+                .build_signed(|hash| {
+                    secp.sign_ecdsa_recoverable(hash, &SecretKey::from_slice(&[4; 32]).unwrap())
+                })
+                .unwrap()
+        };
+
+        let quote = PaymentQuote::ln(&invoice(42), gateway_node, 7, fees).unwrap();
+        assert_eq!(quote.amount, Amount::from_msats(1_000_000));
+        assert_eq!(quote.fee, Amount::from_msats(1100));
+        assert!(!quote.internal);
+
+        // Invoices of the same federation route through its channel id
+        let quote = PaymentQuote::ln(&invoice(7), gateway_node, 7, fees).unwrap();
+        assert_eq!(quote.fee, Amount::ZERO);
+        assert!(quote.internal);
+    }
+
+    #[tokio::test]
+    async fn test_with_deadline() {
+        let payment_hash = Preimage([1; 32]).payment_hash();
+        let deadline = Duration::from_millis(50);
+
+        let preimage = with_deadline(payment_hash, deadline, async { Ok(Preimage([1; 32])) })
+            .await
+            .unwrap();
+        assert_eq!(preimage, Preimage([1; 32]));
+
+        // A payment that never reaches a final state
+        let error = with_deadline(
+            payment_hash,
+            deadline,
+            std::future::pending::<anyhow::Result<Preimage>>(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<PaymentTimedOut>(),
+            Some(&PaymentTimedOut {
+                payment_hash,
+                deadline,
+            })
+        );
+
+        // Failures before the deadline are returned as is
+        let error = with_deadline(payment_hash, deadline, async {
+            Err::<Preimage, _>(anyhow::anyhow!("Payment failed: no route"))
+        })
+        .await
+        .unwrap_err();
+        assert!(error.downcast_ref::<PaymentTimedOut>().is_none());
+    }
+
+    #[test]
+    fn test_from_ln_pay_state() {
+        assert_eq!(
+            PayProgress::from_ln_pay_state(LnPayState::Created),
+            PayProgress::Funding
+        );
+        assert_eq!(
+            PayProgress::from_ln_pay_state(LnPayState::Success {
+                preimage: "ab".repeat(32),
+            }),
+            PayProgress::Succeeded {
+                preimage: Preimage([0xab; 32])
+            }
+        );
+        assert!(matches!(
+            PayProgress::from_ln_pay_state(LnPayState::Success {
+                preimage: "ab".to_string(),
+            }),
+            PayProgress::Failed { .. }
+        ));
+        assert!(PayProgress::from_ln_pay_state(LnPayState::Canceled).is_final());
+    }
+
+    #[tokio::test]
+    async fn test_keyed_locks() {
+        let locks = KeyedLocks::default();
+        let payment_hash = Preimage([1; 32]).payment_hash();
+
+        let guard = locks.lock(payment_hash).await;
+        // Other payment hashes aren't blocked
+        drop(locks.lock(Preimage([2; 32]).payment_hash()).await);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), locks.lock(payment_hash))
+                .await
+                .is_err()
+        );
+
+        drop(guard);
+        drop(locks.lock(payment_hash).await);
+        assert!(locks.locks.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_pay_progress_serde() {
+        let progress = PayProgress::Succeeded {
+            preimage: Preimage([0xab; 32]),
+        };
+        let json = serde_json::to_value(&progress).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({ "state": "succeeded", "preimage": "ab".repeat(32) })
+        );
+        assert_eq!(
+            serde_json::from_value::<PayProgress>(json).unwrap(),
+            progress
+        );
+
+        assert_eq!(
+            serde_json::to_value(PayProgress::AwaitingGateway).unwrap(),
+            serde_json::json!({ "state": "awaiting_gateway" })
+        );
+    }
+
+    #[test]
+    fn test_payment_result() {
+        let fee = Amount::from_msats(1000);
+        let result = PaymentResult::from_progress(
+            PayProgress::Succeeded {
+                preimage: Preimage([0xab; 32]),
+            },
+            fee,
+        );
+        let json = serde_json::to_value(&result).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "status": "succeeded",
+                "preimage": "ab".repeat(32),
+                "fee_msats": 1000,
+            })
+        );
+        assert_eq!(
+            serde_json::from_value::<PaymentResult>(json).unwrap(),
+            result
+        );
+
+        assert_eq!(
+            PaymentResult::from_progress(
+                PayProgress::Failed {
+                    reason: "no route".to_string()
+                },
+                fee
+            ),
+            PaymentResult::Failed {
+                reason: "no route".to_string()
+            }
+        );
+        assert_eq!(
+            PaymentResult::from_progress(PayProgress::AwaitingGateway, fee),
+            PaymentResult::Pending
+        );
+    }
+
+    #[test]
+    fn test_payment_receipt_serde() {
+        let preimage = Preimage([0xab; 32]);
+        let receipt = PaymentReceipt {
+            payment_hash: preimage.payment_hash(),
+            preimage,
+            amount: Amount::from_msats(50_000),
+            fee: Amount::from_msats(1000),
+            internal: false,
+        };
+        let json = serde_json::to_value(&receipt).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "payment_hash": preimage.payment_hash().to_string(),
+                "preimage": "ab".repeat(32),
+                "amount_msats": 50_000,
+                "fee_msats": 1000,
+                "internal": false,
+            })
+        );
+        assert_eq!(
+            serde_json::from_value::<PaymentReceipt>(json).unwrap(),
+            receipt
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//! Hook restricting which payments Blitzi makes, configured via
+//! [`BlitziBuilder::payment_policy`](crate::BlitziBuilder::payment_policy).
+use std::sync::Arc;
+
+use fedimint_core::Amount;
+use fedimint_core::secp256k1::PublicKey;
+use lightning_invoice::Bolt11Invoice;
+
+use crate::error::PolicyDenied;
+use crate::{InvoiceDescription, PaymentHash};
+
+/// A payment Blitzi is about to make, passed to the payment policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PaymentIntent {
+    /// Amount of the invoice, `None` for amountless invoices
+    pub amount: Option<Amount>,
+    /// Node id of the payee
+    pub payee: PublicKey,
+    /// Description or description hash of the invoice
+    pub description: InvoiceDescription,
+    /// Payment hash of the invoice
+    pub payment_hash: PaymentHash,
+}
+
+impl PaymentIntent {
+    pub(crate) fn new(invoice: &Bolt11Invoice) -> Self {
+        PaymentIntent {
+            amount: invoice.amount_milli_satoshis().map(Amount::from_msats),
+            payee: invoice.get_payee_pub_key(),
+            description: InvoiceDescription::of(invoice),
+            payment_hash: invoice.payment_hash().into(),
+        }
+    }
+}
+
+/// Decision of a payment policy about a [`PaymentIntent`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyDecision {
+    /// The payment may be made
+    Allow,
+    /// The payment is rejected with the given reason
+    Deny(String),
+}
+
+pub(crate) type PaymentPolicy = Arc<dyn Fn(&PaymentIntent) -> PolicyDecision + Send + Sync>;
+
+/// Asks `policy` whether `invoice` may be paid.
+pub(crate) fn check(
+    policy: Option<&PaymentPolicy>,
+    invoice: &Bolt11Invoice,
+) -> Result<(), PolicyDenied> {
+    let Some(policy) = policy else {
+        return Ok(());
+    };
+
+    match policy(&PaymentIntent::new(invoice)) {
+        PolicyDecision::Allow => Ok(()),
+        PolicyDecision::Deny(reason) => Err(PolicyDenied { reason }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fedimint_core::BitcoinHash;
+    use fedimint_core::bitcoin::hashes::sha256;
+    use fedimint_core::secp256k1::{Secp256k1, SecretKey};
+    use lightning_invoice::{Currency, InvoiceBuilder, PaymentSecret};
+
+    use super::*;
+
+    fn test_invoice(node_key: &SecretKey) -> Bolt11Invoice {
+        let secp = Secp256k1::new();
+
+        InvoiceBuilder::new(Currency::Regtest)
+            .description("test".into())
+            .payment_hash(sha256::Hash::hash(&[2; 32]))
+            .payment_secret(PaymentSecret([3; 32]))
+            .current_timestamp()
+            .min_final_cltv_expiry_delta(144)
+            .amount_milli_satoshis(1000)
+            .build_signed(|hash| secp.sign_ecdsa_recoverable(hash, node_key))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_check_policy() {
+        let secp = Secp256k1::new();
+        let allowed_key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let allowed_payee = allowed_key.public_key(&secp);
+        let policy: PaymentPolicy = Arc::new(move |intent: &PaymentIntent| {
+            if intent.payee == allowed_payee {
+                PolicyDecision::Allow
+            } else {
+                PolicyDecision::Deny("unknown payee".to_string())
+            }
+        });
+
+        let allowed = test_invoice(&allowed_key);
+        assert_eq!(check(None, &allowed), Ok(()));
+        assert_eq!(check(Some(&policy), &allowed), Ok(()));
+
+        let other = test_invoice(&SecretKey::from_slice(&[4; 32]).unwrap());
+        assert_eq!(
+            check(Some(&policy), &other),
+            Err(PolicyDenied {
+                reason: "unknown payee".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_payment_intent() {
+        let intent = PaymentIntent::new(&test_invoice(&SecretKey::from_slice(&[1; 32]).unwrap()));
+        assert_eq!(intent.amount, Some(Amount::from_msats(1000)));
+        assert_eq!(
+            intent.description,
+            InvoiceDescription::Direct("test".to_string())
+        );
+    }
+}
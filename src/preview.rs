@@ -0,0 +1,88 @@
+//! Previewing a federation before joining it, see
+//! [`BlitziBuilder::preview`](crate::BlitziBuilder::preview).
+use fedimint_client::ClientPreview;
+use fedimint_core::bitcoin::Network;
+use fedimint_core::config::{ClientConfig, FederationId};
+
+use crate::{Blitzi, BlitziBuilder, ConsensusVersion, FederationCapabilities};
+
+/// A guardian of a federation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Guardian {
+    /// Name the guardian chose for itself
+    pub name: String,
+    /// URL of the guardian's API endpoint
+    pub api_url: String,
+}
+
+/// A federation that was previewed using
+/// [`BlitziBuilder::preview`](crate::BlitziBuilder::preview) but not joined
+/// yet, e.g. to ask the user for confirmation. Join it using [`Self::join`] or
+/// drop it to cancel.
+pub struct FederationPreview {
+    /// Id of the federation
+    pub federation_id: FederationId,
+    /// Name of the federation, if it announces one in its config
+    pub name: Option<String>,
+    /// Welcome message of the federation, if it announces one in its config
+    pub welcome_message: Option<String>,
+    /// Guardians running the federation
+    pub guardians: Vec<Guardian>,
+    /// Kinds of the modules the federation runs, see
+    /// [`FederationCapabilities::modules`]
+    pub modules: Vec<String>,
+    /// Bitcoin network the federation operates on
+    pub network: Network,
+    /// Version of the consensus the federation runs
+    pub consensus_version: ConsensusVersion,
+    builder: BlitziBuilder,
+    preview: ClientPreview,
+}
+
+impl FederationPreview {
+    pub(crate) fn new(builder: BlitziBuilder, preview: ClientPreview) -> anyhow::Result<Self> {
+        let config = preview.config();
+        let capabilities = FederationCapabilities::from_config(config)?;
+
+        Ok(FederationPreview {
+            federation_id: config.global.calculate_federation_id(),
+            name: meta_field(config, "federation_name"),
+            welcome_message: meta_field(config, "welcome_message"),
+            guardians: config
+                .global
+                .api_endpoints
+                .values()
+                .map(|peer| Guardian {
+                    name: peer.name.clone(),
+                    api_url: peer.url.to_string(),
+                })
+                .collect(),
+            modules: capabilities.modules,
+            network: capabilities.network,
+            consensus_version: capabilities.consensus_version,
+            builder,
+            preview,
+        })
+    }
+
+    /// Returns the number of guardians running the federation.
+    pub fn guardian_count(&self) -> usize {
+        self.guardians.len()
+    }
+
+    /// Joins the previewed federation using the already fetched config and
+    /// builds the client with the settings of the builder the preview was
+    /// created from.
+    ///
+    /// # Errors
+    /// Returns an error if the data directory (or provided database) already
+    /// contains a wallet, and otherwise the same errors as
+    /// [`BlitziBuilder::build`].
+    pub async fn join(self) -> anyhow::Result<Blitzi> {
+        self.builder.join_preview(self.preview).await
+    }
+}
+
+fn meta_field(config: &ClientConfig, field: &str) -> Option<String> {
+    config.global.meta.get(field).cloned()
+}
@@ -0,0 +1,161 @@
+//! Claiming incoming Lightning payments that arrived while the client was
+//! offline, see [`Blitzi::reclaim_pending`](crate::Blitzi::reclaim_pending),
+//! and finding payments that were interrupted, see
+//! [`Blitzi::resume_pending_payments`](crate::Blitzi::resume_pending_payments).
+use std::time::Duration;
+
+use anyhow::Context;
+use fedimint_client::Client;
+use fedimint_client::oplog::OperationLogEntry;
+use fedimint_core::core::OperationId;
+use fedimint_ln_client::{
+    LightningClientModule, LightningOperationMeta, LightningOperationMetaPay,
+    LightningOperationMetaVariant, LnReceiveState,
+};
+use fedimint_lnv2_common::LightningInvoice;
+use futures_lite::StreamExt;
+
+use crate::{HistoryEntry, HistoryEntryKind, HistoryEntryStatus, LightningVersion, PaymentHash};
+
+/// How long to wait for an incoming payment that hasn't been funded yet to
+/// make progress before assuming its invoice simply wasn't paid yet.
+const UNFUNDED_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to wait for a funded incoming payment to make progress while its
+/// ecash is being claimed.
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Returns the operation ids of all incoming payments without a recorded
+/// outcome together with the Lightning module that created them, newest
+/// first.
+pub(crate) async fn pending_incoming_payments(
+    client: &Client,
+) -> Vec<(OperationId, LightningVersion)> {
+    pending_operations(client, HistoryEntryKind::Receive)
+        .await
+        .into_iter()
+        .map(|(operation_id, _, version)| (operation_id, version))
+        .collect()
+}
+
+/// Returns the payment hashes of all outgoing payments without a recorded
+/// outcome, newest first.
+pub(crate) async fn pending_outgoing_payments(client: &Client) -> Vec<PaymentHash> {
+    pending_operations(client, HistoryEntryKind::Pay)
+        .await
+        .into_iter()
+        .filter_map(|(_, operation, version)| paid_invoice_hash(&operation, version))
+        .collect()
+}
+
+/// Returns the operations of `kind` without a recorded outcome together with
+/// the Lightning module that created them, newest first.
+async fn pending_operations(
+    client: &Client,
+    kind: HistoryEntryKind,
+) -> Vec<(OperationId, OperationLogEntry, LightningVersion)> {
+    const PAGE_SIZE: usize = 100;
+
+    let mut pending = vec![];
+    let mut before = None;
+    loop {
+        let page = client
+            .operation_log()
+            .paginate_operations_rev(PAGE_SIZE, before)
+            .await;
+        let next = match page.last() {
+            Some((key, _)) if page.len() == PAGE_SIZE => Some(*key),
+            _ => None,
+        };
+
+        pending.extend(page.into_iter().filter_map(|(key, operation)| {
+            let entry = HistoryEntry::from_operation(key, &operation)?;
+            if entry.kind != kind || entry.status != HistoryEntryStatus::Pending {
+                return None;
+            }
+            let version = match operation.operation_module_kind() {
+                crate::lnv2::KIND => LightningVersion::V2,
+                _ => LightningVersion::V1,
+            };
+            Some((entry.operation_id, operation, version))
+        }));
+
+        match next {
+            Some(key) => before = Some(key),
+            None => break,
+        }
+    }
+
+    pending
+}
+
+/// Returns the payment hash of the invoice paid by the outgoing payment
+/// `operation`.
+fn paid_invoice_hash(
+    operation: &OperationLogEntry,
+    version: LightningVersion,
+) -> Option<PaymentHash> {
+    match version {
+        LightningVersion::V1 => match operation.meta::<LightningOperationMeta>().variant {
+            LightningOperationMetaVariant::Pay(LightningOperationMetaPay { invoice, .. }) => {
+                Some(invoice.payment_hash().into())
+            }
+            _ => None,
+        },
+        LightningVersion::V2 => match operation.meta() {
+            fedimint_lnv2_client::LightningOperationMeta::Send(meta) => match meta.invoice {
+                LightningInvoice::Bolt11(invoice) => Some(invoice.payment_hash().into()),
+            },
+            fedimint_lnv2_client::LightningOperationMeta::Receive(_) => None,
+        },
+    }
+}
+
+/// Drives pending incoming payments whose contract was already funded to
+/// completion and returns how many were claimed.
+///
+/// Only payments received using the `ln` module need this, the `lnv2` module
+/// claims incoming payments on its own once they are funded.
+pub(crate) async fn reclaim_pending(client: &Client) -> anyhow::Result<usize> {
+    let pending = pending_incoming_payments(client)
+        .await
+        .into_iter()
+        .filter(|(_, version)| *version == LightningVersion::V1)
+        .map(|(operation_id, _)| operation_id)
+        .collect::<Vec<_>>();
+    if pending.is_empty() {
+        return Ok(0);
+    }
+
+    let ln_module = client
+        .get_first_module::<LightningClientModule>()
+        .context("LN module not found")?;
+
+    let mut reclaimed = 0;
+    for operation_id in pending {
+        let mut updates = ln_module
+            .subscribe_ln_receive(operation_id)
+            .await?
+            .into_stream();
+
+        let mut timeout = UNFUNDED_TIMEOUT;
+        loop {
+            match fedimint_core::runtime::timeout(timeout, updates.next()).await {
+                Ok(Some(LnReceiveState::Claimed)) => {
+                    reclaimed += 1;
+                    break;
+                }
+                Ok(Some(LnReceiveState::Funded | LnReceiveState::AwaitingFunds)) => {
+                    timeout = CLAIM_TIMEOUT;
+                }
+                Ok(Some(LnReceiveState::Canceled { .. }) | None) => break,
+                Ok(Some(_)) => {}
+                // The invoice wasn't paid yet or claiming is stuck, the next run
+                // will try again
+                Err(_) => break,
+            }
+        }
+    }
+
+    Ok(reclaimed)
+}
@@ -0,0 +1,169 @@
+//! Progress of recovering a wallet restored from its mnemonic, see
+//! [`Blitzi::recovery_progress`](crate::Blitzi::recovery_progress).
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use fedimint_client::ClientHandle;
+use fedimint_core::core::ModuleInstanceId;
+use fedimint_core::task::TaskGroup;
+use fedimint_core::util::BoxStream;
+use futures_lite::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+/// Progress of the recovery of a wallet
+/// [restored from its mnemonic](crate::BlitziBuilder::restore_from_mnemonic),
+/// returned by [`Blitzi::recovery_progress`](crate::Blitzi::recovery_progress).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecoveryProgress {
+    /// Progress of every module that is or was recovering, empty if the
+    /// wallet isn't recovering
+    pub modules: Vec<ModuleRecoveryProgress>,
+}
+
+impl RecoveryProgress {
+    /// Returns whether all modules finished recovering.
+    pub fn is_done(&self) -> bool {
+        self.modules.iter().all(ModuleRecoveryProgress::is_done)
+    }
+
+    /// Records the progress of the module with `module_id`.
+    fn update(&mut self, module_id: ModuleInstanceId, kind: &str, complete: u32, total: u32) {
+        let progress = ModuleRecoveryProgress {
+            module_id,
+            kind: kind.to_owned(),
+            complete,
+            total,
+        };
+        match self
+            .modules
+            .iter_mut()
+            .find(|module| module.module_id == module_id)
+        {
+            Some(module) => *module = progress,
+            None => {
+                self.modules.push(progress);
+                self.modules.sort_by_key(|module| module.module_id);
+            }
+        }
+    }
+}
+
+/// Recovery progress of a single module, e.g. the ecash notes of the `mint`
+/// module.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModuleRecoveryProgress {
+    /// Instance id of the module
+    pub module_id: ModuleInstanceId,
+    /// Kind of the module, e.g. `mint`
+    pub kind: String,
+    /// Number of steps (e.g. federation sessions) recovered so far
+    pub complete: u32,
+    /// Total number of steps, 0 if recovery hasn't started yet
+    pub total: u32,
+}
+
+impl ModuleRecoveryProgress {
+    /// Returns whether the module finished recovering.
+    pub fn is_done(&self) -> bool {
+        self.total != 0 && self.complete >= self.total
+    }
+
+    /// Returns the completion of the recovery in percent.
+    pub fn percent(&self) -> u8 {
+        if self.total == 0 {
+            return 0;
+        }
+        (u64::from(self.complete.min(self.total)) * 100 / u64::from(self.total)) as u8
+    }
+}
+
+/// Follows the recovery progress of `client` in the background, if any of
+/// its modules is recovering.
+pub(crate) async fn track(
+    client: &Arc<ClientHandle>,
+    task_group: &TaskGroup,
+) -> watch::Receiver<RecoveryProgress> {
+    let (sender, receiver) = watch::channel(RecoveryProgress::default());
+    if !client.has_pending_recoveries() {
+        return receiver;
+    }
+
+    let kinds = client
+        .config()
+        .await
+        .modules
+        .iter()
+        .map(|(module_id, config)| (*module_id, config.kind.to_string()))
+        .collect::<BTreeMap<_, _>>();
+    let client = client.clone();
+    task_group.spawn_cancellable("blitzi-recovery-progress", async move {
+        let mut updates = client.subscribe_to_recovery_progress();
+        while let Some((module_id, progress)) = updates.next().await {
+            let kind = kinds.get(&module_id).map_or("unknown", String::as_str);
+            sender.send_modify(|state| {
+                state.update(module_id, kind, progress.complete, progress.total);
+            });
+            if sender.borrow().is_done() && !client.has_pending_recoveries() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Yields the current progress of `receiver` and every update until the
+/// recovery is done.
+pub(crate) fn subscribe(
+    receiver: watch::Receiver<RecoveryProgress>,
+) -> BoxStream<'static, RecoveryProgress> {
+    Box::pin(futures_lite::stream::unfold(
+        (receiver, true),
+        |(mut receiver, first)| async move {
+            if !first {
+                if receiver.borrow().is_done() {
+                    return None;
+                }
+                receiver.changed().await.ok()?;
+            }
+            let progress = receiver.borrow_and_update().clone();
+            Some((progress, (receiver, false)))
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recovery_progress() {
+        let mut progress = RecoveryProgress::default();
+        assert!(progress.is_done());
+
+        progress.update(1, "mint", 0, 0);
+        progress.update(0, "ln", 5, 10);
+        assert!(!progress.is_done());
+        assert_eq!(progress.modules[0].kind, "ln");
+        assert_eq!(progress.modules[0].percent(), 50);
+        assert_eq!(progress.modules[1].percent(), 0);
+
+        progress.update(1, "mint", 3, 3);
+        progress.update(0, "ln", 10, 10);
+        assert_eq!(progress.modules.len(), 2);
+        assert_eq!(progress.modules[1].percent(), 100);
+        assert!(progress.is_done());
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let (sender, receiver) = watch::channel(RecoveryProgress::default());
+        sender.send_modify(|state| state.update(0, "mint", 1, 2));
+        let mut updates = subscribe(receiver);
+        assert_eq!(updates.next().await.unwrap().modules[0].complete, 1);
+
+        sender.send_modify(|state| state.update(0, "mint", 2, 2));
+        assert!(updates.next().await.unwrap().is_done());
+        assert!(updates.next().await.is_none());
+    }
+}
@@ -0,0 +1,77 @@
+//! Blitzi-level schema version stored next to the Fedimint client data, so
+//! databases written by incompatible versions are detected before the client
+//! is opened instead of failing with obscure decoding errors.
+use fedimint_core::db::{Database, IDatabaseTransactionOpsCore};
+
+use crate::error::IncompatibleDatabase;
+
+/// Current schema version, bump whenever Blitzi changes what it stores in a way
+/// older versions can't read.
+pub(crate) const SCHEMA_VERSION: u64 = 1;
+
+/// Key of the schema version, in the key range Fedimint reserves for external
+/// use (`0xb1..=0xcf`).
+const SCHEMA_VERSION_KEY: &[u8] = b"\xb1blitzi/schema_version";
+
+/// Checks that the database can be used by this version of Blitzi and records
+/// the current schema version in it.
+///
+/// # Errors
+/// Returns an [`IncompatibleDatabase`] error if the database was written by a
+/// newer, incompatible version of Blitzi.
+pub(crate) async fn check_schema_version(db: &Database) -> anyhow::Result<()> {
+    let mut dbtx = db.begin_transaction().await;
+
+    if let Some(bytes) = dbtx.raw_get_bytes(SCHEMA_VERSION_KEY).await? {
+        let found = u64::from_le_bytes(
+            bytes
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Invalid schema version in database"))?,
+        );
+        if found > SCHEMA_VERSION {
+            return Err(IncompatibleDatabase {
+                found,
+                supported: SCHEMA_VERSION,
+            }
+            .into());
+        }
+    }
+
+    dbtx.raw_insert_bytes(SCHEMA_VERSION_KEY, &SCHEMA_VERSION.to_le_bytes())
+        .await?;
+    dbtx.commit_tx_result().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use fedimint_core::db::IRawDatabaseExt;
+    use fedimint_core::db::mem_impl::MemDatabase;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_check_schema_version() {
+        let db = MemDatabase::new().into_database();
+
+        // Fresh and already marked databases are accepted
+        check_schema_version(&db).await.unwrap();
+        check_schema_version(&db).await.unwrap();
+
+        let mut dbtx = db.begin_transaction().await;
+        dbtx.raw_insert_bytes(SCHEMA_VERSION_KEY, &(SCHEMA_VERSION + 1).to_le_bytes())
+            .await
+            .unwrap();
+        dbtx.commit_tx().await;
+
+        let error = check_schema_version(&db).await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<IncompatibleDatabase>(),
+            Some(&IncompatibleDatabase {
+                found: SCHEMA_VERSION + 1,
+                supported: SCHEMA_VERSION,
+            })
+        );
+    }
+}
@@ -0,0 +1,265 @@
+//! Storage of the wallet's mnemonic in the client database, optionally
+//! encrypted with a passphrase, see
+//! [`BlitziBuilder::encryption_passphrase`](crate::BlitziBuilder::encryption_passphrase).
+//!
+//! Plaintext seeds are stored as the bare entropy of the mnemonic (16 bytes for
+//! 12 words). Encrypted seeds start with [`ENCRYPTED_MAGIC`], followed by the
+//! salt of the Argon2id key derivation, the XChaCha20-Poly1305 nonce and the
+//! encrypted entropy, so the two formats can't be confused.
+use anyhow::{anyhow, ensure};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use fedimint_bip39::Mnemonic;
+use fedimint_client::Client;
+use fedimint_client::db::EncodedClientSecretKey;
+use fedimint_core::db::{Database, IDatabaseTransactionOpsCoreTyped};
+use fedimint_core::encoding::Encodable;
+use tracing::warn;
+
+use crate::error::{PassphraseRequired, WrongPassphrase};
+
+/// Prefix of encrypted seeds, the version allows changing the format later.
+const ENCRYPTED_MAGIC: &[u8] = b"blitzi/encrypted-seed/v1";
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// Argon2id parameters of version 1 of the format. These are fixed instead of
+/// using the defaults of the `argon2` crate, which may change.
+const ARGON2_MEMORY_KIB: u32 = 19 * 1024;
+const ARGON2_ITERATIONS: u32 = 2;
+const ARGON2_PARALLELISM: u32 = 1;
+
+/// Loads the mnemonic stored in `db`, `None` if the wallet hasn't been
+/// initialized yet. Encrypted seeds are decrypted using `passphrase`.
+///
+/// # Errors
+/// Returns a [`PassphraseRequired`] error if the seed is encrypted but no
+/// passphrase is given, and a [`WrongPassphrase`] error if it can't be
+/// decrypted using `passphrase`.
+pub(crate) async fn load(
+    db: &Database,
+    passphrase: Option<&str>,
+) -> anyhow::Result<Option<Mnemonic>> {
+    let Some(stored) = Client::load_decodable_client_secret_opt::<Vec<u8>>(db).await? else {
+        return Ok(None);
+    };
+
+    let entropy = if is_encrypted(&stored) {
+        decrypt(&stored, passphrase.ok_or(PassphraseRequired)?)?
+    } else {
+        if passphrase.is_some() {
+            warn!(
+                "The wallet seed is stored unencrypted, use Blitzi::encrypt_existing_secret to \
+                 encrypt it"
+            );
+        }
+        stored
+    };
+    Ok(Some(Mnemonic::from_entropy(&entropy)?))
+}
+
+/// Generates a new mnemonic and stores it in `db`, encrypted using
+/// `passphrase` if one is given.
+pub(crate) async fn generate(db: &Database, passphrase: Option<&str>) -> anyhow::Result<Mnemonic> {
+    let mnemonic = Mnemonic::generate(12)?;
+    store(db, &mnemonic, passphrase).await?;
+    Ok(mnemonic)
+}
+
+/// Stores `mnemonic` in `db`, e.g. to restore a wallet, encrypted using
+/// `passphrase` if one is given.
+pub(crate) async fn store(
+    db: &Database,
+    mnemonic: &Mnemonic,
+    passphrase: Option<&str>,
+) -> anyhow::Result<()> {
+    let entropy = mnemonic.to_entropy();
+    let stored = match passphrase {
+        Some(passphrase) => encrypt(&entropy, passphrase)?,
+        None => entropy,
+    };
+    Client::store_encodable_client_secret(db, &stored).await?;
+    Ok(())
+}
+
+/// Encrypts the plaintext seed stored in `db` using `passphrase`.
+pub(crate) async fn encrypt_existing(db: &Database, passphrase: &str) -> anyhow::Result<()> {
+    let stored = load_stored(db).await?;
+    ensure!(
+        !is_encrypted(&stored),
+        "The wallet seed is already encrypted, use Blitzi::change_passphrase to change its \
+         passphrase"
+    );
+    overwrite(db, encrypt(&stored, passphrase)?).await
+}
+
+/// Re-encrypts the encrypted seed stored in `db` using `new`.
+///
+/// # Errors
+/// Returns a [`WrongPassphrase`] error if the seed can't be decrypted using
+/// `old`.
+pub(crate) async fn change_passphrase(db: &Database, old: &str, new: &str) -> anyhow::Result<()> {
+    let stored = load_stored(db).await?;
+    ensure!(
+        is_encrypted(&stored),
+        "The wallet seed isn't encrypted, use Blitzi::encrypt_existing_secret to encrypt it"
+    );
+    let entropy = decrypt(&stored, old)?;
+    overwrite(db, encrypt(&entropy, new)?).await
+}
+
+async fn load_stored(db: &Database) -> anyhow::Result<Vec<u8>> {
+    Client::load_decodable_client_secret_opt::<Vec<u8>>(db)
+        .await?
+        .ok_or_else(|| anyhow!("The wallet has no seed"))
+}
+
+/// Replaces the stored seed, which `Client::store_encodable_client_secret`
+/// refuses to do.
+async fn overwrite(db: &Database, stored: Vec<u8>) -> anyhow::Result<()> {
+    let mut dbtx = db.begin_transaction().await;
+    dbtx.insert_entry(&EncodedClientSecretKey, &stored.consensus_encode_to_vec())
+        .await;
+    dbtx.commit_tx_result().await?;
+    Ok(())
+}
+
+fn is_encrypted(stored: &[u8]) -> bool {
+    stored.starts_with(ENCRYPTED_MAGIC)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> anyhow::Result<Key> {
+    let params = Params::new(
+        ARGON2_MEMORY_KIB,
+        ARGON2_ITERATIONS,
+        ARGON2_PARALLELISM,
+        Some(32),
+    )
+    .map_err(|e| anyhow!("Invalid key derivation parameters: {}", e))?;
+
+    let mut key = Key::default();
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow!("Failed to derive the encryption key: {}", e))?;
+    Ok(key)
+}
+
+fn encrypt(entropy: &[u8], passphrase: &str) -> anyhow::Result<Vec<u8>> {
+    let mut salt = [0; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let ciphertext = XChaCha20Poly1305::new(&derive_key(passphrase, &salt)?)
+        .encrypt(&nonce, entropy)
+        .map_err(|_| anyhow!("Failed to encrypt the wallet seed"))?;
+    Ok([ENCRYPTED_MAGIC, &salt, &nonce, &ciphertext].concat())
+}
+
+fn decrypt(stored: &[u8], passphrase: &str) -> anyhow::Result<Vec<u8>> {
+    let data = &stored[ENCRYPTED_MAGIC.len()..];
+    ensure!(
+        data.len() > SALT_LEN + NONCE_LEN,
+        "The encrypted wallet seed is truncated"
+    );
+    let (salt, data) = data.split_at(SALT_LEN);
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+
+    // Authentication fails if the passphrase is wrong
+    Ok(XChaCha20Poly1305::new(&derive_key(passphrase, salt)?)
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| WrongPassphrase)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use fedimint_core::db::IRawDatabaseExt;
+    use fedimint_core::db::mem_impl::MemDatabase;
+
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let entropy = [7; 16];
+        let stored = encrypt(&entropy, "correct horse").unwrap();
+        assert!(is_encrypted(&stored));
+        assert!(!is_encrypted(&entropy));
+        // Fresh salt and nonce every time
+        assert_ne!(stored, encrypt(&entropy, "correct horse").unwrap());
+
+        assert_eq!(decrypt(&stored, "correct horse").unwrap(), entropy);
+        assert!(
+            decrypt(&stored, "battery staple")
+                .unwrap_err()
+                .is::<WrongPassphrase>()
+        );
+        assert!(decrypt(&stored[..ENCRYPTED_MAGIC.len() + 8], "correct horse").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_seed() {
+        let db = MemDatabase::new().into_database();
+        assert!(load(&db, Some("secret")).await.unwrap().is_none());
+
+        let mnemonic = generate(&db, Some("secret")).await.unwrap();
+        assert_eq!(
+            load(&db, Some("secret")).await.unwrap(),
+            Some(mnemonic.clone())
+        );
+        assert!(
+            load(&db, None)
+                .await
+                .unwrap_err()
+                .is::<PassphraseRequired>()
+        );
+        assert!(
+            load(&db, Some("wrong"))
+                .await
+                .unwrap_err()
+                .is::<WrongPassphrase>()
+        );
+        assert!(encrypt_existing(&db, "other").await.is_err());
+
+        assert!(
+            change_passphrase(&db, "wrong", "new")
+                .await
+                .unwrap_err()
+                .is::<WrongPassphrase>()
+        );
+        change_passphrase(&db, "secret", "new").await.unwrap();
+        assert!(load(&db, Some("secret")).await.is_err());
+        assert_eq!(load(&db, Some("new")).await.unwrap(), Some(mnemonic));
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_existing_seed() {
+        let db = MemDatabase::new().into_database();
+        let mnemonic = generate(&db, None).await.unwrap();
+        assert_eq!(load(&db, None).await.unwrap(), Some(mnemonic.clone()));
+        assert!(change_passphrase(&db, "", "new").await.is_err());
+
+        encrypt_existing(&db, "secret").await.unwrap();
+        assert!(
+            load(&db, None)
+                .await
+                .unwrap_err()
+                .is::<PassphraseRequired>()
+        );
+        assert_eq!(load(&db, Some("secret")).await.unwrap(), Some(mnemonic));
+    }
+
+    #[tokio::test]
+    async fn test_store_seed() {
+        let mnemonic = Mnemonic::generate(12).unwrap();
+
+        let db = MemDatabase::new().into_database();
+        store(&db, &mnemonic, None).await.unwrap();
+        assert_eq!(load(&db, None).await.unwrap(), Some(mnemonic.clone()));
+
+        let db = MemDatabase::new().into_database();
+        store(&db, &mnemonic, Some("secret")).await.unwrap();
+        assert_eq!(load(&db, Some("secret")).await.unwrap(), Some(mnemonic));
+    }
+}
@@ -0,0 +1,124 @@
+//! Serde helpers for the public result types, pinning their wire format to
+//! hex strings for hashes and ids and integers for amounts and timestamps.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fedimint_core::core::OperationId;
+use lightning_invoice::Bolt11Invoice;
+use serde::{Deserialize, Deserializer, Serializer};
+
+/// Implements `Serialize` and `Deserialize` for a type using its `Display` and
+/// `FromStr` implementations.
+macro_rules! impl_serde_via_string {
+    ($ty:ty) => {
+        impl serde::Serialize for $ty {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $ty {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+                s.parse().map_err(serde::de::Error::custom)
+            }
+        }
+    };
+}
+
+pub(crate) use impl_serde_via_string;
+
+/// (De)serializes a [`SystemTime`] as seconds since the unix epoch, sub-second
+/// precision is dropped.
+pub(crate) mod unix_secs {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        serializer.serialize_u64(secs)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+        Ok(UNIX_EPOCH + Duration::from_secs(u64::deserialize(deserializer)?))
+    }
+}
+
+/// (De)serializes an optional [`SystemTime`] like [`unix_secs`], `None` as
+/// `null`.
+pub(crate) mod unix_secs_option {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        time: &Option<SystemTime>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(time) => unix_secs::serialize(time, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<SystemTime>, D::Error> {
+        Ok(Option::<u64>::deserialize(deserializer)?
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)))
+    }
+}
+
+/// (De)serializes a [`Duration`] as whole seconds.
+pub(crate) mod duration_secs {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_secs())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_secs(u64::deserialize(deserializer)?))
+    }
+}
+
+/// (De)serializes an [`OperationId`] as hex string.
+pub(crate) mod operation_id_hex {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        operation_id: &OperationId,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(operation_id.0))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<OperationId, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        let bytes = hex::decode(s).map_err(serde::de::Error::custom)?;
+        let bytes = bytes
+            .try_into()
+            .map_err(|_| serde::de::Error::custom("Operation id must be 32 bytes"))?;
+        Ok(OperationId(bytes))
+    }
+}
+
+/// (De)serializes a [`Bolt11Invoice`] as its bech32 string.
+pub(crate) mod bolt11_string {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        invoice: &Bolt11Invoice,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(invoice)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Bolt11Invoice, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
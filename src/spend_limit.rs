@@ -0,0 +1,300 @@
+//! Limits on outgoing payments configured via
+//! [`BlitziBuilder::spend_limit`](crate::BlitziBuilder::spend_limit). Payments
+//! counting towards the rolling window are recorded in the client database, so
+//! restarting the client doesn't reset it.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fedimint_core::Amount;
+use fedimint_core::core::OperationId;
+use fedimint_core::db::{Database, IDatabaseTransactionOpsCore};
+use futures_lite::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::error::SpendLimitExceeded;
+use crate::serde_util::unix_secs;
+
+/// Prefix of the recorded payments, in the key range Fedimint reserves for
+/// external use (`0xb1..=0xcf`).
+const SPEND_PREFIX: &[u8] = b"\xb1blitzi/spend/";
+
+/// Limits on outgoing payments, enforced before a payment is started. Both
+/// limits are disabled by default.
+///
+/// ```
+/// # use std::time::Duration;
+/// use blitzi::{SpendLimit, SpendWindow, sats};
+///
+/// // At most 10k sats per payment and 100k sats per 24 hours
+/// let limit = SpendLimit {
+///     per_payment: Some(sats(10_000)),
+///     window: Some(SpendWindow {
+///         max: sats(100_000),
+///         duration: Duration::from_secs(24 * 60 * 60),
+///     }),
+/// };
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpendLimit {
+    /// Maximum amount of a single payment
+    pub per_payment: Option<Amount>,
+    /// Maximum total amount of payments within a rolling window
+    pub window: Option<SpendWindow>,
+}
+
+/// Rolling window of a [`SpendLimit`]. Payments count towards the window for
+/// `duration` after they were started, unless they fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpendWindow {
+    /// Maximum total amount of payments within the window
+    pub max: Amount,
+    /// Length of the window
+    pub duration: Duration,
+}
+
+/// A payment counting towards the window.
+#[derive(Serialize, Deserialize)]
+struct SpendEntry {
+    #[serde(with = "unix_secs")]
+    created_at: SystemTime,
+    #[serde(rename = "amount_msats")]
+    amount: Amount,
+}
+
+/// Enforces a [`SpendLimit`] for a client.
+#[derive(Debug)]
+pub(crate) struct SpendLimiter {
+    limit: SpendLimit,
+    /// Serializes checks so concurrent payments can't both use up the
+    /// remaining window
+    lock: tokio::sync::Mutex<()>,
+}
+
+impl SpendLimiter {
+    pub(crate) fn new(limit: SpendLimit) -> Self {
+        SpendLimiter {
+            limit,
+            lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub(crate) fn limit(&self) -> SpendLimit {
+        self.limit
+    }
+
+    /// Checks that paying `amount` doesn't exceed the limit and records the
+    /// payment under `operation_id` so it counts towards the window.
+    ///
+    /// # Errors
+    /// Returns a [`SpendLimitExceeded`] error if the payment would exceed the
+    /// limit.
+    pub(crate) async fn reserve(
+        &self,
+        db: &Database,
+        operation_id: OperationId,
+        amount: Amount,
+    ) -> anyhow::Result<()> {
+        self.reserve_at(db, operation_id, amount, fedimint_core::time::now())
+            .await
+    }
+
+    async fn reserve_at(
+        &self,
+        db: &Database,
+        operation_id: OperationId,
+        amount: Amount,
+        now: SystemTime,
+    ) -> anyhow::Result<()> {
+        let _guard = self.lock.lock().await;
+        let mut dbtx = db.begin_transaction().await;
+
+        let mut spent = Amount::ZERO;
+        if let Some(window) = self.limit.window {
+            let window_start = now.checked_sub(window.duration).unwrap_or(UNIX_EPOCH);
+            let entries = dbtx
+                .raw_find_by_prefix(SPEND_PREFIX)
+                .await?
+                .collect::<Vec<_>>()
+                .await;
+            for (key, value) in entries {
+                let entry: SpendEntry = serde_json::from_slice(&value)?;
+                if entry.created_at <= window_start {
+                    dbtx.raw_remove_entry(&key).await?;
+                } else {
+                    spent += entry.amount;
+                }
+            }
+        }
+        let window_remaining = self
+            .limit
+            .window
+            .map(|window| Amount::from_msats(window.max.msats.saturating_sub(spent.msats)));
+
+        if let Some(max) = self.limit.per_payment {
+            if amount > max {
+                return Err(SpendLimitExceeded {
+                    limit: max,
+                    attempted: amount,
+                    window_remaining,
+                }
+                .into());
+            }
+        }
+
+        if let Some(window) = self.limit.window {
+            if spent + amount > window.max {
+                return Err(SpendLimitExceeded {
+                    limit: window.max,
+                    attempted: amount,
+                    window_remaining,
+                }
+                .into());
+            }
+
+            let entry = SpendEntry {
+                created_at: now,
+                amount,
+            };
+            dbtx.raw_insert_bytes(&entry_key(operation_id), &serde_json::to_vec(&entry)?)
+                .await?;
+        }
+
+        dbtx.commit_tx_result().await?;
+        Ok(())
+    }
+}
+
+/// Removes a failed payment from the window so its amount can be spent again.
+pub(crate) async fn credit(db: &Database, operation_id: OperationId) -> anyhow::Result<()> {
+    let mut dbtx = db.begin_transaction().await;
+    dbtx.raw_remove_entry(&entry_key(operation_id)).await?;
+    dbtx.commit_tx_result().await?;
+    Ok(())
+}
+
+/// Returns the operation ids of all payments currently counting towards the
+/// window.
+pub(crate) async fn recorded_payments(db: &Database) -> anyhow::Result<Vec<OperationId>> {
+    let mut dbtx = db.begin_transaction_nc().await;
+    let entries = dbtx
+        .raw_find_by_prefix(SPEND_PREFIX)
+        .await?
+        .collect::<Vec<_>>()
+        .await;
+
+    Ok(entries
+        .into_iter()
+        .filter_map(|(key, _)| Some(OperationId(key[SPEND_PREFIX.len()..].try_into().ok()?)))
+        .collect())
+}
+
+fn entry_key(operation_id: OperationId) -> Vec<u8> {
+    [SPEND_PREFIX, &operation_id.0].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use fedimint_core::db::IRawDatabaseExt;
+    use fedimint_core::db::mem_impl::MemDatabase;
+
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    fn limiter() -> SpendLimiter {
+        SpendLimiter::new(SpendLimit {
+            per_payment: Some(Amount::from_msats(800)),
+            window: Some(SpendWindow {
+                max: Amount::from_msats(1000),
+                duration: HOUR,
+            }),
+        })
+    }
+
+    fn exceeded(error: anyhow::Error) -> SpendLimitExceeded {
+        *error.downcast_ref::<SpendLimitExceeded>().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_per_payment_limit() {
+        let db = MemDatabase::new().into_database();
+        let limiter = limiter();
+
+        let error = limiter
+            .reserve(&db, OperationId([1; 32]), Amount::from_msats(900))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            exceeded(error),
+            SpendLimitExceeded {
+                limit: Amount::from_msats(800),
+                attempted: Amount::from_msats(900),
+                window_remaining: Some(Amount::from_msats(1000)),
+            }
+        );
+        assert!(recorded_payments(&db).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_window_rollover() {
+        let db = MemDatabase::new().into_database();
+        let limiter = limiter();
+        let start = UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        limiter
+            .reserve_at(&db, OperationId([1; 32]), Amount::from_msats(600), start)
+            .await
+            .unwrap();
+        let error = limiter
+            .reserve_at(
+                &db,
+                OperationId([2; 32]),
+                Amount::from_msats(600),
+                start + HOUR / 2,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            exceeded(error).window_remaining,
+            Some(Amount::from_msats(400))
+        );
+
+        // Once the first payment left the window the budget is available again
+        limiter
+            .reserve_at(
+                &db,
+                OperationId([2; 32]),
+                Amount::from_msats(600),
+                start + HOUR + Duration::from_secs(1),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            recorded_payments(&db).await.unwrap(),
+            vec![OperationId([2; 32])]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refund_credit() {
+        let db = MemDatabase::new().into_database();
+        let limiter = limiter();
+
+        limiter
+            .reserve(&db, OperationId([1; 32]), Amount::from_msats(600))
+            .await
+            .unwrap();
+        assert!(
+            limiter
+                .reserve(&db, OperationId([2; 32]), Amount::from_msats(600))
+                .await
+                .is_err()
+        );
+
+        // A failed payment no longer counts towards the window
+        credit(&db, OperationId([1; 32])).await.unwrap();
+        limiter
+            .reserve(&db, OperationId([2; 32]), Amount::from_msats(600))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,499 @@
+//! Wallet statistics returned by
+//! [`Blitzi::wallet_stats`](crate::Blitzi::wallet_stats), payment
+//! statistics returned by [`Blitzi::stats`](crate::Blitzi::stats) and fee
+//! reports returned by [`Blitzi::fee_report`](crate::Blitzi::fee_report).
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fedimint_client::Client;
+use fedimint_client::db::ChronologicalOperationLogKey;
+use fedimint_core::Amount;
+use fedimint_core::core::OperationId;
+use serde::{Deserialize, Serialize};
+
+use crate::serde_util::unix_secs;
+use crate::{HistoryEntry, HistoryEntryKind, HistoryEntryStatus};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Statistics about the wallet, e.g. for diagnostics or to decide when to
+/// [`consolidate`](crate::Blitzi::consolidate) notes.
+///
+/// Serialized with the balance as `balance_msats` and the note counts as a
+/// list of `{"denomination_msats": ..., "count": ...}` objects.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WalletStats {
+    /// Total balance held by the wallet
+    #[serde(rename = "balance_msats")]
+    pub balance: Amount,
+    /// Total number of ecash notes held by the wallet
+    pub note_count: usize,
+    /// Number of ecash notes per denomination, ordered by denomination
+    #[serde(with = "denomination_counts")]
+    pub notes_by_denomination: Vec<(Amount, usize)>,
+    /// Number of operations in the operation log whose outcome hasn't been
+    /// observed yet
+    pub pending_operations: usize,
+}
+
+/// Statistics about the Lightning payments started within a time range, e.g.
+/// for monthly reports.
+///
+/// Only finished payments are counted. Amounts are those of the paid
+/// invoices, canceled invoices are ignored and failed outgoing payments, whose
+/// funds were refunded, only count as [`Self::payments_failed`].
+///
+/// Serialized with the amounts as `received_msats`, `sent_msats` and
+/// `fees_msats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeriodStats {
+    /// Total amount of incoming payments
+    #[serde(rename = "received_msats")]
+    pub received: Amount,
+    /// Total amount of successful outgoing payments, excluding fees
+    #[serde(rename = "sent_msats")]
+    pub sent: Amount,
+    /// Total gateway fees paid for successful outgoing payments
+    #[serde(rename = "fees_msats")]
+    pub fees: Amount,
+    /// Number of incoming payments
+    pub payments_received: u64,
+    /// Number of successful outgoing payments
+    pub payments_sent: u64,
+    /// Number of failed outgoing payments
+    pub payments_failed: u64,
+    /// Average fee paid for outgoing payments in parts per million of the
+    /// amount sent, `None` if nothing was sent
+    pub average_fee_ppm: Option<u64>,
+}
+
+impl PeriodStats {
+    /// Adds an entry of the operation history to the statistics.
+    pub(crate) fn add(&mut self, entry: &HistoryEntry) {
+        let amount = entry.amount.unwrap_or(Amount::ZERO);
+        match (entry.kind, entry.status) {
+            (HistoryEntryKind::Receive, HistoryEntryStatus::Succeeded) => {
+                self.received += amount;
+                self.payments_received += 1;
+            }
+            (HistoryEntryKind::Pay, HistoryEntryStatus::Succeeded) => {
+                self.sent += amount;
+                self.fees += entry.fee.unwrap_or(Amount::ZERO);
+                self.payments_sent += 1;
+            }
+            // The funds of failed payments were refunded, only the attempt counts
+            (HistoryEntryKind::Pay, HistoryEntryStatus::Failed) => {
+                self.payments_failed += 1;
+            }
+            _ => {}
+        }
+
+        self.average_fee_ppm = (self.sent.msats != 0).then(|| {
+            (u128::from(self.fees.msats) * 1_000_000 / u128::from(self.sent.msats)) as u64
+        });
+    }
+}
+
+/// Length of the periods of a [fee report](crate::Blitzi::fee_report).
+/// Periods start at midnight UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Bucket {
+    /// One calendar day
+    Day,
+    /// One calendar month
+    Month,
+}
+
+impl Bucket {
+    /// Returns the start of the period containing `time`.
+    fn start(self, time: SystemTime) -> SystemTime {
+        let days = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / SECS_PER_DAY;
+        let days = match self {
+            Bucket::Day => days,
+            Bucket::Month => {
+                let (year, month, _) = civil_from_days(days);
+                days_from_civil(year, month, 1)
+            }
+        };
+        UNIX_EPOCH + Duration::from_secs(days * SECS_PER_DAY)
+    }
+}
+
+/// Gateway fees paid for the outgoing payments started within one period of
+/// a [fee report](crate::Blitzi::fee_report).
+///
+/// Serialized with the period start as unix timestamp and the amounts as
+/// `sent_msats` and `fees_msats`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeeBucket {
+    /// Start of the period
+    #[serde(with = "unix_secs")]
+    pub start: SystemTime,
+    /// Number of successful outgoing payments, including internal payments
+    /// to other users of the federation, which don't pay gateway fees
+    pub payments: u64,
+    /// Total amount of the payments, excluding fees
+    #[serde(rename = "sent_msats")]
+    pub sent: Amount,
+    /// Total gateway fees paid for the payments
+    #[serde(rename = "fees_msats")]
+    pub fees: Amount,
+}
+
+/// Sums up the fees of the successful outgoing payments among `entries` per
+/// `bucket`, ordered by period. Periods without payments are omitted.
+pub(crate) fn fee_buckets(
+    entries: impl IntoIterator<Item = HistoryEntry>,
+    bucket: Bucket,
+) -> Vec<FeeBucket> {
+    let mut buckets = std::collections::BTreeMap::<SystemTime, FeeBucket>::new();
+    for entry in entries {
+        if entry.kind != HistoryEntryKind::Pay || entry.status != HistoryEntryStatus::Succeeded {
+            continue;
+        }
+        let start = bucket.start(entry.timestamp);
+        let fee_bucket = buckets.entry(start).or_insert(FeeBucket {
+            start,
+            payments: 0,
+            sent: Amount::ZERO,
+            fees: Amount::ZERO,
+        });
+        fee_bucket.payments += 1;
+        fee_bucket.sent += entry.amount.unwrap_or(Amount::ZERO);
+        fee_bucket.fees += entry.fee.unwrap_or(Amount::ZERO);
+    }
+    buckets.into_values().collect()
+}
+
+/// Converts days since the unix epoch into a `(year, month, day)` date of the
+/// proleptic Gregorian calendar.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // See https://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Converts a date of the proleptic Gregorian calendar into days since the
+/// unix epoch, the inverse of [`civil_from_days`].
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year % 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Computes the [`PeriodStats`] of the operations started in `since..until`,
+/// paging through the operation log instead of loading it at once.
+pub(crate) async fn period_stats(
+    client: &Client,
+    since: SystemTime,
+    until: SystemTime,
+) -> PeriodStats {
+    let mut stats = PeriodStats::default();
+    for_each_entry(client, since, until, |entry| stats.add(&entry)).await;
+    stats
+}
+
+/// Computes the [`FeeBucket`]s of the operations started in `since..until`.
+pub(crate) async fn fee_report(
+    client: &Client,
+    bucket: Bucket,
+    since: SystemTime,
+    until: SystemTime,
+) -> Vec<FeeBucket> {
+    let mut entries = Vec::new();
+    for_each_entry(client, since, until, |entry| {
+        if entry.kind == HistoryEntryKind::Pay {
+            entries.push(entry);
+        }
+    })
+    .await;
+    fee_buckets(entries, bucket)
+}
+
+/// Calls `f` for the history entry of every operation started in
+/// `since..until`, paging through the operation log instead of loading it at
+/// once.
+async fn for_each_entry(
+    client: &Client,
+    since: SystemTime,
+    until: SystemTime,
+    mut f: impl FnMut(HistoryEntry),
+) {
+    const PAGE_SIZE: usize = 100;
+
+    let mut before = Some(ChronologicalOperationLogKey {
+        creation_time: until,
+        operation_id: OperationId([0; 32]),
+    });
+    loop {
+        let page = client
+            .operation_log()
+            .paginate_operations_rev(PAGE_SIZE, before)
+            .await;
+
+        for (key, operation) in &page {
+            if key.creation_time < since {
+                return;
+            }
+            if let Some(entry) = HistoryEntry::from_operation(*key, operation) {
+                f(entry);
+            }
+        }
+
+        match page.last() {
+            Some((key, _)) if page.len() == PAGE_SIZE => before = Some(*key),
+            _ => return,
+        }
+    }
+}
+
+mod denomination_counts {
+    use fedimint_core::Amount;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    struct DenominationCount {
+        denomination_msats: Amount,
+        count: usize,
+    }
+
+    pub fn serialize<S: Serializer>(
+        counts: &[(Amount, usize)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(counts.iter().map(|&(denomination_msats, count)| {
+            DenominationCount {
+                denomination_msats,
+                count,
+            }
+        }))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(Amount, usize)>, D::Error> {
+        Ok(Vec::<DenominationCount>::deserialize(deserializer)?
+            .into_iter()
+            .map(|count| (count.denomination_msats, count.count))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wallet_stats_serde() {
+        let stats = WalletStats {
+            balance: Amount::from_msats(3072),
+            note_count: 2,
+            notes_by_denomination: vec![
+                (Amount::from_msats(1024), 1),
+                (Amount::from_msats(2048), 1),
+            ],
+            pending_operations: 1,
+        };
+
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "balance_msats": 3072,
+                "note_count": 2,
+                "notes_by_denomination": [
+                    { "denomination_msats": 1024, "count": 1 },
+                    { "denomination_msats": 2048, "count": 1 },
+                ],
+                "pending_operations": 1,
+            })
+        );
+        assert_eq!(serde_json::from_value::<WalletStats>(json).unwrap(), stats);
+    }
+
+    fn entry(
+        kind: HistoryEntryKind,
+        status: HistoryEntryStatus,
+        amount: u64,
+        fee: Option<u64>,
+    ) -> HistoryEntry {
+        let key = ChronologicalOperationLogKey {
+            creation_time: SystemTime::UNIX_EPOCH,
+            operation_id: OperationId([1; 32]),
+        };
+        HistoryEntry {
+            cursor: crate::OperationCursor(key),
+            operation_id: key.operation_id,
+            timestamp: key.creation_time,
+            settled_at: None,
+            kind,
+            amount: Some(Amount::from_msats(amount)),
+            fee: fee.map(Amount::from_msats),
+            status,
+            payment_hash: None,
+            description: None,
+            external_id: None,
+        }
+    }
+
+    #[test]
+    fn test_civil_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        // 2024-02-29
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(days_from_civil(2024, 2, 29), 19_782);
+        for days in (0..100_000).step_by(17) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn test_fee_buckets() {
+        let at = |days: u64, hours: u64, amount: u64, fee: Option<u64>| HistoryEntry {
+            timestamp: UNIX_EPOCH + Duration::from_secs(days * SECS_PER_DAY + hours * 60 * 60),
+            ..entry(
+                HistoryEntryKind::Pay,
+                HistoryEntryStatus::Succeeded,
+                amount,
+                fee,
+            )
+        };
+        // 2024-01-31 and 2024-02-01
+        let jan_31 = days_from_civil(2024, 1, 31);
+        let feb_1 = jan_31 + 1;
+        let entries = vec![
+            at(jan_31, 1, 10_000, Some(20)),
+            at(jan_31, 23, 30_000, Some(60)),
+            // Internal payment without gateway fee
+            at(feb_1, 0, 5_000, None),
+            at(feb_1, 12, 1_000, Some(10)),
+            HistoryEntry {
+                timestamp: UNIX_EPOCH + Duration::from_secs(feb_1 * SECS_PER_DAY),
+                ..entry(
+                    HistoryEntryKind::Pay,
+                    HistoryEntryStatus::Failed,
+                    50_000,
+                    Some(100),
+                )
+            },
+        ];
+        let day = |days: u64| UNIX_EPOCH + Duration::from_secs(days * SECS_PER_DAY);
+
+        assert_eq!(
+            fee_buckets(entries.clone(), Bucket::Day),
+            vec![
+                FeeBucket {
+                    start: day(jan_31),
+                    payments: 2,
+                    sent: Amount::from_msats(40_000),
+                    fees: Amount::from_msats(80),
+                },
+                FeeBucket {
+                    start: day(feb_1),
+                    payments: 2,
+                    sent: Amount::from_msats(6_000),
+                    fees: Amount::from_msats(10),
+                },
+            ]
+        );
+
+        let buckets = fee_buckets(entries, Bucket::Month);
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0].start, day(days_from_civil(2024, 1, 1)));
+        assert_eq!(buckets[0].fees, Amount::from_msats(80));
+        assert_eq!(buckets[1].start, day(feb_1));
+        assert_eq!(buckets[1].payments, 2);
+
+        assert_eq!(
+            serde_json::to_value(&buckets[1]).unwrap(),
+            serde_json::json!({
+                "start": feb_1 * SECS_PER_DAY,
+                "payments": 2,
+                "sent_msats": 6_000,
+                "fees_msats": 10,
+            })
+        );
+    }
+
+    #[test]
+    fn test_period_stats() {
+        let mut stats = PeriodStats::default();
+        for entry in [
+            entry(
+                HistoryEntryKind::Receive,
+                HistoryEntryStatus::Succeeded,
+                5000,
+                None,
+            ),
+            entry(
+                HistoryEntryKind::Receive,
+                HistoryEntryStatus::Failed,
+                7000,
+                None,
+            ),
+            entry(
+                HistoryEntryKind::Pay,
+                HistoryEntryStatus::Succeeded,
+                10_000,
+                Some(20),
+            ),
+            entry(
+                HistoryEntryKind::Pay,
+                HistoryEntryStatus::Succeeded,
+                30_000,
+                Some(60),
+            ),
+            // Refunded payment
+            entry(
+                HistoryEntryKind::Pay,
+                HistoryEntryStatus::Failed,
+                50_000,
+                Some(100),
+            ),
+            entry(
+                HistoryEntryKind::Pay,
+                HistoryEntryStatus::Pending,
+                1000,
+                Some(10),
+            ),
+            entry(
+                HistoryEntryKind::Ecash,
+                HistoryEntryStatus::Succeeded,
+                1000,
+                None,
+            ),
+        ] {
+            stats.add(&entry);
+        }
+
+        assert_eq!(
+            stats,
+            PeriodStats {
+                received: Amount::from_msats(5000),
+                sent: Amount::from_msats(40_000),
+                fees: Amount::from_msats(80),
+                payments_received: 1,
+                payments_sent: 2,
+                payments_failed: 1,
+                average_fee_ppm: Some(2000),
+            }
+        );
+    }
+}
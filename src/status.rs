@@ -0,0 +1,67 @@
+//! Status of the wallet and its connection to the federation returned by
+//! [`Blitzi::status`](crate::Blitzi::status).
+use std::time::Duration;
+
+use fedimint_client::Client;
+use fedimint_core::Amount;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+/// How long a guardian may take to answer before it's considered unreachable.
+pub(crate) const GUARDIAN_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Status of the wallet, cheap enough to be polled e.g. by a metrics exporter.
+/// Unlike [`WalletStats`](crate::WalletStats) it doesn't read the operation
+/// log.
+///
+/// Serialized with the balance as `balance_msats`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WalletStatus {
+    /// Current balance of the wallet
+    #[serde(rename = "balance_msats")]
+    pub balance: Amount,
+    /// Number of invoices that are neither paid nor expired or canceled yet
+    pub pending_invoices: usize,
+    /// Number of guardians of the federation
+    pub guardians: usize,
+    /// Number of guardians that served the federation's config within five
+    /// seconds
+    pub reachable_guardians: usize,
+    /// Number of Lightning gateways registered with the federation
+    pub gateways: usize,
+}
+
+/// Counts the guardians of the federation `client` joined that serve its
+/// config within [`GUARDIAN_PROBE_TIMEOUT`], asking all of them at once.
+pub(crate) async fn reachable_guardians(client: &Client) -> usize {
+    let peers = client
+        .config()
+        .await
+        .global
+        .api_endpoints
+        .keys()
+        .copied()
+        .collect::<Vec<_>>();
+    let probes = peers.into_iter().map(|peer| async move {
+        let Some(invite) = client.invite_code(peer).await else {
+            return false;
+        };
+        let probe = async { crate::client_builder().await?.preview(&invite).await };
+        match fedimint_core::runtime::timeout(GUARDIAN_PROBE_TIMEOUT, probe).await {
+            Ok(Ok(_)) => true,
+            Ok(Err(e)) => {
+                debug!(%peer, error = %e, "Guardian is unreachable");
+                false
+            }
+            Err(_) => {
+                debug!(%peer, "Guardian didn't answer in time");
+                false
+            }
+        }
+    });
+    futures_util::future::join_all(probes)
+        .await
+        .into_iter()
+        .filter(|reachable| *reachable)
+        .count()
+}
@@ -0,0 +1,105 @@
+//! Helpers for integration tests against a local test federation, enabled by
+//! the `devimint-tests` feature.
+//!
+//! The helpers expect to run inside a [devimint] environment (e.g. started
+//! using `devimint dev-fed`), which exports the federation's invite code as
+//! `FM_INVITE_CODE` and a command to control its LND node as `FM_LNCLI`.
+//!
+//! [devimint]: https://github.com/fedimint/fedimint/tree/master/devimint
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{Context, ensure};
+use fedimint_core::Amount;
+use fedimint_core::invite_code::InviteCode;
+use lightning_invoice::Bolt11Invoice;
+use tokio::process::Command;
+
+use crate::{Blitzi, LightningVersion};
+
+/// Returns the invite code of the local test federation.
+///
+/// # Errors
+/// Returns an error if `FM_INVITE_CODE` isn't set or can't be parsed.
+pub fn test_federation() -> anyhow::Result<InviteCode> {
+    let invite = std::env::var("FM_INVITE_CODE")
+        .context("FM_INVITE_CODE not set, are you running inside devimint?")?;
+    InviteCode::from_str(&invite).context("Invalid FM_INVITE_CODE")
+}
+
+/// Returns a fresh temporary data directory.
+pub fn temp_datadir() -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "blitzi-test-{}",
+        hex::encode(rand::random::<[u8; 8]>())
+    ))
+}
+
+/// Builds a new, empty Blitzi client connected to the test federation using a
+/// temporary data directory.
+pub async fn test_client() -> anyhow::Result<Blitzi> {
+    Blitzi::builder()
+        .datadir(temp_datadir())
+        .federation_invite(test_federation()?)
+        .build()
+        .await
+}
+
+/// Builds a new, empty Blitzi client like [`test_client`] that prefers the
+/// given Lightning module, e.g. to test features only the `ln` module supports.
+pub async fn test_client_with_version(version: LightningVersion) -> anyhow::Result<Blitzi> {
+    Blitzi::builder()
+        .datadir(temp_datadir())
+        .federation_invite(test_federation()?)
+        .preferred_lightning_version(version)
+        .build()
+        .await
+}
+
+/// Builds a new Blitzi client connected to the test federation and funds it
+/// with `amount` by paying one of its invoices from the devimint LND node.
+pub async fn funded_client(amount: impl Into<Amount>) -> anyhow::Result<Blitzi> {
+    let blitzi = test_client().await?;
+
+    let invoice = blitzi
+        .lightning_invoice(amount, "Funding test client")
+        .await?;
+    pay_with_lnd(&invoice).await?;
+    blitzi.await_incoming_payment(&invoice).await?;
+
+    Ok(blitzi)
+}
+
+/// Pays `invoice` from the devimint LND node.
+pub async fn pay_with_lnd(invoice: &Bolt11Invoice) -> anyhow::Result<()> {
+    lncli(&["payinvoice", "--force", &invoice.to_string()]).await?;
+    Ok(())
+}
+
+/// Creates an invoice for `amount` on the devimint LND node.
+pub async fn lnd_invoice(amount: impl Into<Amount>) -> anyhow::Result<Bolt11Invoice> {
+    let response = lncli(&["addinvoice", "--amt_msat", &amount.into().msats.to_string()]).await?;
+    let invoice = response["payment_request"]
+        .as_str()
+        .context("LND response is missing payment_request")?;
+    Bolt11Invoice::from_str(invoice).map_err(|e| anyhow::anyhow!("Invalid LND invoice: {}", e))
+}
+
+async fn lncli(args: &[&str]) -> anyhow::Result<serde_json::Value> {
+    let lncli =
+        std::env::var("FM_LNCLI").context("FM_LNCLI not set, are you running inside devimint?")?;
+
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} {}", lncli, args.join(" ")))
+        .output()
+        .await
+        .context("Failed to run lncli")?;
+    ensure!(
+        output.status.success(),
+        "lncli failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    serde_json::from_slice(&output.stdout).context("lncli returned invalid JSON")
+}
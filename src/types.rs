@@ -0,0 +1,167 @@
+//! Strongly typed wrappers around the 32 byte values used by Lightning
+//! payments, so preimages and payment hashes can't be mixed up.
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::Context;
+use fedimint_core::BitcoinHash;
+use fedimint_core::bitcoin::hashes::sha256;
+
+use crate::serde_util::impl_serde_via_string;
+
+/// Preimage of a Lightning payment, acts as proof of payment since its hash
+/// is the [`PaymentHash`] of the invoice that was paid.
+///
+/// Displayed, parsed and serialized as hex.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Preimage(pub [u8; 32]);
+
+impl Preimage {
+    /// Returns the payment hash belonging to this preimage.
+    pub fn payment_hash(&self) -> PaymentHash {
+        PaymentHash(sha256::Hash::hash(&self.0))
+    }
+
+    /// Returns the raw bytes of the preimage.
+    pub fn to_byte_array(self) -> [u8; 32] {
+        self.0
+    }
+}
+
+impl From<[u8; 32]> for Preimage {
+    fn from(bytes: [u8; 32]) -> Self {
+        Preimage(bytes)
+    }
+}
+
+impl From<Preimage> for [u8; 32] {
+    fn from(preimage: Preimage) -> Self {
+        preimage.0
+    }
+}
+
+impl AsRef<[u8]> for Preimage {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Preimage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl FromStr for Preimage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Preimage(parse_hex_32(s).context("Invalid preimage")?))
+    }
+}
+
+/// Payment hash of a Lightning invoice, uniquely identifies a payment.
+///
+/// Displayed, parsed and serialized as hex.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PaymentHash(pub sha256::Hash);
+
+impl PaymentHash {
+    /// Returns the raw bytes of the payment hash.
+    pub fn to_byte_array(self) -> [u8; 32] {
+        self.0.to_byte_array()
+    }
+}
+
+impl From<sha256::Hash> for PaymentHash {
+    fn from(hash: sha256::Hash) -> Self {
+        PaymentHash(hash)
+    }
+}
+
+impl From<&sha256::Hash> for PaymentHash {
+    fn from(hash: &sha256::Hash) -> Self {
+        PaymentHash(*hash)
+    }
+}
+
+impl From<&PaymentHash> for PaymentHash {
+    fn from(hash: &PaymentHash) -> Self {
+        *hash
+    }
+}
+
+impl From<[u8; 32]> for PaymentHash {
+    fn from(bytes: [u8; 32]) -> Self {
+        PaymentHash(sha256::Hash::from_byte_array(bytes))
+    }
+}
+
+impl From<PaymentHash> for sha256::Hash {
+    fn from(hash: PaymentHash) -> Self {
+        hash.0
+    }
+}
+
+impl fmt::Display for PaymentHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0.to_byte_array()))
+    }
+}
+
+impl FromStr for PaymentHash {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(PaymentHash::from(
+            parse_hex_32(s).context("Invalid payment hash")?,
+        ))
+    }
+}
+
+fn parse_hex_32(s: &str) -> anyhow::Result<[u8; 32]> {
+    hex::decode(s)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Expected 32 bytes"))
+}
+
+impl_serde_via_string!(Preimage);
+impl_serde_via_string!(PaymentHash);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preimage_payment_hash() {
+        let preimage = Preimage([1; 32]);
+        assert_eq!(
+            preimage.payment_hash(),
+            PaymentHash(sha256::Hash::hash(&[1; 32]))
+        );
+    }
+
+    #[test]
+    fn test_hex_roundtrip() {
+        let preimage = Preimage([0xab; 32]);
+        assert_eq!(preimage.to_string(), "ab".repeat(32));
+        assert_eq!(preimage.to_string().parse::<Preimage>().unwrap(), preimage);
+
+        let payment_hash = preimage.payment_hash();
+        assert_eq!(
+            payment_hash.to_string().parse::<PaymentHash>().unwrap(),
+            payment_hash
+        );
+
+        assert!("abcd".parse::<PaymentHash>().is_err());
+        assert!("zz".repeat(32).parse::<Preimage>().is_err());
+    }
+
+    #[test]
+    fn test_serde() {
+        let preimage = Preimage([0xab; 32]);
+        let json = serde_json::to_string(&preimage).unwrap();
+        assert_eq!(json, format!("\"{}\"", "ab".repeat(32)));
+        assert_eq!(serde_json::from_str::<Preimage>(&json).unwrap(), preimage);
+    }
+}
@@ -0,0 +1,1528 @@
+//! Integration tests exercising the payment paths against a local test
+//! federation. Run them inside a devimint environment using
+//! `cargo test --features devimint-tests --test devimint`.
+#![cfg(feature = "devimint-tests")]
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use blitzi::testing::{
+    funded_client, lnd_invoice, pay_with_lnd, temp_datadir, test_client, test_client_with_version,
+    test_federation,
+};
+use blitzi::{
+    AlreadyPaid, Amount, BalanceCap, BalanceCapExceeded, BalanceTimeout, Blitzi, BlitziEvent,
+    EcashAlreadySpent, ExternalIdInUse, GatewaySelection, GatewayUnavailable, HistoryEntryKind,
+    HistoryEntryStatus, IdempotencyKeyConflict, InvalidRouteHint, InvoiceAmountError,
+    InvoiceOptions, InvoiceStatus, LeaveFederationError, LightningVersion, PassphraseRequired,
+    PayOptions, PayProgress, PaymentHash, PaymentResult, PeriodStats, PolicyDecision, PolicyDenied,
+    ReceiveState, RecoveryProgress, SpendLimit, SpendLimitExceeded, SpendWindow, TransferExpired,
+    WrongPassphrase, msats, sats,
+};
+use futures_lite::StreamExt;
+
+#[tokio::test]
+async fn test_receive_from_lightning() -> anyhow::Result<()> {
+    let blitzi = test_client().await?;
+
+    let invoice = blitzi.lightning_invoice(sats(1_000), "test").await?;
+    pay_with_lnd(&invoice).await?;
+    blitzi.await_incoming_payment(&invoice).await?;
+
+    assert_eq!(
+        blitzi.invoice_status(invoice.payment_hash()).await?,
+        InvoiceStatus::Paid
+    );
+    assert!(blitzi.balance().await > sats(0));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pay_lightning_invoice() -> anyhow::Result<()> {
+    let blitzi = funded_client(sats(10_000)).await?;
+
+    let invoice = lnd_invoice(sats(1_000)).await?;
+    let preimage = blitzi.pay(&invoice).await?;
+    assert_eq!(&preimage.payment_hash().0, invoice.payment_hash());
+
+    // Paying the same invoice again returns the result of the first payment
+    let balance = blitzi.balance().await;
+    assert_eq!(blitzi.pay(&invoice).await?, preimage);
+    assert_eq!(blitzi.balance().await, balance);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_internal_payment() -> anyhow::Result<()> {
+    let sender = funded_client(sats(10_000)).await?;
+    let receiver = test_client().await?;
+
+    let invoice = receiver.lightning_invoice(sats(1_000), "internal").await?;
+    let preimage = sender.pay(&invoice).await?;
+    assert_eq!(&preimage.payment_hash().0, invoice.payment_hash());
+
+    receiver.await_incoming_payment(&invoice).await?;
+    assert_eq!(receiver.balance().await, sats(1_000));
+
+    // Re-paying an internal payment is idempotent too
+    assert_eq!(sender.pay(&invoice).await?, preimage);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_await_balance_at_least() -> anyhow::Result<()> {
+    let blitzi = test_client().await?;
+
+    let error = blitzi
+        .await_balance_at_least(msats(1), Duration::from_millis(100))
+        .await
+        .unwrap_err();
+    assert!(error.downcast_ref::<BalanceTimeout>().is_some());
+
+    let invoice = blitzi.lightning_invoice(sats(1_000), "top up").await?;
+    pay_with_lnd(&invoice).await?;
+    // Gateway fees may be deducted, so only wait for the payment to arrive
+    let balance = blitzi
+        .await_balance_at_least(msats(1), Duration::from_secs(60))
+        .await?;
+    assert_eq!(balance, blitzi.balance().await);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_leave_federation() -> anyhow::Result<()> {
+    let funded = funded_client(sats(1_000)).await?;
+    let error = funded.leave_federation(false).await.unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<LeaveFederationError>(),
+        Some(LeaveFederationError::NonZeroBalance { .. })
+    ));
+
+    let datadir = temp_datadir();
+    let blitzi = Blitzi::builder()
+        .datadir(&datadir)
+        .federation_invite(test_federation()?)
+        .build()
+        .await?;
+    assert!(datadir.exists());
+
+    blitzi.leave_federation(false).await?;
+    assert!(!datadir.exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_invoice_options() -> anyhow::Result<()> {
+    // Choosing gateways and route hints is only supported by the ln module
+    let blitzi = test_client_with_version(LightningVersion::V1).await?;
+
+    let created = blitzi
+        .lightning_invoice_with_options(
+            sats(1_000),
+            "options",
+            InvoiceOptions {
+                max_route_hints: Some(0),
+                expiry: Some(Duration::from_secs(600)),
+                ..Default::default()
+            },
+        )
+        .await?;
+    assert_eq!(created.invoice.expiry_time(), Duration::from_secs(600));
+
+    // Selecting the same gateway explicitly works
+    let created = blitzi
+        .lightning_invoice_with_options(
+            sats(1_000),
+            "options",
+            InvoiceOptions {
+                gateway: Some(created.gateway_id),
+                ..Default::default()
+            },
+        )
+        .await?;
+    pay_with_lnd(&created.invoice).await?;
+    blitzi.await_incoming_payment(&created.invoice).await?;
+
+    let secp = blitzi::secp256k1::Secp256k1::new();
+    let unknown_gateway = blitzi::secp256k1::SecretKey::from_slice(&[1; 32])?.public_key(&secp);
+    let error = blitzi
+        .lightning_invoice_with_options(
+            sats(1_000),
+            "options",
+            InvoiceOptions {
+                gateway: Some(unknown_gateway),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<GatewayUnavailable>(),
+        Some(&GatewayUnavailable {
+            gateway_id: Some(unknown_gateway)
+        })
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pay_with_updates() -> anyhow::Result<()> {
+    let blitzi = funded_client(sats(10_000)).await?;
+
+    let invoice = lnd_invoice(sats(1_000)).await?;
+    let updates = blitzi
+        .pay_with_updates(&invoice)
+        .await?
+        .collect::<Vec<_>>()
+        .await;
+
+    assert!(
+        updates[..updates.len() - 1]
+            .iter()
+            .all(|progress| !progress.is_final())
+    );
+    match updates.last() {
+        Some(PayProgress::Succeeded { preimage }) => {
+            assert_eq!(&preimage.payment_hash().0, invoice.payment_hash());
+        }
+        progress => panic!("Unexpected final progress: {:?}", progress),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_prune_operations() -> anyhow::Result<()> {
+    let blitzi = funded_client(sats(10_000)).await?;
+    let paid = lnd_invoice(sats(1_000)).await?;
+    blitzi.pay(&paid).await?;
+    let unpaid = blitzi.lightning_invoice(sats(1_000), "unpaid").await?;
+
+    let balance = blitzi.balance().await;
+    let operations = blitzi.list_operations(100, None).await;
+    assert!(operations.len() >= 3);
+
+    let pruned = blitzi.prune_operations(SystemTime::now()).await?;
+    assert!(pruned >= 2);
+    assert_eq!(blitzi.balance().await, balance);
+
+    // The unpaid invoice is still pending and survives pruning
+    let remaining = blitzi.list_operations(100, None).await;
+    assert_eq!(remaining.len(), operations.len() - pruned as usize);
+    assert!(
+        remaining
+            .iter()
+            .any(|entry| entry.status == HistoryEntryStatus::Pending)
+    );
+    assert_eq!(
+        blitzi.invoice_status(unpaid.payment_hash()).await?,
+        InvoiceStatus::Pending
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_concurrent_pay() -> anyhow::Result<()> {
+    let blitzi = Arc::new(funded_client(sats(10_000)).await?);
+    let invoice = lnd_invoice(sats(1_000)).await?;
+
+    let payments = (0..10)
+        .map(|_| {
+            let blitzi = blitzi.clone();
+            let invoice = invoice.clone();
+            tokio::spawn(async move { blitzi.pay(&invoice).await })
+        })
+        .collect::<Vec<_>>();
+
+    let mut preimages = vec![];
+    for payment in payments {
+        preimages.push(payment.await??);
+    }
+    assert!(preimages.iter().all(|preimage| *preimage == preimages[0]));
+
+    let pay_operations = blitzi
+        .list_operations(100, None)
+        .await
+        .into_iter()
+        .filter(|entry| entry.kind == HistoryEntryKind::Pay)
+        .count();
+    assert_eq!(pay_operations, 1);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_invoices_and_payments() -> anyhow::Result<()> {
+    const COUNT: usize = 10;
+
+    let sender = Arc::new(funded_client(sats(50_000)).await?);
+    let receiver = Arc::new(test_client().await?);
+    let sender_balance = sender.balance().await;
+
+    // Every task creates its own invoice and pays it while others do the same
+    let tasks = (0..COUNT)
+        .map(|i| {
+            let sender = sender.clone();
+            let receiver = receiver.clone();
+            tokio::spawn(async move {
+                let invoice = receiver
+                    .lightning_invoice(sats(1_000), &format!("concurrent {}", i))
+                    .await?;
+                let balance = sender.balance().await;
+                assert!(balance <= sender_balance);
+                sender.pay(&invoice).await?;
+                receiver.await_incoming_payment(&invoice).await?;
+                anyhow::Ok(invoice)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut payment_hashes = vec![];
+    for task in tasks {
+        payment_hashes.push(*task.await??.payment_hash());
+    }
+    payment_hashes.sort();
+    payment_hashes.dedup();
+    assert_eq!(payment_hashes.len(), COUNT);
+
+    assert_eq!(receiver.balance().await, sats(1_000 * COUNT as u64));
+    assert!(sender.balance().await <= sender_balance - sats(1_000 * COUNT as u64));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pay_idempotent() -> anyhow::Result<()> {
+    let blitzi = funded_client(sats(10_000)).await?;
+
+    let invoice = lnd_invoice(sats(1_000)).await?;
+    let payment = blitzi
+        .pay_idempotent("order-1", &invoice, PayOptions::default())
+        .await?;
+    assert_eq!(&payment.payment_hash.0, invoice.payment_hash());
+    assert_eq!(payment.warning, None);
+
+    // A retry with a fresh invoice for the same purchase doesn't pay again
+    let balance = blitzi.balance().await;
+    let retry = lnd_invoice(sats(1_000)).await?;
+    let repeated = blitzi
+        .pay_idempotent("order-1", &retry, PayOptions::default())
+        .await?;
+    assert_eq!(repeated.preimage, payment.preimage);
+    assert!(repeated.warning.is_some());
+    assert_eq!(blitzi.balance().await, balance);
+
+    let larger = lnd_invoice(sats(2_000)).await?;
+    let error = blitzi
+        .pay_idempotent("order-1", &larger, PayOptions::default())
+        .await
+        .unwrap_err();
+    assert!(error.downcast_ref::<IdempotencyKeyConflict>().is_some());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lightning_invoice_idempotent() -> anyhow::Result<()> {
+    let blitzi = test_client().await?;
+
+    let created = blitzi
+        .lightning_invoice_idempotent("order-1", sats(1_000), "order", InvoiceOptions::default())
+        .await?;
+    assert!(!created.reused);
+    assert_eq!(created.status, InvoiceStatus::Pending);
+
+    // A double submit returns the same invoice
+    let repeated = blitzi
+        .lightning_invoice_idempotent("order-1", sats(1_000), "order", InvoiceOptions::default())
+        .await?;
+    assert!(repeated.reused);
+    assert_eq!(repeated.invoice, created.invoice);
+
+    let error = blitzi
+        .lightning_invoice_idempotent("order-1", sats(2_000), "order", InvoiceOptions::default())
+        .await
+        .unwrap_err();
+    assert!(error.downcast_ref::<IdempotencyKeyConflict>().is_some());
+
+    // Once paid the invoice is returned as paid instead of charging again
+    pay_with_lnd(&created.invoice.invoice).await?;
+    blitzi
+        .await_incoming_payment(&created.invoice.invoice)
+        .await?;
+    let paid = blitzi
+        .lightning_invoice_idempotent("order-1", sats(1_000), "order", InvoiceOptions::default())
+        .await?;
+    assert!(paid.reused);
+    assert_eq!(paid.status, InvoiceStatus::Paid);
+    assert_eq!(paid.invoice, created.invoice);
+
+    // A canceled invoice is replaced
+    let canceled = blitzi
+        .lightning_invoice_idempotent("order-2", sats(1_000), "order", InvoiceOptions::default())
+        .await?;
+    blitzi.cancel_invoice(canceled.invoice.payment_hash).await?;
+    let replaced = blitzi
+        .lightning_invoice_idempotent("order-2", sats(1_000), "order", InvoiceOptions::default())
+        .await?;
+    assert!(!replaced.reused);
+    assert_ne!(replaced.invoice, canceled.invoice);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reclaim_pending() -> anyhow::Result<()> {
+    let blitzi = test_client().await?;
+
+    // Unpaid invoices are skipped without waiting for them
+    let unpaid = blitzi.lightning_invoice(sats(1_000), "unpaid").await?;
+    assert_eq!(blitzi.reclaim_pending().await?, 0);
+    assert_eq!(
+        blitzi.invoice_status(unpaid.payment_hash()).await?,
+        InvoiceStatus::Pending
+    );
+
+    let paid = blitzi.lightning_invoice(sats(1_000), "paid").await?;
+    pay_with_lnd(&paid).await?;
+    blitzi.await_incoming_payment(&paid).await?;
+    assert_eq!(blitzi.reclaim_pending().await?, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_spend_limit() -> anyhow::Result<()> {
+    let blitzi = Blitzi::builder()
+        .datadir(temp_datadir())
+        .federation_invite(test_federation()?)
+        .spend_limit(SpendLimit {
+            per_payment: Some(sats(2_000)),
+            window: Some(SpendWindow {
+                max: sats(2_500),
+                duration: Duration::from_secs(60 * 60),
+            }),
+        })
+        .build()
+        .await?;
+    let invoice = blitzi.lightning_invoice(sats(10_000), "funding").await?;
+    pay_with_lnd(&invoice).await?;
+    blitzi.await_incoming_payment(&invoice).await?;
+
+    let error = blitzi
+        .pay(&lnd_invoice(sats(3_000)).await?)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<SpendLimitExceeded>().map(|e| e.limit),
+        Some(sats(2_000))
+    );
+
+    blitzi.pay(&lnd_invoice(sats(2_000)).await?).await?;
+    let error = blitzi
+        .pay(&lnd_invoice(sats(1_000)).await?)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        error
+            .downcast_ref::<SpendLimitExceeded>()
+            .and_then(|e| e.window_remaining),
+        Some(sats(500))
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_spend_and_reclaim_ecash() -> anyhow::Result<()> {
+    let blitzi = funded_client(sats(10_000)).await?;
+    let balance = blitzi.balance().await;
+
+    let spent = blitzi
+        .spend_ecash_with_timeout(sats(1_000), Duration::from_secs(60 * 60))
+        .await?;
+    assert!(spent.amount >= sats(1_000));
+    assert!(blitzi.balance().await < balance);
+
+    blitzi.reclaim_ecash(spent.operation_id).await?;
+    blitzi
+        .await_balance_at_least(balance, Duration::from_secs(60))
+        .await?;
+
+    // Unredeemed notes are reclaimed automatically once the timeout passes
+    blitzi
+        .spend_ecash_with_timeout(sats(1_000), Duration::from_secs(1))
+        .await?;
+    blitzi
+        .await_balance_at_least(balance, Duration::from_secs(60))
+        .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_spend_ecash_exact_amount() -> anyhow::Result<()> {
+    let blitzi = funded_client(sats(10_000)).await?;
+    let balance = blitzi.balance().await;
+
+    // Not representable by the notes received when funding, requires change
+    let spent = blitzi
+        .spend_ecash_with_timeout(msats(1_337), Duration::from_secs(60 * 60))
+        .await?;
+    assert_eq!(spent.amount, msats(1_337));
+    assert!(blitzi.balance().await <= balance - msats(1_337));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_payment_policy() -> anyhow::Result<()> {
+    let blitzi = Blitzi::builder()
+        .datadir(temp_datadir())
+        .federation_invite(test_federation()?)
+        .payment_policy(|intent| match intent.amount {
+            Some(amount) if amount <= sats(1_000) => PolicyDecision::Allow,
+            _ => PolicyDecision::Deny("too large".to_string()),
+        })
+        .build()
+        .await?;
+
+    let error = blitzi
+        .pay(&lnd_invoice(sats(2_000)).await?)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<PolicyDenied>(),
+        Some(&PolicyDenied {
+            reason: "too large".to_string()
+        })
+    );
+    assert!(blitzi.list_operations(10, None).await.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lightning_invoice_with_hints() -> anyhow::Result<()> {
+    use blitzi::lightning_invoice::{RouteHint, RouteHintHop, RoutingFees};
+
+    let blitzi = test_client_with_version(LightningVersion::V1).await?;
+    let secp = blitzi::secp256k1::Secp256k1::new();
+    let hop = RouteHintHop {
+        src_node_id: blitzi::secp256k1::SecretKey::from_slice(&[1; 32])?.public_key(&secp),
+        short_channel_id: 1234,
+        fees: RoutingFees {
+            base_msat: 0,
+            proportional_millionths: 0,
+        },
+        cltv_expiry_delta: 144,
+        htlc_minimum_msat: None,
+        htlc_maximum_msat: None,
+    };
+
+    let invoice = blitzi
+        .lightning_invoice_with_hints(sats(1_000), "hints", vec![RouteHint(vec![hop.clone()])])
+        .await?;
+    assert!(
+        invoice
+            .route_hints()
+            .iter()
+            .any(|hint| hint.0.first() == Some(&hop))
+    );
+
+    let error = blitzi
+        .lightning_invoice_with_hints(sats(1_000), "hints", vec![RouteHint(vec![])])
+        .await
+        .unwrap_err();
+    assert!(error.downcast_ref::<InvalidRouteHint>().is_some());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_period_stats() -> anyhow::Result<()> {
+    let since = SystemTime::now();
+    let blitzi = funded_client(sats(10_000)).await?;
+
+    blitzi.pay(&lnd_invoice(sats(1_000)).await?).await?;
+    let stats = blitzi
+        .stats(since, SystemTime::now() + Duration::from_secs(1))
+        .await?;
+    assert_eq!(stats.received, sats(10_000));
+    assert_eq!(stats.payments_received, 1);
+    assert_eq!(stats.sent, sats(1_000));
+    assert_eq!(stats.payments_sent, 1);
+    assert_eq!(stats.payments_failed, 0);
+
+    // Operations outside the range aren't counted
+    let stats = blitzi.stats(since, since).await?;
+    assert_eq!(stats, PeriodStats::default());
+    assert!(blitzi.stats(SystemTime::now(), since).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_payment_result() -> anyhow::Result<()> {
+    let blitzi = funded_client(sats(10_000)).await?;
+
+    let invoice = lnd_invoice(sats(1_000)).await?;
+    assert_eq!(blitzi.payment_result(invoice.payment_hash()).await?, None);
+
+    let preimage = blitzi.pay(&invoice).await?;
+    // The outcome may only be recorded shortly after `pay` returned
+    let result = loop {
+        match blitzi.payment_result(invoice.payment_hash()).await? {
+            Some(PaymentResult::Pending) => tokio::time::sleep(Duration::from_millis(100)).await,
+            result => break result,
+        }
+    };
+    match result {
+        Some(PaymentResult::Succeeded {
+            preimage: result, ..
+        }) => assert_eq!(result, preimage),
+        result => panic!("Unexpected payment result: {:?}", result),
+    }
+
+    // Incoming payments aren't outgoing payments
+    let incoming = blitzi.lightning_invoice(sats(1_000), "incoming").await?;
+    assert_eq!(blitzi.payment_result(incoming.payment_hash()).await?, None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_gateway_selection() -> anyhow::Result<()> {
+    let gateways = test_client().await?.list_gateways().await;
+    let pinned = gateways.last().expect("test federation has a gateway");
+
+    let blitzi = Blitzi::builder()
+        .datadir(temp_datadir())
+        .federation_invite(test_federation()?)
+        .gateway_selection(GatewaySelection::Pinned(pinned.gateway_id))
+        .preferred_lightning_version(LightningVersion::V1)
+        .build()
+        .await?;
+    let created = blitzi
+        .lightning_invoice_with_options(sats(1_000), "pinned", InvoiceOptions::default())
+        .await?;
+    assert_eq!(created.gateway_id, pinned.gateway_id);
+
+    let blitzi = Blitzi::builder()
+        .datadir(temp_datadir())
+        .federation_invite(test_federation()?)
+        .gateway_selection(GatewaySelection::Cheapest)
+        .preferred_lightning_version(LightningVersion::V1)
+        .build()
+        .await?;
+    let cheapest = gateways
+        .iter()
+        .map(|gateway| gateway.fee(sats(1_000)))
+        .min()
+        .expect("test federation has a gateway");
+    let created = blitzi
+        .lightning_invoice_with_options(sats(1_000), "cheapest", InvoiceOptions::default())
+        .await?;
+    let selected = gateways
+        .iter()
+        .find(|gateway| gateway.gateway_id == created.gateway_id)
+        .expect("selected a registered gateway");
+    assert_eq!(selected.fee(sats(1_000)), cheapest);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lightning_versions() -> anyhow::Result<()> {
+    let v1 = test_client_with_version(LightningVersion::V1).await?;
+    let v2 = test_client_with_version(LightningVersion::V2).await?;
+    assert_eq!(v1.lightning_version(), LightningVersion::V1);
+    assert_eq!(v2.lightning_version(), LightningVersion::V2);
+    assert_eq!(v1.network(), v2.network());
+
+    for (receiver, sender) in [(&v1, &v2), (&v2, &v1)] {
+        let invoice = receiver.lightning_invoice(sats(5_000), "funding").await?;
+        pay_with_lnd(&invoice).await?;
+        receiver.await_incoming_payment(&invoice).await?;
+        assert_eq!(
+            receiver.invoice_status(invoice.payment_hash()).await?,
+            InvoiceStatus::Paid
+        );
+
+        let invoice = lnd_invoice(sats(1_000)).await?;
+        let preimage = receiver.pay(&invoice).await?;
+        // Paying again follows the previous payment
+        assert_eq!(receiver.pay(&invoice).await?, preimage);
+        assert!(matches!(
+            receiver.payment_result(invoice.payment_hash()).await?,
+            Some(PaymentResult::Succeeded { .. } | PaymentResult::Pending)
+        ));
+
+        // Payments between clients using different modules work too
+        let invoice = sender.lightning_invoice(sats(1_000), "cross").await?;
+        receiver.pay(&invoice).await?;
+        sender.await_incoming_payment(&invoice).await?;
+    }
+
+    // Operations created by the lnv2 module show up in the history
+    let entries = v2.list_operations(10, None).await;
+    assert!(
+        entries
+            .iter()
+            .any(|entry| entry.kind == HistoryEntryKind::Receive
+                && entry.status == HistoryEntryStatus::Succeeded)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_capabilities() -> anyhow::Result<()> {
+    let blitzi = test_client().await?;
+    let capabilities = blitzi.capabilities().await?;
+
+    assert!(capabilities.supports("mint"));
+    assert!(capabilities.supports("ln") || capabilities.supports("lnv2"));
+    assert_eq!(capabilities.network, blitzi::bitcoin::Network::Regtest);
+    assert_eq!(capabilities.network, blitzi.network());
+    // The chosen module is one the federation supports
+    let kind = match blitzi.lightning_version() {
+        LightningVersion::V1 => "ln",
+        LightningVersion::V2 => "lnv2",
+    };
+    assert!(capabilities.supports(kind));
+
+    let meta = blitzi.meta().await?;
+    assert_eq!(blitzi.meta().await?, meta);
+    assert_eq!(blitzi.meta_value("no_such_field").await?, None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_status() -> anyhow::Result<()> {
+    let blitzi = test_client().await?;
+    let invoice = blitzi.lightning_invoice(sats(1_000), "status").await?;
+
+    let status = blitzi.status().await;
+    assert_eq!(status.balance, blitzi.balance().await);
+    assert_eq!(status.pending_invoices, 1);
+    assert!(status.guardians > 0);
+    assert_eq!(status.reachable_guardians, status.guardians);
+
+    pay_with_lnd(&invoice).await?;
+    blitzi.await_incoming_payment(&invoice).await?;
+    // The watcher stops following the invoice right after claiming it
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while blitzi.status().await.pending_invoices != 0 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_gateway_stats() -> anyhow::Result<()> {
+    // Only payments made using the ln module are recorded per gateway
+    let blitzi = test_client_with_version(LightningVersion::V1).await?;
+    assert!(blitzi.gateway_stats().await?.is_empty());
+
+    let invoice = blitzi.lightning_invoice(sats(10_000), "funding").await?;
+    pay_with_lnd(&invoice).await?;
+    blitzi.await_incoming_payment(&invoice).await?;
+    blitzi.pay(&lnd_invoice(sats(1_000)).await?).await?;
+
+    // The outcome is recorded in the background shortly after `pay` returned
+    let stats = loop {
+        let stats = blitzi.gateway_stats().await?;
+        if !stats.is_empty() {
+            break stats;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    };
+    assert_eq!(stats.len(), 1);
+    assert_eq!((stats[0].successes, stats[0].failures), (1, 0));
+    assert!(stats[0].last_used.is_some());
+    assert!(
+        blitzi
+            .list_gateways()
+            .await
+            .iter()
+            .any(|gateway| gateway.gateway_id == stats[0].gateway_id)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_preview_federation() -> anyhow::Result<()> {
+    let invite = test_federation()?;
+    let datadir = temp_datadir();
+
+    let preview = Blitzi::builder()
+        .datadir(&datadir)
+        .federation_invite(invite.clone())
+        .preview()
+        .await?;
+    assert_eq!(preview.federation_id, invite.federation_id());
+    assert!(!preview.guardians.is_empty());
+    assert!(preview.modules.iter().any(|kind| kind == "mint"));
+    assert_eq!(preview.network, blitzi::bitcoin::Network::Regtest);
+    // Previewing doesn't create the data directory
+    drop(preview);
+    assert!(!datadir.exists());
+
+    let blitzi = Blitzi::builder()
+        .datadir(&datadir)
+        .federation_invite(invite.clone())
+        .preview()
+        .await?
+        .join()
+        .await?;
+    assert_eq!(blitzi.network(), blitzi::bitcoin::Network::Regtest);
+    blitzi.lightning_invoice(sats(1_000), "preview").await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_preview_federation_standalone() -> anyhow::Result<()> {
+    let invite = test_federation()?;
+
+    let preview = blitzi::preview_federation(&invite.to_string()).await?;
+    assert_eq!(preview.federation_id, invite.federation_id());
+    assert_eq!(preview.guardian_count(), preview.guardians.len());
+    assert!(preview.guardian_count() > 0);
+    assert!(preview.consensus_version.major > 0);
+
+    assert!(blitzi::preview_federation("not an invite").await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cancel_invoice() -> anyhow::Result<()> {
+    for version in [LightningVersion::V1, LightningVersion::V2] {
+        let blitzi = test_client_with_version(version).await?;
+
+        let unpaid = blitzi.lightning_invoice(sats(1_000), "abandoned").await?;
+        blitzi.cancel_invoice(unpaid.payment_hash()).await?;
+        assert_eq!(
+            blitzi.invoice_status(unpaid.payment_hash()).await?,
+            InvoiceStatus::Canceled
+        );
+        let err = blitzi
+            .await_incoming_payment(&unpaid)
+            .await
+            .expect_err("canceled invoice must not be awaited");
+        assert!(err.to_string().contains("canceled by user"));
+        assert!(blitzi.list_operations(10, None).await.iter().any(|entry| {
+            entry.kind == HistoryEntryKind::Receive && entry.status == HistoryEntryStatus::Failed
+        }));
+
+        // Paid invoices can't be canceled
+        let paid = blitzi.lightning_invoice(sats(1_000), "paid").await?;
+        pay_with_lnd(&paid).await?;
+        blitzi.await_incoming_payment(&paid).await?;
+        let err = blitzi
+            .cancel_invoice(paid.payment_hash())
+            .await
+            .expect_err("paid invoice must not be canceled");
+        assert!(err.downcast_ref::<AlreadyPaid>().is_some());
+        assert_eq!(
+            blitzi.invoice_status(paid.payment_hash()).await?,
+            InvoiceStatus::Paid
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_balance_cap() -> anyhow::Result<()> {
+    let blitzi = Blitzi::builder()
+        .datadir(temp_datadir())
+        .federation_invite(test_federation()?)
+        .max_balance(BalanceCap::Fixed(sats(5_000)))
+        .build()
+        .await?;
+
+    let error = blitzi
+        .lightning_invoice(sats(6_000), "too much")
+        .await
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<BalanceCapExceeded>().map(|e| e.max),
+        Some(sats(5_000))
+    );
+
+    let invoice = blitzi.lightning_invoice(sats(4_000), "funding").await?;
+    pay_with_lnd(&invoice).await?;
+    blitzi.await_incoming_payment(&invoice).await?;
+
+    // The cap takes the current balance into account
+    let error = blitzi
+        .lightning_invoice(sats(2_000), "too much")
+        .await
+        .unwrap_err();
+    assert!(error.downcast_ref::<BalanceCapExceeded>().is_some());
+    blitzi.lightning_invoice(sats(1_000), "fits").await?;
+
+    // No cap is enforced if the federation doesn't announce one
+    let blitzi = Blitzi::builder()
+        .datadir(temp_datadir())
+        .federation_invite(test_federation()?)
+        .max_balance(BalanceCap::FromFederation)
+        .build()
+        .await?;
+    if blitzi.capabilities().await?.max_balance.is_none() {
+        blitzi.lightning_invoice(sats(100_000), "uncapped").await?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_accounts() -> anyhow::Result<()> {
+    let blitzi = funded_client(sats(10_000)).await?;
+    let balance = blitzi.balance().await;
+
+    // Account 0 is the wallet itself
+    let default = blitzi.account(0).await?;
+    assert_eq!(default.index(), 0);
+    assert_eq!(default.balance().await, balance);
+
+    let account = blitzi.account(1).await?;
+    assert_eq!(account.balance().await, sats(0));
+    let invoice = account.lightning_invoice(sats(2_000), "account").await?;
+    pay_with_lnd(&invoice).await?;
+    account.await_incoming_payment(&invoice).await?;
+    assert_eq!(account.balance().await, sats(2_000));
+    assert_eq!(blitzi.balance().await, balance);
+
+    let moved = blitzi.transfer_between_accounts(0, 2, sats(1_000)).await?;
+    assert_eq!(moved, sats(1_000));
+    assert_eq!(blitzi.account(2).await?.balance().await, sats(1_000));
+    // Making change may cost fees
+    let remaining = blitzi.balance().await;
+    assert!(remaining <= balance - sats(1_000));
+
+    let balances = blitzi.account_balances().await?;
+    assert_eq!(
+        balances,
+        BTreeMap::from([(0, remaining), (1, sats(2_000)), (2, sats(1_000))])
+    );
+
+    assert!(
+        blitzi
+            .transfer_between_accounts(1, 1, sats(1))
+            .await
+            .is_err()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_subscribe_balance() -> anyhow::Result<()> {
+    let blitzi = test_client().await?;
+
+    let mut updates = blitzi.subscribe_balance().await;
+    assert_eq!(updates.next().await, Some(sats(0)));
+
+    let invoice = blitzi.lightning_invoice(sats(1_000), "balance").await?;
+    pay_with_lnd(&invoice).await?;
+    blitzi.await_incoming_payment(&invoice).await?;
+
+    // Issuing the notes of the payment results in a single update
+    let balance = tokio::time::timeout(Duration::from_secs(10), updates.next())
+        .await?
+        .expect("stream ended");
+    assert_eq!(balance, blitzi.balance().await);
+    assert!(
+        tokio::time::timeout(Duration::from_secs(1), updates.next())
+            .await
+            .is_err()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_federation_invite_code() -> anyhow::Result<()> {
+    let blitzi = test_client().await?;
+    let invite = blitzi.federation_invite_code().await?;
+    assert_eq!(invite.federation_id(), test_federation()?.federation_id());
+
+    // The invite code can be used to join the federation
+    let other = Blitzi::builder()
+        .datadir(temp_datadir())
+        .federation_invite(invite)
+        .build()
+        .await?;
+    assert_eq!(other.network(), blitzi.network());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_preferred_gateway() -> anyhow::Result<()> {
+    let datadir = temp_datadir();
+    let build = || {
+        Blitzi::builder()
+            .datadir(&datadir)
+            .federation_invite(test_federation().expect("test federation"))
+            .preferred_lightning_version(LightningVersion::V1)
+            .build()
+    };
+
+    let blitzi = build().await?;
+    let preferred = blitzi
+        .list_gateways()
+        .await
+        .last()
+        .expect("test federation has a gateway")
+        .gateway_id;
+    assert_eq!(blitzi.preferred_gateway().await?, None);
+    blitzi.set_preferred_gateway(preferred).await?;
+
+    // The preference persists across restarts
+    blitzi.shutdown().await?;
+    let blitzi = build().await?;
+    assert_eq!(blitzi.preferred_gateway().await?, Some(preferred));
+    let created = blitzi
+        .lightning_invoice_with_options(sats(1_000), "preferred", InvoiceOptions::default())
+        .await?;
+    assert_eq!(created.gateway_id, preferred);
+
+    blitzi.clear_preferred_gateway().await?;
+    assert_eq!(blitzi.preferred_gateway().await?, None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_encrypted_seed() -> anyhow::Result<()> {
+    let datadir = temp_datadir();
+    let open = |passphrase: Option<&str>| {
+        let mut builder = Blitzi::builder()
+            .datadir(&datadir)
+            .federation_invite(test_federation().expect("test federation"));
+        if let Some(passphrase) = passphrase {
+            builder = builder.encryption_passphrase(passphrase);
+        }
+        builder.build()
+    };
+
+    // Existing plaintext wallets can be migrated
+    open(None).await?.encrypt_existing_secret("secret").await?;
+
+    let error = open(None).await.err().expect("passphrase is required");
+    assert!(error.downcast_ref::<PassphraseRequired>().is_some());
+    let error = open(Some("wrong"))
+        .await
+        .err()
+        .expect("passphrase is wrong");
+    assert!(error.downcast_ref::<WrongPassphrase>().is_some());
+
+    let blitzi = open(Some("secret")).await?;
+    let invoice = blitzi.lightning_invoice(sats(1_000), "encrypted").await?;
+    pay_with_lnd(&invoice).await?;
+    blitzi.await_incoming_payment(&invoice).await?;
+    blitzi.change_passphrase("secret", "new secret").await?;
+    blitzi.shutdown().await?;
+
+    assert!(open(Some("secret")).await.is_err());
+    assert!(open(Some("new secret")).await?.balance().await > sats(0));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_resume_pending_payments() -> anyhow::Result<()> {
+    let blitzi = funded_client(sats(10_000)).await?;
+    let invoice = lnd_invoice(sats(1_000)).await?;
+
+    // Stop following the payment right after it started, like a crash would
+    let mut updates = blitzi.pay_with_updates(&invoice).await?;
+    updates.next().await;
+    drop(updates);
+
+    let resumed = blitzi.resume_pending_payments().await;
+    assert!(
+        resumed
+            .iter()
+            .all(|(payment_hash, _)| payment_hash.0 == *invoice.payment_hash())
+    );
+    assert!(matches!(
+        blitzi.payment_result(invoice.payment_hash()).await?,
+        Some(PaymentResult::Succeeded { .. })
+    ));
+    // Nothing is left to resume
+    assert!(blitzi.resume_pending_payments().await.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_restore_from_mnemonic() -> anyhow::Result<()> {
+    let blitzi = funded_client(sats(10_000)).await?;
+    let mnemonic = blitzi.mnemonic()?.clone();
+    let balance = blitzi.balance().await;
+    blitzi.shutdown().await?;
+
+    let restored = Blitzi::builder()
+        .datadir(temp_datadir())
+        .federation_invite(test_federation()?)
+        .restore_from_mnemonic(mnemonic.clone())
+        .build()
+        .await?;
+    assert_eq!(restored.mnemonic()?, &mnemonic);
+    assert_eq!(restored.balance().await, balance);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_await_incoming_payment_details() -> anyhow::Result<()> {
+    for version in [LightningVersion::V1, LightningVersion::V2] {
+        let blitzi = test_client_with_version(version).await?;
+        let invoice = blitzi.lightning_invoice(sats(1_000), "details").await?;
+        pay_with_lnd(&invoice).await?;
+
+        let received = blitzi.await_incoming_payment_details(&invoice).await?;
+        assert_eq!(&received.payment_hash.0, invoice.payment_hash());
+        assert!(received.amount > sats(0) && received.amount <= sats(1_000));
+        assert_eq!(blitzi.balance().await, received.amount);
+        assert!(received.claimed_at <= SystemTime::now());
+
+        // Already paid invoices return the same details
+        assert_eq!(
+            blitzi.await_incoming_payment_details(&invoice).await?,
+            received
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_await_incoming_payment_details_amountless() -> anyhow::Result<()> {
+    for version in [LightningVersion::V1, LightningVersion::V2] {
+        let blitzi = test_client_with_version(version).await?;
+
+        // Amountless invoices aren't issued, so the claimed amount is known
+        let error = blitzi
+            .lightning_invoice(Amount::ZERO, "amountless")
+            .await
+            .expect_err("amountless invoices are rejected");
+        assert_eq!(
+            error.downcast_ref::<InvoiceAmountError>(),
+            Some(&InvoiceAmountError::Zero)
+        );
+
+        // Amountless invoices issued elsewhere aren't awaited
+        let invoice = lnd_invoice(Amount::ZERO).await?;
+        assert_eq!(invoice.amount_milli_satoshis(), None);
+        assert!(
+            blitzi
+                .await_incoming_payment_details(&invoice)
+                .await
+                .is_err()
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_subscribe_invoice_updates() -> anyhow::Result<()> {
+    for version in [LightningVersion::V1, LightningVersion::V2] {
+        let blitzi = test_client_with_version(version).await?;
+        let invoice = blitzi.lightning_invoice(sats(1_000), "updates").await?;
+        let mut updates = blitzi
+            .subscribe_invoice_updates(invoice.payment_hash())
+            .await?;
+        assert_eq!(updates.next().await, Some(ReceiveState::AwaitingPayment));
+
+        pay_with_lnd(&invoice).await?;
+        let states = updates.collect::<Vec<_>>().await;
+        assert_eq!(states.last(), Some(&ReceiveState::Claimed));
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_await_incoming_payment_by_operation() -> anyhow::Result<()> {
+    for version in [LightningVersion::V1, LightningVersion::V2] {
+        let blitzi = test_client_with_version(version).await?;
+        let created = blitzi
+            .lightning_invoice_with_options(sats(1_000), "by operation", InvoiceOptions::default())
+            .await?;
+        assert_eq!(&created.payment_hash.0, created.invoice.payment_hash());
+        pay_with_lnd(&created.invoice).await?;
+
+        blitzi
+            .await_incoming_payment_by_operation(created.operation_id)
+            .await?;
+        let received = blitzi
+            .await_incoming_payment_details_by_operation(created.operation_id)
+            .await?;
+        assert_eq!(received.payment_hash, created.payment_hash);
+        assert_eq!(
+            blitzi
+                .await_incoming_payment_details(&created.invoice)
+                .await?,
+            received
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lightning_invoice_sats() -> anyhow::Result<()> {
+    let blitzi = test_client().await?;
+    let invoice = blitzi.lightning_invoice_sats(21, "sats").await?;
+    assert_eq!(invoice.amount_milli_satoshis(), Some(21_000));
+    assert!(
+        blitzi
+            .lightning_invoice_sats(u64::MAX, "sats")
+            .await
+            .is_err()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_is_invoice_ours() -> anyhow::Result<()> {
+    for version in [LightningVersion::V1, LightningVersion::V2] {
+        let blitzi = test_client_with_version(version).await?;
+        let invoice = blitzi.lightning_invoice(sats(1_000), "ours").await?;
+        assert!(blitzi.is_invoice_ours(&invoice).await?);
+        assert!(
+            blitzi
+                .is_invoice_ours_by_hash(invoice.payment_hash())
+                .await?
+        );
+
+        // Invoices of other nodes, even if paid by us, aren't ours
+        let foreign = lnd_invoice(sats(1_000)).await?;
+        assert!(!blitzi.is_invoice_ours(&foreign).await?);
+        assert!(
+            !blitzi
+                .is_invoice_ours_by_hash(foreign.payment_hash())
+                .await?
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_subscribe_events() -> anyhow::Result<()> {
+    let blitzi = test_client().await?;
+    let mut events = blitzi.subscribe_events().await;
+    assert_eq!(
+        events.next().await,
+        Some(BlitziEvent::BalanceChanged { balance: sats(0) })
+    );
+
+    let created = blitzi
+        .lightning_invoice_with_options(sats(1_000), "events", InvoiceOptions::default())
+        .await?;
+    pay_with_lnd(&created.invoice).await?;
+    let mut invoice_paid = false;
+    let mut balance_changed = false;
+    while !(invoice_paid && balance_changed) {
+        match events.next().await.expect("stream ended") {
+            BlitziEvent::InvoicePaid {
+                operation_id,
+                payment_hash,
+                amount,
+            } => {
+                assert_eq!(operation_id, created.operation_id);
+                assert_eq!(payment_hash, Some(created.payment_hash));
+                assert!(amount.is_some_and(|amount| amount <= sats(1_000)));
+                invoice_paid = true;
+            }
+            BlitziEvent::BalanceChanged { balance } => balance_changed = balance > sats(0),
+            event => panic!("Unexpected event {event:?}"),
+        }
+    }
+
+    let spent = blitzi
+        .spend_ecash_with_timeout(sats(100), Duration::from_secs(60 * 60))
+        .await?;
+    loop {
+        match events.next().await.expect("stream ended") {
+            BlitziEvent::EcashSpent {
+                operation_id,
+                amount,
+            } => {
+                assert_eq!(operation_id, spent.operation_id);
+                assert_eq!(amount, spent.amount);
+                break;
+            }
+            BlitziEvent::BalanceChanged { .. } => {}
+            event => panic!("Unexpected event {event:?}"),
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_payment_events_emitted_once() -> anyhow::Result<()> {
+    // A spend limit makes the client follow the payment in the background too
+    let blitzi = Blitzi::builder()
+        .datadir(temp_datadir())
+        .federation_invite(test_federation()?)
+        .spend_limit(SpendLimit {
+            per_payment: Some(sats(5_000)),
+            window: None,
+        })
+        .build()
+        .await?;
+    let invoice = blitzi.lightning_invoice(sats(10_000), "funding").await?;
+    pay_with_lnd(&invoice).await?;
+    blitzi.await_incoming_payment(&invoice).await?;
+    let mut events = blitzi.subscribe_events().await;
+
+    let invoice = lnd_invoice(sats(1_000)).await?;
+    blitzi.pay(&invoice).await?;
+    // Following the finished payment again doesn't report it again
+    blitzi.pay(&invoice).await?;
+    blitzi.payment_result(invoice.payment_hash()).await?;
+    blitzi.resume_pending_payments().await;
+
+    let mut sent = 0;
+    while let Ok(Some(event)) = tokio::time::timeout(Duration::from_secs(5), events.next()).await {
+        match event {
+            BlitziEvent::PaymentSent { .. } => sent += 1,
+            BlitziEvent::PaymentFailed { .. } => panic!("Unexpected event {event:?}"),
+            _ => {}
+        }
+    }
+    assert_eq!(sent, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_recovery_progress() -> anyhow::Result<()> {
+    let blitzi = funded_client(sats(10_000)).await?;
+    let mnemonic = blitzi.mnemonic()?.clone();
+    let balance = blitzi.balance().await;
+    blitzi.shutdown().await?;
+
+    let restored = Blitzi::builder()
+        .datadir(temp_datadir())
+        .federation_invite(test_federation()?)
+        .restore_from_mnemonic(mnemonic)
+        .recover_in_background(true)
+        .build()
+        .await?;
+    let updates = restored.subscribe_recovery().collect::<Vec<_>>().await;
+    assert!(updates.last().is_none_or(RecoveryProgress::is_done));
+    restored.await_recovery().await?;
+    assert!(restored.recovery_progress().await.is_done());
+    assert_eq!(restored.balance().await, balance);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pay_batch() -> anyhow::Result<()> {
+    let blitzi = funded_client(sats(20_000)).await?;
+
+    let first = lnd_invoice(sats(1_000)).await?;
+    let second = lnd_invoice(sats(2_000)).await?;
+    // Exceeds the balance
+    let too_large = lnd_invoice(sats(100_000)).await?;
+    let invoices = vec![
+        first.clone(),
+        second.clone(),
+        first.clone(),
+        too_large.clone(),
+    ];
+
+    let results = blitzi
+        .pay_batch(
+            invoices,
+            PayOptions {
+                concurrency: Some(2),
+                ..PayOptions::default()
+            },
+        )
+        .await;
+    // The duplicate is only paid and reported once
+    assert_eq!(
+        results
+            .iter()
+            .map(|(payment_hash, _)| payment_hash.0)
+            .collect::<Vec<_>>(),
+        vec![
+            *first.payment_hash(),
+            *second.payment_hash(),
+            *too_large.payment_hash()
+        ]
+    );
+    for (_, result) in &results[..2] {
+        let receipt = result.as_ref().expect("payment succeeded");
+        assert_eq!(receipt.preimage.payment_hash(), receipt.payment_hash);
+    }
+    assert!(results[2].1.is_err());
+
+    // Paying the batch again returns the previous results
+    let again = blitzi.pay_batch(vec![first], PayOptions::default()).await;
+    assert_eq!(again[0].1.as_ref().unwrap(), results[0].1.as_ref().unwrap());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_ecash() -> anyhow::Result<()> {
+    let sender = funded_client(sats(10_000)).await?;
+    let recipient = test_client().await?;
+
+    // Tokens can only be redeemed once
+    let token = sender
+        .transfer_ecash(sats(1_000), Duration::from_secs(60 * 60))
+        .await?;
+    assert!(sender.reclaim_transfer(&token).await.is_err());
+    assert_eq!(recipient.redeem_transfer(&token).await?, token.amount);
+    assert!(recipient.redeem_transfer(&token).await.is_err());
+    assert_eq!(recipient.balance().await, token.amount);
+
+    // Expired tokens can't be redeemed, the sender gets the funds back
+    let balance = sender.balance().await;
+    let token = sender
+        .transfer_ecash(sats(1_000), Duration::from_secs(2))
+        .await?;
+    assert!(sender.balance().await < balance);
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let error = recipient.redeem_transfer(&token).await.unwrap_err();
+    assert!(error.downcast_ref::<TransferExpired>().is_some());
+    sender.reclaim_transfer(&token).await?;
+    assert_eq!(sender.balance().await, balance);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_receive_ecash() -> anyhow::Result<()> {
+    let sender = funded_client(sats(10_000)).await?;
+    let recipient = test_client().await?;
+    let other = test_client().await?;
+
+    let spent = sender
+        .spend_ecash_with_timeout(sats(1_000), Duration::from_secs(60 * 60))
+        .await?;
+    assert_eq!(recipient.receive_ecash(&spent.notes).await?, spent.amount);
+    assert_eq!(recipient.balance().await, spent.amount);
+
+    // Notes can only be redeemed once, by the same or another client
+    for client in [&recipient, &other] {
+        let error = client.receive_ecash(&spent.notes).await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<EcashAlreadySpent>(),
+            Some(&EcashAlreadySpent {
+                amount: spent.amount
+            })
+        );
+    }
+    assert_eq!(other.balance().await, Amount::ZERO);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_check_federation_config() -> anyhow::Result<()> {
+    let blitzi = Blitzi::builder()
+        .datadir(temp_datadir())
+        .federation_invite(test_federation()?)
+        .config_check_interval(Duration::from_secs(1))
+        .build()
+        .await?;
+
+    // The guardians serve the config the client joined with
+    assert!(!blitzi.check_federation_config().await?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_external_id() -> anyhow::Result<()> {
+    let blitzi = test_client().await?;
+    let created = blitzi
+        .lightning_invoice_tagged(sats(1_000), "order", "order-1")
+        .await?;
+    assert_eq!(
+        blitzi.find_by_external_id("order-1").await?,
+        Some(created.operation_id)
+    );
+    assert_eq!(
+        blitzi
+            .invoice_external_id(created.payment_hash)
+            .await?
+            .as_deref(),
+        Some("order-1")
+    );
+    assert_eq!(blitzi.find_by_external_id("order-2").await?, None);
+
+    let error = blitzi
+        .lightning_invoice_tagged(sats(1_000), "order", "order-1")
+        .await
+        .unwrap_err();
+    assert!(error.downcast_ref::<ExternalIdInUse>().is_some());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_list_transactions() -> anyhow::Result<()> {
+    let blitzi = funded_client(sats(10_000)).await?;
+    let paid = lnd_invoice(sats(1_000)).await?;
+    blitzi.pay(&paid).await?;
+    let created = blitzi
+        .lightning_invoice_tagged(sats(2_000), "unpaid", "order-1")
+        .await?;
+
+    let invoices = blitzi
+        .list_transactions(
+            HistoryEntryKind::Receive,
+            Some(HistoryEntryStatus::Pending),
+            10,
+            None,
+        )
+        .await;
+    assert_eq!(invoices.len(), 1);
+    assert_eq!(invoices[0].payment_hash, Some(created.payment_hash));
+    assert_eq!(invoices[0].description.as_deref(), Some("unpaid"));
+    assert_eq!(invoices[0].external_id.as_deref(), Some("order-1"));
+
+    let payments = blitzi
+        .list_transactions(HistoryEntryKind::Pay, None, 10, None)
+        .await;
+    assert_eq!(payments.len(), 1);
+    assert_eq!(
+        payments[0].payment_hash,
+        Some(PaymentHash::from(paid.payment_hash()))
+    );
+    assert_eq!(payments[0].status, HistoryEntryStatus::Succeeded);
+
+    Ok(())
+}
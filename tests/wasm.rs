@@ -0,0 +1,18 @@
+//! Smoke test for wasm builds, run using
+//! `wasm-pack test --headless --firefox -- --no-default-features --features
+//! wasm`.
+#![cfg(target_family = "wasm")]
+
+use blitzi::Blitzi;
+use wasm_bindgen_test::wasm_bindgen_test;
+
+wasm_bindgen_test::wasm_bindgen_test_configure!(run_in_browser);
+
+#[wasm_bindgen_test]
+async fn test_new_requires_database() {
+    let error = Blitzi::new()
+        .await
+        .err()
+        .expect("There is no default database on wasm");
+    assert!(error.to_string().contains("BlitziBuilder::database"));
+}